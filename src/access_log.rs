@@ -0,0 +1,150 @@
+/// This module keeps an access log of every HTTP request to the web service,
+/// separate from the program's own log, to see who's knocking on the exposed
+/// port.
+///
+/// Each request gets a line in the access log file, either in the combined
+/// log format web servers use, with how long it took added at the end, or as
+/// a JSON object, like the history in `crate::history`. Both have the client's
+/// address, the request line, the status and the size of the response. IPv4
+/// clients of the dual stack listener are logged by their IPv4 address.
+use crate::config::{AccessLogConfig, AccessLogFormat};
+use chrono::{Local, SecondsFormat, Utc};
+use failure::{Error, Fail, ResultExt};
+use hyper::{Body, Request, Response};
+use serde::Serialize;
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    net::SocketAddr,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+use tracing::{info, warn};
+
+/// Request being served, noted before it's processed so it can be logged
+/// along with the response
+pub struct Pending {
+    started: Instant,
+    client: SocketAddr,
+    method: String,
+    uri: String,
+    version: String,
+    referer: Option<String>,
+    user_agent: Option<String>,
+}
+
+impl Pending {
+    /// Note the request from `client`, just received
+    pub fn new(request: &Request<Body>, client: SocketAddr) -> Pending {
+        let header = |name| {
+            request
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_owned)
+        };
+        Pending {
+            started: Instant::now(),
+            client,
+            method: request.method().to_string(),
+            uri: request.uri().to_string(),
+            version: format!("{:?}", request.version()),
+            referer: header("Referer"),
+            user_agent: header("User-Agent"),
+        }
+    }
+}
+
+/// Line of the JSON access log
+#[derive(Serialize)]
+struct Entry<'a> {
+    time: String,
+    client: String,
+    method: &'a str,
+    uri: &'a str,
+    version: &'a str,
+    status: u16,
+    bytes: Option<u64>,
+    latency_ms: u64,
+    referer: Option<&'a str>,
+    user_agent: Option<&'a str>,
+}
+
+/// Appends a line for each request to the access log file. Cloned for each
+/// request.
+#[derive(Clone)]
+pub struct AccessLog {
+    format: AccessLogFormat,
+    file: Arc<Mutex<File>>,
+}
+
+impl AccessLog {
+    /// Open the access log file, creating it if needed. Lines are added to
+    /// the end of an existing log.
+    pub fn open(config: &AccessLogConfig) -> Result<AccessLog, Error> {
+        let path = Path::new(&config.path);
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| e.context(format!("Could not open access log: {}", path.display())))?;
+        info!("Logging HTTP requests to: {}", path.display());
+
+        Ok(AccessLog {
+            format: config.format,
+            file: Arc::new(Mutex::new(file)),
+        })
+    }
+
+    /// Add the line for the request, now that it's been answered with
+    /// `response`. Failing to write is only logged, as the request was served
+    /// either way.
+    pub fn record(&self, request: Pending, response: &Response<Body>) {
+        if let Err(e) = self.write(request, response) {
+            warn!("Could not write to access log: {}", e);
+        }
+    }
+
+    fn write(&self, request: Pending, response: &Response<Body>) -> Result<(), Error> {
+        let status = response.status().as_u16();
+        let bytes = hyper::body::HttpBody::size_hint(response.body()).exact();
+        let latency_ms = request.started.elapsed().as_millis() as u64;
+
+        let mut line = match self.format {
+            AccessLogFormat::Combined => format!(
+                "{} - - [{}] \"{} {} {}\" {} {} \"{}\" \"{}\" {}",
+                request.client.ip().to_canonical(),
+                Local::now().format("%d/%b/%Y:%H:%M:%S %z"),
+                request.method,
+                request.uri,
+                request.version,
+                status,
+                bytes.map_or("-".to_owned(), |bytes| bytes.to_string()),
+                request.referer.as_deref().unwrap_or("-"),
+                request.user_agent.as_deref().unwrap_or("-"),
+                latency_ms,
+            ),
+            AccessLogFormat::Json => serde_json::to_string(&Entry {
+                time: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+                client: request.client.ip().to_canonical().to_string(),
+                method: &request.method,
+                uri: &request.uri,
+                version: &request.version,
+                status,
+                bytes,
+                latency_ms,
+                referer: request.referer.as_deref(),
+                user_agent: request.user_agent.as_deref(),
+            })?,
+        };
+        line.push('\n');
+
+        self.file
+            .lock()
+            .unwrap()
+            .write_all(line.as_bytes())
+            .context("Could not write line")?;
+        Ok(())
+    }
+}
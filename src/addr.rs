@@ -0,0 +1,103 @@
+/// This module parses the addresses given on the command line and in the
+/// config file, IPv4 or IPv6, and opens the listeners for the web service,
+/// HomeKit and replay.
+///
+/// IPv6 literals can be given bare, like `fd00::50`, or in brackets, which
+/// is needed to give a port along with them, like `[fd00::50]:8102`. Hostnames
+/// with only AAAA records are resolved like any other, see `crate::resolve`.
+///
+/// Listening on `::`, the default, accepts connections over both IPv4 and
+/// IPv6 where the system allows it, falling back to IPv4 only if it has no
+/// IPv6.
+use failure::{bail, format_err, Error, ResultExt};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener};
+use tracing::warn;
+
+/// Number of connections waiting to be accepted before more are refused
+const BACKLOG: i32 = 1024;
+
+/// Host without the brackets around an IPv6 literal
+pub fn unbracket(host: &str) -> &str {
+    host.strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host)
+}
+
+/// Split a host given on the command line into the host and its port, if it
+/// has one. An IPv6 literal only has a port when it's in brackets.
+///
+/// ```
+/// use alexa_avr_control::addr::split_host;
+///
+/// assert_eq!(split_host("[fd00::50]:8102").unwrap(), ("fd00::50", Some(8102)));
+/// assert_eq!(split_host("fd00::50").unwrap(), ("fd00::50", None));
+/// assert_eq!(split_host("avr.local:23").unwrap(), ("avr.local", Some(23)));
+/// ```
+pub fn split_host(host: &str) -> Result<(&str, Option<u16>), Error> {
+    let (name, port) = match host.strip_prefix('[') {
+        Some(rest) => match rest.split_once(']') {
+            Some((name, "")) => (name, None),
+            Some((name, port)) => match port.strip_prefix(':') {
+                Some(port) => (name, Some(port)),
+                None => bail!("Unexpected text after IPv6 address: {}", host),
+            },
+            None => bail!("Missing closing bracket in IPv6 address: {}", host),
+        },
+        None => match host.split_once(':') {
+            Some((name, port)) if !port.contains(':') => (name, Some(port)),
+            _ => (host, None),
+        },
+    };
+    if name.is_empty() {
+        bail!("No host given: {}", host);
+    }
+    let port = port
+        .map(|port| port.parse::<u16>())
+        .transpose()
+        .map_err(|_| format_err!("Port provided not valid: {}", host))?;
+    Ok((name, port))
+}
+
+/// Host and port as they'd be written in a URL, with an IPv6 literal in
+/// brackets
+pub fn join(host: &str, port: u16) -> String {
+    match unbracket(host).parse::<IpAddr>() {
+        Ok(ip) => SocketAddr::new(ip, port).to_string(),
+        Err(_) => format!("{}:{}", host, port),
+    }
+}
+
+/// Parse an address to listen on, an IPv6 one optionally in brackets
+pub fn parse_ip(addr: &str) -> Result<IpAddr, Error> {
+    unbracket(addr)
+        .parse::<IpAddr>()
+        .map_err(|_| format_err!("Address provided not valid: {}", addr))
+}
+
+/// Bind a listener to `addr`. The IPv6 unspecified address listens on IPv4
+/// too, or only on IPv4 if the system has no IPv6.
+pub fn listener(addr: SocketAddr) -> Result<TcpListener, Error> {
+    match bind(addr) {
+        Err(e) if addr.ip() == Ipv6Addr::UNSPECIFIED => {
+            warn!("Could not listen on IPv6, listening on IPv4 only: {}", e);
+            Ok(bind(SocketAddr::new(
+                Ipv4Addr::UNSPECIFIED.into(),
+                addr.port(),
+            ))?)
+        }
+        result => Ok(result.context(format!("Could not listen on {}", addr))?),
+    }
+}
+
+fn bind(addr: SocketAddr) -> Result<TcpListener, std::io::Error> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(!addr.ip().is_unspecified())?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(BACKLOG)?;
+    Ok(socket.into())
+}
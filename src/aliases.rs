@@ -0,0 +1,225 @@
+/// This module resolves an input's spoken name to its number.
+///
+/// The Input intent takes either the input's number or its name. Names are
+/// matched against the AVR's input names and the aliases configured for them,
+/// like "playstation" and "ps5" for Game, ignoring case, spaces and
+/// punctuation. Alexa passes along whatever it heard for the name, so a name
+/// that doesn't match exactly gets the closest input name or alias, as long as
+/// it's close enough. That way the interaction model only needs the input
+/// names, and not every way of saying them.
+///
+/// Inputs can also be hidden, leaving them out of the lists of inputs given to
+/// Alexa, Google and HomeKit. A hidden input's name isn't matched any more,
+/// though its aliases still are. Inputs the AVR's model doesn't have are
+/// hidden too.
+///
+/// Once learned from the AVR, see `crate::labels`, the names inputs were given
+/// on its on-screen menu are used in place of the AVR's names, and matched
+/// too, and inputs the AVR says it doesn't have are hidden.
+use crate::{avr::AvrCommand, protocol::AvrProtocol};
+use failure::{bail, Error};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::{Arc, RwLock},
+};
+use tracing::info;
+
+/// How similar a name must be to an input name or alias to match it, from 0
+/// to 1
+const MIN_SIMILARITY: f64 = 0.75;
+
+/// Input names and aliases of an AVR, normalized, along with the number of
+/// the input each is for, the numbers and names of the inputs that aren't
+/// hidden, and what was learned from the AVR. Cloned for each request,
+/// sharing what's learned.
+#[derive(Clone, Debug, Default)]
+pub struct InputNames {
+    names: Vec<(String, u8)>,
+    visible: Vec<(u8, String)>,
+    learned: Arc<RwLock<Learned>>,
+}
+
+/// What the AVR reported for one of its inputs
+#[derive(Clone, Debug, PartialEq)]
+pub enum Label {
+    /// Input has the name it came with
+    Default,
+    /// Input was given this name on the on-screen menu
+    Renamed(String),
+    /// AVR doesn't have the input
+    Missing,
+}
+
+/// Names given to inputs on the AVR's on-screen menu, by input number, and
+/// the numbers of the inputs the AVR doesn't have
+#[derive(Debug, Default)]
+struct Learned {
+    labels: BTreeMap<u8, String>,
+    missing: BTreeSet<u8>,
+}
+
+impl InputNames {
+    /// Names of the protocol's inputs, along with `aliases`, keyed by the
+    /// name or number of the input they're for, leaving out the names of the
+    /// `hidden` inputs, given the same way, and of those the AVR doesn't
+    /// support
+    pub fn new(
+        protocol: &dyn AvrProtocol,
+        aliases: &BTreeMap<String, Vec<String>>,
+        hidden: &[String],
+    ) -> Result<InputNames, Error> {
+        let inputs = protocol.inputs();
+        let mut names: Vec<_> = inputs
+            .iter()
+            .zip(1..)
+            .map(|(name, n)| (normalize(name), n))
+            .collect();
+
+        let mut hidden_numbers: Vec<_> = (1..=inputs.len() as u8)
+            .filter(|n| !protocol.supports(&AvrCommand::ChangeInput(*n)))
+            .collect();
+        for input in hidden {
+            match number(&inputs, &names, input) {
+                Some(n) => hidden_numbers.push(n),
+                None => bail!("No input named {:?} to hide", input),
+            }
+        }
+
+        let mut alias_names = vec![];
+        for (input, input_aliases) in aliases {
+            let n = match number(&inputs, &names, input) {
+                Some(n) => n,
+                None => bail!("No input named {:?} for aliases", input),
+            };
+            alias_names.extend(input_aliases.iter().map(|alias| (normalize(alias), n)));
+        }
+        names.retain(|(_, n)| !hidden_numbers.contains(n));
+        names.extend(alias_names);
+
+        let visible = inputs
+            .into_iter()
+            .zip(1..)
+            .filter(|(_, n)| !hidden_numbers.contains(n))
+            .map(|(name, n)| (n, name))
+            .collect();
+
+        Ok(InputNames {
+            names,
+            visible,
+            learned: Arc::default(),
+        })
+    }
+
+    /// Numbers and names of the inputs that aren't hidden, using the names
+    /// learned from the AVR
+    pub fn visible(&self) -> Vec<(u8, String)> {
+        let learned = self.learned.read().unwrap();
+        self.visible
+            .iter()
+            .filter(|(n, _)| !learned.missing.contains(n))
+            .map(|(n, name)| (*n, learned.labels.get(n).unwrap_or(name).clone()))
+            .collect()
+    }
+
+    /// Name of the input, as learned from the AVR or otherwise the protocol's,
+    /// given the protocol's names
+    pub fn name(&self, n: u8, inputs: &[String]) -> Option<String> {
+        if let Some(label) = self.learned.read().unwrap().labels.get(&n) {
+            return Some(label.clone());
+        }
+        usize::from(n)
+            .checked_sub(1)
+            .and_then(|i| inputs.get(i))
+            .cloned()
+    }
+
+    /// Record what the AVR reported for the input
+    pub fn learn(&self, n: u8, label: Label) {
+        let mut learned = self.learned.write().unwrap();
+        learned.labels.remove(&n);
+        learned.missing.remove(&n);
+        match label {
+            Label::Default => {}
+            Label::Renamed(label) => {
+                learned.labels.insert(n, label);
+            }
+            Label::Missing => {
+                learned.missing.insert(n);
+            }
+        }
+    }
+
+    /// Whether the input number is hidden
+    pub fn is_hidden(&self, n: u8) -> bool {
+        !self.visible().iter().any(|(visible, _)| *visible == n)
+    }
+
+    /// Number of the input going by the spoken name, or the closest to it
+    pub fn resolve(&self, spoken: &str) -> Option<u8> {
+        let spoken = normalize(spoken);
+        let mut names = self.names.clone();
+        names.extend(
+            self.learned
+                .read()
+                .unwrap()
+                .labels
+                .iter()
+                .filter(|(n, _)| self.visible.iter().any(|(visible, _)| visible == *n))
+                .map(|(n, label)| (normalize(label), *n)),
+        );
+        if let Some((_, n)) = names.iter().find(|(name, _)| *name == spoken) {
+            return Some(*n);
+        }
+
+        let (name, n, similarity) = names
+            .iter()
+            .map(|(name, n)| (name, *n, strsim::normalized_levenshtein(name, &spoken)))
+            .max_by(|(_, _, a), (_, _, b)| a.total_cmp(b))?;
+        if similarity < MIN_SIMILARITY {
+            return None;
+        }
+        info!("Heard {:?} as input name {:?}", spoken, name);
+        Some(n)
+    }
+}
+
+/// Number of the input given by its number, or its name as normalized in
+/// `names`
+fn number(inputs: &[String], names: &[(String, u8)], input: &str) -> Option<u8> {
+    match input.parse::<u8>() {
+        Ok(n) if n >= 1 && usize::from(n) <= inputs.len() => Some(n),
+        _ => names
+            .iter()
+            .find(|(name, _)| *name == normalize(input))
+            .map(|(_, n)| *n),
+    }
+}
+
+/// The item going by the spoken name, or the closest to it if it's close
+/// enough, given items along with their normalized names. Used for names other
+/// than inputs', like presets'.
+pub(crate) fn closest<'a, T>(
+    names: impl IntoIterator<Item = (&'a str, T)>,
+    spoken: &str,
+) -> Option<T> {
+    let spoken = normalize(spoken);
+    let (name, item, similarity) = names
+        .into_iter()
+        .map(|(name, item)| (name, item, strsim::normalized_levenshtein(name, &spoken)))
+        .max_by(|(_, _, a), (_, _, b)| a.total_cmp(b))?;
+    if similarity < MIN_SIMILARITY {
+        return None;
+    }
+    if name != spoken {
+        info!("Heard {:?} as {:?}", spoken, name);
+    }
+    Some(item)
+}
+
+/// Lower case letters and digits of the name, so "PS-5" and "ps 5" match
+pub(crate) fn normalize(name: &str) -> String {
+    name.chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
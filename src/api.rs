@@ -0,0 +1,180 @@
+/// This module implements the optional local control API, for scripts and
+/// home automation to send commands to the AVRs over HTTP.
+///
+/// Requests must have the configured `token` as a bearer token, unless they
+/// come with a client certificate over TLS, see `crate::tls`. Commands are
+/// sent through the AVR's command queue, like the skill's, and failures are
+/// answered with a JSON error with a stable code, so callers can tell them
+/// apart without parsing messages:
+///
+/// ```json
+/// {"error": {"code": "power_off", "message": "Power is off, it must be turned on to execute command."}}
+/// ```
+///
+/// Raw codes configured for an AVR can be posted to `/api/raw`, see
+/// `crate::raw`, which answers with what the AVR sent back.
+use crate::{
+    avr::{AvrCommand, AvrError},
+    config::ApiConfig,
+    device::{Device, Devices},
+    queue::Source,
+    raw::RawError,
+};
+use failure::{bail, Error};
+use hyper::StatusCode;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+/// Command request posted to `/api/command`. The first AVR is used when no
+/// device is given.
+#[derive(Deserialize, Debug)]
+pub struct CommandRequest {
+    pub device: Option<String>,
+    pub command: AvrCommand,
+}
+
+/// Raw code request posted to `/api/raw`. The first AVR is used when no
+/// device is given.
+#[derive(Deserialize, Debug)]
+pub struct RawRequest {
+    pub device: Option<String>,
+    pub code: String,
+}
+
+/// What the AVR answered a raw code with, each message on its own line
+#[derive(Serialize, Debug)]
+pub struct RawResponse {
+    pub response: String,
+}
+
+/// Error answered by the API, serialized as `{"error": {"code", "message"}}`
+#[derive(Serialize, Debug)]
+pub struct ApiError {
+    #[serde(skip)]
+    pub status: StatusCode,
+    error: ErrorBody,
+}
+
+#[derive(Serialize, Debug)]
+struct ErrorBody {
+    code: &'static str,
+    message: String,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> ApiError {
+        ApiError {
+            status,
+            error: ErrorBody {
+                code,
+                message: message.into(),
+            },
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        self.error.code
+    }
+
+    /// The error a command failed with. `AvrError`s get their own code, as do
+    /// raw codes that aren't configured, anything else is `internal`.
+    pub fn from_error(e: &Error) -> ApiError {
+        if let Some(RawError::NotAllowed { .. }) = e.downcast_ref::<RawError>() {
+            return ApiError::new(StatusCode::FORBIDDEN, "not_allowed", e.to_string());
+        }
+        let (status, code) = match e.downcast_ref::<AvrError>() {
+            Some(AvrError::Timeout) => (StatusCode::GATEWAY_TIMEOUT, "timeout"),
+            Some(AvrError::Busy) => (StatusCode::SERVICE_UNAVAILABLE, "busy"),
+            Some(AvrError::PowerAlreadyOff) | Some(AvrError::PowerOffCantProcess) => {
+                (StatusCode::CONFLICT, "power_off")
+            }
+            Some(AvrError::PowerAlreadyOn) => (StatusCode::CONFLICT, "power_on"),
+            Some(AvrError::ResponseDoesntMatch { .. }) => {
+                (StatusCode::BAD_GATEWAY, "validation_failed")
+            }
+            Some(AvrError::Unsupported { .. }) => (StatusCode::UNPROCESSABLE_ENTITY, "unsupported"),
+            Some(AvrError::NetworkStandbyOff) => (StatusCode::GATEWAY_TIMEOUT, "network_standby"),
+            None => (StatusCode::INTERNAL_SERVER_ERROR, "internal"),
+        };
+        ApiError::new(status, code, e.to_string())
+    }
+}
+
+pub struct Api {
+    config: ApiConfig,
+    devices: Devices,
+}
+
+impl Api {
+    pub fn new(config: ApiConfig, devices: &Devices) -> Result<Api, Error> {
+        if config.token.is_none() && config.tls.is_none() {
+            bail!("The control API needs a token, or TLS with client certificates");
+        }
+        info!("Local control API enabled");
+        Ok(Api {
+            config,
+            devices: devices.clone(),
+        })
+    }
+
+    /// Whether the request was already verified, by a client certificate or
+    /// Basic credentials, or the Authorization header has the configured token
+    pub fn authorized(&self, authorization: &str, verified: bool) -> bool {
+        verified
+            || self
+                .config
+                .token
+                .as_ref()
+                .is_some_and(|token| authorization == format!("Bearer {}", token))
+    }
+
+    /// Process the command against the requested AVR
+    pub async fn command(&self, request: CommandRequest) -> Result<(), ApiError> {
+        process(&self.devices, request, Source::Api).await
+    }
+
+    /// Send the raw code to the requested AVR, if it's configured for it
+    pub async fn raw(&self, request: RawRequest) -> Result<RawResponse, ApiError> {
+        let device = device(&self.devices, request.device)?;
+        info!("Raw code for {}: {:?}", device.name, request.code);
+
+        match device.raw.send(&request.code).await {
+            Ok(response) => Ok(RawResponse { response }),
+            Err(e) => {
+                warn!("Raw code failed: {}", e);
+                Err(ApiError::from_error(&e))
+            }
+        }
+    }
+}
+
+/// The requested AVR, or the `unknown_device` error
+fn device(devices: &Devices, name: Option<String>) -> Result<&Device, ApiError> {
+    devices.get(name.as_deref()).ok_or_else(|| {
+        ApiError::new(
+            StatusCode::NOT_FOUND,
+            "unknown_device",
+            format!("No AVR named {:?}", name.unwrap_or_default()),
+        )
+    })
+}
+
+/// Process the command against the requested AVR, through its command queue,
+/// with the error code on failure
+pub async fn process(
+    devices: &Devices,
+    request: CommandRequest,
+    source: Source,
+) -> Result<(), ApiError> {
+    let device = device(devices, request.device)?;
+    info!("Command for {}: {:?}", device.name, request.command);
+
+    device
+        .queue
+        .process(request.command, source)
+        .await
+        .map_err(|e| {
+            warn!("Command failed: {}", e);
+            ApiError::from_error(&e)
+        })
+}
@@ -0,0 +1,154 @@
+/// This module keeps an audit log of every command sent to the AVRs, to
+/// answer who turned the volume all the way up, and when.
+///
+/// The audit log is a file with one `Entry` per line, as JSON, only ever
+/// appended to. Each AVR's command queue adds an entry for every command it
+/// processes, with what it came from, the AVR's state before and after, and
+/// whether it worked. Unlike the history in `crate::history`, it also has
+/// who asked, when that's known: the Alexa person or account ID the request
+/// came with. That's tracked per task while the request is processed, and
+/// taken along with each command it adds to a queue.
+use crate::{avr::AvrCommand, events::CachedState, queue::Source};
+use chrono::{SecondsFormat, Utc};
+use failure::{Error, Fail, ResultExt};
+use serde::Serialize;
+use std::{
+    fs::{File, OpenOptions},
+    future::Future,
+    io::Write,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+use tracing::info;
+
+tokio::task_local! {
+    /// Alexa person or account ID of the request being processed by this task
+    static USER: Option<String>;
+}
+
+/// Run `future` with the commands it sends attributed to `user`
+pub async fn as_user<F: Future>(user: Option<String>, future: F) -> F::Output {
+    USER.scope(user, future).await
+}
+
+/// Who the commands sent by this task are attributed to, if anyone
+pub fn current_user() -> Option<String> {
+    USER.try_with(Clone::clone).ok().flatten()
+}
+
+/// The AVR's state a command can change, as far as it's known
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct State {
+    pub power: Option<bool>,
+    pub volume: Option<u8>,
+    pub mute: Option<bool>,
+    pub input: Option<String>,
+    pub preset: Option<String>,
+    pub sound_retriever: Option<bool>,
+    pub dialog_enhancement: Option<bool>,
+    pub listening_mode: Option<String>,
+    pub speaker_system: Option<String>,
+    pub subwoofer_level: Option<u8>,
+    pub lfe_attenuation: Option<u8>,
+    pub video_converter: Option<bool>,
+    pub video_resolution: Option<String>,
+    pub network_standby: Option<bool>,
+}
+
+impl From<CachedState> for State {
+    fn from(state: CachedState) -> State {
+        State {
+            power: state.power,
+            volume: state.volume,
+            mute: state.mute,
+            input: state.input,
+            preset: state.preset,
+            sound_retriever: state.sound_retriever,
+            dialog_enhancement: state.dialog_enhancement,
+            listening_mode: state.listening_mode,
+            speaker_system: state.speaker_system,
+            subwoofer_level: state.subwoofer_level,
+            lfe_attenuation: state.lfe_attenuation,
+            video_converter: state.video_converter,
+            video_resolution: state.video_resolution,
+            network_standby: state.network_standby,
+        }
+    }
+}
+
+/// Command processed against an AVR, who it came from, the AVR's state
+/// before and after, and why it failed, if it did
+#[derive(Serialize, Debug, Clone)]
+pub struct Entry {
+    pub time: String,
+    pub device: String,
+    pub source: Source,
+    pub user: Option<String>,
+    pub command: AvrCommand,
+    pub before: State,
+    pub after: State,
+    pub ok: bool,
+    pub error: Option<String>,
+}
+
+impl Entry {
+    /// Entry for a command processed just now
+    pub fn new(
+        device: &str,
+        source: Source,
+        user: Option<String>,
+        command: AvrCommand,
+        before: State,
+        after: State,
+        result: Result<(), &Error>,
+    ) -> Entry {
+        Entry {
+            time: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            device: device.to_owned(),
+            source,
+            user,
+            command,
+            before,
+            after,
+            ok: result.is_ok(),
+            error: result.err().map(ToString::to_string),
+        }
+    }
+}
+
+/// Appends entries to the audit log file. Cloned for each command queue.
+#[derive(Clone)]
+pub struct Audit {
+    file: Arc<Mutex<File>>,
+}
+
+impl Audit {
+    /// Open the audit log file, creating it if needed. Entries are added to
+    /// the end of an existing log.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Audit, Error> {
+        let path = path.as_ref();
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| e.context(format!("Could not open audit log: {}", path.display())))?;
+        info!("Recording audit log to: {}", path.display());
+
+        Ok(Audit {
+            file: Arc::new(Mutex::new(file)),
+        })
+    }
+
+    /// Add the entry to the end of the audit log
+    pub fn record(&self, entry: &Entry) -> Result<(), Error> {
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+
+        self.file
+            .lock()
+            .unwrap()
+            .write_all(line.as_bytes())
+            .context("Could not write to audit log")?;
+        Ok(())
+    }
+}
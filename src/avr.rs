@@ -2,23 +2,78 @@
 /// Intent into the proper AVR command code that can be sent over telnet
 /// to control the AVR. It will also validate that the response from the
 /// AVR via telnet matches the expected response, confirming that the command
-/// was executed successfuly.
-use crate::{CHANNEL_A, CHANNEL_B};
-use crossbeam_channel::select;
-use failure::{bail, Error, Fail};
-use log::{debug, info};
-use std::time::Duration;
-
-/// Entry point to use from skill module to request the appropriate command
-pub fn process(cmd: AvrCommand) -> Result<(), Error> {
-    send_and_validate(cmd)?;
+/// was executed successfuly.   
+///
+/// The actual codes and responses depend on the brand of AVR, and come from
+/// the connection's `AvrProtocol`.
+///
+/// In dry run mode, commands are logged and checked against the cached state
+/// but never sent, and the cached state is updated as if they had been.
+///
+/// Before a command sets the volume or mutes, the volume and mute are
+/// remembered, so they can be put back, see `EventBus::remember_volume`.
+use crate::{
+    events::AvrEvent,
+    protocol::{AvrProtocol, Message},
+    telnet::AvrConnection,
+};
+use failure::{bail, format_err, Error, Fail};
+use serde::{Deserialize, Serialize};
+use std::{convert::TryFrom, time::Duration};
+use tokio::time::{sleep, Instant};
+use tracing::{debug, info};
+
+/// Most times the AVR's state is queried to confirm a command
+const VERIFY_ATTEMPTS: u32 = 4;
+
+/// Time between the queries confirming a command
+const VERIFY_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How long after the first query confirming a command it can be queried again
+const VERIFY_DEADLINE: Duration = Duration::from_millis(2_000);
+
+/// How long to wait for the AVR to confirm setting the volume directly,
+/// before stepping it there instead
+const DIRECT_VOLUME_WAIT: Duration = Duration::from_millis(1_500);
+
+/// Entry point to use from skill module to request the appropriate command.
+/// Returns the AVR's response confirming the command, which is empty in dry
+/// run mode.
+pub async fn process(cmd: AvrCommand, conn: &AvrConnection) -> Result<String, Error> {
+    if !conn.protocol().supports(&cmd) {
+        return Err(AvrError::Unsupported { command: cmd }.into());
+    }
+    if conn.dry_run() {
+        dry_run(cmd, conn)?;
+        return Ok(String::new());
+    }
+    send_and_validate(cmd, conn).await
+}
+
+/// Query the AVR's power, volume, mute and input, and whether headphones are
+/// plugged in if its protocol can ask, so the responses update its cached
+/// state
+pub async fn refresh(conn: &AvrConnection) -> Result<(), Error> {
+    for query in &[
+        AvrQuery::Power,
+        AvrQuery::Volume,
+        AvrQuery::Mute,
+        AvrQuery::Input,
+    ] {
+        query.query(conn).await?;
+    }
+    if conn.protocol().headphones_code().is_some() {
+        AvrQuery::Headphones.query(conn).await?;
+    }
     Ok(())
 }
 
 /// Commands that can be sent to AVR
-#[derive(PartialEq)]
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
 pub enum AvrCommand {
     SetVolume(u8),
+    /// Volume in dB, rounded to the nearest level the AVR has, like -30.5
+    SetVolumeDb(f32),
     Mute,
     Unmute,
     PowerOn,
@@ -26,228 +81,633 @@ pub enum AvrCommand {
     ChangeInput(u8),
     VolumeDown,
     VolumeUp,
+    SelectPreset(String),
+    Gallery(Key),
+    Ipod(Key),
+    Adapter(Key),
+    BluetoothPairing,
+    SoundRetrieverOn,
+    SoundRetrieverOff,
+    DialogEnhancementOn,
+    DialogEnhancementOff,
+    SetListeningMode(String),
+    /// Speaker system setting, like surround back speakers or the B speakers
+    /// assigned to Zone 2, by the AVR's code for it
+    SetSpeakerSystem(String),
+    /// Subwoofer channel level up or down a step, for more or less bass
+    SubwooferUp,
+    SubwooferDown,
+    /// dB the LFE channel is attenuated by, like 10
+    SetLfeAttenuation(u8),
+    /// Video converter, upscaling analog sources to HDMI
+    VideoConverterOn,
+    VideoConverterOff,
+    /// Resolution video is output at over HDMI, by the AVR's code for it
+    SetVideoResolution(String),
+    /// Keep the network on in standby, so the AVR can be turned on over
+    /// telnet. There's deliberately no command turning it off.
+    NetworkStandbyOn,
+}
+
+/// Key of a source's on-screen menu or transport controls
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum Key {
+    Up,
+    Down,
+    Left,
+    Right,
+    Enter,
+    Return,
+    Play,
+    Pause,
+    Stop,
+    Previous,
+    Next,
 }
 
-enum AvrQuery {
+/// Queries that can be sent to AVR to get its current state
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum AvrQuery {
     Volume,
     Mute,
     Power,
     Input,
+    Preset,
+    SoundRetriever,
+    DialogEnhancement,
+    ListeningMode,
+    SpeakerSystem,
+    SubwooferLevel,
+    LfeAttenuation,
+    /// Whether headphones are plugged in
+    Headphones,
+    VideoConverter,
+    VideoResolution,
+    NetworkStandby,
+    /// Name input `n` was given on the AVR's on-screen menu
+    InputLabel(u8),
+    /// Model name and firmware version, to tell which profile fits it
+    Model,
+    Firmware,
 }
 
 impl AvrCommand {
-    /// Convert enum to the appropriate telnet command supported
-    /// by the AVR
-    fn code(&self) -> String {
-        match &self {
-            AvrCommand::SetVolume(n) => get_volume_code(*n),
-            AvrCommand::ChangeInput(n) => get_input_code(*n),
-            AvrCommand::PowerOn => "PO\r".to_owned(),
-            AvrCommand::PowerOff => "PF\r".to_owned(),
-            AvrCommand::Mute => "MO\r".to_owned(),
-            AvrCommand::Unmute => "MF\r".to_owned(),
-            AvrCommand::VolumeDown => "VD\r\n".to_owned(),
-            AvrCommand::VolumeUp => "VU\r\n".to_owned(),
+    /// Whether this command changes the power state of the AVR
+    pub fn is_power(&self) -> bool {
+        matches!(self, AvrCommand::PowerOn | AvrCommand::PowerOff)
+    }
+
+    /// Whether this command only changes what the speakers play, which
+    /// headphones being plugged in turns off
+    pub fn is_speaker(&self) -> bool {
+        matches!(
+            self,
+            AvrCommand::SetSpeakerSystem(_)
+                | AvrCommand::SubwooferUp
+                | AvrCommand::SubwooferDown
+                | AvrCommand::SetLfeAttenuation(_)
+        )
+    }
+
+    /// Name of the command, without its value, like `SetVolume`
+    pub fn name(&self) -> &'static str {
+        match self {
+            AvrCommand::SetVolume(_) => "SetVolume",
+            AvrCommand::SetVolumeDb(_) => "SetVolumeDb",
+            AvrCommand::Mute => "Mute",
+            AvrCommand::Unmute => "Unmute",
+            AvrCommand::PowerOn => "PowerOn",
+            AvrCommand::PowerOff => "PowerOff",
+            AvrCommand::ChangeInput(_) => "ChangeInput",
+            AvrCommand::VolumeDown => "VolumeDown",
+            AvrCommand::VolumeUp => "VolumeUp",
+            AvrCommand::SelectPreset(_) => "SelectPreset",
+            AvrCommand::Gallery(_) => "Gallery",
+            AvrCommand::Ipod(_) => "Ipod",
+            AvrCommand::Adapter(_) => "Adapter",
+            AvrCommand::BluetoothPairing => "BluetoothPairing",
+            AvrCommand::SoundRetrieverOn => "SoundRetrieverOn",
+            AvrCommand::SoundRetrieverOff => "SoundRetrieverOff",
+            AvrCommand::DialogEnhancementOn => "DialogEnhancementOn",
+            AvrCommand::DialogEnhancementOff => "DialogEnhancementOff",
+            AvrCommand::SetListeningMode(_) => "SetListeningMode",
+            AvrCommand::SetSpeakerSystem(_) => "SetSpeakerSystem",
+            AvrCommand::SubwooferUp => "SubwooferUp",
+            AvrCommand::SubwooferDown => "SubwooferDown",
+            AvrCommand::SetLfeAttenuation(_) => "SetLfeAttenuation",
+            AvrCommand::VideoConverterOn => "VideoConverterOn",
+            AvrCommand::VideoConverterOff => "VideoConverterOff",
+            AvrCommand::SetVideoResolution(_) => "SetVideoResolution",
+            AvrCommand::NetworkStandbyOn => "NetworkStandbyOn",
         }
     }
 
-    fn query(&self) -> Result<String, Error> {
-        let query_type = match &self {
-            AvrCommand::SetVolume(_) => AvrQuery::Volume,
-            AvrCommand::ChangeInput(_) => AvrQuery::Input,
-            AvrCommand::PowerOn => AvrQuery::Power,
-            AvrCommand::PowerOff => AvrQuery::Power,
-            AvrCommand::Mute => AvrQuery::Mute,
-            AvrCommand::Unmute => AvrQuery::Mute,
-            AvrCommand::VolumeDown => AvrQuery::Volume,
-            AvrCommand::VolumeUp => AvrQuery::Volume,
-        };
-        query_type.query()
+    /// Whether every AVR can be sent this command, rather than only those
+    /// whose protocol supports it
+    pub fn is_basic(&self) -> bool {
+        !matches!(
+            self,
+            AvrCommand::SelectPreset(_)
+                | AvrCommand::Gallery(_)
+                | AvrCommand::Ipod(_)
+                | AvrCommand::Adapter(_)
+                | AvrCommand::BluetoothPairing
+                | AvrCommand::SoundRetrieverOn
+                | AvrCommand::SoundRetrieverOff
+                | AvrCommand::DialogEnhancementOn
+                | AvrCommand::DialogEnhancementOff
+                | AvrCommand::SetListeningMode(_)
+                | AvrCommand::SetSpeakerSystem(_)
+                | AvrCommand::SubwooferUp
+                | AvrCommand::SubwooferDown
+                | AvrCommand::SetLfeAttenuation(_)
+                | AvrCommand::VideoConverterOn
+                | AvrCommand::VideoConverterOff
+                | AvrCommand::SetVideoResolution(_)
+                | AvrCommand::NetworkStandbyOn
+                | AvrCommand::SetVolumeDb(_)
+        )
     }
 
-    fn expected(&self) -> String {
+    /// Query to confirm the command was executed. Commands that don't change
+    /// any state the AVR reports, like menu keys, have none, and are confirmed
+    /// by the AVR not answering with an error.
+    pub fn query_type(&self) -> Option<AvrQuery> {
         match &self {
-            AvrCommand::SetVolume(_) => format!("VOL{}\r\n", &self.code()[0..3]),
-            AvrCommand::ChangeInput(_) => format!("FN{}\r\n", &self.code()[0..2]),
-            AvrCommand::Mute => "MUT0\r\n".to_owned(),
-            AvrCommand::Unmute => "MUT1\r\n".to_owned(),
-            AvrCommand::PowerOn => "PWR0\r\n".to_owned(),
-            AvrCommand::PowerOff => "PWR2\r\n".to_owned(),
-            AvrCommand::VolumeDown => "VOL".to_owned(),
-            AvrCommand::VolumeUp => "VOL".to_owned(),
+            AvrCommand::SetVolume(_) => Some(AvrQuery::Volume),
+            AvrCommand::SetVolumeDb(_) => Some(AvrQuery::Volume),
+            AvrCommand::ChangeInput(_) => Some(AvrQuery::Input),
+            AvrCommand::PowerOn => Some(AvrQuery::Power),
+            AvrCommand::PowerOff => Some(AvrQuery::Power),
+            AvrCommand::Mute => Some(AvrQuery::Mute),
+            AvrCommand::Unmute => Some(AvrQuery::Mute),
+            AvrCommand::VolumeDown => Some(AvrQuery::Volume),
+            AvrCommand::VolumeUp => Some(AvrQuery::Volume),
+            AvrCommand::SelectPreset(_) => Some(AvrQuery::Preset),
+            AvrCommand::SoundRetrieverOn => Some(AvrQuery::SoundRetriever),
+            AvrCommand::SoundRetrieverOff => Some(AvrQuery::SoundRetriever),
+            AvrCommand::DialogEnhancementOn => Some(AvrQuery::DialogEnhancement),
+            AvrCommand::DialogEnhancementOff => Some(AvrQuery::DialogEnhancement),
+            AvrCommand::SetListeningMode(_) => Some(AvrQuery::ListeningMode),
+            AvrCommand::SetSpeakerSystem(_) => Some(AvrQuery::SpeakerSystem),
+            AvrCommand::SubwooferUp => Some(AvrQuery::SubwooferLevel),
+            AvrCommand::SubwooferDown => Some(AvrQuery::SubwooferLevel),
+            AvrCommand::SetLfeAttenuation(_) => Some(AvrQuery::LfeAttenuation),
+            AvrCommand::VideoConverterOn => Some(AvrQuery::VideoConverter),
+            AvrCommand::VideoConverterOff => Some(AvrQuery::VideoConverter),
+            AvrCommand::SetVideoResolution(_) => Some(AvrQuery::VideoResolution),
+            AvrCommand::NetworkStandbyOn => Some(AvrQuery::NetworkStandby),
+            AvrCommand::Gallery(_)
+            | AvrCommand::Ipod(_)
+            | AvrCommand::Adapter(_)
+            | AvrCommand::BluetoothPairing => None,
         }
     }
 }
 
 impl AvrQuery {
-    /// Convert enum to the appropriate telnet command supported
-    /// by the AVR
-    fn code(&self) -> String {
-        match &self {
-            AvrQuery::Volume => "?V\r".to_owned(),
-            AvrQuery::Mute => "?M\r".to_owned(),
-            AvrQuery::Power => "?P\r".to_owned(),
-            AvrQuery::Input => "?F\r".to_owned(),
-        }
+    async fn query(&self, conn: &AvrConnection) -> Result<String, Error> {
+        conn.query(*self).await
     }
 
-    fn query(&self) -> Result<String, Error> {
-        send_command(&self.code())
+    /// Whether the event reports the state this query asks for
+    pub fn answered_by(&self, event: &AvrEvent) -> bool {
+        matches!(
+            (self, event),
+            (AvrQuery::Volume, AvrEvent::Volume(_))
+                | (AvrQuery::Mute, AvrEvent::Mute(_))
+                | (AvrQuery::Power, AvrEvent::Power(_))
+                | (AvrQuery::Input, AvrEvent::Input(_))
+                | (AvrQuery::Preset, AvrEvent::Preset(_))
+                | (AvrQuery::SoundRetriever, AvrEvent::SoundRetriever(_))
+                | (AvrQuery::DialogEnhancement, AvrEvent::DialogEnhancement(_))
+                | (AvrQuery::ListeningMode, AvrEvent::ListeningMode(_))
+                | (AvrQuery::SpeakerSystem, AvrEvent::SpeakerSystem(_))
+                | (AvrQuery::SubwooferLevel, AvrEvent::SubwooferLevel(_))
+                | (AvrQuery::LfeAttenuation, AvrEvent::LfeAttenuation(_))
+                | (AvrQuery::Headphones, AvrEvent::Headphones(_))
+                | (AvrQuery::VideoConverter, AvrEvent::VideoConverter(_))
+                | (AvrQuery::VideoResolution, AvrEvent::VideoResolution(_))
+                | (AvrQuery::NetworkStandby, AvrEvent::NetworkStandby(_))
+                | (AvrQuery::InputLabel(_), AvrEvent::InputLabel(..))
+                | (AvrQuery::Model, AvrEvent::Model(_))
+                | (AvrQuery::Firmware, AvrEvent::Firmware(_))
+        )
     }
 }
 
-/// Convert volume of 1 - 10 to appropriate AVR volume code.   
-///
-/// 161 is equal to 0.0dB and I don't want to set any higher via this skill,
-/// so I've set this as the ceiling.
+/// Convert AvrCommand to the appropriate AVR command code and then send to the
+/// telnet task, so it can be sent along to the AVR.
 ///
-/// Must be padded to three digits: "{:0>3}"
-fn get_volume_code(n: u8) -> String {
-    let ceiling = 101.0;
-    let weight = f32::from(n) / 10.0;
-    let volume = (weight * ceiling).ceil() as u8;
-    let mut volume = format!("{:0>3}", volume);
-    volume.push_str("VL\r");
-    volume
-}
+/// Telnet task will send response back from AVR, which then can be validated
+/// to give us confidence that the requested command was successful.
+async fn send_and_validate(cmd: AvrCommand, conn: &AvrConnection) -> Result<String, Error> {
+    let protocol = conn.protocol();
+    let code = protocol.code(&cmd);
+    info!("Translated to code: {:?}", &code);
 
-/// Convert input to AVR input code.
-fn get_input_code(n: u8) -> String {
-    let code = match n {
-        1 => "25",  // BD
-        2 => "49",  // Game
-        3 => "19",  // HDMI 1
-        4 => "15",  // DVR/BDR
-        5 => "10",  // VIDEO 1(VIDEO)
-        6 => "14",  // VIDEO 2
-        7 => "05",  // TV/SAT
-        8 => "20",  // HDMI 2
-        9 => "21",  // HDMI 3
-        10 => "22", // HDMI 4
-        11 => "23", // HDMI 5
-        12 => "24", // HDMI 6
-        13 => "26", // HOME MEDIA GALLERY(Internet Radio)
-        14 => "17", // iPod/USB
-        15 => "01", // CD
-        16 => "03", // CD-R/TAPE
-        17 => "02", // TUNER
-        18 => "00", // PHONO
-        19 => "12", // MULTI CH IN
-        20 => "33", // ADAPTER PORT
-        21 => "27", // SIRIUS
-        22 => "31", // HDMI (cyclic)
-        23 => "04", // DVD
-        _ => "",    // Should never be reached
+    power_validation(&cmd, conn).await?;
+    if cmd == AvrCommand::Mute {
+        conn.events().remember_volume(None);
+    }
+
+    // Don't care about this response (unreliable), will query to confirm.
+    // Waiting for the AVR to report the change means it has processed the
+    // command before it's queried.
+    let response = match cmd {
+        AvrCommand::SetVolume(_) | AvrCommand::SetVolumeDb(_) => {
+            volume_control(&cmd, conn).await?;
+            String::new()
+        }
+        AvrCommand::SubwooferUp | AvrCommand::SubwooferDown => {
+            subwoofer_control(&cmd, conn).await?;
+            String::new()
+        }
+        _ => conn.send(&code, awaited_event(&cmd, conn)).await?,
     };
-    let mut code = code.to_owned();
-    code.push_str("FN\r");
-    code
+
+    // Without a query, the response itself is all there is to go by. A
+    // command the AVR rejected outright won't take if given more time.
+    match cmd.query_type() {
+        Some(query) if rejected(conn, &response) => converge(&cmd, query, conn, 1).await,
+        Some(query) => converge(&cmd, query, conn, VERIFY_ATTEMPTS).await,
+        None => {
+            validate_response(&cmd, conn, &response)?;
+            Ok(response)
+        }
+    }
 }
 
-/// Convert AvrCommand to the appropriate AVR command code and then send to the
-/// telnet thread, so it can be sent along to the AVR.
-///
-/// Telnet thread will send response back from AVR, which then can be validated
-/// to give us confidence that the requested command was successful.
-fn send_and_validate(cmd: AvrCommand) -> Result<(), Error> {
-    info!("Translated to code: {:?}", &cmd.code());
+/// Query the state the command changes until it confirms the command, up to
+/// `attempts` times within `VERIFY_DEADLINE`, so an AVR slow to carry it out
+/// has time to catch up. Fails as the last query's response doesn't match.
+async fn converge(
+    cmd: &AvrCommand,
+    query: AvrQuery,
+    conn: &AvrConnection,
+    attempts: u32,
+) -> Result<String, Error> {
+    let deadline = Instant::now() + VERIFY_DEADLINE;
+    let mut attempt = 1;
+    loop {
+        let response = query.query(conn).await?;
+        match validate_response(cmd, conn, &response) {
+            Ok(()) => return Ok(response),
+            Err(e) if attempt < attempts && Instant::now() + VERIFY_INTERVAL < deadline => {
+                debug!("{} Querying again: {:?}", e, response);
+            }
+            Err(e) => return Err(e),
+        }
+        attempt += 1;
+        sleep(VERIFY_INTERVAL).await;
+    }
+}
 
-    power_validation(&cmd)?;
+/// Whether the AVR answered the command with an error
+fn rejected(conn: &AvrConnection, response: &str) -> bool {
+    response
+        .split_terminator("\r\n")
+        .any(|message| matches!(conn.protocol().classify(message), Message::Error(_)))
+}
 
-    // Don't care about this response (unreliable), will query to confirm
+/// State change the AVR reports once it has processed the command, if it's
+/// known without knowing the current state
+fn awaited_event(cmd: &AvrCommand, conn: &AvrConnection) -> Option<AvrEvent> {
     match cmd {
-        AvrCommand::SetVolume(_) => {
-            volume_control(cmd.code())?;
-            // Sleep to allow AVR to process before querying for final Vol
-            std::thread::sleep(Duration::from_millis(2_000));
-        }
-        AvrCommand::PowerOn => {
-            let _ = send_command(&cmd.code())?;
-            // Sleep to allow AVR to process before querying for final Vol
-            std::thread::sleep(Duration::from_millis(1_000));
-        }
-        _ => {
-            let _ = send_command(&cmd.code())?;
+        AvrCommand::PowerOn => Some(AvrEvent::Power(true)),
+        AvrCommand::PowerOff => Some(AvrEvent::Power(false)),
+        AvrCommand::Mute => Some(AvrEvent::Mute(true)),
+        AvrCommand::Unmute => Some(AvrEvent::Mute(false)),
+        AvrCommand::ChangeInput(n) => conn.protocol().input_code(*n).map(AvrEvent::Input),
+        AvrCommand::SelectPreset(preset) => {
+            conn.protocol().preset_code(preset).map(AvrEvent::Preset)
         }
+        AvrCommand::SoundRetrieverOn => Some(AvrEvent::SoundRetriever(true)),
+        AvrCommand::SoundRetrieverOff => Some(AvrEvent::SoundRetriever(false)),
+        AvrCommand::DialogEnhancementOn => Some(AvrEvent::DialogEnhancement(true)),
+        AvrCommand::DialogEnhancementOff => Some(AvrEvent::DialogEnhancement(false)),
+        AvrCommand::SetListeningMode(mode) => conn
+            .protocol()
+            .listening_mode_code(mode)
+            .map(AvrEvent::ListeningMode),
+        AvrCommand::SetSpeakerSystem(system) => conn
+            .protocol()
+            .speaker_system_code(system)
+            .map(AvrEvent::SpeakerSystem),
+        AvrCommand::SetLfeAttenuation(db) => Some(AvrEvent::LfeAttenuation(*db)),
+        AvrCommand::VideoConverterOn => Some(AvrEvent::VideoConverter(true)),
+        AvrCommand::VideoConverterOff => Some(AvrEvent::VideoConverter(false)),
+        AvrCommand::SetVideoResolution(resolution) => conn
+            .protocol()
+            .video_resolution_code(resolution)
+            .map(AvrEvent::VideoResolution),
+        AvrCommand::NetworkStandbyOn => Some(AvrEvent::NetworkStandby(true)),
+        AvrCommand::SetVolume(_)
+        | AvrCommand::SetVolumeDb(_)
+        | AvrCommand::VolumeUp
+        | AvrCommand::VolumeDown
+        | AvrCommand::SubwooferUp
+        | AvrCommand::SubwooferDown
+        | AvrCommand::Gallery(_)
+        | AvrCommand::Ipod(_)
+        | AvrCommand::Adapter(_)
+        | AvrCommand::BluetoothPairing => None,
     }
+}
 
-    let query_response = cmd.query()?;
+/// Check the command can be processed in the current power state. The cached
+/// state is used if the AVR has reported its power state, otherwise it's
+/// queried.
+async fn power_validation(cmd: &AvrCommand, conn: &AvrConnection) -> Result<(), Error> {
+    let protocol = conn.protocol();
+    let power_on = match conn.events().state().power {
+        Some(power_on) => Some(power_on),
+        None => {
+            let current_power = AvrQuery::Power.query(conn).await?;
+            if protocol.matches(&AvrCommand::PowerOff, &current_power) {
+                Some(false)
+            } else if protocol.matches(&AvrCommand::PowerOn, &current_power) {
+                Some(true)
+            } else {
+                None
+            }
+        }
+    };
 
-    validate_response(cmd, query_response)
+    check_power(cmd, power_on)
 }
 
-fn power_validation(cmd: &AvrCommand) -> Result<(), Error> {
-    let current_power = AvrQuery::Power.query()?;
-    if current_power.contains(&AvrCommand::PowerOff.expected()) && cmd != &AvrCommand::PowerOn {
+/// Check the command can be processed in the given power state, if known
+fn check_power(cmd: &AvrCommand, power_on: Option<bool>) -> Result<(), Error> {
+    if power_on == Some(false) && cmd != &AvrCommand::PowerOn {
         if cmd == &AvrCommand::PowerOff {
             return Err(AvrError::PowerAlreadyOff.into());
         } else {
             return Err(AvrError::PowerOffCantProcess.into());
         }
-    } else if current_power.contains(&AvrCommand::PowerOn.expected()) && cmd == &AvrCommand::PowerOn
-    {
+    } else if power_on == Some(true) && cmd == &AvrCommand::PowerOn {
         return Err(AvrError::PowerAlreadyOn.into());
     }
     Ok(())
 }
 
-fn volume_control(code: String) -> Result<(), Error> {
-    let current_volume = AvrQuery::Volume
-        .query()?
-        .trim_end()
-        .trim_start_matches("VOL")
-        .parse::<i8>()?;
-    let desired_volume = &code[0..3].parse::<i8>()?;
-    let diff = desired_volume - current_volume;
-    let steps = diff / 2;
-    let vol_adj = if steps > 0 {
-        AvrCommand::VolumeUp.code().repeat(steps as usize)
-    } else {
-        AvrCommand::VolumeDown.code().repeat(steps.abs() as usize)
+/// Log the code the command translates to without sending it, then update the
+/// cached state as if the AVR had reported the change, so the response and
+/// later commands see it. The power state is only checked if it's cached, as
+/// the AVR can't be queried.
+fn dry_run(cmd: AvrCommand, conn: &AvrConnection) -> Result<(), Error> {
+    let protocol = conn.protocol();
+    let events = conn.events();
+    let state = events.state();
+    info!("Dry run, not sending code: {:?}", protocol.code(&cmd));
+
+    check_power(&cmd, state.power)?;
+    if matches!(
+        cmd,
+        AvrCommand::SetVolume(_) | AvrCommand::SetVolumeDb(_) | AvrCommand::Mute
+    ) {
+        events.remember_volume(None);
+    }
+
+    let step = protocol.volume_step();
+    let event = match cmd {
+        AvrCommand::PowerOn => Some(AvrEvent::Power(true)),
+        AvrCommand::PowerOff => Some(AvrEvent::Power(false)),
+        AvrCommand::Mute => Some(AvrEvent::Mute(true)),
+        AvrCommand::Unmute => Some(AvrEvent::Mute(false)),
+        AvrCommand::SetVolume(n) => Some(AvrEvent::Volume(protocol.volume_level(n))),
+        AvrCommand::SetVolumeDb(db) => protocol.db_level(db).map(AvrEvent::Volume),
+        AvrCommand::VolumeUp => state
+            .volume
+            .map(|volume| AvrEvent::Volume(volume.saturating_add(step))),
+        AvrCommand::VolumeDown => state
+            .volume
+            .map(|volume| AvrEvent::Volume(volume.saturating_sub(step))),
+        AvrCommand::ChangeInput(n) => match protocol.input_code(n) {
+            Some(input) => Some(AvrEvent::Input(input)),
+            None => bail!("Not an input of the AVR: {}", n),
+        },
+        AvrCommand::SelectPreset(preset) => {
+            // Presets are selected on the tuner
+            if let Some(tuner) = protocol.tuner_input() {
+                events.simulate(AvrEvent::Input(tuner));
+            }
+            protocol.preset_code(&preset).map(AvrEvent::Preset)
+        }
+        AvrCommand::SoundRetrieverOn => Some(AvrEvent::SoundRetriever(true)),
+        AvrCommand::SoundRetrieverOff => Some(AvrEvent::SoundRetriever(false)),
+        AvrCommand::DialogEnhancementOn => Some(AvrEvent::DialogEnhancement(true)),
+        AvrCommand::DialogEnhancementOff => Some(AvrEvent::DialogEnhancement(false)),
+        AvrCommand::SetListeningMode(mode) => protocol
+            .listening_mode_code(&mode)
+            .map(AvrEvent::ListeningMode),
+        AvrCommand::SetSpeakerSystem(system) => protocol
+            .speaker_system_code(&system)
+            .map(AvrEvent::SpeakerSystem),
+        AvrCommand::SubwooferUp => state
+            .subwoofer_level
+            .map(|level| AvrEvent::SubwooferLevel(level.saturating_add(protocol.subwoofer_step()))),
+        AvrCommand::SubwooferDown => state
+            .subwoofer_level
+            .map(|level| AvrEvent::SubwooferLevel(level.saturating_sub(protocol.subwoofer_step()))),
+        AvrCommand::SetLfeAttenuation(db) => Some(AvrEvent::LfeAttenuation(db)),
+        AvrCommand::VideoConverterOn => Some(AvrEvent::VideoConverter(true)),
+        AvrCommand::VideoConverterOff => Some(AvrEvent::VideoConverter(false)),
+        AvrCommand::SetVideoResolution(resolution) => protocol
+            .video_resolution_code(&resolution)
+            .map(AvrEvent::VideoResolution),
+        AvrCommand::NetworkStandbyOn => Some(AvrEvent::NetworkStandby(true)),
+        AvrCommand::Gallery(_)
+        | AvrCommand::Ipod(_)
+        | AvrCommand::Adapter(_)
+        | AvrCommand::BluetoothPairing => None,
+    };
+    if let Some(event) = event {
+        events.simulate(event);
+    }
+    Ok(())
+}
+
+/// Change the volume from the current level to the level requested by
+/// `AvrCommand::SetVolume` or `AvrCommand::SetVolumeDb`. The level is set
+/// directly if the AVR's protocol has a code for it. If the AVR rejects that,
+/// or it has none, the volume is stepped there using repeated `VolumeUp` or
+/// `VolumeDown` codes, paced as configured for the AVR. A `SetVolumeDb`
+/// target steps can't reach, half a dB on a Pioneer, is finished directly.
+async fn volume_control(cmd: &AvrCommand, conn: &AvrConnection) -> Result<(), Error> {
+    let protocol = conn.protocol();
+    let desired_volume = match cmd {
+        AvrCommand::SetVolume(n) => i16::from(protocol.volume_level(*n)),
+        AvrCommand::SetVolumeDb(db) => match protocol.db_level(*db) {
+            Some(level) => i16::from(level),
+            None => bail!("No volume level at {} dB", db),
+        },
+        _ => bail!("Not a volume command: {:?}", cmd),
     };
 
-    send_command(&vol_adj)?;
+    let response = AvrQuery::Volume.query(conn).await?;
+    let current_volume = protocol
+        .parse_events(&response)
+        .into_iter()
+        .find_map(|event| match event {
+            AvrEvent::Volume(volume) => Some(i16::from(volume)),
+            _ => None,
+        })
+        .ok_or_else(|| format_err!("Couldn't read current volume from: {:?}", response))?;
+    conn.events()
+        .remember_volume(u8::try_from(current_volume).ok());
+
+    if desired_volume != current_volume && set_volume_directly(desired_volume, conn).await? {
+        return Ok(());
+    }
+
+    let step = i16::from(protocol.volume_step());
+    let steps = (desired_volume - current_volume) / step;
+    let reached = current_volume + steps * step;
+    if steps != 0 {
+        step_volume(current_volume, steps, step, conn).await?;
+    }
+
+    // Steps move more than one level, so a dB target they fall short of is
+    // set directly, if the AVR takes that
+    if reached != desired_volume && matches!(cmd, AvrCommand::SetVolumeDb(_)) {
+        set_volume_directly(desired_volume, conn).await?;
+    }
 
     Ok(())
 }
 
-fn send_command(code: &str) -> Result<String, Error> {
-    // Clear channel A if full, it shouldn't be
-    if CHANNEL_A.0.is_full() {
-        select! {
-            recv(CHANNEL_A.1) -> _ => {}
-            default => {}
+/// Send `steps` `VolumeUp` or `VolumeDown` codes, each moving the volume
+/// `step` levels from `current_volume`, paced as configured for the AVR
+async fn step_volume(
+    current_volume: i16,
+    steps: i16,
+    step: i16,
+    conn: &AvrConnection,
+) -> Result<(), Error> {
+    let protocol = conn.protocol();
+    let code = if steps > 0 {
+        protocol.code(&AvrCommand::VolumeUp)
+    } else {
+        protocol.code(&AvrCommand::VolumeDown)
+    };
+    let count = steps.abs();
+    let step = step * steps.signum();
+    let ramp = conn.volume_ramp();
+
+    if ramp.step_delay == 0 {
+        // Wait for the AVR to report the level the last step takes it to
+        let reached = u8::try_from(current_volume + count * step).ok();
+        conn.send(&code.repeat(count as usize), reached.map(AvrEvent::Volume))
+            .await?;
+    } else {
+        for n in 1..=count {
+            if n > 1 {
+                sleep(Duration::from_millis(ramp.step_delay)).await;
+            }
+            let reached = u8::try_from(current_volume + n * step).ok();
+            conn.send(&code, reached.map(AvrEvent::Volume)).await?;
         }
-        debug!("Had to clear channel A");
     }
-    CHANNEL_A.0.send(code.to_owned())?;
-    debug!("Sent code via channel A: {:?}", code);
 
-    get_response()
+    if ramp.settle > 0 {
+        sleep(Duration::from_millis(ramp.settle)).await;
+    }
+
+    Ok(())
 }
 
-/// Get response code back from AVR. If this response takes longer than 1.5
-/// second, assume error.
-fn get_response() -> Result<String, Error> {
-    select! {
-        recv(CHANNEL_B.1) -> msg => {
-            let msg = msg?;
-            debug!("Response code received via channel B: {:?}", msg);
-            Ok(msg)
-        },
-        default(Duration::from_millis(1_500)) => {
-            bail!(AvrError::Timeout);
+/// Step the subwoofer's channel level up or down from the current level.
+/// AVRs set channel levels to a value rather than stepping them, so the
+/// current level is queried first.
+async fn subwoofer_control(cmd: &AvrCommand, conn: &AvrConnection) -> Result<(), Error> {
+    let protocol = conn.protocol();
+    let response = AvrQuery::SubwooferLevel.query(conn).await?;
+    let current = protocol
+        .parse_events(&response)
+        .into_iter()
+        .find_map(|event| match event {
+            AvrEvent::SubwooferLevel(level) => Some(level),
+            _ => None,
+        })
+        .ok_or_else(|| format_err!("Couldn't read current subwoofer level from: {:?}", response))?;
+
+    let step = protocol.subwoofer_step();
+    let level = match cmd {
+        AvrCommand::SubwooferUp => current.saturating_add(step),
+        AvrCommand::SubwooferDown => current.saturating_sub(step),
+        _ => bail!("Not a subwoofer command: {:?}", cmd),
+    };
+    let code = match protocol.subwoofer_level_code(level) {
+        Some(code) => code,
+        None => bail!("Subwoofer level can't go past {}", current),
+    };
+
+    conn.send(&code, Some(AvrEvent::SubwooferLevel(level)))
+        .await?;
+    Ok(())
+}
+
+/// Set the volume straight to `level`, if the AVR takes it. `false` if the
+/// volume has to be stepped there instead. Only an AVR answering with an
+/// error is remembered to reject it, so it's stepped right away next time.
+/// One that doesn't confirm it within `DIRECT_VOLUME_WAIT` is stepped just
+/// this once, leaving time to step within the request.
+async fn set_volume_directly(level: i16, conn: &AvrConnection) -> Result<bool, Error> {
+    let level = match u8::try_from(level) {
+        Ok(level) => level,
+        Err(_) => return Ok(false),
+    };
+    let code = match conn.protocol().volume_code(level) {
+        Some(code) if conn.direct_volume() => code,
+        _ => return Ok(false),
+    };
+
+    let reached = AvrEvent::Volume(level);
+    match conn
+        .send_within(&code, Some(reached.clone()), DIRECT_VOLUME_WAIT)
+        .await
+    {
+        Ok(response) if conn.protocol().parse_events(&response).contains(&reached) => Ok(true),
+        Ok(response) if rejected(conn, &response) => {
+            info!(
+                "AVR rejected direct volume, stepping instead: {:?}",
+                response
+            );
+            conn.reject_direct_volume();
+            Ok(false)
+        }
+        Ok(_) => {
+            info!("AVR didn't confirm direct volume in time, stepping instead");
+            Ok(false)
+        }
+        Err(e) if matches!(e.downcast_ref(), Some(AvrError::Timeout)) => {
+            info!("AVR didn't answer direct volume, stepping instead");
+            Ok(false)
         }
+        Err(e) => Err(e),
     }
 }
 
 /// AVR sends back code validating the request. Confirm that this response code
 /// matches the expected response, per documentation. If not, the request most
 /// likely wasn't succesful.
-fn validate_response(cmd: AvrCommand, response: String) -> Result<(), Error> {
-    let expected = cmd.expected();
-    if !response.contains(&expected) {
-        bail!(AvrError::ResponseDoesntMatch { expected });
+fn validate_response(cmd: &AvrCommand, conn: &AvrConnection, response: &str) -> Result<(), Error> {
+    let protocol = conn.protocol();
+    let expected = protocol.expected(cmd);
+    let matches = match cmd {
+        // Stepping from a level that's off by one can end up next to the
+        // curve's level rather than on it, but a dB target is reached exactly
+        AvrCommand::SetVolume(n) => {
+            volume_near(&**protocol, Some(protocol.volume_level(*n)), 1, response)
+        }
+        AvrCommand::SetVolumeDb(db) => {
+            volume_near(&**protocol, protocol.db_level(*db), 0, response)
+        }
+        _ => protocol.matches(cmd, response),
+    };
+    if !matches {
+        return Err(AvrError::ResponseDoesntMatch { expected }.into());
     }
     info!(
         "AVR response matches expected code: {:?}. Update appears to have worked.",
@@ -256,19 +716,43 @@ fn validate_response(cmd: AvrCommand, response: String) -> Result<(), Error> {
     Ok(())
 }
 
-#[derive(Fail, Debug)]
+/// Whether the volume reported in the response is the `target` level, or
+/// within `tolerance` levels of it
+fn volume_near(
+    protocol: &dyn AvrProtocol,
+    target: Option<u8>,
+    tolerance: u8,
+    response: &str,
+) -> bool {
+    match target {
+        Some(target) => protocol.parse_events(response).iter().any(
+            |event| matches!(event, AvrEvent::Volume(level) if level.abs_diff(target) <= tolerance),
+        ),
+        None => false,
+    }
+}
+
+#[derive(Fail, Debug, Clone)]
 pub enum AvrError {
     #[fail(display = "Timeout. Didn't get response from AVR.")]
     Timeout,
+    #[fail(display = "AVR is busy processing another command.")]
+    Busy,
     #[fail(display = "Power already off.")]
     PowerAlreadyOff,
     #[fail(display = "Power already on.")]
     PowerAlreadyOn,
     #[fail(display = "Power is off, it must be turned on to execute command.")]
     PowerOffCantProcess,
+    #[fail(display = "AVR doesn't support command: {:?}", command)]
+    Unsupported { command: AvrCommand },
     #[fail(
         display = "AVR response doesn't match expected code: {:?}. Can't confirm update took place.",
         expected
     )]
     ResponseDoesntMatch { expected: String },
+    #[fail(
+        display = "AVR can't be reached to turn it on. Its network standby setting appears to be off."
+    )]
+    NetworkStandbyOff,
 }
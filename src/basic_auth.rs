@@ -0,0 +1,102 @@
+/// This module checks HTTP Basic credentials, a lighter alternative to the
+/// API token for home users.
+///
+/// With `[basic_auth]` configured, the web service's `/stats`, `/api/history`
+/// and `/api/openapi.json` are only served to requests with the username and
+/// password, and the control API takes them in place of its
+/// token. `/health` is left open, so uptime monitors can still reach it.
+///
+/// The password is kept hashed in the config file with PBKDF2-HMAC-SHA256, as
+/// `pbkdf2-sha256$<rounds>$<salt>$<hash>`, the salt and hash in hex, so it
+/// can't be cheaply guessed back from the file. `alexa-avr-control
+/// hash-password` prints one for a password.
+use crate::config::BasicAuthConfig;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use failure::{bail, Error};
+use pbkdf2::pbkdf2_hmac;
+use rand::Rng;
+use sha2::Sha256;
+
+/// PBKDF2 rounds for newly hashed passwords
+const ROUNDS: u32 = 600_000;
+
+/// Checks the Basic credentials of requests against the configured ones
+pub struct BasicAuth {
+    username: String,
+    rounds: u32,
+    salt: String,
+    hash: String,
+}
+
+impl BasicAuth {
+    pub fn new(config: BasicAuthConfig) -> Result<BasicAuth, Error> {
+        let parts: Vec<_> = config.password.split('$').collect();
+        match parts.as_slice() {
+            ["pbkdf2-sha256", rounds, salt, hash] if hash.len() == 64 => {
+                match rounds.parse::<u32>() {
+                    Ok(rounds) if rounds > 0 => Ok(BasicAuth {
+                        username: config.username,
+                        rounds,
+                        salt: (*salt).to_owned(),
+                        hash: hash.to_ascii_lowercase(),
+                    }),
+                    _ => bail!("Basic auth password has invalid rounds: {}", rounds),
+                }
+            }
+            _ => bail!("Basic auth password must be hashed with `alexa-avr-control hash-password`"),
+        }
+    }
+
+    /// Whether the Authorization header has the configured username and
+    /// password
+    pub fn authorized(&self, authorization: &str) -> bool {
+        let credentials = match authorization
+            .strip_prefix("Basic ")
+            .and_then(|encoded| STANDARD.decode(encoded.trim()).ok())
+            .and_then(|decoded| String::from_utf8(decoded).ok())
+        {
+            Some(credentials) => credentials,
+            None => return false,
+        };
+        match credentials.split_once(':') {
+            Some((username, password)) => {
+                // Both are compared in full either way, so the time taken
+                // doesn't tell which was wrong
+                let username_ok = same(username.as_bytes(), self.username.as_bytes());
+                let password_ok = same(
+                    digest(self.rounds, &self.salt, password).as_bytes(),
+                    self.hash.as_bytes(),
+                );
+                username_ok && password_ok
+            }
+            None => false,
+        }
+    }
+}
+
+/// Hash the password with a random salt, for the config file
+pub fn hash(password: &str) -> String {
+    let salt = hex(&rand::thread_rng().gen::<[u8; 16]>());
+    format!(
+        "pbkdf2-sha256${}${}${}",
+        ROUNDS,
+        salt,
+        digest(ROUNDS, &salt, password)
+    )
+}
+
+fn digest(rounds: u32, salt: &str, password: &str) -> String {
+    let mut key = [0; 32];
+    pbkdf2_hmac::<Sha256>(password.as_bytes(), salt.as_bytes(), rounds, &mut key);
+    hex(&key)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Whether the two are equal, comparing every byte rather than stopping at
+/// the first difference
+fn same(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
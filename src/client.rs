@@ -0,0 +1,180 @@
+/// This module contains `AvrClient`, for controlling an AVR from other
+/// programs using this crate as a library.
+///
+/// The client owns the telnet task for the AVR, and takes typed commands:
+/// a `Volume` is always on the 1 to 10 scale, and an `Input` is one the AVR
+/// has. Each command is translated to the AVR's code, sent, and confirmed
+/// with a query, the same as for the skill, so a method only succeeds once
+/// the AVR has done it.
+///
+/// ```no_run
+/// # async fn example(config: alexa_avr_control::config::DeviceConfig) -> Result<(), failure::Error> {
+/// use alexa_avr_control::{client::{AvrClient, Volume}, telnet::Backoff};
+/// use std::time::Duration;
+///
+/// let backoff = Backoff {
+///     min: Duration::from_secs(1),
+///     max: Duration::from_secs(60),
+///     connect_timeout: Duration::from_secs(5),
+/// };
+/// let client = AvrClient::connect(&config, backoff)?;
+/// client.power_on().await?;
+/// client.set_volume(Volume::new(4)?).await?;
+/// if let Some(input) = client.find_input("BD") {
+///     client.input(input).await?;
+/// }
+/// println!("{:?}", client.status().await?);
+/// # Ok(())
+/// # }
+/// ```
+use crate::{
+    avr::{self, AvrCommand},
+    config::DeviceConfig,
+    events::EventBus,
+    protocol,
+    telnet::{self, AvrConnection, Backoff},
+};
+use failure::{bail, Error};
+
+/// Volume on the same 1 to 10 scale as the skill
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Volume(u8);
+
+impl Volume {
+    pub fn new(n: u8) -> Result<Volume, Error> {
+        if !(1..=10).contains(&n) {
+            bail!("Volume not between 1 and 10: {}", n);
+        }
+        Ok(Volume(n))
+    }
+
+    pub fn get(self) -> u8 {
+        self.0
+    }
+}
+
+/// Number of one of the AVR's inputs, from `AvrClient::find_input`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Input(u8);
+
+impl Input {
+    pub fn get(self) -> u8 {
+        self.0
+    }
+}
+
+/// State of the AVR, as far as it has reported it
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AvrState {
+    pub power: Option<bool>,
+    /// Volume level, as the AVR reports it
+    pub volume: Option<u8>,
+    /// Volume as a percentage of the highest volume set, 10
+    pub volume_percent: Option<u8>,
+    pub mute: Option<bool>,
+    pub input: Option<Input>,
+    pub input_name: Option<String>,
+    /// Whether headphones are plugged in, if the AVR can tell
+    pub headphones: Option<bool>,
+}
+
+/// Connection to an AVR taking typed commands. Cloned wherever it's needed.
+#[derive(Clone)]
+pub struct AvrClient {
+    conn: AvrConnection,
+}
+
+impl AvrClient {
+    /// Start the telnet task for the configured AVR, reconnecting per
+    /// `backoff`. Must be called within a Tokio runtime.
+    pub fn connect(config: &DeviceConfig, backoff: Backoff) -> Result<AvrClient, Error> {
+        let protocol = protocol::from_config(config)?;
+        let events = EventBus::new(protocol.clone());
+        let conn = telnet::run(
+            config.host.clone(),
+            config.port,
+            backoff.with_connect_timeout(config.connect_timeout),
+            protocol,
+            events,
+            false,
+            None,
+        );
+        Ok(AvrClient { conn })
+    }
+
+    /// Client using an existing connection
+    pub fn from_connection(conn: AvrConnection) -> AvrClient {
+        AvrClient { conn }
+    }
+
+    /// `EventBus` with the AVR's cached state and state changes
+    pub fn events(&self) -> &EventBus {
+        self.conn.events()
+    }
+
+    /// The AVR's input with the name, ignoring case, or number
+    pub fn find_input(&self, name: &str) -> Option<Input> {
+        self.conn.protocol().find_input(name).map(Input)
+    }
+
+    pub async fn power_on(&self) -> Result<(), Error> {
+        self.process(AvrCommand::PowerOn).await
+    }
+
+    pub async fn power_off(&self) -> Result<(), Error> {
+        self.process(AvrCommand::PowerOff).await
+    }
+
+    pub async fn mute(&self) -> Result<(), Error> {
+        self.process(AvrCommand::Mute).await
+    }
+
+    pub async fn unmute(&self) -> Result<(), Error> {
+        self.process(AvrCommand::Unmute).await
+    }
+
+    pub async fn volume_up(&self) -> Result<(), Error> {
+        self.process(AvrCommand::VolumeUp).await
+    }
+
+    pub async fn volume_down(&self) -> Result<(), Error> {
+        self.process(AvrCommand::VolumeDown).await
+    }
+
+    pub async fn set_volume(&self, volume: Volume) -> Result<(), Error> {
+        self.process(AvrCommand::SetVolume(volume.get())).await
+    }
+
+    pub async fn input(&self, input: Input) -> Result<(), Error> {
+        self.process(AvrCommand::ChangeInput(input.get())).await
+    }
+
+    /// Query the AVR's power, volume, mute and input, and whether headphones
+    /// are plugged in
+    pub async fn status(&self) -> Result<AvrState, Error> {
+        avr::refresh(&self.conn).await?;
+
+        let protocol = self.conn.protocol();
+        let state = self.conn.events().state();
+        Ok(AvrState {
+            power: state.power,
+            volume: state.volume,
+            volume_percent: state.volume.map(|level| protocol.volume_percent(level)),
+            mute: state.mute,
+            input: state
+                .input
+                .as_ref()
+                .and_then(|input| protocol.input_number(input))
+                .map(Input),
+            input_name: state
+                .input
+                .as_ref()
+                .and_then(|input| protocol.input_name(input)),
+            headphones: state.headphones,
+        })
+    }
+
+    async fn process(&self, cmd: AvrCommand) -> Result<(), Error> {
+        avr::process(cmd, &self.conn).await.map(|_| ())
+    }
+}
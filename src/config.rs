@@ -0,0 +1,1087 @@
+/// This module loads the optional configuration file.   
+///
+/// A single AVR can be set up using only command line arguments. The config
+/// file is needed for anything more, like controlling several AVRs.   
+///
+/// The file is TOML, for example:
+///
+/// ```toml
+/// [[device]]
+/// name = "living room"
+/// host = "192.168.1.50"
+/// port = 23
+///
+/// [[device]]
+/// name = "bedroom"
+/// host = "192.168.1.51"
+/// protocol = "pioneer"
+///
+/// [[device]]
+/// name = "den"
+/// host = "192.168.1.52"
+/// protocol = "table"
+/// table = "denon.toml"
+///
+/// [skill]
+/// confirm_power_off = true
+/// confirm_volume_jump = 4
+///
+/// [speech]
+/// ssml = true
+/// locales = "locales"
+/// verbosity = "verbose"
+///
+/// [homekit]
+/// pin = "031-45-154"
+/// device = "living room"
+///
+/// [smart_home]
+/// client_id = "amzn1.application-oa2-client.0123456789abcdef"
+/// client_secret = "0123456789abcdef"
+/// secret = "shared with the Lambda function"
+///
+/// [google]
+/// token = "0123456789abcdef"
+///
+/// [api]
+/// token = "0123456789abcdef"
+///
+/// [heartbeat]
+/// url = "https://hc-ping.com/0123456789abcdef"
+///
+/// [[webhook]]
+/// url = "https://ntfy.sh/my-receiver"
+/// events = ["power", "input"]
+///
+/// [[schedule]]
+/// name = "bedtime"
+/// at = "23:30"
+/// days = ["weekdays"]
+/// command = "PowerOff"
+///
+/// [quiet_hours]
+/// start = "22:00"
+/// end = "07:00"
+/// max_volume = 4
+///
+/// [volume_memory]
+/// restore = true
+///
+/// [timers]
+/// path = "timers.json"
+/// ```
+use crate::{avr::AvrCommand, pioneer::Pioneer, protocol::AvrProtocol};
+use failure::{bail, Error, ResultExt};
+use serde::Deserialize;
+use std::{collections::BTreeMap, fs, path::Path};
+
+/// Commented config file written by `init`, with `{inputs}` replaced by the
+/// list of Pioneer inputs
+const EXAMPLE: &str = r#"# alexa-avr-control config file
+
+# Each AVR to control. Requests can name the AVR ("turn on the bedroom
+# receiver"), otherwise the first one is used. Add each name as a value of the
+# DeviceName slot type in the interaction model.
+[[device]]
+name = "receiver"
+# Host / ip of the AVR, IPv4 or IPv6, hostnames and mDNS .local names are
+# resolved again each time the connection is retried
+host = "192.168.1.50"
+# Telnet port of the AVR, 8102 on some models
+port = 23
+# Seconds to wait for the AVR to accept the connection, --connect-timeout by
+# default
+# connect_timeout = 5
+# Brand of AVR, "pioneer", or "table" to read the codes from a table file
+protocol = "pioneer"
+# table = "denon.toml"
+# Model of the AVR, "VSX-1021" or "SC-LX901", or the path of a profile file
+# listing the inputs, Zone 2 and commands it has
+# profile = "VSX-1021"
+# Turn the AVR off once it has been on for this many hours without any
+# commands, changes or signal on its input
+# idle_off = 3
+# Keep Zone 2's volume this many dB from the main zone's, following it
+# whenever it changes
+# zone2_offset = -10
+# How the 1 to 10 volume scale maps to the AVR's volume levels: "linear",
+# "logarithmic" for bigger steps at the low end, or the level for each number
+# volume_curve = "logarithmic"
+# Milliseconds between the commands of a sequence
+# sequence_delay = 1000
+# Inputs not in use, by name or number, left out of the inputs listed by
+# Alexa, Google and HomeKit
+# hidden = ["Phono", "CD-R/Tape", "Sirius"]
+# Ask the AVR for the names inputs were given on its on-screen menu each time
+# it connects, and use them instead
+# input_labels = true
+# Log a warning for commands taking longer than this many milliseconds to be
+# carried out
+# slow_command = 2000
+
+# Intents that can't be used on this AVR, or the only ones that can
+# [device.intents]
+# deny = ["Input"]
+# allow = ["On", "Off", "Volume"]
+
+# Other names for inputs, keyed by the input's name or number, so "switch to
+# the playstation" changes to Game
+# [device.aliases]
+# Game = ["playstation", "ps5"]
+# Tuner = ["radio"]
+
+# How many dB louder inputs are than the rest, keyed the same way. The volume
+# is set that much lower on them, and reported as if it weren't.
+# [device.offsets]
+# CD = 6
+
+# Names for tuner presets, so "play NPR on the radio" switches to the tuner
+# and selects A2. Pioneer presets are classes A to G, numbered 1 to 9.
+# [device.presets]
+# NPR = "A2"
+# "Jazz FM" = "B5"
+
+# Commands to send right after selecting an input, keyed the same way. If one
+# fails, the input is changed back to the one before.
+# [device.on_select]
+# Tuner = ["Unmute", { SetVolume = 4 }]
+
+# Named sequences of commands, so "play internet radio favorite three" walks
+# the Home Media Gallery's menu. Gallery keys are Up, Down, Left, Right, Enter,
+# Return, Play, Pause, Stop, Previous and Next.
+# [device.sequences]
+# "internet radio favorite three" = [{ ChangeInput = 26 }, { Gallery = "Enter" }, { Gallery = "Down" }, { Gallery = "Down" }, { Gallery = "Enter" }]
+
+# Codes that can be sent to the AVR as they are, by name, with "send speaker
+# status" or the control API, reading back what it answers. None by default.
+# [device.raw_codes]
+# "speaker status" = "?SPK"
+
+# Retry commands the AVR didn't answer or confirm, as often happens right after
+# it's turned on. The delay in milliseconds doubles with each retry.
+# [device.retry]
+# attempts = 2
+# delay = 500
+
+# Poll the AVR's power for up to max_wait milliseconds until it reports being
+# on after turning it on, then wait settle milliseconds more before sending
+# the next command
+# [device.power_on]
+# max_wait = 8000
+# settle = 0
+
+# Write volume steps one at a time, step_delay milliseconds apart, for AVRs
+# that drop steps sent back to back, and wait settle milliseconds after the
+# last one before checking the volume
+# [device.volume_ramp]
+# step_delay = 0
+# settle = 0
+
+# Pioneer inputs, by the number used to ask for them ("input 3"):
+#
+{inputs}
+
+# How the skill behaves
+[skill]
+# Ask before turning an AVR off
+confirm_power_off = false
+# Ask before changing the volume by more than this many steps
+# confirm_volume_jump = 4
+# What to do with a request while the AVR is busy with another: "queue" to
+# process it afterwards, or "reject" to say the AVR is busy
+when_busy = "queue"
+# Only process a request once if Alexa delivers it again within this many
+# milliseconds, 0 to turn off
+debounce = 2000
+# Turn away a request seen again after that but within this many seconds, as
+# a captured request being replayed, 0 to turn off
+replay_window = 150
+# Leave the session open after each command, asking if there's anything else,
+# so the next one doesn't need the invocation name
+keep_open = false
+# Answer requests that can't be processed, like unsigned ones, with a JSON
+# error saying why rather than an empty 400
+debug = false
+# Don't change the speaker system or subwoofer while headphones are plugged
+# in, saying why instead
+skip_speakers_with_headphones = false
+# Only the Alexa accounts with these user IDs can use the skill, anyone else
+# is politely turned away. Every account can if not given.
+# allowed_accounts = ["amzn1.ask.account.0123456789abcdef"]
+
+# Intents that can't be used at all, or the only ones that can. They're left
+# out of the interaction model by export-model.
+# [skill.intents]
+# deny = ["Input"]
+# allow = ["On", "Off", "Volume", "Mute", "Unmute"]
+
+# Settings for one person, matched by the person ID Alexa sends when it
+# recognizes their voice, or the account's user ID. Only the listed intents
+# can be used.
+# [[skill.user]]
+# id = "amzn1.ask.person.0123456789abcdef"
+# name = "kids"
+# max_volume = 5
+# default_input = 2
+# intents = ["On", "Volume", "Input", "Mute", "Unmute"]
+
+# How the skill speaks
+[speech]
+# Send speech as SSML instead of plain text
+ssml = false
+# Directory of speech tables for locales other than en-US
+# locales = "locales"
+# What's said once a command is done: "terse", "normal" or "verbose"
+verbosity = "terse"
+
+# Expose an AVR to the Home app and Siri
+# [homekit]
+# pin = "031-45-154"
+# device = "receiver"
+# name = "Receiver"
+# port = 51826
+# storage = "homekit.json"
+
+# Accept Alexa Smart Home directives forwarded by the skill's Lambda function
+# [smart_home]
+# client_id = "amzn1.application-oa2-client.0123456789abcdef"
+# client_secret = "0123456789abcdef"
+# secret = "shared with the Lambda function"
+# gateway = "https://api.amazonalexa.com/v3/events"
+# storage = "smart_home.json"
+
+# Accept Google Assistant fulfillment requests
+# [google]
+# token = "0123456789abcdef"
+# agent_user_id = "alexa-avr-control"
+
+# Local control API, to send commands with POST /api/command. Requests must
+# have the token as a bearer token.
+# [api]
+# token = "0123456789abcdef"
+
+# Serve the control API over TLS as well, to clients with a certificate signed
+# by client_ca, which don't need the token
+# [api.tls]
+# port = 8443
+# cert = "server.pem"
+# key = "server.key"
+# client_ca = "clients-ca.pem"
+
+# Ask for a username and password for /stats, /api/history and
+# /api/openapi.json, which also work in place of the API token. Hash the
+# password with `alexa-avr-control hash-password`.
+# [basic_auth]
+# username = "admin"
+# password = "pbkdf2-sha256$<rounds>$<salt>$<hash>"
+
+# Serve several households' Alexa accounts, linked through an OAuth server,
+# each controlling only the AVRs listed for it. Access tokens are checked with
+# the server's token introspection endpoint.
+# [account_linking]
+# introspection_url = "https://auth.example.com/oauth2/introspect"
+# client_id = "alexa-avr-control"
+# client_secret = "0123456789abcdef"
+# [account_linking.accounts]
+# "alice@example.com" = ["receiver"]
+
+# Accept commands, one JSON object per line, on a Unix domain socket only the
+# user running the service can use
+# [socket]
+# path = "/run/alexa-avr-control/control.sock"
+
+# Take commands like /vol 5 from a Telegram chat, and alert it when the
+# connection to an AVR is lost
+# [telegram]
+# token = "123456:ABC-DEF1234ghIkl-zyx57W2v1u123ew11"
+# chats = [12345678]
+# device = "receiver"
+
+# Ping a dead man's switch, like healthchecks.io, every interval seconds while
+# the web service answers and every AVR is connected
+# [heartbeat]
+# url = "https://hc-ping.com/0123456789abcdef"
+# interval = 60
+
+# Notify when an AVR's connection looks dead: nothing, not even a heartbeat,
+# received for heartbeat_timeout seconds, or max_failures attempts to reconnect
+# failing in a row. Any of webhook, email and mqtt can be set.
+# [watchdog]
+# heartbeat_timeout = 90
+# max_failures = 3
+# webhook = "https://ntfy.sh/my-receiver-outages"
+#
+# [watchdog.email]
+# server = "smtp.example.com"
+# port = 587
+# username = "me@example.com"
+# password = "hunter2"
+# from = "Receiver <me@example.com>"
+# to = ["me@example.com"]
+#
+# [watchdog.mqtt]
+# host = "192.168.1.10"
+# port = 1883
+# topic = "alexa-avr-control/outage"
+
+# POST a JSON payload when an AVR's state changes. Events can be "power",
+# "volume", "mute", "input", "preset" and "connection".
+# [[webhook]]
+# url = "https://ntfy.sh/my-receiver"
+# events = ["power", "input"]
+# device = "receiver"
+
+# Keep a history of every command processed, served at /api/history, in an
+# SQLite database. Entries older than keep_days are removed, if it's set.
+# [history]
+# path = "history.db"
+# keep_days = 90
+
+# Keep an audit log of every command sent to the AVRs, with who asked for it
+# and the AVR's state before and after
+# [audit]
+# path = "audit.jsonl"
+
+# Log every HTTP request to the web service, with the client's address, status
+# and how long it took, in the "combined" format web servers use or as "json"
+# [access_log]
+# path = "access.log"
+# format = "combined"
+
+# Send a command at a time of day. Days can be "mon" to "sun", "weekdays" or
+# "weekends", every day if not given. Commands are "PowerOn", "PowerOff",
+# "Mute", "Unmute", { SetVolume = 30 } and { ChangeInput = 2 }.
+# [[schedule]]
+# name = "bedtime"
+# at = "23:30"
+# days = ["weekdays"]
+# command = "PowerOff"
+# device = "receiver"
+# enabled = true
+
+# Keep the volume down at night, whatever asks for it
+# [quiet_hours]
+# start = "22:00"
+# end = "07:00"
+# max_volume = 4
+
+# Remember the volume last used on each input, in a JSON file, and with
+# restore set it again when that input is changed back to
+# [volume_memory]
+# path = "volume_memory.json"
+# restore = true
+
+# Keep the commands waiting on a timer, like "turn it off in 45 minutes", in a
+# JSON file, so they're still sent after a restart
+# [timers]
+# path = "timers.json"
+"#;
+
+#[derive(Deserialize, Default)]
+pub struct Config {
+    #[serde(default, rename = "device")]
+    pub devices: Vec<DeviceConfig>,
+    #[serde(default)]
+    pub skill: SkillConfig,
+    #[serde(default)]
+    pub speech: SpeechConfig,
+    pub homekit: Option<HomekitConfig>,
+    pub smart_home: Option<SmartHomeConfig>,
+    pub google: Option<GoogleConfig>,
+    pub api: Option<ApiConfig>,
+    pub basic_auth: Option<BasicAuthConfig>,
+    pub account_linking: Option<AccountLinkingConfig>,
+    pub socket: Option<SocketConfig>,
+    pub telegram: Option<TelegramConfig>,
+    pub heartbeat: Option<HeartbeatConfig>,
+    pub watchdog: Option<WatchdogConfig>,
+    #[serde(default, rename = "webhook")]
+    pub webhooks: Vec<WebhookConfig>,
+    pub history: Option<HistoryConfig>,
+    pub access_log: Option<AccessLogConfig>,
+    pub audit: Option<AuditConfig>,
+    #[serde(default, rename = "schedule")]
+    pub schedules: Vec<ScheduleConfig>,
+    pub quiet_hours: Option<QuietHoursConfig>,
+    pub volume_memory: Option<VolumeMemoryConfig>,
+    pub timers: Option<TimersConfig>,
+}
+
+/// Connection details of an AVR, along with the name used to refer to it by
+/// voice and the protocol for its brand. `connect_timeout` is how many seconds
+/// to wait for it to accept the connection, if not the `--connect-timeout`
+/// given. The `table` protocol also needs the path of its table file, and
+/// `profile` limits it to its model's inputs and commands. `volume_curve` maps
+/// the 1 to 10 volume scale to its levels, see `crate::curve`. With `idle_off`,
+/// the AVR is turned off once it has been idle for that many hours. `intents`
+/// limits the intents that can be used on it, and `aliases` are other names for
+/// its inputs, keyed by the input's name or number. `hidden` inputs, given the
+/// same way, are left out of the lists of its inputs, and with `input_labels`,
+/// the names inputs were given on its on-screen menu are learned from it.
+/// `offsets` are how many dB louder inputs are than the rest, and `on_select`
+/// the commands to send on selecting an input, keyed the same way. With
+/// `zone2_offset`, Zone 2's volume is kept that many dB from the main zone's.
+/// `presets` are names for tuner presets, `sequences` named commands to send in
+/// turn, `sequence_delay` milliseconds apart, and `raw_codes` named codes that
+/// can be sent as they are, see `crate::raw`. A command taking longer than
+/// `slow_command` milliseconds to be carried out is logged as slow, see
+/// `crate::latency`.
+#[derive(Deserialize, Clone)]
+pub struct DeviceConfig {
+    pub name: String,
+    pub host: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    pub connect_timeout: Option<u64>,
+    #[serde(default = "default_protocol")]
+    pub protocol: String,
+    pub table: Option<String>,
+    pub profile: Option<String>,
+    pub volume_curve: Option<VolumeCurve>,
+    #[serde(default)]
+    pub retry: RetryConfig,
+    #[serde(default)]
+    pub power_on: PowerOnConfig,
+    #[serde(default)]
+    pub volume_ramp: VolumeRampConfig,
+    pub idle_off: Option<u64>,
+    #[serde(default)]
+    pub intents: IntentsConfig,
+    #[serde(default)]
+    pub hidden: Vec<String>,
+    #[serde(default)]
+    pub input_labels: bool,
+    #[serde(default)]
+    pub aliases: BTreeMap<String, Vec<String>>,
+    #[serde(default)]
+    pub offsets: BTreeMap<String, f32>,
+    #[serde(default)]
+    pub on_select: BTreeMap<String, Vec<AvrCommand>>,
+    pub zone2_offset: Option<f32>,
+    #[serde(default)]
+    pub presets: BTreeMap<String, String>,
+    #[serde(default)]
+    pub sequences: BTreeMap<String, Vec<AvrCommand>>,
+    pub sequence_delay: Option<u64>,
+    #[serde(default)]
+    pub raw_codes: BTreeMap<String, String>,
+    pub slow_command: Option<u64>,
+}
+
+impl DeviceConfig {
+    /// AVR at `host` and `port` going by `name`, with every other setting
+    /// left as it is when not in the config file
+    pub fn new(name: &str, host: &str, port: u16) -> DeviceConfig {
+        DeviceConfig {
+            name: name.to_owned(),
+            host: host.to_owned(),
+            port,
+            connect_timeout: None,
+            protocol: default_protocol(),
+            table: None,
+            profile: None,
+            volume_curve: None,
+            retry: RetryConfig::default(),
+            power_on: PowerOnConfig::default(),
+            volume_ramp: VolumeRampConfig::default(),
+            idle_off: None,
+            intents: IntentsConfig::default(),
+            hidden: vec![],
+            input_labels: false,
+            aliases: BTreeMap::new(),
+            offsets: BTreeMap::new(),
+            on_select: BTreeMap::new(),
+            zone2_offset: None,
+            presets: BTreeMap::new(),
+            sequences: BTreeMap::new(),
+            sequence_delay: None,
+            raw_codes: BTreeMap::new(),
+            slow_command: None,
+        }
+    }
+}
+
+fn default_port() -> u16 {
+    23
+}
+
+fn default_protocol() -> String {
+    "pioneer".to_owned()
+}
+
+/// Retrying commands that failed because the AVR didn't answer in time or
+/// didn't confirm the change. `attempts` is how many times to retry, none by
+/// default, and `delay` how many milliseconds to wait before the first retry,
+/// doubling with each retry after that.
+#[derive(Deserialize, Clone, Copy, Debug)]
+pub struct RetryConfig {
+    #[serde(default)]
+    pub attempts: u32,
+    #[serde(default = "default_retry_delay")]
+    pub delay: u64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig {
+            attempts: 0,
+            delay: default_retry_delay(),
+        }
+    }
+}
+
+fn default_retry_delay() -> u64 {
+    500
+}
+
+/// Waiting for an AVR to be ready after turning it on. If it hasn't reported
+/// being on when the power on command is checked, its power is polled for up
+/// to `max_wait` milliseconds after the command was sent, 8000 by default.
+/// Once it's on, commands after it wait another `settle` milliseconds, none by
+/// default, for models that need longer before they accept them.
+#[derive(Deserialize, Clone, Copy, Debug)]
+pub struct PowerOnConfig {
+    #[serde(default = "default_power_on_max_wait")]
+    pub max_wait: u64,
+    #[serde(default)]
+    pub settle: u64,
+}
+
+impl Default for PowerOnConfig {
+    fn default() -> Self {
+        PowerOnConfig {
+            max_wait: default_power_on_max_wait(),
+            settle: 0,
+        }
+    }
+}
+
+fn default_power_on_max_wait() -> u64 {
+    8_000
+}
+
+/// Pacing of the volume up and down codes used to reach a volume level. With a
+/// `step_delay` of 0 milliseconds, the default, all steps are written at once.
+/// Otherwise each step is written on its own once the AVR reported the last,
+/// that many milliseconds apart. The level is checked `settle` milliseconds
+/// after the last step, none by default.
+#[derive(Deserialize, Clone, Copy, Debug, Default)]
+pub struct VolumeRampConfig {
+    #[serde(default)]
+    pub step_delay: u64,
+    #[serde(default)]
+    pub settle: u64,
+}
+
+/// Mapping of the 1 to 10 volume scale to an AVR's volume levels, either
+/// `linear`, `logarithmic`, or the level for each number from 1 to 10
+#[derive(Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum VolumeCurve {
+    Named(String),
+    Table(Vec<u8>),
+}
+
+/// How the Alexa skill behaves. `confirm_power_off` asks before turning an AVR
+/// off, and `confirm_volume_jump` asks before changing the volume by more than
+/// that many steps. `when_busy` decides what happens to a request arriving
+/// while the AVR is still processing another. A request Alexa delivers again
+/// within `debounce` milliseconds is only processed once, and one seen again
+/// after that but within `replay_window` seconds is rejected. Each of `users` has
+/// their own settings, and if `allowed_accounts` is given, only the Alexa
+/// accounts with those user IDs can use the skill. `intents` limits the
+/// intents that can be used at all.
+/// With `keep_open`, answers to commands leave the session open for the next
+/// one. With `debug`, requests that can't be processed are answered with a JSON
+/// error rather than an empty 400. With `skip_speakers_with_headphones`,
+/// commands only changing what the speakers play aren't sent while headphones
+/// are plugged in.
+#[derive(Deserialize, Clone)]
+pub struct SkillConfig {
+    #[serde(default)]
+    pub confirm_power_off: bool,
+    pub confirm_volume_jump: Option<u8>,
+    #[serde(default)]
+    pub when_busy: WhenBusy,
+    #[serde(default = "default_debounce")]
+    pub debounce: u64,
+    #[serde(default = "default_replay_window")]
+    pub replay_window: u64,
+    #[serde(default, rename = "user")]
+    pub users: Vec<UserConfig>,
+    #[serde(default)]
+    pub allowed_accounts: Vec<String>,
+    #[serde(default)]
+    pub intents: IntentsConfig,
+    #[serde(default)]
+    pub keep_open: bool,
+    #[serde(default)]
+    pub debug: bool,
+    #[serde(default)]
+    pub skip_speakers_with_headphones: bool,
+}
+
+impl Default for SkillConfig {
+    fn default() -> Self {
+        SkillConfig {
+            confirm_power_off: false,
+            confirm_volume_jump: None,
+            when_busy: WhenBusy::default(),
+            debounce: default_debounce(),
+            replay_window: default_replay_window(),
+            users: vec![],
+            allowed_accounts: vec![],
+            intents: IntentsConfig::default(),
+            keep_open: false,
+            debug: false,
+            skip_speakers_with_headphones: false,
+        }
+    }
+}
+
+fn default_debounce() -> u64 {
+    2_000
+}
+
+fn default_replay_window() -> u64 {
+    150
+}
+
+/// Intents that are enabled. Only those in `allow` are, if given, and none of
+/// those in `deny`.
+#[derive(Deserialize, Clone, Default)]
+pub struct IntentsConfig {
+    pub allow: Option<Vec<String>>,
+    #[serde(default)]
+    pub deny: Vec<String>,
+}
+
+impl IntentsConfig {
+    pub fn enabled(&self, intent: &str) -> bool {
+        let allowed = match &self.allow {
+            Some(allow) => allow.iter().any(|allowed| allowed == intent),
+            None => true,
+        };
+        allowed && !self.deny.iter().any(|denied| denied == intent)
+    }
+}
+
+/// Settings for one person, matched by `id`, either the person ID Alexa sends
+/// for a recognized voice or the account's user ID. `max_volume` is the
+/// highest volume they can set, `default_input` the input used when they ask
+/// to change the input without saying which, and `intents` the only intents
+/// they can use, all of them if not given.
+#[derive(Deserialize, Clone)]
+pub struct UserConfig {
+    pub id: String,
+    pub name: Option<String>,
+    pub max_volume: Option<u8>,
+    pub default_input: Option<u8>,
+    pub intents: Option<Vec<String>>,
+}
+
+/// What to do with a command while the AVR is busy with another. `queue`
+/// processes it once the AVR is done, and `reject` says the AVR is busy
+/// instead.
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum WhenBusy {
+    #[default]
+    Queue,
+    Reject,
+}
+
+/// How the skill speaks. `ssml` sends speech as SSML, for more natural
+/// pauses, emphasis and numbers, instead of plain text. `locales` is a
+/// directory of speech tables for locales other than en-US.
+#[derive(Deserialize, Clone, Default)]
+pub struct SpeechConfig {
+    #[serde(default)]
+    pub ssml: bool,
+    pub locales: Option<String>,
+    #[serde(default)]
+    pub verbosity: Verbosity,
+}
+
+/// How much is said once a command is done. `terse` just says "Ok.",
+/// `normal` repeats the command, and `verbose` reads back the AVR's state
+/// after it.
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Verbosity {
+    #[default]
+    Terse,
+    Normal,
+    Verbose,
+}
+
+/// Settings for the HomeKit accessory server, which only runs if configured.
+/// `pin` is the setup code entered in the Home app when adding the accessory,
+/// and `device` the name of the AVR to expose, the first AVR if not given.
+#[derive(Deserialize, Clone)]
+pub struct HomekitConfig {
+    pub pin: String,
+    #[serde(default = "default_homekit_name")]
+    pub name: String,
+    #[serde(default = "default_homekit_port")]
+    pub port: u16,
+    #[serde(default = "default_homekit_storage")]
+    pub storage: String,
+    pub device: Option<String>,
+}
+
+fn default_homekit_name() -> String {
+    "Receiver".to_owned()
+}
+
+fn default_homekit_port() -> u16 {
+    51826
+}
+
+fn default_homekit_storage() -> String {
+    "homekit.json".to_owned()
+}
+
+/// Settings for the Alexa Smart Home interface, which only runs if
+/// configured. `client_id` and `client_secret` are the skill's credentials for
+/// sending events to Alexa, and `secret` is sent by the skill's Lambda function
+/// when forwarding directives. `gateway` is the event gateway for the skill's
+/// region.
+#[derive(Deserialize, Clone)]
+pub struct SmartHomeConfig {
+    pub client_id: String,
+    pub client_secret: String,
+    pub secret: String,
+    #[serde(default = "default_smart_home_gateway")]
+    pub gateway: String,
+    #[serde(default = "default_smart_home_storage")]
+    pub storage: String,
+}
+
+fn default_smart_home_gateway() -> String {
+    "https://api.amazonalexa.com/v3/events".to_owned()
+}
+
+fn default_smart_home_storage() -> String {
+    "smart_home.json".to_owned()
+}
+
+/// Settings for the Google Assistant fulfillment, which only runs if
+/// configured. `token` is the access token the Action's account linking gives
+/// Google, which it sends with every request.
+#[derive(Deserialize, Clone)]
+pub struct GoogleConfig {
+    pub token: String,
+    #[serde(default = "default_google_agent_user_id")]
+    pub agent_user_id: String,
+}
+
+fn default_google_agent_user_id() -> String {
+    "alexa-avr-control".to_owned()
+}
+
+/// Settings for the local control API, which only runs if configured. `token`
+/// must be sent as a bearer token with every request, unless it's made over
+/// `tls` with a client certificate. It needs one or the other.
+#[derive(Deserialize, Clone)]
+pub struct ApiConfig {
+    pub token: Option<String>,
+    pub tls: Option<ApiTlsConfig>,
+}
+
+/// Serving the control API over TLS on `port`, with the server's `cert` and
+/// `key`, to clients with a certificate signed by `client_ca`, all PEM files.
+/// See `crate::tls`.
+#[derive(Deserialize, Clone)]
+pub struct ApiTlsConfig {
+    #[serde(default = "default_api_tls_port")]
+    pub port: u16,
+    pub cert: String,
+    pub key: String,
+    pub client_ca: String,
+}
+
+fn default_api_tls_port() -> u16 {
+    8443
+}
+
+/// Username and hashed password protecting the web service's statistics,
+/// history and OpenAPI document, which the control API also takes in place of
+/// its token, see `crate::basic_auth`
+#[derive(Deserialize, Clone)]
+pub struct BasicAuthConfig {
+    pub username: String,
+    pub password: String,
+}
+
+/// Account linking, for one instance serving several Alexa accounts. Access
+/// tokens are checked with the OAuth server's token introspection endpoint,
+/// `introspection_url`, authenticating with `client_id` and `client_secret`,
+/// and `accounts` lists the AVRs each account controls, keyed by the `sub`
+/// or `username` the endpoint answers with. See `crate::linking`.
+#[derive(Deserialize, Clone)]
+pub struct AccountLinkingConfig {
+    pub introspection_url: String,
+    pub client_id: String,
+    pub client_secret: String,
+    #[serde(default)]
+    pub accounts: BTreeMap<String, Vec<String>>,
+}
+
+/// Unix domain socket accepting commands from the same host, at `path`
+#[derive(Deserialize, Clone)]
+pub struct SocketConfig {
+    pub path: String,
+}
+
+/// Settings for the Telegram bot, which only runs if configured. `token` is
+/// the bot's token from BotFather, and `chats` the IDs of the only chats it
+/// takes commands from and sends alerts to. Commands go to `device`, the first
+/// AVR if not given.
+#[derive(Deserialize, Clone)]
+pub struct TelegramConfig {
+    pub token: String,
+    #[serde(default)]
+    pub chats: Vec<i64>,
+    pub device: Option<String>,
+}
+
+/// Url to ping every `interval` seconds while the service is healthy
+#[derive(Deserialize, Clone)]
+pub struct HeartbeatConfig {
+    pub url: String,
+    #[serde(default = "default_heartbeat_interval")]
+    pub interval: u64,
+}
+
+fn default_heartbeat_interval() -> u64 {
+    60
+}
+
+/// Settings for the connectivity watchdog, which only runs if configured. An
+/// outage is nothing received from a connected AVR for `heartbeat_timeout`
+/// seconds, 0 to never check, or `max_failures` failed attempts to reconnect
+/// in a row. Outages are notified to `webhook`, `email` and `mqtt`.
+#[derive(Deserialize, Clone)]
+pub struct WatchdogConfig {
+    #[serde(default = "default_watchdog_heartbeat_timeout")]
+    pub heartbeat_timeout: u64,
+    #[serde(default = "default_watchdog_max_failures")]
+    pub max_failures: u32,
+    pub webhook: Option<String>,
+    pub email: Option<EmailConfig>,
+    pub mqtt: Option<MqttConfig>,
+}
+
+fn default_watchdog_heartbeat_timeout() -> u64 {
+    90
+}
+
+fn default_watchdog_max_failures() -> u32 {
+    3
+}
+
+/// SMTP server to send email through, and who it's from and to
+#[derive(Deserialize, Clone)]
+pub struct EmailConfig {
+    pub server: String,
+    #[serde(default = "default_email_port")]
+    pub port: u16,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub from: String,
+    pub to: Vec<String>,
+}
+
+fn default_email_port() -> u16 {
+    587
+}
+
+/// MQTT broker, and the topic to publish to
+#[derive(Deserialize, Clone)]
+pub struct MqttConfig {
+    pub host: String,
+    #[serde(default = "default_mqtt_port")]
+    pub port: u16,
+    pub topic: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+fn default_mqtt_port() -> u16 {
+    1883
+}
+
+/// Url to POST events to. `events` limits it to the listed events, and
+/// `device` to events from the named AVR.
+#[derive(Deserialize, Clone)]
+pub struct WebhookConfig {
+    pub url: String,
+    #[serde(default)]
+    pub events: Vec<String>,
+    pub device: Option<String>,
+}
+
+/// Database to keep the command history in, which is only kept if
+/// configured, and how many days of it to keep, all of it if not given
+#[derive(Deserialize, Clone)]
+pub struct HistoryConfig {
+    pub path: String,
+    pub keep_days: Option<u32>,
+}
+
+/// File to keep the audit log of commands in, which is only kept if
+/// configured
+#[derive(Deserialize, Clone)]
+pub struct AuditConfig {
+    pub path: String,
+}
+
+/// File to log every HTTP request to the web service in, and its format
+#[derive(Deserialize, Clone)]
+pub struct AccessLogConfig {
+    pub path: String,
+    #[serde(default)]
+    pub format: AccessLogFormat,
+}
+
+/// Format of the access log's lines. `combined` is the combined log format
+/// of web servers, with the milliseconds the request took added at the end,
+/// and `json` a JSON object.
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessLogFormat {
+    #[default]
+    Combined,
+    Json,
+}
+
+/// Command sent at a time of day. `at` is the local time as `HH:MM`, and
+/// `days` the days of the week to send it on, every day if not given.
+/// `device` is the AVR to send it to, the first AVR if not given, and
+/// `enabled` turns the job off without removing it.
+#[derive(Deserialize, Clone)]
+pub struct ScheduleConfig {
+    pub name: String,
+    pub at: String,
+    #[serde(default)]
+    pub days: Vec<String>,
+    pub command: AvrCommand,
+    pub device: Option<String>,
+    #[serde(default = "default_schedule_enabled")]
+    pub enabled: bool,
+}
+
+fn default_schedule_enabled() -> bool {
+    true
+}
+
+/// Quiet hours, from `start` to `end` in local time as `HH:MM`, which can span
+/// midnight. The volume is kept to `max_volume` at most, from 1 to 10, during
+/// them.
+#[derive(Deserialize, Clone)]
+pub struct QuietHoursConfig {
+    pub start: String,
+    pub end: String,
+    pub max_volume: u8,
+}
+
+/// Volume last used on each input, remembered in the JSON file at `path`.
+/// With `restore`, it's set again when the input is changed back to.
+#[derive(Deserialize, Clone)]
+pub struct VolumeMemoryConfig {
+    #[serde(default = "default_volume_memory_path")]
+    pub path: String,
+    #[serde(default)]
+    pub restore: bool,
+}
+
+fn default_volume_memory_path() -> String {
+    "volume_memory.json".to_owned()
+}
+
+/// Commands waiting on a timer, kept in the JSON file at `path` so they
+/// survive restarts
+#[derive(Deserialize, Clone)]
+pub struct TimersConfig {
+    #[serde(default = "default_timers_path")]
+    pub path: String,
+}
+
+fn default_timers_path() -> String {
+    "timers.json".to_owned()
+}
+
+/// Read and parse the config file at path
+pub fn load(path: &str) -> Result<Config, Error> {
+    let contents =
+        fs::read_to_string(path).context(format!("Could not read config file: {}", path))?;
+    let config =
+        toml::from_str(&contents).context(format!("Could not parse config file: {}", path))?;
+    Ok(config)
+}
+
+/// Example config file, with every setting commented
+pub fn example() -> String {
+    let inputs: Vec<_> = Pioneer
+        .inputs()
+        .iter()
+        .enumerate()
+        .map(|(i, name)| format!("#   {:>2}  {}", i + 1, name))
+        .collect();
+    EXAMPLE.replace("{inputs}", &inputs.join("\n"))
+}
+
+/// Example config file with the AVR's name, host and port filled in, as
+/// found by the `setup` wizard, along with the inputs to hide and other names
+/// for inputs, if any
+pub fn generated(
+    name: &str,
+    host: &str,
+    port: u16,
+    hidden: &[String],
+    aliases: &BTreeMap<String, Vec<String>>,
+) -> String {
+    let quote = |value: &str| toml::Value::String(value.to_owned()).to_string();
+    let list = |values: &[String]| {
+        let values: Vec<_> = values.iter().map(|value| quote(value)).collect();
+        format!("[{}]", values.join(", "))
+    };
+
+    let mut config = example()
+        .replacen("name = \"receiver\"", &format!("name = {}", quote(name)), 1)
+        .replacen(
+            "host = \"192.168.1.50\"",
+            &format!("host = {}", quote(host)),
+            1,
+        )
+        .replacen("\nport = 23\n", &format!("\nport = {}\n", port), 1);
+    if !hidden.is_empty() {
+        config = config.replacen(
+            "# hidden = [\"Phono\", \"CD-R/Tape\", \"Sirius\"]",
+            &format!("hidden = {}", list(hidden)),
+            1,
+        );
+    }
+    if !aliases.is_empty() {
+        let section: Vec<_> = aliases
+            .iter()
+            .map(|(input, names)| format!("{} = {}", quote(input), list(names)))
+            .collect();
+        config = config.replacen(
+            "# [device.aliases]\n# Game = [\"playstation\", \"ps5\"]\n# Tuner = [\"radio\"]",
+            &format!("[device.aliases]\n{}", section.join("\n")),
+            1,
+        );
+    }
+    config
+}
+
+/// Write the example config file to path, unless there's a file there already
+pub fn init(path: &str) -> Result<(), Error> {
+    if Path::new(path).exists() {
+        bail!("Config file already exists: {}", path);
+    }
+    fs::write(path, example()).context(format!("Could not write config file: {}", path))?;
+    Ok(())
+}
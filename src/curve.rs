@@ -0,0 +1,224 @@
+/// This module changes how the 1 to 10 volume scale maps to an AVR's volume
+/// levels, for speakers where the low numbers are barely audible.
+///
+/// By default the scale is linear, each step being a tenth of the highest
+/// level this skill sets. A logarithmic curve takes bigger steps at the low
+/// end and smaller ones near the top, and a table gives the level of each
+/// number from 1 to 10 outright:
+///
+/// ```toml
+/// volume_curve = "logarithmic"
+/// volume_curve = [40, 60, 72, 80, 86, 91, 95, 98, 100, 101]
+/// ```
+///
+/// Volume levels reported back are mapped through the same curve, so the
+/// volume read back is the number it was set to.
+use crate::{
+    avr::{AvrCommand, AvrQuery, Key},
+    config::{DeviceConfig, VolumeCurve},
+    events::AvrEvent,
+    protocol::{AvrProtocol, Message},
+};
+use failure::{bail, Error};
+use std::sync::Arc;
+use tracing::info;
+
+/// The AVR's protocol, with the volume scale mapped to its levels by a curve
+pub struct Curve {
+    inner: Arc<dyn AvrProtocol>,
+    /// Volume level of each number from 0 to 10
+    levels: [u8; 11],
+}
+
+impl Curve {
+    /// Wrap the protocol with the AVR's configured volume curve, if it isn't
+    /// linear
+    pub fn wrap(
+        inner: Arc<dyn AvrProtocol>,
+        config: &DeviceConfig,
+    ) -> Result<Arc<dyn AvrProtocol>, Error> {
+        let mut levels = [0; 11];
+        match &config.volume_curve {
+            None => return Ok(inner),
+            Some(VolumeCurve::Named(name)) if name.eq_ignore_ascii_case("linear") => {
+                return Ok(inner)
+            }
+            Some(VolumeCurve::Named(name)) if name.eq_ignore_ascii_case("logarithmic") => {
+                let max = f32::from(inner.volume_level(10));
+                for (n, level) in levels.iter_mut().enumerate() {
+                    let weight = (n as f32).ln_1p() / 10_f32.ln_1p();
+                    *level = (weight * max).ceil() as u8;
+                }
+            }
+            Some(VolumeCurve::Named(name)) => bail!("Unknown volume curve: {:?}", name),
+            Some(VolumeCurve::Table(table)) => {
+                if table.len() != 10 {
+                    bail!("Volume curve needs 10 levels, got {}", table.len());
+                }
+                if table.windows(2).any(|pair| pair[0] > pair[1]) {
+                    bail!("Volume curve levels must not decrease: {:?}", table);
+                }
+                levels[1..].copy_from_slice(table);
+            }
+        }
+        info!("Volume curve for {}: {:?}", config.name, &levels[1..]);
+
+        Ok(Arc::new(Curve { inner, levels }))
+    }
+}
+
+impl AvrProtocol for Curve {
+    fn supports(&self, cmd: &AvrCommand) -> bool {
+        self.inner.supports(cmd)
+    }
+
+    fn code(&self, cmd: &AvrCommand) -> String {
+        self.inner.code(cmd)
+    }
+
+    fn query_code(&self, query: &AvrQuery) -> String {
+        self.inner.query_code(query)
+    }
+
+    fn expected(&self, cmd: &AvrCommand) -> String {
+        self.inner.expected(cmd)
+    }
+
+    /// The volume is confirmed with the level on the curve
+    fn matches(&self, cmd: &AvrCommand, response: &str) -> bool {
+        match cmd {
+            AvrCommand::SetVolume(n) => self
+                .parse_events(response)
+                .contains(&AvrEvent::Volume(self.volume_level(*n))),
+            _ => self.inner.matches(cmd, response),
+        }
+    }
+
+    fn volume_level(&self, n: u8) -> u8 {
+        self.levels[usize::from(n.min(10))]
+    }
+
+    fn volume_step(&self) -> u8 {
+        self.inner.volume_step()
+    }
+
+    fn level_db(&self) -> f32 {
+        self.inner.level_db()
+    }
+
+    fn volume_db(&self, level: u8) -> Option<f32> {
+        self.inner.volume_db(level)
+    }
+
+    /// Where the level falls on the curve, between the numbers either side of
+    /// it, so a level set from the scale reads back as the same number
+    fn volume_percent(&self, level: u8) -> u8 {
+        for n in 1..self.levels.len() {
+            let (low, high) = (self.levels[n - 1], self.levels[n]);
+            if level <= high {
+                let part = match high - low {
+                    0 => 1.0,
+                    span => f32::from(level.saturating_sub(low)) / f32::from(span),
+                };
+                return ((n as f32 - 1.0 + part) * 10.0).round() as u8;
+            }
+        }
+        100
+    }
+
+    fn db_level(&self, db: f32) -> Option<u8> {
+        self.inner.db_level(db)
+    }
+
+    fn volume_code(&self, level: u8) -> Option<String> {
+        self.inner.volume_code(level)
+    }
+
+    fn zone2_volume_code(&self, db: f32) -> Option<String> {
+        self.inner.zone2_volume_code(db)
+    }
+
+    fn inputs(&self) -> Vec<String> {
+        self.inner.inputs()
+    }
+
+    fn input_number(&self, input: &str) -> Option<u8> {
+        self.inner.input_number(input)
+    }
+
+    fn input_code(&self, n: u8) -> Option<String> {
+        self.inner.input_code(n)
+    }
+
+    fn input_name(&self, input: &str) -> Option<String> {
+        self.inner.input_name(input)
+    }
+
+    fn tuner_input(&self) -> Option<String> {
+        self.inner.tuner_input()
+    }
+
+    fn preset_code(&self, preset: &str) -> Option<String> {
+        self.inner.preset_code(preset)
+    }
+
+    fn listening_mode_code(&self, mode: &str) -> Option<String> {
+        self.inner.listening_mode_code(mode)
+    }
+
+    fn listening_mode_named(&self, name: &str) -> Option<String> {
+        self.inner.listening_mode_named(name)
+    }
+
+    fn speaker_system_code(&self, system: &str) -> Option<String> {
+        self.inner.speaker_system_code(system)
+    }
+
+    fn speaker_system_named(&self, name: &str) -> Option<String> {
+        self.inner.speaker_system_named(name)
+    }
+
+    fn video_resolution_code(&self, resolution: &str) -> Option<String> {
+        self.inner.video_resolution_code(resolution)
+    }
+
+    fn video_resolution_named(&self, name: &str) -> Option<String> {
+        self.inner.video_resolution_named(name)
+    }
+
+    fn subwoofer_level_code(&self, level: u8) -> Option<String> {
+        self.inner.subwoofer_level_code(level)
+    }
+
+    fn subwoofer_step(&self) -> u8 {
+        self.inner.subwoofer_step()
+    }
+
+    fn lfe_attenuation_code(&self, db: u8) -> Option<String> {
+        self.inner.lfe_attenuation_code(db)
+    }
+
+    fn transport(&self, input: &str, key: Key) -> Option<AvrCommand> {
+        self.inner.transport(input, key)
+    }
+
+    fn headphones_code(&self) -> Option<String> {
+        self.inner.headphones_code()
+    }
+
+    fn audio_status_code(&self) -> Option<String> {
+        self.inner.audio_status_code()
+    }
+
+    fn signal_present(&self, response: &str) -> Option<bool> {
+        self.inner.signal_present(response)
+    }
+
+    fn parse_event(&self, message: &str) -> Option<AvrEvent> {
+        self.inner.parse_event(message)
+    }
+
+    fn classify(&self, message: &str) -> Message {
+        self.inner.classify(message)
+    }
+}
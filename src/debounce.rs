@@ -0,0 +1,77 @@
+/// This module deduplicates Alexa requests delivered more than once, and
+/// rejects captured requests replayed later.
+///
+/// Alexa sometimes delivers the same request twice within a second, with the
+/// same request id. Processing both would send the AVR the command twice, so
+/// a second input change or volume jump. Instead, a request with the id of
+/// one seen within the window gets the first one's response, waiting for it if
+/// the first is still being processed.
+///
+/// Request ids are remembered for longer than that, as long as the signature
+/// check accepts a request's timestamp. A signed request captured and posted
+/// again would pass that check, so one with an id seen after the window but
+/// within that time is a replay, and isn't processed at all.
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::sync::OnceCell;
+use tracing::{info, warn};
+
+/// When a request was first seen, and its response once it's ready
+type Seen<T> = (Instant, Arc<OnceCell<T>>);
+
+/// Responses of the requests seen within the window, and the ids of those
+/// seen within `remember`, by request id. Cloned for each request.
+#[derive(Clone)]
+pub struct Debounce<T> {
+    window: Duration,
+    remember: Duration,
+    recent: Arc<Mutex<HashMap<String, Seen<T>>>>,
+}
+
+impl<T: Clone> Debounce<T> {
+    /// Deduplicate requests seen within `window` of each other, and reject
+    /// those seen again after it but within `remember`. A window of zero
+    /// turns deduplication off, and `remember` of zero replay protection.
+    pub fn new(window: Duration, remember: Duration) -> Self {
+        Debounce {
+            window,
+            remember,
+            recent: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Get the response to the request with id `key` from `process`, unless
+    /// it was already seen within the window, in which case the response to
+    /// the first delivery is used. `None` if it's a replay of a request seen
+    /// before that.
+    pub async fn run<F: Future<Output = T>>(&self, key: &str, process: F) -> Option<T> {
+        let response = {
+            let mut recent = self.recent.lock().unwrap();
+            let now = Instant::now();
+            let keep = self.window.max(self.remember);
+            recent.retain(|_, (seen, _)| now.duration_since(*seen) < keep);
+
+            match recent.get(key) {
+                Some((seen, response)) if now.duration_since(*seen) < self.window => {
+                    info!("Duplicate request, reusing response of first delivery");
+                    response.clone()
+                }
+                Some(_) => {
+                    warn!("Replayed request, not processing it");
+                    return None;
+                }
+                None => {
+                    let response = Arc::new(OnceCell::new());
+                    recent.insert(key.to_owned(), (now, response.clone()));
+                    response
+                }
+            }
+        };
+
+        Some(response.get_or_init(|| process).await.clone())
+    }
+}
@@ -0,0 +1,223 @@
+/// This module manages the configured AVRs.   
+///
+/// Each AVR gets its own telnet task, event bus and command queue, and
+/// requests are routed to one of them by name.
+use crate::{
+    aliases::InputNames,
+    audit::Audit,
+    avr::AvrCommand,
+    config::{DeviceConfig, IntentsConfig},
+    events::{self, EventBus},
+    history::History,
+    identity::Identifier,
+    idle, labels,
+    latency::Latencies,
+    presets::Presets,
+    protocol::{self, AvrProtocol},
+    queue::{self, CommandQueue, Records},
+    quiet::QuietHours,
+    raw::RawCodes,
+    replay::Recorder,
+    scheduler::Timers,
+    sequence::Sequences,
+    telnet::{self, Backoff},
+    undo::UndoStack,
+    zone,
+};
+use failure::{bail, Error};
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
+
+/// A configured AVR, the queue to send it commands and its event bus, along
+/// with the quiet hours and intents that apply to it and the names of its
+/// inputs, presets, sequences and raw codes, the commands waiting to be sent
+/// to it after a delay, the changes that can be undone, what it says it is,
+/// and how long it takes to carry out commands
+#[derive(Clone)]
+pub struct Device {
+    pub name: String,
+    pub queue: CommandQueue,
+    pub events: EventBus,
+    pub quiet: Option<QuietHours>,
+    pub intents: IntentsConfig,
+    pub inputs: InputNames,
+    pub presets: Presets,
+    pub sequences: Sequences,
+    pub raw: RawCodes,
+    pub timers: Timers,
+    pub undo: UndoStack,
+    pub identity: Identifier,
+    pub latency: Latencies,
+}
+
+impl Device {
+    /// Name of the input reported by `AvrEvent::Input`, as learned from the
+    /// AVR or otherwise the protocol's
+    pub fn input_name(&self, input: &str) -> Option<String> {
+        let protocol = self.events.protocol();
+        let n = protocol.input_number(input)?;
+        self.inputs.name(n, &protocol.inputs())
+    }
+}
+
+/// Milliseconds a command can take before it's logged as slow, unless the
+/// AVR's `slow_command` says otherwise
+const DEFAULT_SLOW_COMMAND: u64 = 2_000;
+
+/// All configured AVRs. Cloned for each request.
+#[derive(Clone)]
+pub struct Devices {
+    devices: Arc<Vec<Device>>,
+}
+
+impl Devices {
+    pub fn iter(&self) -> impl Iterator<Item = &Device> {
+        self.devices.iter()
+    }
+
+    /// Only the AVRs with the names, in the order they're configured
+    pub fn only(&self, names: &[String]) -> Devices {
+        let devices = self
+            .devices
+            .iter()
+            .filter(|device| {
+                names
+                    .iter()
+                    .any(|name| device.name.eq_ignore_ascii_case(name))
+            })
+            .cloned()
+            .collect();
+        Devices {
+            devices: Arc::new(devices),
+        }
+    }
+
+    /// Get the AVR with the given name, ignoring case. The first configured
+    /// AVR is used when no name is given.
+    pub fn get(&self, name: Option<&str>) -> Option<&Device> {
+        match name {
+            Some(name) => self
+                .devices
+                .iter()
+                .find(|device| device.name.eq_ignore_ascii_case(name.trim())),
+            None => self.devices.first(),
+        }
+    }
+}
+
+/// Start the telnet and command queue tasks for each configured AVR, the idle
+/// task for those turned off when idle, and the task learning the input labels
+/// of those configured to. In dry run mode, commands are
+/// never sent to the AVRs. With a `recorder`, the exchanges with every AVR are
+/// recorded to its transcript, and with a `history` or an `audit` log, the
+/// commands processed are added to them. During `quiet` hours, the volume is
+/// kept down.
+pub fn run(
+    configs: Vec<DeviceConfig>,
+    backoff: Backoff,
+    dry_run: bool,
+    recorder: Option<Recorder>,
+    history: Option<History>,
+    audit: Option<Audit>,
+    quiet: Option<QuietHours>,
+) -> Result<Devices, Error> {
+    let mut devices = vec![];
+
+    for config in configs {
+        let protocol = protocol::from_config(&config)?;
+        let inputs = InputNames::new(&*protocol, &config.aliases, &config.hidden)?;
+        let presets = Presets::new(&*protocol, &config.presets)?;
+        let sequences = Sequences::new(&*protocol, &config.sequences, config.sequence_delay)?;
+        let on_select = on_select(&*protocol, &config)?;
+        let events = EventBus::new(protocol.clone());
+        events::log_changes(&config.name, &events);
+
+        let retry = config.retry;
+        let power_on = config.power_on;
+        let idle_off = config.idle_off;
+        let zone2_offset = config.zone2_offset;
+        let input_labels = config.input_labels;
+        let latency = Latencies::new(Duration::from_millis(
+            config.slow_command.unwrap_or(DEFAULT_SLOW_COMMAND),
+        ));
+        let conn = telnet::run(
+            config.host,
+            config.port,
+            backoff.with_connect_timeout(config.connect_timeout),
+            protocol,
+            events.clone(),
+            dry_run,
+            recorder.clone(),
+        )
+        .with_volume_ramp(config.volume_ramp);
+        let queue = queue::run(
+            config.name.clone(),
+            conn.clone(),
+            retry,
+            power_on,
+            Records {
+                history: history.clone(),
+                audit: audit.clone(),
+                latency: latency.clone(),
+            },
+            quiet,
+            on_select,
+        );
+        if let Some(offset) = zone2_offset {
+            zone::link(config.name.clone(), offset, conn.clone())?;
+        }
+        if input_labels {
+            labels::run(config.name.clone(), conn.clone(), inputs.clone())?;
+        }
+        let raw = RawCodes::new(&config.raw_codes, conn.clone());
+        let identity = Identifier::new(conn.clone());
+        if let Some(hours) = idle_off {
+            idle::run(config.name.clone(), hours, conn, queue.clone());
+        }
+        devices.push(Device {
+            queue,
+            name: config.name,
+            events,
+            quiet,
+            intents: config.intents,
+            inputs,
+            presets,
+            sequences,
+            raw,
+            timers: Timers::default(),
+            undo: UndoStack::default(),
+            identity,
+            latency,
+        });
+    }
+
+    Ok(Devices {
+        devices: Arc::new(devices),
+    })
+}
+
+/// Commands to send on selecting each of the AVR's inputs, keyed by input
+/// number rather than the configured name or number
+fn on_select(
+    protocol: &dyn AvrProtocol,
+    config: &DeviceConfig,
+) -> Result<BTreeMap<u8, Vec<AvrCommand>>, Error> {
+    let mut on_select = BTreeMap::new();
+    for (input, commands) in &config.on_select {
+        let n = match protocol.find_input(input) {
+            Some(n) => n,
+            None => bail!("No input named {:?} for on_select", input),
+        };
+        if let Some(cmd) = commands
+            .iter()
+            .find(|cmd| matches!(cmd, AvrCommand::ChangeInput(_)))
+        {
+            bail!(
+                "Can't change input on selecting input {:?}: {:?}",
+                input,
+                cmd
+            );
+        }
+        on_select.insert(n, commands.clone());
+    }
+    Ok(on_select)
+}
@@ -0,0 +1,129 @@
+/// This module finds Pioneer AVRs on the local network using SSDP.
+///
+/// An M-SEARCH request is multicast for UPnP media renderers, then the device
+/// description of everything that responds is fetched to check whether it's a
+/// Pioneer AVR, and to get its name and model.
+use failure::{Error, ResultExt};
+use hyper::{body, Client};
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant},
+};
+use tokio::{net::UdpSocket, time::timeout};
+use tracing::{debug, info};
+
+const SSDP_ADDR: &str = "239.255.255.250:1900";
+const SEARCH_TARGET: &str = "urn:schemas-upnp-org:device:MediaRenderer:1";
+
+/// AVR found on the network
+pub struct Receiver {
+    pub host: IpAddr,
+    pub name: String,
+    pub model: String,
+}
+
+/// Search the network for AVRs, waiting `wait` for responses
+pub async fn discover(wait: Duration) -> Result<Vec<Receiver>, Error> {
+    info!("Searching for AVRs via SSDP...");
+
+    let socket = UdpSocket::bind("0.0.0.0:0")
+        .await
+        .context("Could not bind socket for SSDP discovery")?;
+    let request = format!(
+        "M-SEARCH * HTTP/1.1\r\nHOST: {}\r\nMAN: \"ssdp:discover\"\r\nMX: {}\r\nST: {}\r\n\r\n",
+        SSDP_ADDR,
+        wait.as_secs().max(1),
+        SEARCH_TARGET
+    );
+    socket
+        .send_to(request.as_bytes(), SSDP_ADDR)
+        .await
+        .context("Could not send SSDP search request")?;
+
+    let locations = search_responses(&socket, wait).await;
+
+    let mut receivers = vec![];
+    for (addr, location) in locations {
+        match describe(&location).await {
+            Ok(description) => {
+                let manufacturer = xml_value(&description, "manufacturer").unwrap_or_default();
+                if !manufacturer.to_lowercase().contains("pioneer") {
+                    debug!("Ignoring {} device at {}", manufacturer, addr);
+                    continue;
+                }
+                let receiver = Receiver {
+                    host: addr.ip(),
+                    name: xml_value(&description, "friendlyName").unwrap_or_default(),
+                    model: xml_value(&description, "modelName").unwrap_or_default(),
+                };
+                info!(
+                    "Found AVR: {} ({}) at {}",
+                    receiver.name, receiver.model, receiver.host
+                );
+                receivers.push(receiver);
+            }
+            Err(e) => debug!("Could not get device description from {}: {}", location, e),
+        }
+    }
+
+    Ok(receivers)
+}
+
+/// Collect the address and description location of each device responding to
+/// the search, until `wait` has passed
+async fn search_responses(socket: &UdpSocket, wait: Duration) -> Vec<(SocketAddr, String)> {
+    let deadline = Instant::now() + wait;
+    let mut buf = [0; 2048];
+    let mut locations: Vec<(SocketAddr, String)> = vec![];
+
+    while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+        let (n, addr) = match timeout(remaining, socket.recv_from(&mut buf)).await {
+            Ok(Ok(received)) => received,
+            Ok(Err(e)) => {
+                debug!("Error receiving SSDP response: {}", e);
+                continue;
+            }
+            Err(_) => break,
+        };
+
+        let response = String::from_utf8_lossy(&buf[..n]);
+        debug!("SSDP response from {}: {:?}", addr, response);
+        if let Some(location) = header(&response, "location") {
+            if !locations.iter().any(|(_, known)| known == location) {
+                locations.push((addr, location.to_owned()));
+            }
+        }
+    }
+
+    locations
+}
+
+/// Fetch the device description XML from its location
+async fn describe(location: &str) -> Result<String, Error> {
+    let response = Client::new().get(location.parse()?).await?;
+    let bytes = body::to_bytes(response.into_body()).await?;
+    Ok(String::from_utf8(bytes.to_vec())?)
+}
+
+/// Get the value of a header in an SSDP response, ignoring case of the name
+fn header<'a>(response: &'a str, name: &str) -> Option<&'a str> {
+    response.lines().find_map(|line| {
+        let mut parts = line.splitn(2, ':');
+        let key = parts.next()?.trim();
+        let value = parts.next()?.trim();
+        if key.eq_ignore_ascii_case(name) {
+            Some(value)
+        } else {
+            None
+        }
+    })
+}
+
+/// Get the text of the first element with the given tag in an XML document
+fn xml_value(xml: &str, tag: &str) -> Option<String> {
+    let open = format!("<{}>", tag);
+    let close = format!("</{}>", tag);
+    let start = xml.find(&open)? + open.len();
+    let end = xml[start..].find(&close)? + start;
+    Some(xml[start..end].trim().to_owned())
+}
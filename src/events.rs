@@ -0,0 +1,301 @@
+/// This module handles the state change events parsed from the messages the
+/// AVR sends over telnet, and keeps a cache of the last known state.
+///
+/// The AVR reports every change of state, whether it was requested by this
+/// skill or made some other way, like with the physical remote. The telnet
+/// task classifies each message it receives using the AVR's `AvrProtocol`,
+/// and publishes the state changes to the `EventBus`, which updates the
+/// cached state and broadcasts the change to any subscribers.
+use crate::protocol::AvrProtocol;
+use std::{
+    sync::{Arc, Mutex, RwLock},
+    time::Instant,
+};
+use tokio::sync::broadcast;
+use tracing::info;
+
+/// State change reported by the AVR, or a change to the telnet connection to
+/// it
+#[derive(Clone, Debug, PartialEq)]
+pub enum AvrEvent {
+    Power(bool),
+    Volume(u8),
+    Mute(bool),
+    Input(String),
+    Preset(String),
+    SoundRetriever(bool),
+    DialogEnhancement(bool),
+    ListeningMode(String),
+    SpeakerSystem(String),
+    /// Subwoofer channel level, in the AVR's levels
+    SubwooferLevel(u8),
+    /// dB the LFE channel is attenuated by
+    LfeAttenuation(u8),
+    /// Whether headphones are plugged in, which turns the speakers off
+    Headphones(bool),
+    VideoConverter(bool),
+    VideoResolution(String),
+    /// Whether the network stays on in standby, so the AVR can be turned on
+    /// over telnet
+    NetworkStandby(bool),
+    /// Name the input was given on the AVR's on-screen menu, `None` if it
+    /// wasn't renamed
+    InputLabel(String, Option<String>),
+    Model(String),
+    Firmware(String),
+    Connected(bool),
+}
+
+/// Last known state of the AVR. Fields are `None` until the AVR reports them.
+/// `previous_input` is the input before the current one, once the input has
+/// changed, and `previous_volume` the volume before the last command setting
+/// the volume or muting, see `EventBus::remember_volume`. `preset` is the
+/// tuner preset, as the AVR reports it. `sound_retriever` and
+/// `dialog_enhancement` are whether those are on, and `listening_mode` the
+/// listening mode and `speaker_system` the speaker system setting, as the AVR
+/// reports them. `subwoofer_level` is the subwoofer's channel level, in the
+/// AVR's levels, and `lfe_attenuation` the dB the LFE channel is attenuated
+/// by. `headphones` is whether headphones are plugged in.
+/// `video_converter` is whether the video converter is on, and
+/// `video_resolution` the HDMI output resolution, as the AVR reports it.
+/// `network_standby` is whether the network stays on in standby.
+/// `model` and `firmware` are only known once the AVR is asked for them, see
+/// `crate::identity`.
+#[derive(Clone, Debug, Default)]
+pub struct CachedState {
+    pub power: Option<bool>,
+    pub volume: Option<u8>,
+    pub mute: Option<bool>,
+    pub input: Option<String>,
+    pub previous_input: Option<String>,
+    pub previous_volume: Option<PreviousVolume>,
+    pub preset: Option<String>,
+    pub sound_retriever: Option<bool>,
+    pub dialog_enhancement: Option<bool>,
+    pub listening_mode: Option<String>,
+    pub speaker_system: Option<String>,
+    pub subwoofer_level: Option<u8>,
+    pub lfe_attenuation: Option<u8>,
+    pub headphones: Option<bool>,
+    pub video_converter: Option<bool>,
+    pub video_resolution: Option<String>,
+    pub network_standby: Option<bool>,
+    pub model: Option<String>,
+    pub firmware: Option<String>,
+    pub connected: Option<bool>,
+}
+
+impl CachedState {
+    /// Known state as the events that would produce it
+    pub fn events(&self) -> Vec<AvrEvent> {
+        let mut events = vec![];
+        events.extend(self.power.map(AvrEvent::Power));
+        events.extend(self.volume.map(AvrEvent::Volume));
+        events.extend(self.mute.map(AvrEvent::Mute));
+        events.extend(self.input.clone().map(AvrEvent::Input));
+        events.extend(self.preset.clone().map(AvrEvent::Preset));
+        events.extend(self.sound_retriever.map(AvrEvent::SoundRetriever));
+        events.extend(self.dialog_enhancement.map(AvrEvent::DialogEnhancement));
+        events.extend(self.listening_mode.clone().map(AvrEvent::ListeningMode));
+        events.extend(self.speaker_system.clone().map(AvrEvent::SpeakerSystem));
+        events.extend(self.subwoofer_level.map(AvrEvent::SubwooferLevel));
+        events.extend(self.lfe_attenuation.map(AvrEvent::LfeAttenuation));
+        events.extend(self.headphones.map(AvrEvent::Headphones));
+        events.extend(self.video_converter.map(AvrEvent::VideoConverter));
+        events.extend(self.video_resolution.clone().map(AvrEvent::VideoResolution));
+        events.extend(self.network_standby.map(AvrEvent::NetworkStandby));
+        events.extend(self.model.clone().map(AvrEvent::Model));
+        events.extend(self.firmware.clone().map(AvrEvent::Firmware));
+        events.extend(self.connected.map(AvrEvent::Connected));
+        events
+    }
+
+    /// Apply event, returning whether it changed the state
+    fn apply(&mut self, event: &AvrEvent) -> bool {
+        match event {
+            AvrEvent::Power(on) => replace(&mut self.power, *on),
+            AvrEvent::Volume(volume) => replace(&mut self.volume, *volume),
+            AvrEvent::Mute(mute) => replace(&mut self.mute, *mute),
+            AvrEvent::Input(input) => {
+                let previous = self.input.clone();
+                let changed = replace(&mut self.input, input.clone());
+                if changed && previous.is_some() {
+                    self.previous_input = previous;
+                }
+                changed
+            }
+            AvrEvent::Preset(preset) => replace(&mut self.preset, preset.clone()),
+            AvrEvent::SoundRetriever(on) => replace(&mut self.sound_retriever, *on),
+            AvrEvent::DialogEnhancement(on) => replace(&mut self.dialog_enhancement, *on),
+            AvrEvent::ListeningMode(mode) => replace(&mut self.listening_mode, mode.clone()),
+            AvrEvent::SpeakerSystem(system) => replace(&mut self.speaker_system, system.clone()),
+            AvrEvent::SubwooferLevel(level) => replace(&mut self.subwoofer_level, *level),
+            AvrEvent::LfeAttenuation(db) => replace(&mut self.lfe_attenuation, *db),
+            AvrEvent::Headphones(plugged) => replace(&mut self.headphones, *plugged),
+            AvrEvent::VideoConverter(on) => replace(&mut self.video_converter, *on),
+            AvrEvent::VideoResolution(resolution) => {
+                replace(&mut self.video_resolution, resolution.clone())
+            }
+            AvrEvent::NetworkStandby(on) => replace(&mut self.network_standby, *on),
+            AvrEvent::Model(model) => replace(&mut self.model, model.clone()),
+            AvrEvent::Firmware(firmware) => replace(&mut self.firmware, firmware.clone()),
+            AvrEvent::Connected(connected) => replace(&mut self.connected, *connected),
+            // Not cached, the input names keep them, see `crate::labels`
+            AvrEvent::InputLabel(..) => true,
+        }
+    }
+}
+
+/// Volume level and mute before a command changed them, the level being
+/// `None` if it wasn't known
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PreviousVolume {
+    pub level: Option<u8>,
+    pub muted: bool,
+}
+
+/// Set field to value, returning whether it was different
+fn replace<T: PartialEq>(field: &mut Option<T>, value: T) -> bool {
+    let changed = field.as_ref() != Some(&value);
+    *field = Some(value);
+    changed
+}
+
+/// Health of the telnet connection: when anything, like a heartbeat, was last
+/// received from the AVR, and how many attempts to connect to it have failed
+/// in a row. `unreachable` is whether the last of those found the AVR's
+/// address but it refused the connection or didn't answer, as it does in
+/// standby with its network standby setting off.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Link {
+    pub last_heard: Option<Instant>,
+    pub failures: u32,
+    pub unreachable: bool,
+}
+
+/// Cached AVR state along with a broadcast channel of state changes. Cloned
+/// wherever the state is needed.
+#[derive(Clone)]
+pub struct EventBus {
+    protocol: Arc<dyn AvrProtocol>,
+    state: Arc<RwLock<CachedState>>,
+    link: Arc<Mutex<Link>>,
+    events: broadcast::Sender<AvrEvent>,
+}
+
+impl EventBus {
+    pub fn new(protocol: Arc<dyn AvrProtocol>) -> Self {
+        let (events, _) = broadcast::channel(32);
+        EventBus {
+            protocol,
+            state: Arc::new(RwLock::new(CachedState::default())),
+            link: Arc::new(Mutex::new(Link::default())),
+            events,
+        }
+    }
+
+    /// Publish a state change reported by the AVR, if it changes the cached
+    /// state.
+    pub fn publish(&self, event: AvrEvent) {
+        self.send(event);
+    }
+
+    /// Publish a change to the telnet connection
+    pub fn connected(&self, connected: bool) {
+        if connected {
+            *self.link.lock().unwrap() = Link {
+                last_heard: Some(Instant::now()),
+                failures: 0,
+                unreachable: false,
+            };
+        }
+        self.send(AvrEvent::Connected(connected));
+    }
+
+    /// Record that a message was received from the AVR
+    pub fn heard(&self) {
+        self.link.lock().unwrap().last_heard = Some(Instant::now());
+    }
+
+    /// Record the number of attempts to connect that have failed in a row,
+    /// and whether the last one was refused or timed out by the AVR's address
+    pub fn connect_failed(&self, failures: u32, unreachable: bool) {
+        let mut link = self.link.lock().unwrap();
+        link.failures = failures;
+        link.unreachable = unreachable;
+    }
+
+    /// Publish a state change the AVR didn't report, as if it had, for
+    /// commands that are never sent in dry run mode
+    pub fn simulate(&self, event: AvrEvent) {
+        self.send(event);
+    }
+
+    /// Apply event to the cached state, broadcasting it if it's a change
+    fn send(&self, event: AvrEvent) {
+        let changed = self.state.write().unwrap().apply(&event);
+        if changed {
+            // Only fails if nothing is subscribed
+            let _ = self.events.send(event);
+        }
+    }
+
+    /// Protocol used to parse the AVR's messages
+    pub fn protocol(&self) -> &Arc<dyn AvrProtocol> {
+        &self.protocol
+    }
+
+    /// Remember the current volume and mute, before a command changes them,
+    /// so they can be put back. `level` is the volume level if it was just
+    /// read from the AVR, otherwise the cached one is used.
+    pub fn remember_volume(&self, level: Option<u8>) {
+        let mut state = self.state.write().unwrap();
+        state.previous_volume = Some(PreviousVolume {
+            level: level.or(state.volume),
+            muted: state.mute == Some(true),
+        });
+    }
+
+    /// Forget the cached state, as it can't be trusted while disconnected.
+    /// The previous input and volume are kept, as they're still the ones to
+    /// go back to.
+    pub fn clear(&self) {
+        let mut state = self.state.write().unwrap();
+        *state = CachedState {
+            previous_input: state.previous_input.take(),
+            previous_volume: state.previous_volume.take(),
+            ..CachedState::default()
+        };
+    }
+
+    /// Get a copy of the last known state
+    pub fn state(&self) -> CachedState {
+        self.state.read().unwrap().clone()
+    }
+
+    /// Get a copy of the connection's health
+    pub fn link(&self) -> Link {
+        *self.link.lock().unwrap()
+    }
+
+    /// Subscribe to state changes published from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<AvrEvent> {
+        self.events.subscribe()
+    }
+}
+
+/// Spawn a new task logging every state change of the named AVR
+pub fn log_changes(name: &str, bus: &EventBus) {
+    let name = name.to_owned();
+    let mut events = bus.subscribe();
+
+    tokio::spawn(async move {
+        loop {
+            match events.recv().await {
+                Ok(event) => info!("AVR state changed: {}: {:?}", name, event),
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    });
+}
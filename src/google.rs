@@ -0,0 +1,274 @@
+/// This module implements the optional Google Assistant Smart Home
+/// fulfillment, so the same service can be used from Google Assistant.
+///
+/// Google sends SYNC, QUERY and EXECUTE intents, with the access token from
+/// the Action's account linking, which must be the configured `token`. Each
+/// AVR is an audio video receiver device, and EXECUTE commands are converted
+/// to `AvrCommand`s and sent through the AVR's command queue, like the Alexa
+/// skill's.
+use crate::{
+    avr::{AvrCommand, AvrError},
+    config::GoogleConfig,
+    device::{Device, Devices},
+    queue::Source,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tracing::{info, warn};
+
+/// Fulfillment request from Google
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Request {
+    request_id: String,
+    inputs: Vec<Input>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Input {
+    intent: String,
+    #[serde(default)]
+    payload: Value,
+}
+
+pub struct Google {
+    config: GoogleConfig,
+    devices: Devices,
+}
+
+impl Google {
+    pub fn new(config: GoogleConfig, devices: &Devices) -> Google {
+        info!("Google Assistant fulfillment enabled");
+        Google {
+            config,
+            devices: devices.clone(),
+        }
+    }
+
+    /// Whether the Authorization header has the configured token
+    pub fn authorized(&self, authorization: &str) -> bool {
+        authorization == format!("Bearer {}", self.config.token)
+    }
+
+    /// Process the request's intent, returning the response
+    pub async fn process_request(&self, request: Request) -> Value {
+        let input = match request.inputs.first() {
+            Some(input) => input,
+            None => return json!({ "requestId": request.request_id }),
+        };
+        info!("Google intent: {}", input.intent);
+
+        let payload = match input.intent.as_str() {
+            "action.devices.SYNC" => self.sync(),
+            "action.devices.QUERY" => self.query(&input.payload),
+            "action.devices.EXECUTE" => self.execute(&input.payload).await,
+            "action.devices.DISCONNECT" => return json!({}),
+            _ => json!({ "errorCode": "notSupported" }),
+        };
+
+        json!({
+            "requestId": request.request_id,
+            "payload": payload,
+        })
+    }
+
+    /// Every AVR is a receiver, with its inputs from its protocol
+    fn sync(&self) -> Value {
+        let devices: Vec<_> = self
+            .devices
+            .iter()
+            .map(|device| {
+                let inputs: Vec<_> = device
+                    .inputs
+                    .visible()
+                    .iter()
+                    .map(|(n, name)| {
+                        json!({
+                            "key": n.to_string(),
+                            "names": [{ "lang": "en", "name_synonym": [name] }],
+                        })
+                    })
+                    .collect();
+
+                json!({
+                    "id": device.name,
+                    "type": "action.devices.types.AUDIO_VIDEO_RECEIVER",
+                    "traits": [
+                        "action.devices.traits.OnOff",
+                        "action.devices.traits.Volume",
+                        "action.devices.traits.InputSelector",
+                    ],
+                    "name": { "name": device.name },
+                    "willReportState": false,
+                    "attributes": {
+                        "volumeMaxLevel": 100,
+                        "volumeCanMuteAndUnmute": true,
+                        "levelStepSize": 10,
+                        "availableInputs": inputs,
+                        "orderedInputs": true,
+                    },
+                })
+            })
+            .collect();
+
+        json!({
+            "agentUserId": self.config.agent_user_id,
+            "devices": devices,
+        })
+    }
+
+    /// Last known state of each requested AVR
+    fn query(&self, payload: &Value) -> Value {
+        let mut states = json!({});
+
+        for id in device_ids(&payload["devices"]) {
+            states[&id] = match self.devices.get(Some(&id)) {
+                Some(device) => state(device),
+                None => json!({ "status": "ERROR", "errorCode": "deviceNotFound" }),
+            };
+        }
+
+        json!({ "devices": states })
+    }
+
+    /// Run each command on each of its AVRs, one result per AVR
+    async fn execute(&self, payload: &Value) -> Value {
+        let mut results = vec![];
+        let commands = payload["commands"].as_array().cloned().unwrap_or_default();
+
+        for command in &commands {
+            for id in device_ids(&command["devices"]) {
+                let device = match self.devices.get(Some(&id)) {
+                    Some(device) => device,
+                    None => {
+                        results.push(error(&id, "deviceNotFound"));
+                        continue;
+                    }
+                };
+
+                let executions = command["execution"].as_array().cloned().unwrap_or_default();
+                let mut result = None;
+                for execution in &executions {
+                    if let Err(code) = execute(device, execution).await {
+                        result = Some(error(&id, code));
+                        break;
+                    }
+                }
+
+                results.push(result.unwrap_or_else(|| {
+                    let mut states = state(device);
+                    if let Some(states) = states.as_object_mut() {
+                        states.remove("status");
+                    }
+                    json!({ "ids": [id], "status": "SUCCESS", "states": states })
+                }));
+            }
+        }
+
+        json!({ "commands": results })
+    }
+}
+
+/// Convert the execution to AVR commands and process them, returning the
+/// Google error code on failure
+async fn execute(device: &Device, execution: &Value) -> Result<(), &'static str> {
+    for cmd in commands(device, execution)? {
+        if let Err(e) = device.queue.process(cmd, Source::Google).await {
+            warn!("Google command failed: {}", e);
+            match e.downcast_ref::<AvrError>() {
+                // Already in the requested state
+                Some(AvrError::PowerAlreadyOn) | Some(AvrError::PowerAlreadyOff) => {}
+                Some(AvrError::PowerOffCantProcess) => return Err("deviceTurnedOff"),
+                Some(AvrError::Timeout) | Some(AvrError::NetworkStandbyOff) => {
+                    return Err("deviceOffline")
+                }
+                _ => return Err("transientError"),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// AVR commands for the execution
+fn commands(device: &Device, execution: &Value) -> Result<Vec<AvrCommand>, &'static str> {
+    let params = &execution["params"];
+    let commands = match execution["command"].as_str().unwrap_or("") {
+        "action.devices.commands.OnOff" => match params["on"].as_bool() {
+            Some(true) => vec![AvrCommand::PowerOn],
+            Some(false) => vec![AvrCommand::PowerOff],
+            None => return Err("protocolError"),
+        },
+        "action.devices.commands.mute" => match params["mute"].as_bool() {
+            Some(true) => vec![AvrCommand::Mute],
+            Some(false) => vec![AvrCommand::Unmute],
+            None => return Err("protocolError"),
+        },
+        "action.devices.commands.setVolume" => match params["volumeLevel"].as_u64() {
+            Some(percent) if percent <= 100 => {
+                vec![AvrCommand::SetVolume(((percent + 5) / 10) as u8)]
+            }
+            _ => return Err("valueOutOfRange"),
+        },
+        "action.devices.commands.volumeRelative" => match params["relativeSteps"].as_i64() {
+            Some(steps) if steps >= 0 => (0..steps.min(10)).map(|_| AvrCommand::VolumeUp).collect(),
+            Some(steps) => (0..(-steps).min(10))
+                .map(|_| AvrCommand::VolumeDown)
+                .collect(),
+            None => return Err("protocolError"),
+        },
+        "action.devices.commands.SetInput" => {
+            let inputs = device.events.protocol().inputs().len();
+            match params["newInput"]
+                .as_str()
+                .and_then(|key| key.parse::<u8>().ok())
+            {
+                Some(n) if n >= 1 && usize::from(n) <= inputs => vec![AvrCommand::ChangeInput(n)],
+                _ => return Err("unsupportedInput"),
+            }
+        }
+        _ => return Err("functionNotSupported"),
+    };
+    Ok(commands)
+}
+
+fn error(id: &str, code: &str) -> Value {
+    json!({ "ids": [id], "status": "ERROR", "errorCode": code })
+}
+
+/// QUERY state of the AVR, from its cached state
+fn state(device: &Device) -> Value {
+    let state = device.events.state();
+    let protocol = device.events.protocol();
+
+    let mut states = json!({
+        "online": state.connected == Some(true),
+        "status": "SUCCESS",
+    });
+    if let Some(on) = state.power {
+        states["on"] = json!(on);
+    }
+    if let Some(level) = state.volume {
+        states["currentVolume"] = json!(protocol.volume_percent(level));
+    }
+    if let Some(mute) = state.mute {
+        states["isMuted"] = json!(mute);
+    }
+    if let Some(n) = state.input.and_then(|input| protocol.input_number(&input)) {
+        states["currentInput"] = json!(n.to_string());
+    }
+    states
+}
+
+/// Ids of the devices in the request
+fn device_ids(devices: &Value) -> Vec<String> {
+    devices
+        .as_array()
+        .map(|devices| {
+            devices
+                .iter()
+                .filter_map(|device| device["id"].as_str())
+                .map(str::to_owned)
+                .collect()
+        })
+        .unwrap_or_default()
+}
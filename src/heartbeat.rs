@@ -0,0 +1,78 @@
+/// This module pings a dead man's switch, like healthchecks.io, while the
+/// service is healthy, so its owner is alerted when the pings stop.
+///
+/// Every `interval` seconds, the web service is asked for its `/health` on
+/// this machine, which is served without Basic credentials, and each AVR's
+/// connection is checked. Only if the web service
+/// answers and every AVR is connected is `url` pinged with a GET. Anything
+/// wrong just skips the ping, and the service on the other end does the
+/// alerting.
+use crate::{config::HeartbeatConfig, device::Devices};
+use failure::{bail, Error};
+use hyper::{client::HttpConnector, Client, Uri};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use std::time::Duration;
+use tokio::time::{interval, timeout};
+use tracing::{debug, info, warn};
+
+type HttpsClient = Client<HttpsConnector<HttpConnector>>;
+
+/// How long to wait for the web service, and for the ping
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Start the task pinging the configured url while the web service on `port`
+/// and the AVRs' connections are healthy
+pub fn run(config: HeartbeatConfig, devices: &Devices, port: u16) -> Result<(), Error> {
+    let url: Uri = config.url.parse()?;
+    let health: Uri = format!("http://127.0.0.1:{}/health", port).parse()?;
+    if config.interval == 0 {
+        bail!("Heartbeat interval must be at least 1 second");
+    }
+    info!("Pinging {} every {}s while healthy", url, config.interval);
+
+    let connector = HttpsConnectorBuilder::new()
+        .with_webpki_roots()
+        .https_or_http()
+        .enable_http1()
+        .build();
+    let client: HttpsClient = Client::builder().build(connector);
+    let devices = devices.clone();
+    let period = Duration::from_secs(config.interval);
+
+    tokio::spawn(async move {
+        let mut ticks = interval(period);
+        loop {
+            ticks.tick().await;
+            match healthy(&client, &health, &devices).await {
+                Ok(()) => ping(&client, &url).await,
+                Err(e) => warn!("Not pinging heartbeat: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Check every AVR is connected and the web service answers
+async fn healthy(client: &HttpsClient, health: &Uri, devices: &Devices) -> Result<(), Error> {
+    for device in devices.iter() {
+        if device.events.state().connected != Some(true) {
+            bail!("Not connected to {}", device.name);
+        }
+    }
+
+    match timeout(TIMEOUT, client.get(health.clone())).await {
+        Ok(Ok(response)) if response.status().is_success() => Ok(()),
+        Ok(Ok(response)) => bail!("Web service answered {}", response.status()),
+        Ok(Err(e)) => bail!("Web service isn't answering: {}", e),
+        Err(_) => bail!("Web service didn't answer in time"),
+    }
+}
+
+async fn ping(client: &HttpsClient, url: &Uri) {
+    match timeout(TIMEOUT, client.get(url.clone())).await {
+        Ok(Ok(response)) if response.status().is_success() => debug!("Pinged heartbeat"),
+        Ok(Ok(response)) => warn!("Heartbeat ping answered {}", response.status()),
+        Ok(Err(e)) => warn!("Could not ping heartbeat: {}", e),
+        Err(_) => warn!("Heartbeat ping timed out"),
+    }
+}
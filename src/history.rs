@@ -0,0 +1,176 @@
+/// This module keeps a history of the commands processed against the AVRs,
+/// so it can be looked up later what turned the receiver on, and when.
+///
+/// The history is an SQLite database with one row per `Entry`, indexed on
+/// its time. Each AVR's command queue adds an entry for every command it
+/// processes, with what the command came from, the AVR's response, whether
+/// it worked and how long it took. The web service serves the latest entries
+/// at `/api/history`. Entries older than `keep_days` are removed as new ones
+/// are added, if it's configured.
+use crate::{avr::AvrCommand, queue::Source};
+use chrono::{DateTime, Duration as Days, SecondsFormat, Utc};
+use failure::{Error, ResultExt};
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tracing::info;
+
+/// Command processed against an AVR. `response` is the AVR's response
+/// confirming the command, and `error` why it failed, if it did.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Entry {
+    pub time: String,
+    pub device: String,
+    pub source: Source,
+    pub command: AvrCommand,
+    pub response: String,
+    pub error: Option<String>,
+    pub latency_ms: u64,
+}
+
+impl Entry {
+    /// Entry for a command processed just now
+    pub fn new(
+        device: &str,
+        source: Source,
+        command: AvrCommand,
+        result: Result<&str, &Error>,
+        latency: Duration,
+    ) -> Entry {
+        let (response, error) = match result {
+            Ok(response) => (response.to_owned(), None),
+            Err(e) => (String::new(), Some(e.to_string())),
+        };
+        Entry {
+            time: timestamp(Utc::now()),
+            device: device.to_owned(),
+            source,
+            command,
+            response,
+            error,
+            latency_ms: latency.as_millis() as u64,
+        }
+    }
+}
+
+/// Adds entries to the history database, and reads them back. Cloned for
+/// each command queue.
+#[derive(Clone)]
+pub struct History {
+    conn: Arc<Mutex<Connection>>,
+    keep_days: Option<u32>,
+}
+
+impl History {
+    /// Open the history database, creating it if needed. Entries are added to
+    /// those of an existing history.
+    pub fn open<P: AsRef<Path>>(path: P, keep_days: Option<u32>) -> Result<History, Error> {
+        let path = path.as_ref();
+        let conn = Connection::open(path)
+            .context(format!("Could not open history: {}", path.display()))?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS history (
+                 id INTEGER PRIMARY KEY,
+                 time TEXT NOT NULL,
+                 device TEXT NOT NULL,
+                 source TEXT NOT NULL,
+                 command TEXT NOT NULL,
+                 response TEXT NOT NULL,
+                 error TEXT,
+                 latency_ms INTEGER NOT NULL
+             );
+             CREATE INDEX IF NOT EXISTS history_time ON history (time);",
+        )
+        .context(format!("Could not create history: {}", path.display()))?;
+        info!("Recording command history to: {}", path.display());
+
+        Ok(History {
+            conn: Arc::new(Mutex::new(conn)),
+            keep_days,
+        })
+    }
+
+    /// Add the entry to the history, removing those too old to keep
+    pub fn record(&self, entry: &Entry) -> Result<(), Error> {
+        let source = serde_json::to_value(entry.source)?;
+        let command = serde_json::to_string(&entry.command)?;
+
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO history (time, device, source, command, response, error, latency_ms)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                entry.time,
+                entry.device,
+                source.as_str(),
+                command,
+                entry.response,
+                entry.error,
+                entry.latency_ms as i64,
+            ],
+        )
+        .context("Could not write to history")?;
+
+        if let Some(days) = self.keep_days {
+            let oldest = timestamp(Utc::now() - Days::days(days.into()));
+            conn.execute("DELETE FROM history WHERE time < ?1", params![oldest])
+                .context("Could not remove old history")?;
+        }
+        Ok(())
+    }
+
+    /// The latest `limit` entries, newest first. Rows that can't be read
+    /// back, like a command this version doesn't know, are skipped.
+    pub fn latest(&self, limit: usize) -> Result<Vec<Entry>, Error> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn
+            .prepare(
+                "SELECT time, device, source, command, response, error, latency_ms
+                 FROM history ORDER BY time DESC, id DESC LIMIT ?1",
+            )
+            .context("Could not read history")?;
+        let rows = statement
+            .query_map(params![limit as i64], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, String>(3)?,
+                    row.get::<_, String>(4)?,
+                    row.get::<_, Option<String>>(5)?,
+                    row.get::<_, i64>(6)?,
+                ))
+            })
+            .context("Could not read history")?;
+
+        let mut entries = vec![];
+        for row in rows {
+            let (time, device, source, command, response, error, latency_ms) =
+                row.context("Could not read history")?;
+            let source = serde_json::from_value(Value::String(source));
+            let command = serde_json::from_str(&command);
+            if let (Ok(source), Ok(command)) = (source, command) {
+                entries.push(Entry {
+                    time,
+                    device,
+                    source,
+                    command,
+                    response,
+                    error,
+                    latency_ms: latency_ms as u64,
+                });
+            }
+        }
+        Ok(entries)
+    }
+}
+
+/// The time as stored in the history, which sorts the same as text
+fn timestamp(time: DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Millis, true)
+}
@@ -0,0 +1,364 @@
+/// This module describes the AVR as a HomeKit television accessory, with a
+/// television speaker and an input source for each of the AVR's inputs.
+/// Hidden inputs are marked as not configured and hidden, so the Home app
+/// leaves them out.
+///
+/// Characteristic values are read from the AVR's cached state, and writes are
+/// turned into commands added to its command queue.
+use crate::{
+    avr::{AvrCommand, AvrError},
+    device::Device,
+    events::AvrEvent,
+    queue::Source,
+};
+use serde_json::{json, Value};
+use tracing::{info, warn};
+
+/// Accessory id, there's only the one
+pub const AID: u64 = 1;
+
+/// Status codes returned for individual characteristics
+pub const STATUS_COMMUNICATION: i32 = -70402;
+pub const STATUS_READ_ONLY: i32 = -70404;
+pub const STATUS_NOT_FOUND: i32 = -70409;
+pub const STATUS_INVALID_VALUE: i32 = -70410;
+
+// Instance ids of the services and their characteristics
+const INFORMATION: u64 = 1;
+const IDENTIFY: u64 = 2;
+const MANUFACTURER: u64 = 3;
+const MODEL: u64 = 4;
+const NAME: u64 = 5;
+const SERIAL_NUMBER: u64 = 6;
+const FIRMWARE_REVISION: u64 = 7;
+const PROTOCOL_INFORMATION: u64 = 8;
+const VERSION: u64 = 9;
+const TELEVISION: u64 = 10;
+const ACTIVE: u64 = 11;
+const ACTIVE_IDENTIFIER: u64 = 12;
+const CONFIGURED_NAME: u64 = 13;
+const SLEEP_DISCOVERY_MODE: u64 = 14;
+const REMOTE_KEY: u64 = 15;
+const SPEAKER: u64 = 20;
+const MUTE: u64 = 21;
+const SPEAKER_ACTIVE: u64 = 22;
+const VOLUME_CONTROL_TYPE: u64 = 23;
+const VOLUME_SELECTOR: u64 = 24;
+const VOLUME: u64 = 25;
+
+/// Input source services start at this instance id, 10 apart, with their
+/// characteristics at these offsets
+const INPUT_SOURCES: u64 = 100;
+const INPUT_IDENTIFIER: u64 = 1;
+const INPUT_CONFIGURED_NAME: u64 = 2;
+const INPUT_SOURCE_TYPE: u64 = 3;
+const INPUT_IS_CONFIGURED: u64 = 4;
+const INPUT_VISIBILITY: u64 = 5;
+const INPUT_NAME: u64 = 6;
+
+pub struct Accessory {
+    name: String,
+    device: Device,
+    inputs: Vec<String>,
+}
+
+impl Accessory {
+    pub fn new(name: String, device: Device) -> Accessory {
+        let inputs = device.events.protocol().inputs();
+        Accessory {
+            name,
+            device,
+            inputs,
+        }
+    }
+
+    pub fn device(&self) -> &Device {
+        &self.device
+    }
+
+    /// Full attribute database, as returned by `/accessories`
+    pub fn database(&self) -> Value {
+        let mut services = vec![
+            self.service(
+                INFORMATION,
+                "3E",
+                &[
+                    (IDENTIFY, "14", "bool", &["pw"]),
+                    (MANUFACTURER, "20", "string", &["pr"]),
+                    (MODEL, "21", "string", &["pr"]),
+                    (NAME, "23", "string", &["pr"]),
+                    (SERIAL_NUMBER, "30", "string", &["pr"]),
+                    (FIRMWARE_REVISION, "52", "string", &["pr"]),
+                ],
+            ),
+            self.service(
+                PROTOCOL_INFORMATION,
+                "A2",
+                &[(VERSION, "37", "string", &["pr"])],
+            ),
+            self.service(
+                TELEVISION,
+                "D8",
+                &[
+                    (ACTIVE, "B0", "uint8", &["pr", "pw", "ev"]),
+                    (ACTIVE_IDENTIFIER, "E7", "uint32", &["pr", "pw", "ev"]),
+                    (CONFIGURED_NAME, "E3", "string", &["pr", "pw", "ev"]),
+                    (SLEEP_DISCOVERY_MODE, "E8", "uint8", &["pr", "ev"]),
+                    (REMOTE_KEY, "E1", "uint8", &["pw"]),
+                ],
+            ),
+            self.service(
+                SPEAKER,
+                "113",
+                &[
+                    (MUTE, "11A", "bool", &["pr", "pw", "ev"]),
+                    (SPEAKER_ACTIVE, "B0", "uint8", &["pr", "ev"]),
+                    (VOLUME_CONTROL_TYPE, "E9", "uint8", &["pr", "ev"]),
+                    (VOLUME_SELECTOR, "EA", "uint8", &["pw"]),
+                    (VOLUME, "119", "uint8", &["pr", "pw", "ev"]),
+                ],
+            ),
+        ];
+
+        services[2]["primary"] = json!(true);
+        services[2]["linked"] = json!((1..=self.inputs.len() as u64)
+            .map(input_service)
+            .chain(std::iter::once(SPEAKER))
+            .collect::<Vec<_>>());
+        services[3]["characteristics"][4]["unit"] = json!("percentage");
+        services[3]["characteristics"][4]["minValue"] = json!(0);
+        services[3]["characteristics"][4]["maxValue"] = json!(100);
+        services[3]["characteristics"][4]["minStep"] = json!(1);
+
+        for n in 1..=self.inputs.len() as u64 {
+            let iid = input_service(n);
+            services.push(self.service(
+                iid,
+                "D9",
+                &[
+                    (iid + INPUT_IDENTIFIER, "E6", "uint32", &["pr"]),
+                    (
+                        iid + INPUT_CONFIGURED_NAME,
+                        "E3",
+                        "string",
+                        &["pr", "pw", "ev"],
+                    ),
+                    (iid + INPUT_SOURCE_TYPE, "DB", "uint8", &["pr", "ev"]),
+                    (
+                        iid + INPUT_IS_CONFIGURED,
+                        "D6",
+                        "uint8",
+                        &["pr", "pw", "ev"],
+                    ),
+                    (iid + INPUT_VISIBILITY, "135", "uint8", &["pr", "ev"]),
+                    (iid + INPUT_NAME, "23", "string", &["pr"]),
+                ],
+            ));
+        }
+
+        json!({
+            "accessories": [{
+                "aid": AID,
+                "services": services,
+            }]
+        })
+    }
+
+    fn service(
+        &self,
+        iid: u64,
+        kind: &str,
+        characteristics: &[(u64, &str, &str, &[&str])],
+    ) -> Value {
+        let characteristics: Vec<_> = characteristics
+            .iter()
+            .map(|(iid, kind, format, perms)| {
+                let mut characteristic = json!({
+                    "iid": iid,
+                    "type": kind,
+                    "format": format,
+                    "perms": perms,
+                });
+                if perms.contains(&"pr") {
+                    characteristic["value"] = self.read(*iid).unwrap_or(Value::Null);
+                }
+                characteristic
+            })
+            .collect();
+
+        json!({
+            "iid": iid,
+            "type": kind,
+            "characteristics": characteristics,
+        })
+    }
+
+    /// Current value of a readable characteristic
+    pub fn read(&self, iid: u64) -> Option<Value> {
+        let state = self.device.events.state();
+        let protocol = self.device.events.protocol();
+
+        let value = match iid {
+            MANUFACTURER => json!("Alexa AVR Control"),
+            MODEL => json!("AVR"),
+            NAME | CONFIGURED_NAME => json!(self.name),
+            SERIAL_NUMBER => json!(self.device.name),
+            FIRMWARE_REVISION => json!(env!("CARGO_PKG_VERSION")),
+            VERSION => json!("1.1.0"),
+            ACTIVE => json!(u8::from(state.power == Some(true))),
+            ACTIVE_IDENTIFIER => json!(state
+                .input
+                .and_then(|input| protocol.input_number(&input))
+                .unwrap_or(0)),
+            SLEEP_DISCOVERY_MODE => json!(1),
+            MUTE => json!(state.mute.unwrap_or(false)),
+            SPEAKER_ACTIVE => json!(1),
+            // Absolute
+            VOLUME_CONTROL_TYPE => json!(3),
+            VOLUME => json!(protocol.volume_percent(state.volume.unwrap_or(0))),
+            iid if iid > INPUT_SOURCES => {
+                let n = (iid - INPUT_SOURCES) / 10 + 1;
+                let name = self.inputs.get(n as usize - 1)?;
+                let hidden = self.device.inputs.is_hidden(n as u8);
+                match (iid - INPUT_SOURCES) % 10 {
+                    INPUT_IDENTIFIER => json!(n),
+                    INPUT_CONFIGURED_NAME | INPUT_NAME => json!(name),
+                    INPUT_SOURCE_TYPE => json!(source_type(name)),
+                    INPUT_IS_CONFIGURED => json!(u8::from(!hidden)),
+                    // Shown, or hidden
+                    INPUT_VISIBILITY => json!(u8::from(hidden)),
+                    _ => return None,
+                }
+            }
+            _ => return None,
+        };
+
+        Some(value)
+    }
+
+    /// Write a characteristic, returning the HAP status code on failure
+    pub async fn write(&self, iid: u64, value: &Value) -> Result<(), i32> {
+        let cmd = match iid {
+            ACTIVE => match value_u64(value) {
+                Some(0) => AvrCommand::PowerOff,
+                Some(1) => AvrCommand::PowerOn,
+                _ => return Err(STATUS_INVALID_VALUE),
+            },
+            ACTIVE_IDENTIFIER => match value_u64(value) {
+                Some(n) if n >= 1 && n <= self.inputs.len() as u64 => {
+                    AvrCommand::ChangeInput(n as u8)
+                }
+                _ => return Err(STATUS_INVALID_VALUE),
+            },
+            MUTE => match value_u64(value) {
+                Some(0) => AvrCommand::Unmute,
+                Some(1) => AvrCommand::Mute,
+                _ => return Err(STATUS_INVALID_VALUE),
+            },
+            VOLUME_SELECTOR => match value_u64(value) {
+                Some(0) => AvrCommand::VolumeUp,
+                Some(1) => AvrCommand::VolumeDown,
+                _ => return Err(STATUS_INVALID_VALUE),
+            },
+            VOLUME => match value_u64(value) {
+                Some(percent) if percent <= 100 => {
+                    AvrCommand::SetVolume(((percent + 5) / 10) as u8)
+                }
+                _ => return Err(STATUS_INVALID_VALUE),
+            },
+            IDENTIFY => {
+                self.identify();
+                return Ok(());
+            }
+            // Accepted, but the names come from the AVR's protocol and remote
+            // keys aren't supported
+            CONFIGURED_NAME | REMOTE_KEY => return Ok(()),
+            iid if iid > INPUT_SOURCES => match (iid - INPUT_SOURCES) % 10 {
+                INPUT_CONFIGURED_NAME | INPUT_IS_CONFIGURED => return Ok(()),
+                _ => return Err(STATUS_READ_ONLY),
+            },
+            _ => return Err(STATUS_NOT_FOUND),
+        };
+
+        match self.device.queue.process(cmd, Source::Homekit).await {
+            Ok(()) => Ok(()),
+            Err(e) => match e.downcast_ref::<AvrError>() {
+                // Already in the requested state
+                Some(AvrError::PowerAlreadyOn) | Some(AvrError::PowerAlreadyOff) => Ok(()),
+                _ => {
+                    warn!("HomeKit write failed: {}", e);
+                    Err(STATUS_COMMUNICATION)
+                }
+            },
+        }
+    }
+
+    /// The AVR has no way to identify itself, so just log it
+    pub fn identify(&self) {
+        info!("HomeKit identify requested for AVR: {}", self.device.name);
+    }
+
+    /// Whether the characteristic supports event notifications
+    pub fn has_events(&self, iid: u64) -> bool {
+        match iid {
+            ACTIVE | ACTIVE_IDENTIFIER | CONFIGURED_NAME | SLEEP_DISCOVERY_MODE | MUTE
+            | SPEAKER_ACTIVE | VOLUME_CONTROL_TYPE | VOLUME => true,
+            iid if iid > INPUT_SOURCES => matches!(
+                (iid - INPUT_SOURCES) % 10,
+                INPUT_CONFIGURED_NAME | INPUT_SOURCE_TYPE | INPUT_IS_CONFIGURED | INPUT_VISIBILITY
+            ),
+            _ => false,
+        }
+    }
+}
+
+/// Characteristic whose value changes with the event, if any
+pub fn changed(event: &AvrEvent) -> Option<u64> {
+    match event {
+        AvrEvent::Power(_) => Some(ACTIVE),
+        AvrEvent::Volume(_) => Some(VOLUME),
+        AvrEvent::Mute(_) => Some(MUTE),
+        AvrEvent::Input(_) => Some(ACTIVE_IDENTIFIER),
+        AvrEvent::Preset(_)
+        | AvrEvent::SoundRetriever(_)
+        | AvrEvent::DialogEnhancement(_)
+        | AvrEvent::ListeningMode(_)
+        | AvrEvent::SpeakerSystem(_)
+        | AvrEvent::SubwooferLevel(_)
+        | AvrEvent::LfeAttenuation(_)
+        | AvrEvent::Headphones(_)
+        | AvrEvent::VideoConverter(_)
+        | AvrEvent::VideoResolution(_)
+        | AvrEvent::NetworkStandby(_)
+        | AvrEvent::InputLabel(..)
+        | AvrEvent::Model(_)
+        | AvrEvent::Firmware(_)
+        | AvrEvent::Connected(_) => None,
+    }
+}
+
+fn input_service(n: u64) -> u64 {
+    INPUT_SOURCES + (n - 1) * 10
+}
+
+/// Input source type shown in the Home app, guessed from the input's name
+fn source_type(name: &str) -> u8 {
+    let name = name.to_lowercase();
+    if name.contains("hdmi") {
+        3
+    } else if name.contains("tuner") {
+        2
+    } else if name.contains("usb") {
+        8
+    } else {
+        0
+    }
+}
+
+/// Characteristic value as an integer, with booleans as 0 or 1
+fn value_u64(value: &Value) -> Option<u64> {
+    match value {
+        Value::Bool(value) => Some(u64::from(*value)),
+        value => value.as_u64(),
+    }
+}
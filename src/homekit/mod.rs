@@ -0,0 +1,142 @@
+/// This module runs the optional HomeKit accessory server, so the AVR can also
+/// be controlled from the Home app and Siri.
+///
+/// The AVR is exposed as a television accessory with power, volume, mute and
+/// input control, advertised over mDNS. Controllers pair with it using the
+/// setup code from the config file, and commands go through the same command
+/// queue as the Alexa skill's.
+use crate::{addr, config::HomekitConfig, device::Devices, log_error};
+use accessory::Accessory;
+use failure::{bail, format_err, Error, Fail, ResultExt};
+use mdns_sd::{ServiceDaemon, ServiceInfo};
+use std::{
+    net::Ipv6Addr,
+    sync::{atomic::AtomicBool, Arc, Mutex},
+};
+use storage::Storage;
+use tokio::net::TcpListener;
+use tracing::{debug, info, warn};
+
+mod accessory;
+mod pairing;
+mod session;
+mod srp;
+mod storage;
+mod tlv;
+
+const SERVICE_TYPE: &str = "_hap._tcp.local.";
+
+/// Television
+const CATEGORY: &str = "31";
+
+/// State shared between all controller connections
+pub struct Server {
+    config: HomekitConfig,
+    accessory: Accessory,
+    storage: Mutex<Storage>,
+    mdns: ServiceDaemon,
+    setup_busy: Arc<AtomicBool>,
+}
+
+impl Server {
+    /// Advertise the accessory over mDNS, again whenever it's paired or
+    /// unpaired as the advertisement includes that
+    fn advertise(&self) {
+        if let Err(e) = self.register() {
+            log_error(&e.context("Could not advertise HomeKit accessory").into());
+        }
+    }
+
+    fn register(&self) -> Result<(), Error> {
+        let storage = self.storage.lock().unwrap();
+        let config_number = storage.config_number.to_string();
+        let status = if storage.is_paired() { "0" } else { "1" };
+        let properties = [
+            ("c#", config_number.as_str()),
+            ("ff", "0"),
+            ("id", storage.device_id.as_str()),
+            ("md", self.config.name.as_str()),
+            ("pv", "1.1"),
+            ("s#", "1"),
+            ("sf", status),
+            ("ci", CATEGORY),
+        ];
+        let host_name = format!("{}.local.", storage.device_id.replace(':', ""));
+
+        let service = ServiceInfo::new(
+            SERVICE_TYPE,
+            &self.config.name,
+            &host_name,
+            "",
+            self.config.port,
+            &properties[..],
+        )
+        .map_err(|e| format_err!("{}", e))?
+        .enable_addr_auto();
+        self.mdns
+            .register(service)
+            .map_err(|e| format_err!("{}", e))?;
+
+        debug!("HomeKit accessory advertised, status flag: {}", status);
+        Ok(())
+    }
+}
+
+/// Start the HomeKit server for the configured AVR, the first AVR if not
+/// given
+pub async fn run(config: HomekitConfig, devices: &Devices) -> Result<(), Error> {
+    if !valid_pin(&config.pin) {
+        bail!("HomeKit pin must be formatted like 123-45-678");
+    }
+    let device = devices
+        .get(config.device.as_deref())
+        .ok_or_else(|| format_err!("No AVR named {:?} for HomeKit", config.device))?
+        .clone();
+
+    let storage = Storage::load(&config.storage)?;
+    let listener = addr::listener((Ipv6Addr::UNSPECIFIED, config.port).into())
+        .and_then(|listener| Ok(TcpListener::from_std(listener)?))
+        .context(format!(
+            "Could not start HomeKit server on port {}",
+            config.port
+        ))?;
+    let mdns = ServiceDaemon::new().map_err(|e| format_err!("Could not start mDNS: {}", e))?;
+
+    info!(
+        "Starting HomeKit server on port {} for AVR: {}",
+        config.port, device.name
+    );
+    let server = Arc::new(Server {
+        accessory: Accessory::new(config.name.clone(), device),
+        config,
+        storage: Mutex::new(storage),
+        mdns,
+        setup_busy: Arc::new(AtomicBool::new(false)),
+    });
+    server.advertise();
+
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, addr)) => {
+                    debug!("HomeKit connection from {}", addr);
+                    let server = server.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = session::run(stream, server).await {
+                            warn!("HomeKit connection from {} closed: {}", addr, e);
+                        }
+                    });
+                }
+                Err(e) => log_error(&e.context("Could not accept HomeKit connection").into()),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Setup code must be 8 digits formatted like 123-45-678
+fn valid_pin(pin: &str) -> bool {
+    let parts: Vec<_> = pin.split('-').map(str::len).collect();
+    parts == [3, 2, 3] && pin.chars().all(|c| c.is_ascii_digit() || c == '-')
+}
@@ -0,0 +1,523 @@
+/// This module handles the HomeKit pairing endpoints.
+///
+/// Pair setup exchanges long term keys with a new controller, using SRP with
+/// the setup code to authenticate both sides. Only one connection can run it
+/// at a time, and it's refused for good after `MAX_SETUP_TRIES` wrong setup
+/// codes, so the code can't be guessed. Pair verify then uses those
+/// keys at the start of every connection to agree on the session keys that
+/// encrypt the rest of it. Admin controllers can add, remove and list other
+/// pairings.
+use super::{
+    srp::SrpServer,
+    storage::Pairing,
+    tlv::{self, Tlv},
+    Server,
+};
+use crate::log_error;
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    ChaCha20Poly1305, Nonce,
+};
+use ed25519_dalek::{Signature, Signer, Verifier, VerifyingKey};
+use failure::{err_msg, Error};
+use hkdf::Hkdf;
+use rand::rngs::OsRng;
+use sha2::Sha512;
+use std::{
+    convert::TryInto,
+    mem,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+use tracing::{debug, info, warn};
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+/// Keys for the encrypted session once pair verify succeeds. Read is for
+/// data from the controller, write for data to it.
+pub struct SessionKeys {
+    pub read: [u8; 32],
+    pub write: [u8; 32],
+}
+
+/// Failed pair setup attempts after which it's refused
+const MAX_SETUP_TRIES: u32 = 100;
+
+/// Progress of pair setup on a connection
+pub enum Setup {
+    Idle,
+    Started(SrpServer, SetupLock),
+    Verified(Vec<u8>, SetupLock),
+}
+
+/// Held by the connection running pair setup, so no other can start one
+/// until it's done or the connection closes
+pub struct SetupLock(Arc<AtomicBool>);
+
+impl SetupLock {
+    fn acquire(busy: &Arc<AtomicBool>) -> Option<SetupLock> {
+        busy.compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .ok()
+            .map(|_| SetupLock(busy.clone()))
+    }
+}
+
+impl Drop for SetupLock {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Progress of pair verify on a connection
+pub enum Verify {
+    Idle,
+    Started {
+        shared_secret: [u8; 32],
+        session_key: [u8; 32],
+        accessory_public: [u8; 32],
+        controller_public: [u8; 32],
+    },
+}
+
+/// Controller that completed pair verify, and the keys for its session
+pub struct Verified {
+    pub controller: String,
+    pub keys: SessionKeys,
+}
+
+/// Handle a pair setup request, returning the TLV response
+pub fn setup(server: &Server, setup: &mut Setup, body: &[u8]) -> Vec<u8> {
+    let request = match Tlv::decode(body) {
+        Ok(request) => request,
+        Err(_) => return tlv::error(2, tlv::ERROR_UNKNOWN),
+    };
+
+    match request.byte(tlv::STATE) {
+        Some(1) => setup_start(server, setup),
+        Some(3) => setup_verify(server, setup, &request),
+        Some(5) => match setup_exchange(server, setup, &request) {
+            Ok(response) => response,
+            Err(e) => {
+                warn!("HomeKit pair setup failed: {}", e);
+                *setup = Setup::Idle;
+                tlv::error(6, tlv::ERROR_AUTHENTICATION)
+            }
+        },
+        state => tlv::error(state.unwrap_or(0) + 1, tlv::ERROR_UNKNOWN),
+    }
+}
+
+/// M1 -> M2: send the SRP salt and public key. Only allowed while unpaired,
+/// with tries left, and no other connection running pair setup.
+fn setup_start(server: &Server, setup: &mut Setup) -> Vec<u8> {
+    // Starting over releases this connection's own setup
+    *setup = Setup::Idle;
+    {
+        let storage = server.storage.lock().unwrap();
+        if storage.is_paired() {
+            debug!("HomeKit pair setup requested while already paired");
+            return tlv::error(2, tlv::ERROR_UNAVAILABLE);
+        }
+        if storage.failed_setups >= MAX_SETUP_TRIES {
+            warn!(
+                "HomeKit pair setup refused after {} wrong setup codes",
+                storage.failed_setups
+            );
+            return tlv::error(2, tlv::ERROR_MAX_TRIES);
+        }
+    }
+    let lock = match SetupLock::acquire(&server.setup_busy) {
+        Some(lock) => lock,
+        None => {
+            debug!("HomeKit pair setup requested while another is in progress");
+            return tlv::error(2, tlv::ERROR_BUSY);
+        }
+    };
+
+    let srp = SrpServer::new(&server.config.pin);
+    let response = tlv::encode(&[
+        (tlv::STATE, &[2]),
+        (tlv::SALT, srp.salt()),
+        (tlv::PUBLIC_KEY, &srp.public_key()),
+    ]);
+    *setup = Setup::Started(srp, lock);
+    response
+}
+
+/// M3 -> M4: check the controller's SRP proof, which fails if the wrong
+/// setup code was entered, counting it against the tries left
+fn setup_verify(server: &Server, setup: &mut Setup, request: &Tlv) -> Vec<u8> {
+    let srp = match setup {
+        Setup::Started(srp, _) => srp,
+        _ => return tlv::error(4, tlv::ERROR_UNKNOWN),
+    };
+    let (public, proof) = match (request.get(tlv::PUBLIC_KEY), request.get(tlv::PROOF)) {
+        (Some(public), Some(proof)) => (public, proof),
+        _ => return tlv::error(4, tlv::ERROR_UNKNOWN),
+    };
+
+    match srp.verify(public, proof) {
+        Some(verified) => {
+            let response = tlv::encode(&[(tlv::STATE, &[4]), (tlv::PROOF, &verified.proof)]);
+            if let Setup::Started(_, lock) = mem::replace(setup, Setup::Idle) {
+                *setup = Setup::Verified(verified.session_key, lock);
+            }
+            response
+        }
+        None => {
+            let mut storage = server.storage.lock().unwrap();
+            storage.failed_setups += 1;
+            warn!(
+                "HomeKit pair setup failed, wrong setup code, {} of {} tries",
+                storage.failed_setups, MAX_SETUP_TRIES
+            );
+            if let Err(e) = storage.save() {
+                log_error(&e);
+            }
+            *setup = Setup::Idle;
+            tlv::error(4, tlv::ERROR_AUTHENTICATION)
+        }
+    }
+}
+
+/// M5 -> M6: store the controller's long term key and send back the
+/// accessory's
+fn setup_exchange(server: &Server, setup: &mut Setup, request: &Tlv) -> Result<Vec<u8>, Error> {
+    let srp_key = match setup {
+        Setup::Verified(key, _) => key.clone(),
+        _ => return Err(err_msg("Pair setup exchange out of order")),
+    };
+    let encrypted = request
+        .get(tlv::ENCRYPTED_DATA)
+        .ok_or_else(|| err_msg("Missing encrypted data"))?;
+
+    let key = derive_key(
+        &srp_key,
+        b"Pair-Setup-Encrypt-Salt",
+        b"Pair-Setup-Encrypt-Info",
+    );
+    let sub = Tlv::decode(&open(&key, &label_nonce(b"PS-Msg05"), &[], encrypted)?)?;
+    let id = sub
+        .get(tlv::IDENTIFIER)
+        .ok_or_else(|| err_msg("Missing controller identifier"))?;
+    let public_key = sub
+        .get(tlv::PUBLIC_KEY)
+        .ok_or_else(|| err_msg("Missing controller public key"))?;
+    let signature = sub
+        .get(tlv::SIGNATURE)
+        .ok_or_else(|| err_msg("Missing controller signature"))?;
+
+    let controller_x = derive_key(
+        &srp_key,
+        b"Pair-Setup-Controller-Sign-Salt",
+        b"Pair-Setup-Controller-Sign-Info",
+    );
+    verify_signature(
+        public_key,
+        &[&controller_x, id, public_key].concat(),
+        signature,
+    )?;
+
+    let id = String::from_utf8(id.to_vec())?;
+    let mut storage = server.storage.lock().unwrap();
+    storage.pairings.push(Pairing {
+        id: id.clone(),
+        public_key: public_key.to_vec(),
+        admin: true,
+    });
+    storage.failed_setups = 0;
+    storage.save()?;
+    info!("HomeKit paired with controller: {}", id);
+
+    let signing_key = storage.signing_key();
+    let accessory_public = signing_key.verifying_key().to_bytes();
+    let device_id = storage.device_id.clone();
+    drop(storage);
+    server.advertise();
+
+    let accessory_x = derive_key(
+        &srp_key,
+        b"Pair-Setup-Accessory-Sign-Salt",
+        b"Pair-Setup-Accessory-Sign-Info",
+    );
+    let info = [&accessory_x, device_id.as_bytes(), &accessory_public].concat();
+    let signature = signing_key.sign(&info).to_bytes();
+    let sub = tlv::encode(&[
+        (tlv::IDENTIFIER, device_id.as_bytes()),
+        (tlv::PUBLIC_KEY, &accessory_public),
+        (tlv::SIGNATURE, &signature),
+    ]);
+    let encrypted = seal(&key, &label_nonce(b"PS-Msg06"), &[], &sub)?;
+
+    *setup = Setup::Idle;
+    Ok(tlv::encode(&[
+        (tlv::STATE, &[6]),
+        (tlv::ENCRYPTED_DATA, &encrypted),
+    ]))
+}
+
+/// Handle a pair verify request, returning the TLV response and, once
+/// verified, the controller and its session keys
+pub fn verify(server: &Server, verify: &mut Verify, body: &[u8]) -> (Vec<u8>, Option<Verified>) {
+    let request = match Tlv::decode(body) {
+        Ok(request) => request,
+        Err(_) => return (tlv::error(2, tlv::ERROR_UNKNOWN), None),
+    };
+
+    match request.byte(tlv::STATE) {
+        Some(1) => match verify_start(server, verify, &request) {
+            Ok(response) => (response, None),
+            Err(e) => {
+                warn!("HomeKit pair verify failed: {}", e);
+                (tlv::error(2, tlv::ERROR_UNKNOWN), None)
+            }
+        },
+        Some(3) => match verify_finish(server, verify, &request) {
+            Ok(verified) => (tlv::encode(&[(tlv::STATE, &[4])]), Some(verified)),
+            Err(e) => {
+                warn!("HomeKit pair verify failed: {}", e);
+                *verify = Verify::Idle;
+                (tlv::error(4, tlv::ERROR_AUTHENTICATION), None)
+            }
+        },
+        state => (tlv::error(state.unwrap_or(0) + 1, tlv::ERROR_UNKNOWN), None),
+    }
+}
+
+/// M1 -> M2: agree on a shared secret with the controller and prove the
+/// accessory's identity
+fn verify_start(server: &Server, verify: &mut Verify, request: &Tlv) -> Result<Vec<u8>, Error> {
+    let controller_public: [u8; 32] = request
+        .get(tlv::PUBLIC_KEY)
+        .and_then(|key| key.try_into().ok())
+        .ok_or_else(|| err_msg("Missing controller public key"))?;
+
+    let secret = EphemeralSecret::random_from_rng(OsRng);
+    let accessory_public = PublicKey::from(&secret).to_bytes();
+    let shared_secret = secret
+        .diffie_hellman(&PublicKey::from(controller_public))
+        .to_bytes();
+
+    let storage = server.storage.lock().unwrap();
+    let info = [
+        &accessory_public[..],
+        storage.device_id.as_bytes(),
+        &controller_public,
+    ]
+    .concat();
+    let signature = storage.signing_key().sign(&info).to_bytes();
+    let sub = tlv::encode(&[
+        (tlv::IDENTIFIER, storage.device_id.as_bytes()),
+        (tlv::SIGNATURE, &signature),
+    ]);
+    drop(storage);
+
+    let session_key = derive_key(
+        &shared_secret,
+        b"Pair-Verify-Encrypt-Salt",
+        b"Pair-Verify-Encrypt-Info",
+    );
+    let encrypted = seal(&session_key, &label_nonce(b"PV-Msg02"), &[], &sub)?;
+
+    *verify = Verify::Started {
+        shared_secret,
+        session_key,
+        accessory_public,
+        controller_public,
+    };
+    Ok(tlv::encode(&[
+        (tlv::STATE, &[2]),
+        (tlv::PUBLIC_KEY, &accessory_public),
+        (tlv::ENCRYPTED_DATA, &encrypted),
+    ]))
+}
+
+/// M3 -> M4: check the controller is paired and proves its identity
+fn verify_finish(server: &Server, verify: &mut Verify, request: &Tlv) -> Result<Verified, Error> {
+    let (shared_secret, session_key, accessory_public, controller_public) = match verify {
+        Verify::Started {
+            shared_secret,
+            session_key,
+            accessory_public,
+            controller_public,
+        } => (
+            *shared_secret,
+            *session_key,
+            *accessory_public,
+            *controller_public,
+        ),
+        Verify::Idle => return Err(err_msg("Pair verify finish out of order")),
+    };
+    let encrypted = request
+        .get(tlv::ENCRYPTED_DATA)
+        .ok_or_else(|| err_msg("Missing encrypted data"))?;
+
+    let sub = Tlv::decode(&open(
+        &session_key,
+        &label_nonce(b"PV-Msg03"),
+        &[],
+        encrypted,
+    )?)?;
+    let id = sub
+        .get(tlv::IDENTIFIER)
+        .ok_or_else(|| err_msg("Missing controller identifier"))?;
+    let signature = sub
+        .get(tlv::SIGNATURE)
+        .ok_or_else(|| err_msg("Missing controller signature"))?;
+
+    let id = String::from_utf8(id.to_vec())?;
+    let pairing = server
+        .storage
+        .lock()
+        .unwrap()
+        .pairing(&id)
+        .cloned()
+        .ok_or_else(|| err_msg(format!("Unknown controller: {}", id)))?;
+    let info = [&controller_public[..], id.as_bytes(), &accessory_public].concat();
+    verify_signature(&pairing.public_key, &info, signature)?;
+
+    *verify = Verify::Idle;
+    debug!("HomeKit controller verified: {}", id);
+    Ok(Verified {
+        controller: id,
+        keys: SessionKeys {
+            read: derive_key(
+                &shared_secret,
+                b"Control-Salt",
+                b"Control-Write-Encryption-Key",
+            ),
+            write: derive_key(
+                &shared_secret,
+                b"Control-Salt",
+                b"Control-Read-Encryption-Key",
+            ),
+        },
+    })
+}
+
+/// Handle a request to add, remove or list pairings from a verified
+/// controller, which must be an admin
+pub fn pairings(server: &Server, controller: &str, body: &[u8]) -> Vec<u8> {
+    let request = match Tlv::decode(body) {
+        Ok(request) => request,
+        Err(_) => return tlv::error(2, tlv::ERROR_UNKNOWN),
+    };
+
+    let mut storage = server.storage.lock().unwrap();
+    let admin = storage
+        .pairing(controller)
+        .map(|pairing| pairing.admin)
+        .unwrap_or(false);
+    if !admin {
+        return tlv::error(2, tlv::ERROR_AUTHENTICATION);
+    }
+    let was_paired = storage.is_paired();
+
+    let response = match request.byte(tlv::METHOD) {
+        // Add pairing
+        Some(3) => {
+            let id = request
+                .get(tlv::IDENTIFIER)
+                .map(|id| String::from_utf8_lossy(id).into_owned());
+            let public_key = request.get(tlv::PUBLIC_KEY);
+            let admin = request.byte(tlv::PERMISSIONS) == Some(1);
+            match (id, public_key) {
+                (Some(id), Some(public_key)) => {
+                    match storage.pairings.iter_mut().find(|pairing| pairing.id == id) {
+                        Some(pairing) if pairing.public_key != public_key => {
+                            return tlv::error(2, tlv::ERROR_UNKNOWN);
+                        }
+                        Some(pairing) => pairing.admin = admin,
+                        None => storage.pairings.push(Pairing {
+                            id: id.clone(),
+                            public_key: public_key.to_vec(),
+                            admin,
+                        }),
+                    }
+                    info!("HomeKit pairing added: {}", id);
+                    tlv::encode(&[(tlv::STATE, &[2])])
+                }
+                _ => return tlv::error(2, tlv::ERROR_UNKNOWN),
+            }
+        }
+        // Remove pairing
+        Some(4) => {
+            let id = match request.get(tlv::IDENTIFIER) {
+                Some(id) => String::from_utf8_lossy(id).into_owned(),
+                None => return tlv::error(2, tlv::ERROR_UNKNOWN),
+            };
+            storage.pairings.retain(|pairing| pairing.id != id);
+            info!("HomeKit pairing removed: {}", id);
+            tlv::encode(&[(tlv::STATE, &[2])])
+        }
+        // List pairings
+        Some(5) => {
+            let mut items: Vec<(u8, &[u8])> = vec![(tlv::STATE, &[2])];
+            for (i, pairing) in storage.pairings.iter().enumerate() {
+                if i > 0 {
+                    items.push((tlv::SEPARATOR, &[]));
+                }
+                items.push((tlv::IDENTIFIER, pairing.id.as_bytes()));
+                items.push((tlv::PUBLIC_KEY, &pairing.public_key));
+                items.push((tlv::PERMISSIONS, if pairing.admin { &[1] } else { &[0] }));
+            }
+            return tlv::encode(&items);
+        }
+        _ => return tlv::error(2, tlv::ERROR_UNKNOWN),
+    };
+
+    if let Err(e) = storage.save() {
+        warn!("Could not save HomeKit pairings: {}", e);
+    }
+    let paired = storage.is_paired();
+    drop(storage);
+    if paired != was_paired {
+        server.advertise();
+    }
+
+    response
+}
+
+/// Derive a 32 byte key with HKDF-SHA-512
+fn derive_key(secret: &[u8], salt: &[u8], info: &[u8]) -> [u8; 32] {
+    let mut key = [0; 32];
+    Hkdf::<Sha512>::new(Some(salt), secret)
+        .expand(info, &mut key)
+        .expect("32 bytes is a valid HKDF-SHA-512 output length");
+    key
+}
+
+/// Nonce used by the pairing messages, an 8 byte label padded with zeros
+fn label_nonce(label: &[u8; 8]) -> [u8; 12] {
+    let mut nonce = [0; 12];
+    nonce[4..].copy_from_slice(label);
+    nonce
+}
+
+/// Encrypt with ChaCha20-Poly1305, appending the tag
+pub fn seal(key: &[u8; 32], nonce: &[u8; 12], aad: &[u8], msg: &[u8]) -> Result<Vec<u8>, Error> {
+    ChaCha20Poly1305::new(key.into())
+        .encrypt(Nonce::from_slice(nonce), Payload { msg, aad })
+        .map_err(|_| err_msg("Could not encrypt HomeKit message"))
+}
+
+/// Decrypt and authenticate with ChaCha20-Poly1305
+pub fn open(key: &[u8; 32], nonce: &[u8; 12], aad: &[u8], data: &[u8]) -> Result<Vec<u8>, Error> {
+    ChaCha20Poly1305::new(key.into())
+        .decrypt(Nonce::from_slice(nonce), Payload { msg: data, aad })
+        .map_err(|_| err_msg("Could not decrypt HomeKit message"))
+}
+
+fn verify_signature(public_key: &[u8], msg: &[u8], signature: &[u8]) -> Result<(), Error> {
+    let public_key: [u8; 32] = public_key
+        .try_into()
+        .map_err(|_| err_msg("Invalid public key length"))?;
+    let public_key =
+        VerifyingKey::from_bytes(&public_key).map_err(|_| err_msg("Invalid public key"))?;
+    let signature =
+        Signature::from_slice(signature).map_err(|_| err_msg("Invalid signature length"))?;
+    public_key
+        .verify(msg, &signature)
+        .map_err(|_| err_msg("Signature doesn't match"))
+}
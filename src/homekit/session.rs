@@ -0,0 +1,405 @@
+/// This module handles a single controller connection to the HomeKit server.
+///
+/// HomeKit uses HTTP/1.1 over a persistent connection. Once pair verify
+/// succeeds, everything after is encrypted in frames of up to 1024 bytes,
+/// each prefixed by its length and followed by an authentication tag. The
+/// accessory also sends unrequested `EVENT/1.0` messages over the connection
+/// for characteristics the controller subscribed to.
+use super::{
+    accessory::{self, AID, STATUS_NOT_FOUND},
+    pairing::{self, SessionKeys, Setup, Verify},
+    Server,
+};
+use failure::{bail, Error, ResultExt};
+use serde_json::{json, Value};
+use std::{collections::HashSet, sync::Arc};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    sync::broadcast,
+};
+use tracing::debug;
+
+const FRAME_LENGTH: usize = 1024;
+const TAG_LENGTH: usize = 16;
+
+/// Status returned when a request needs an encrypted connection
+const STATUS_INSUFFICIENT_PRIVILEGES: i32 = -70401;
+
+struct Request {
+    method: String,
+    path: String,
+    body: Vec<u8>,
+}
+
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+/// Encryption state of the connection, with the frame counter for each
+/// direction
+struct Encryption {
+    keys: SessionKeys,
+    read_count: u64,
+    write_count: u64,
+}
+
+struct Session {
+    server: Arc<Server>,
+    stream: TcpStream,
+    /// Received data that hasn't been decrypted yet
+    encrypted: Vec<u8>,
+    /// Received data that hasn't been parsed as a request yet
+    plain: Vec<u8>,
+    encryption: Option<Encryption>,
+    /// Enabled after the response that completes pair verify is sent
+    pending_keys: Option<SessionKeys>,
+    controller: Option<String>,
+    setup: Setup,
+    verify: Verify,
+    subscriptions: HashSet<u64>,
+}
+
+/// Serve requests on the connection until the controller closes it
+pub async fn run(stream: TcpStream, server: Arc<Server>) -> Result<(), Error> {
+    let mut events = server.accessory.device().events.subscribe();
+    let mut session = Session {
+        server,
+        stream,
+        encrypted: vec![],
+        plain: vec![],
+        encryption: None,
+        pending_keys: None,
+        controller: None,
+        setup: Setup::Idle,
+        verify: Verify::Idle,
+        subscriptions: HashSet::new(),
+    };
+    let mut buf = [0; 4096];
+
+    loop {
+        while let Some(request) = session.next_request()? {
+            debug!("HomeKit request: {} {}", request.method, request.path);
+            let response = session.handle(request).await;
+            session.send_response(response).await?;
+            if let Some(keys) = session.pending_keys.take() {
+                session.encryption = Some(Encryption {
+                    keys,
+                    read_count: 0,
+                    write_count: 0,
+                });
+            }
+        }
+
+        tokio::select! {
+            read = session.stream.read(&mut buf) => {
+                let n = read.context("Error reading from HomeKit connection")?;
+                if n == 0 {
+                    return Ok(());
+                }
+                session.receive(&buf[..n])?;
+            }
+            event = events.recv() => match event {
+                Ok(event) => {
+                    if let Some(iid) = accessory::changed(&event) {
+                        if session.subscriptions.contains(&iid) {
+                            session.send_event(iid).await?;
+                        }
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return Ok(()),
+            }
+        }
+    }
+}
+
+impl Session {
+    /// Add received data, decrypting any complete frames
+    fn receive(&mut self, data: &[u8]) -> Result<(), Error> {
+        let encryption = match &mut self.encryption {
+            Some(encryption) => encryption,
+            None => {
+                self.plain.extend_from_slice(data);
+                return Ok(());
+            }
+        };
+
+        self.encrypted.extend_from_slice(data);
+        while self.encrypted.len() >= 2 {
+            let len = usize::from(u16::from_le_bytes([self.encrypted[0], self.encrypted[1]]));
+            if self.encrypted.len() < 2 + len + TAG_LENGTH {
+                break;
+            }
+            let frame: Vec<u8> = self.encrypted.drain(..2 + len + TAG_LENGTH).collect();
+            let plain = pairing::open(
+                &encryption.keys.read,
+                &counter_nonce(encryption.read_count),
+                &frame[..2],
+                &frame[2..],
+            )?;
+            encryption.read_count += 1;
+            self.plain.extend_from_slice(&plain);
+        }
+
+        Ok(())
+    }
+
+    /// Parse the next complete request out of the received data
+    fn next_request(&mut self) -> Result<Option<Request>, Error> {
+        let head_end = match find(&self.plain, b"\r\n\r\n") {
+            Some(i) => i,
+            None => return Ok(None),
+        };
+        let head = String::from_utf8_lossy(&self.plain[..head_end]).into_owned();
+        let mut lines = head.split("\r\n");
+
+        let mut request_line = lines.next().unwrap_or("").split(' ');
+        let method = request_line.next().unwrap_or("").to_owned();
+        let path = request_line.next().unwrap_or("").to_owned();
+
+        let mut content_length = 0;
+        for line in lines {
+            let mut parts = line.splitn(2, ':');
+            let name = parts.next().unwrap_or("").trim();
+            let value = parts.next().unwrap_or("").trim();
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = match value.parse::<usize>() {
+                    Ok(len) => len,
+                    Err(_) => bail!("Invalid content length: {:?}", value),
+                };
+            }
+        }
+
+        let body_start = head_end + 4;
+        if self.plain.len() < body_start + content_length {
+            return Ok(None);
+        }
+        let body = self.plain[body_start..body_start + content_length].to_vec();
+        self.plain.drain(..body_start + content_length);
+
+        Ok(Some(Request { method, path, body }))
+    }
+
+    async fn handle(&mut self, request: Request) -> Response {
+        let (path, query) = match request.path.find('?') {
+            Some(i) => (&request.path[..i], &request.path[i + 1..]),
+            None => (request.path.as_str(), ""),
+        };
+        let server = self.server.clone();
+
+        match (request.method.as_str(), path) {
+            ("POST", "/pair-setup") => tlv(pairing::setup(&server, &mut self.setup, &request.body)),
+            ("POST", "/pair-verify") => {
+                let (body, verified) = pairing::verify(&server, &mut self.verify, &request.body);
+                if let Some(verified) = verified {
+                    self.controller = Some(verified.controller);
+                    self.pending_keys = Some(verified.keys);
+                }
+                tlv(body)
+            }
+            ("POST", "/identify") if !server.storage.lock().unwrap().is_paired() => {
+                server.accessory.identify();
+                empty("204 No Content")
+            }
+            _ if self.encryption.is_none() => hap_json(
+                "470 Connection Authorization Required",
+                &json!({ "status": STATUS_INSUFFICIENT_PRIVILEGES }),
+            ),
+            ("POST", "/pairings") => {
+                let controller = self.controller.clone().unwrap_or_default();
+                tlv(pairing::pairings(&server, &controller, &request.body))
+            }
+            ("GET", "/accessories") => hap_json("200 OK", &server.accessory.database()),
+            ("GET", "/characteristics") => self.read(query),
+            ("PUT", "/characteristics") => self.write(&request.body).await,
+            _ => empty("404 Not Found"),
+        }
+    }
+
+    /// Read the characteristics listed in the query, like `id=1.11,1.12`
+    fn read(&self, query: &str) -> Response {
+        let ids = query
+            .split('&')
+            .find_map(|param| param.strip_prefix("id="))
+            .unwrap_or("");
+
+        let mut all_ok = true;
+        let characteristics: Vec<_> = ids
+            .split(',')
+            .filter_map(parse_id)
+            .map(|(aid, iid)| {
+                let value = if aid == AID {
+                    self.server.accessory.read(iid)
+                } else {
+                    None
+                };
+                match value {
+                    Some(value) => json!({ "aid": aid, "iid": iid, "value": value }),
+                    None => {
+                        all_ok = false;
+                        json!({ "aid": aid, "iid": iid, "status": STATUS_NOT_FOUND })
+                    }
+                }
+            })
+            .collect();
+
+        let status = if all_ok { "200 OK" } else { "207 Multi-Status" };
+        hap_json(status, &json!({ "characteristics": characteristics }))
+    }
+
+    /// Write values or change event subscriptions of the characteristics in
+    /// the body
+    async fn write(&mut self, body: &[u8]) -> Response {
+        let request: Value = match serde_json::from_slice(body) {
+            Ok(request) => request,
+            Err(_) => return empty("400 Bad Request"),
+        };
+        let writes = request["characteristics"]
+            .as_array()
+            .cloned()
+            .unwrap_or_default();
+
+        let mut all_ok = true;
+        let mut statuses = vec![];
+        for write in writes {
+            let aid = write["aid"].as_u64().unwrap_or(0);
+            let iid = write["iid"].as_u64().unwrap_or(0);
+
+            let mut status = 0;
+            if aid != AID {
+                status = STATUS_NOT_FOUND;
+            } else {
+                if let Some(ev) = write["ev"].as_bool() {
+                    if self.server.accessory.has_events(iid) {
+                        if ev {
+                            self.subscriptions.insert(iid);
+                        } else {
+                            self.subscriptions.remove(&iid);
+                        }
+                    } else {
+                        status = STATUS_NOT_FOUND;
+                    }
+                }
+                if !write["value"].is_null() {
+                    if let Err(e) = self.server.accessory.write(iid, &write["value"]).await {
+                        status = e;
+                    }
+                }
+            }
+
+            all_ok &= status == 0;
+            statuses.push(json!({ "aid": aid, "iid": iid, "status": status }));
+        }
+
+        if all_ok {
+            empty("204 No Content")
+        } else {
+            hap_json("207 Multi-Status", &json!({ "characteristics": statuses }))
+        }
+    }
+
+    async fn send_response(&mut self, response: Response) -> Result<(), Error> {
+        let mut data = format!("HTTP/1.1 {}\r\n", response.status).into_bytes();
+        data.extend(message(response.content_type, response.body));
+        self.send(&data).await
+    }
+
+    /// Send the current value of a subscribed characteristic
+    async fn send_event(&mut self, iid: u64) -> Result<(), Error> {
+        let value = self.server.accessory.read(iid).unwrap_or(Value::Null);
+        let body = serde_json::to_vec(&json!({
+            "characteristics": [{ "aid": AID, "iid": iid, "value": value }]
+        }))?;
+
+        let mut data = b"EVENT/1.0 200 OK\r\n".to_vec();
+        data.extend(message("application/hap+json", body));
+        self.send(&data).await
+    }
+
+    /// Write data, encrypting it once pair verify has completed
+    async fn send(&mut self, data: &[u8]) -> Result<(), Error> {
+        let data = match &mut self.encryption {
+            Some(encryption) => {
+                let mut frames = vec![];
+                for chunk in data.chunks(FRAME_LENGTH) {
+                    let len = (chunk.len() as u16).to_le_bytes();
+                    let sealed = pairing::seal(
+                        &encryption.keys.write,
+                        &counter_nonce(encryption.write_count),
+                        &len,
+                        chunk,
+                    )?;
+                    encryption.write_count += 1;
+                    frames.extend_from_slice(&len);
+                    frames.extend(sealed);
+                }
+                frames
+            }
+            None => data.to_vec(),
+        };
+
+        self.stream
+            .write_all(&data)
+            .await
+            .context("Could not write to HomeKit connection")?;
+        Ok(())
+    }
+}
+
+/// Headers and body of a response or event
+fn message(content_type: &str, body: Vec<u8>) -> Vec<u8> {
+    let mut data = vec![];
+    if !content_type.is_empty() {
+        data.extend(format!("Content-Type: {}\r\n", content_type).into_bytes());
+    }
+    data.extend(format!("Content-Length: {}\r\n\r\n", body.len()).into_bytes());
+    data.extend(body);
+    data
+}
+
+fn tlv(body: Vec<u8>) -> Response {
+    Response {
+        status: "200 OK",
+        content_type: "application/pairing+tlv8",
+        body,
+    }
+}
+
+fn hap_json(status: &'static str, body: &Value) -> Response {
+    Response {
+        status,
+        content_type: "application/hap+json",
+        body: serde_json::to_vec(body).unwrap_or_default(),
+    }
+}
+
+fn empty(status: &'static str) -> Response {
+    Response {
+        status,
+        content_type: "",
+        body: vec![],
+    }
+}
+
+/// Parse a characteristic id like `1.11` into the accessory and instance ids
+fn parse_id(id: &str) -> Option<(u64, u64)> {
+    let mut parts = id.splitn(2, '.');
+    let aid = parts.next()?.parse().ok()?;
+    let iid = parts.next()?.parse().ok()?;
+    Some((aid, iid))
+}
+
+/// Nonce for an encrypted frame, the frame's counter padded with zeros
+fn counter_nonce(count: u64) -> [u8; 12] {
+    let mut nonce = [0; 12];
+    nonce[4..].copy_from_slice(&count.to_le_bytes());
+    nonce
+}
+
+fn find(data: &[u8], pattern: &[u8]) -> Option<usize> {
+    data.windows(pattern.len())
+        .position(|window| window == pattern)
+}
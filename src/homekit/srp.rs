@@ -0,0 +1,133 @@
+/// This module implements the server side of SRP-6a, used by HomeKit pair
+/// setup to prove both sides know the setup code without sending it.
+///
+/// HomeKit uses the 3072-bit group from RFC 5054 with SHA-512, and the fixed
+/// username "Pair-Setup".
+use num_bigint::BigUint;
+use rand::RngCore;
+use sha2::{Digest, Sha512};
+use subtle::ConstantTimeEq;
+
+const USERNAME: &[u8] = b"Pair-Setup";
+const GENERATOR: u32 = 5;
+
+/// 3072-bit prime of the RFC 5054 group
+const PRIME: [&str; 12] = [
+    "FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74",
+    "020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F1437",
+    "4FE1356D6D51C245E485B576625E7EC6F44C42E9A637ED6B0BFF5CB6F406B7ED",
+    "EE386BFB5A899FA5AE9F24117C4B1FE649286651ECE45B3DC2007CB8A163BF05",
+    "98DA48361C55D39A69163FA8FD24CF5F83655D23DCA3AD961C62F356208552BB",
+    "9ED529077096966D670C354E4ABC9804F1746C08CA18217C32905E462E36CE3B",
+    "E39E772C180E86039B2783A2EC07A28FB5C55DF06F4C52C9DE2BCBF695581718",
+    "3995497CEA956AE515D2261898FA051015728E5A8AAAC42DAD33170D04507A33",
+    "A85521ABDF1CBA64ECFB850458DBEF0A8AEA71575D060C7DB3970F85A6E1E4C7",
+    "ABF5AE8CDB0933D71E8C94E04A25619DCEE3D2261AD2EE6BF12FFA06D98A0864",
+    "D87602733EC86A64521F2B18177B200CBBE117577A615D6C770988C0BAD946E2",
+    "08E24FA074E5AB3143DB5BFCE0FD108E4B82D120A93AD2CAFFFFFFFFFFFFFFFF",
+];
+
+pub struct SrpServer {
+    salt: [u8; 16],
+    verifier: BigUint,
+    private: BigUint,
+    public: BigUint,
+}
+
+/// Session key and server proof, once the client's proof is verified
+pub struct Verified {
+    pub session_key: Vec<u8>,
+    pub proof: Vec<u8>,
+}
+
+impl SrpServer {
+    /// Start a new SRP session for the setup code, with a random salt and
+    /// private key
+    pub fn new(pin: &str) -> SrpServer {
+        let mut rng = rand::thread_rng();
+        let mut salt = [0; 16];
+        rng.fill_bytes(&mut salt);
+        let mut private = [0; 32];
+        rng.fill_bytes(&mut private);
+
+        let n = prime();
+        let g = BigUint::from(GENERATOR);
+
+        let credentials = hash(&[USERNAME, b":", pin.as_bytes()]);
+        let x = BigUint::from_bytes_be(&hash(&[&salt, &credentials]));
+        let verifier = g.modpow(&x, &n);
+
+        let k = BigUint::from_bytes_be(&hash(&[&n.to_bytes_be(), &pad(&g, &n)]));
+        let private = BigUint::from_bytes_be(&private);
+        let public = (k * &verifier + g.modpow(&private, &n)) % &n;
+
+        SrpServer {
+            salt,
+            verifier,
+            private,
+            public,
+        }
+    }
+
+    pub fn salt(&self) -> &[u8] {
+        &self.salt
+    }
+
+    pub fn public_key(&self) -> Vec<u8> {
+        self.public.to_bytes_be()
+    }
+
+    /// Check the client's proof against its public key, returning `None` if
+    /// it doesn't match, meaning the client used the wrong setup code
+    pub fn verify(&self, client_public: &[u8], client_proof: &[u8]) -> Option<Verified> {
+        let n = prime();
+        let a = BigUint::from_bytes_be(client_public);
+        if (&a % &n) == BigUint::from(0u32) {
+            return None;
+        }
+
+        let u = BigUint::from_bytes_be(&hash(&[&pad(&a, &n), &pad(&self.public, &n)]));
+        let s = (&a * self.verifier.modpow(&u, &n)).modpow(&self.private, &n);
+        let session_key = hash(&[&s.to_bytes_be()]);
+
+        let hash_n = hash(&[&n.to_bytes_be()]);
+        let hash_g = hash(&[&BigUint::from(GENERATOR).to_bytes_be()]);
+        let group: Vec<u8> = hash_n.iter().zip(&hash_g).map(|(n, g)| n ^ g).collect();
+        let proof = hash(&[
+            &group,
+            &hash(&[USERNAME]),
+            &self.salt,
+            &a.to_bytes_be(),
+            &self.public.to_bytes_be(),
+            &session_key,
+        ]);
+        if !bool::from(proof.as_slice().ct_eq(client_proof)) {
+            return None;
+        }
+
+        let proof = hash(&[&a.to_bytes_be(), &proof, &session_key]);
+        Some(Verified { session_key, proof })
+    }
+}
+
+fn prime() -> BigUint {
+    BigUint::parse_bytes(PRIME.concat().as_bytes(), 16).unwrap()
+}
+
+/// Big-endian bytes of value, left padded with zeros to the length of the
+/// prime
+fn pad(value: &BigUint, n: &BigUint) -> Vec<u8> {
+    let bytes = value.to_bytes_be();
+    let len = n.to_bytes_be().len();
+    let mut padded = vec![0; len.saturating_sub(bytes.len())];
+    padded.extend_from_slice(&bytes);
+    padded
+}
+
+fn hash(parts: &[&[u8]]) -> Vec<u8> {
+    let mut hasher = Sha512::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().to_vec()
+}
@@ -0,0 +1,90 @@
+/// This module persists the accessory's identity, its pairings and the number
+/// of failed pair setup attempts, so they survive restarts.
+///
+/// The file is created with a random device id and signing key the first time
+/// the HomeKit server starts. Deleting it resets the accessory, which then has
+/// to be removed and added to the Home app again.
+use ed25519_dalek::SigningKey;
+use failure::{bail, Error, ResultExt};
+use rand::{rngs::OsRng, Rng};
+use serde::{Deserialize, Serialize};
+use std::{fs, path::Path};
+use tracing::info;
+
+#[derive(Serialize, Deserialize)]
+pub struct Storage {
+    pub device_id: String,
+    secret_key: Vec<u8>,
+    pub config_number: u32,
+    pub pairings: Vec<Pairing>,
+    #[serde(default)]
+    pub failed_setups: u32,
+    #[serde(skip)]
+    path: String,
+}
+
+/// Controller paired with the accessory
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Pairing {
+    pub id: String,
+    pub public_key: Vec<u8>,
+    pub admin: bool,
+}
+
+impl Storage {
+    /// Load the file at path, or create it with a new identity if it doesn't
+    /// exist
+    pub fn load(path: &str) -> Result<Storage, Error> {
+        let mut storage = if Path::new(path).exists() {
+            let contents = fs::read_to_string(path)
+                .context(format!("Could not read HomeKit storage: {}", path))?;
+            serde_json::from_str(&contents)
+                .context(format!("Could not parse HomeKit storage: {}", path))?
+        } else {
+            info!("Creating new HomeKit identity: {}", path);
+            let device_id = rand::thread_rng()
+                .gen::<[u8; 6]>()
+                .iter()
+                .map(|byte| format!("{:02X}", byte))
+                .collect::<Vec<_>>()
+                .join(":");
+            Storage {
+                device_id,
+                secret_key: SigningKey::generate(&mut OsRng).to_bytes().to_vec(),
+                config_number: 1,
+                pairings: vec![],
+                failed_setups: 0,
+                path: String::new(),
+            }
+        };
+
+        if storage.secret_key.len() != 32 {
+            bail!("Invalid signing key in HomeKit storage: {}", path);
+        }
+        storage.path = path.to_owned();
+        storage.save()?;
+        Ok(storage)
+    }
+
+    pub fn save(&self) -> Result<(), Error> {
+        let contents = serde_json::to_string_pretty(self)?;
+        fs::write(&self.path, contents)
+            .context(format!("Could not write HomeKit storage: {}", self.path))?;
+        Ok(())
+    }
+
+    /// Long term key the accessory signs pairing messages with
+    pub fn signing_key(&self) -> SigningKey {
+        let mut secret = [0; 32];
+        secret.copy_from_slice(&self.secret_key);
+        SigningKey::from_bytes(&secret)
+    }
+
+    pub fn is_paired(&self) -> bool {
+        !self.pairings.is_empty()
+    }
+
+    pub fn pairing(&self, id: &str) -> Option<&Pairing> {
+        self.pairings.iter().find(|pairing| pairing.id == id)
+    }
+}
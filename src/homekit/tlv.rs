@@ -0,0 +1,94 @@
+/// This module encodes and decodes the TLV8 format used by the HomeKit pairing
+/// endpoints.
+///
+/// Each item is a type byte, a length byte and up to 255 bytes of value. Longer
+/// values are split into consecutive items of the same type.
+use failure::{bail, Error};
+
+pub const METHOD: u8 = 0x00;
+pub const IDENTIFIER: u8 = 0x01;
+pub const SALT: u8 = 0x02;
+pub const PUBLIC_KEY: u8 = 0x03;
+pub const PROOF: u8 = 0x04;
+pub const ENCRYPTED_DATA: u8 = 0x05;
+pub const STATE: u8 = 0x06;
+pub const ERROR: u8 = 0x07;
+pub const SIGNATURE: u8 = 0x0a;
+pub const PERMISSIONS: u8 = 0x0b;
+pub const SEPARATOR: u8 = 0xff;
+
+pub const ERROR_UNKNOWN: u8 = 0x01;
+pub const ERROR_AUTHENTICATION: u8 = 0x02;
+pub const ERROR_MAX_TRIES: u8 = 0x05;
+pub const ERROR_UNAVAILABLE: u8 = 0x06;
+pub const ERROR_BUSY: u8 = 0x07;
+
+/// Decoded items, with split values joined back together
+pub struct Tlv {
+    items: Vec<(u8, Vec<u8>)>,
+}
+
+impl Tlv {
+    pub fn decode(data: &[u8]) -> Result<Tlv, Error> {
+        let mut items: Vec<(u8, Vec<u8>)> = vec![];
+        let mut rest = data;
+
+        while !rest.is_empty() {
+            if rest.len() < 2 {
+                bail!("Truncated TLV item");
+            }
+            let (kind, len) = (rest[0], usize::from(rest[1]));
+            if rest.len() < 2 + len {
+                bail!("Truncated TLV value");
+            }
+            let value = &rest[2..2 + len];
+
+            match items.last_mut() {
+                Some((last, joined)) if *last == kind => joined.extend_from_slice(value),
+                _ => items.push((kind, value.to_vec())),
+            }
+            rest = &rest[2 + len..];
+        }
+
+        Ok(Tlv { items })
+    }
+
+    /// Value of the first item of type
+    pub fn get(&self, kind: u8) -> Option<&[u8]> {
+        self.items
+            .iter()
+            .find(|(item, _)| *item == kind)
+            .map(|(_, value)| value.as_slice())
+    }
+
+    /// Value of the first item of type, which must be a single byte
+    pub fn byte(&self, kind: u8) -> Option<u8> {
+        match self.get(kind) {
+            Some([byte]) => Some(*byte),
+            _ => None,
+        }
+    }
+}
+
+/// Encode items, splitting values longer than 255 bytes
+pub fn encode(items: &[(u8, &[u8])]) -> Vec<u8> {
+    let mut data = vec![];
+
+    for (kind, value) in items {
+        if value.is_empty() {
+            data.extend_from_slice(&[*kind, 0]);
+        }
+        for chunk in value.chunks(255) {
+            data.push(*kind);
+            data.push(chunk.len() as u8);
+            data.extend_from_slice(chunk);
+        }
+    }
+
+    data
+}
+
+/// Encode an error response for the given pairing state
+pub fn error(state: u8, error: u8) -> Vec<u8> {
+    encode(&[(STATE, &[state]), (ERROR, &[error])])
+}
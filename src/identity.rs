@@ -0,0 +1,59 @@
+/// This module asks an AVR for its model name and firmware version, which
+/// tell which profile fits it, see `crate::profile`.
+///
+/// The AVR is only asked when it's wanted, by the Identify intent ("what
+/// receiver is this") or `GET /health`, as not every protocol has the queries.
+/// Its answers are kept in its cached state, see `crate::events`, until it
+/// disconnects, so it's only asked once.
+use crate::{avr::AvrQuery, telnet::AvrConnection};
+use failure::Error;
+use serde::Serialize;
+
+/// Model name and firmware version of an AVR, `None` if it hasn't said
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Identity {
+    pub model: Option<String>,
+    pub firmware: Option<String>,
+}
+
+/// Asks an AVR for its identity. Cloned for each request.
+#[derive(Clone)]
+pub struct Identifier {
+    conn: AvrConnection,
+}
+
+impl Identifier {
+    pub fn new(conn: AvrConnection) -> Identifier {
+        Identifier { conn }
+    }
+
+    /// What the AVR has said, without asking it
+    pub fn known(&self) -> Identity {
+        let state = self.conn.events().state();
+        Identity {
+            model: state.model,
+            firmware: state.firmware,
+        }
+    }
+
+    /// Model name and firmware version, asking the AVR for whichever it
+    /// hasn't said yet. Nothing is asked in dry run mode, or if its protocol
+    /// has no query for it.
+    pub async fn identify(&self) -> Result<Identity, Error> {
+        let known = self.known();
+        if self.conn.dry_run() {
+            return Ok(known);
+        }
+
+        let protocol = self.conn.protocol();
+        for (query, missing) in &[
+            (AvrQuery::Model, known.model.is_none()),
+            (AvrQuery::Firmware, known.firmware.is_none()),
+        ] {
+            if *missing && !protocol.query_code(query).is_empty() {
+                self.conn.query(*query).await?;
+            }
+        }
+        Ok(self.known())
+    }
+}
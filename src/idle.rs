@@ -0,0 +1,68 @@
+/// This module turns an AVR off once it has been left on with nothing
+/// happening, so the amp isn't left running overnight.
+///
+/// The AVR counts as idle while it's on and no commands are sent to it, it
+/// reports no state changes, like the volume being changed with the remote,
+/// and its current input has no signal. The input signal is polled with the
+/// AVR's audio status, for protocols that have one. Once the AVR has been idle
+/// for the configured number of hours, it's turned off through its command
+/// queue.
+use crate::{
+    avr::AvrCommand,
+    events::AvrEvent,
+    queue::{CommandQueue, Source},
+    telnet::AvrConnection,
+};
+use std::time::{Duration, Instant};
+use tokio::{sync::broadcast::error::TryRecvError, time::interval};
+use tracing::{debug, info, warn};
+
+/// How often to check whether the AVR is idle
+const POLL: Duration = Duration::from_secs(60);
+
+/// Start a task turning the named AVR off once it has been idle for `hours`
+pub fn run(name: String, hours: u64, conn: AvrConnection, queue: CommandQueue) {
+    info!("Turning {} off after {} hours idle", name, hours);
+    tokio::spawn(watch(name, hours, conn, queue));
+}
+
+async fn watch(name: String, hours: u64, conn: AvrConnection, queue: CommandQueue) {
+    let limit = Duration::from_secs(hours * 3_600);
+    let mut events = conn.events().subscribe();
+    let mut active = Instant::now();
+    let mut poll = interval(POLL);
+
+    loop {
+        poll.tick().await;
+
+        loop {
+            match events.try_recv() {
+                Ok(AvrEvent::Connected(_)) | Ok(AvrEvent::InputLabel(..)) => {}
+                Ok(_) | Err(TryRecvError::Lagged(_)) => active = Instant::now(),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Closed) => return,
+            }
+        }
+        active = active.max(queue.last_command());
+
+        // Only time spent on counts
+        if conn.events().state().power != Some(true) {
+            active = Instant::now();
+            continue;
+        }
+        match conn.signal_present().await {
+            Ok(Some(true)) => active = Instant::now(),
+            Ok(_) => {}
+            Err(e) => debug!("Could not get audio status of {}: {}", name, e),
+        }
+        if active.elapsed() < limit {
+            continue;
+        }
+
+        info!("{} has been idle for {} hours, turning it off", name, hours);
+        if let Err(e) = queue.process(AvrCommand::PowerOff, Source::Idle).await {
+            warn!("Could not turn {} off: {}", name, e);
+        }
+        active = Instant::now();
+    }
+}
@@ -0,0 +1,84 @@
+/// This module learns the names an AVR's inputs were given on its on-screen
+/// menu, so the names the skill uses stay in sync with the ones on screen.
+///
+/// Each time the AVR connects, it's asked for the label of every input, in
+/// turn. Inputs that were renamed, like HDMI 1 to "PS5", go by their new name
+/// in the lists of inputs and can be asked for by it, and inputs the AVR
+/// answers with an error for are taken to be missing from its model and
+/// hidden, see `crate::aliases`.
+use crate::{
+    aliases::{InputNames, Label},
+    avr::AvrQuery,
+    events::AvrEvent,
+    protocol::Message,
+    telnet::AvrConnection,
+};
+use failure::{bail, Error};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{debug, info, warn};
+
+/// Start a task learning the named AVR's input labels each time it connects
+pub fn run(name: String, conn: AvrConnection, inputs: InputNames) -> Result<(), Error> {
+    if conn
+        .protocol()
+        .query_code(&AvrQuery::InputLabel(1))
+        .is_empty()
+    {
+        bail!("Input labels can't be learned for {}'s protocol", name);
+    }
+    info!("Learning {}'s input labels", name);
+    tokio::spawn(watch(name, conn, inputs));
+    Ok(())
+}
+
+async fn watch(name: String, conn: AvrConnection, inputs: InputNames) {
+    let mut events = conn.events().subscribe();
+    let mut connected = conn.events().state().connected == Some(true);
+
+    loop {
+        if connected {
+            match learn(&conn, &inputs).await {
+                Ok(renamed) => info!("Learned {}'s input labels, {} renamed", name, renamed),
+                Err(e) => warn!("Could not learn {}'s input labels: {}", name, e),
+            }
+        }
+        connected = match events.recv().await {
+            Ok(AvrEvent::Connected(true)) => true,
+            Ok(_) | Err(RecvError::Lagged(_)) => false,
+            Err(RecvError::Closed) => return,
+        };
+    }
+}
+
+/// Ask the AVR for the label of each of its inputs, recording them in
+/// `inputs`, and return how many were renamed
+pub async fn learn(conn: &AvrConnection, inputs: &InputNames) -> Result<usize, Error> {
+    let protocol = conn.protocol().clone();
+    let mut renamed = 0;
+
+    for n in 1..=protocol.inputs().len() as u8 {
+        let response = conn.query(AvrQuery::InputLabel(n)).await?;
+        let mut label = None;
+        for message in response.split_terminator("\r\n") {
+            match protocol.classify(message) {
+                Message::Event(AvrEvent::InputLabel(_, Some(name))) => {
+                    label = Some(Label::Renamed(name))
+                }
+                Message::Event(AvrEvent::InputLabel(_, None)) => label = Some(Label::Default),
+                Message::Error(_) => label = Some(Label::Missing),
+                _ => {}
+            }
+        }
+        match label {
+            Some(label) => {
+                debug!("Input {} label: {:?}", n, label);
+                if let Label::Renamed(_) = label {
+                    renamed += 1;
+                }
+                inputs.learn(n, label);
+            }
+            None => bail!("No label for input {}", n),
+        }
+    }
+    Ok(renamed)
+}
@@ -0,0 +1,90 @@
+/// This module measures how long an AVR takes to carry out each type of
+/// command, to tell a flaky receiver from a slow network.
+///
+/// The time is from the command's code being written to the AVR until its
+/// response confirmed it, for commands that worked. The latest `SAMPLES` of
+/// each type of command are kept in memory, and summed up as percentiles in
+/// the `/stats` summary, see `crate::stats`. A command taking longer than the
+/// AVR's slow threshold is logged as a warning.
+use crate::avr::AvrCommand;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tracing::warn;
+
+/// How many of the latest times are kept for each type of command
+const SAMPLES: usize = 500;
+
+/// Percentiles of the latest times of a type of command, in milliseconds
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Percentiles {
+    pub count: usize,
+    pub p50_ms: u64,
+    pub p95_ms: u64,
+    pub p99_ms: u64,
+}
+
+/// Latest times of each type of command processed against an AVR. Cloned for
+/// its command queue.
+#[derive(Clone)]
+pub struct Latencies {
+    slow: Duration,
+    samples: Arc<Mutex<BTreeMap<&'static str, VecDeque<Duration>>>>,
+}
+
+impl Latencies {
+    /// Keep the times of an AVR's commands, warning about those over `slow`
+    pub fn new(slow: Duration) -> Latencies {
+        Latencies {
+            slow,
+            samples: Arc::new(Mutex::new(BTreeMap::new())),
+        }
+    }
+
+    /// Add the time the named AVR took to carry out the command
+    pub fn record(&self, device: &str, cmd: &AvrCommand, latency: Duration) {
+        if latency > self.slow {
+            warn!(
+                "Slow command: {}: {:?} took {}ms",
+                device,
+                cmd,
+                latency.as_millis()
+            );
+        }
+
+        let mut samples = self.samples.lock().unwrap();
+        let samples = samples.entry(cmd.name()).or_default();
+        if samples.len() == SAMPLES {
+            samples.pop_front();
+        }
+        samples.push_back(latency);
+    }
+
+    /// Percentiles of each type of command carried out so far, by name
+    pub fn summary(&self) -> BTreeMap<String, Percentiles> {
+        self.samples
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, samples)| !samples.is_empty())
+            .map(|(name, samples)| {
+                let mut sorted: Vec<_> = samples.iter().copied().collect();
+                sorted.sort();
+                let percentile = |p: usize| {
+                    let index = (sorted.len() * p).div_ceil(100).max(1) - 1;
+                    sorted[index].as_millis() as u64
+                };
+                let percentiles = Percentiles {
+                    count: sorted.len(),
+                    p50_ms: percentile(50),
+                    p95_ms: percentile(95),
+                    p99_ms: percentile(99),
+                };
+                (name.to_string(), percentiles)
+            })
+            .collect()
+    }
+}
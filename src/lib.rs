@@ -0,0 +1,72 @@
+/// The modules making up the program, built as a library so they can be used
+/// by the tests in `tests/` as well as by `main.rs`. Other programs can control
+/// an AVR with `client::AvrClient`.
+use failure::Error;
+use tracing::error;
+
+pub mod access_log;
+pub mod addr;
+pub mod aliases;
+pub mod api;
+pub mod audit;
+pub mod avr;
+pub mod basic_auth;
+pub mod client;
+pub mod config;
+pub mod curve;
+pub mod debounce;
+pub mod device;
+pub mod discovery;
+pub mod events;
+pub mod google;
+pub mod heartbeat;
+pub mod history;
+pub mod homekit;
+pub mod identity;
+pub mod idle;
+pub mod labels;
+pub mod latency;
+pub mod linking;
+pub mod logging;
+pub mod memory;
+pub mod model;
+pub mod offset;
+pub mod openapi;
+pub mod pioneer;
+pub mod presets;
+pub mod profile;
+pub mod progressive;
+pub mod protocol;
+pub mod queue;
+pub mod quiet;
+pub mod raw;
+pub mod replay;
+pub mod resolve;
+pub mod scheduler;
+pub mod sequence;
+pub mod setup;
+pub mod site;
+pub mod skill;
+pub mod smart_home;
+pub mod socket;
+pub mod speech;
+pub mod state;
+pub mod stats;
+pub mod status;
+pub mod table;
+pub mod telegram;
+pub mod telnet;
+pub mod tls;
+pub mod undo;
+pub mod users;
+pub mod watchdog;
+pub mod webhook;
+pub mod zone;
+
+/// Log any errors and causes
+pub fn log_error(e: &Error) {
+    error!("{}", e);
+    for cause in e.iter_causes() {
+        error!("Caused by: {}", cause);
+    }
+}
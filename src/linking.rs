@@ -0,0 +1,168 @@
+/// This module checks the access token of each Alexa request when account
+/// linking is set up, so one hosted instance can serve several households'
+/// Alexa accounts, each controlling only its own AVRs.
+///
+/// Once the skill's account linking is set up with an OAuth server, Alexa
+/// sends the access token the account was linked with in every request. It's
+/// checked with the server's token introspection endpoint, as in RFC 7662, and
+/// the account it belongs to, its `sub` or else its `username`, gets the AVRs
+/// listed for it in the config. Answers are kept for a few minutes, or until
+/// the token expires if that's sooner, so not every request waits on the
+/// server. Requests without a token, or with one that isn't active or is for
+/// an account with no AVRs, are asked to link the account in the Alexa app.
+use crate::{
+    config::AccountLinkingConfig,
+    device::Devices,
+    progressive::{HttpsClient, Progress},
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::Utc;
+use failure::{bail, Error, ResultExt};
+use hyper::{
+    body,
+    header::{AUTHORIZATION, CONTENT_TYPE},
+    Body, Request,
+};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tokio::time::timeout;
+use tracing::{debug, info};
+
+/// Longest an introspection answer is kept
+const CACHE_FOR: Duration = Duration::from_secs(300);
+
+/// How long to wait for the introspection endpoint, leaving time to process
+/// the request within Alexa's 8 seconds
+const TIMEOUT: Duration = Duration::from_secs(4);
+
+/// Introspection endpoint's answer, as in RFC 7662
+#[derive(Deserialize)]
+struct Introspection {
+    active: bool,
+    sub: Option<String>,
+    username: Option<String>,
+    exp: Option<i64>,
+}
+
+/// Checks access tokens with the introspection endpoint, keeping the answers
+pub struct AccountLinking {
+    config: AccountLinkingConfig,
+    client: HttpsClient,
+    /// Account of each token checked, `None` if it wasn't active, and when
+    /// to check it again
+    cache: Mutex<HashMap<String, (Option<String>, Instant)>>,
+}
+
+impl AccountLinking {
+    /// Checking tokens with the configured endpoint, making sure every AVR
+    /// listed for an account is configured
+    pub fn new(config: AccountLinkingConfig, devices: &Devices) -> Result<AccountLinking, Error> {
+        config
+            .introspection_url
+            .parse::<hyper::Uri>()
+            .context("Introspection URL not valid")?;
+        for (account, names) in &config.accounts {
+            for name in names {
+                if devices.get(Some(name)).is_none() {
+                    bail!("No AVR named {:?} for account {:?}", name, account);
+                }
+            }
+        }
+        info!(
+            "Account linking enabled, for {} accounts",
+            config.accounts.len()
+        );
+
+        Ok(AccountLinking {
+            config,
+            client: Progress::client(),
+            cache: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// The AVRs the account with the access token controls, `None` if there's
+    /// no token, it isn't active, or the account has none
+    pub async fn devices(
+        &self,
+        token: Option<&str>,
+        devices: &Devices,
+    ) -> Result<Option<Devices>, Error> {
+        let token = match token {
+            Some(token) => token,
+            None => {
+                info!("Request has no access token, account isn't linked");
+                return Ok(None);
+            }
+        };
+        let account = match self.account(token).await? {
+            Some(account) => account,
+            None => {
+                info!("Access token isn't active");
+                return Ok(None);
+            }
+        };
+        info!("Linked account: {}", account);
+
+        match self.config.accounts.get(&account) {
+            Some(names) => Ok(Some(devices.only(names))),
+            None => {
+                info!("No AVRs configured for account: {}", account);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Account the token belongs to, if it's active, introspecting it unless
+    /// the answer is kept
+    async fn account(&self, token: &str) -> Result<Option<String>, Error> {
+        let now = Instant::now();
+        {
+            let mut cache = self.cache.lock().unwrap();
+            cache.retain(|_, (_, until)| *until > now);
+            if let Some((account, _)) = cache.get(token) {
+                return Ok(account.clone());
+            }
+        }
+
+        let introspection = self.introspect(token).await?;
+        let account = match introspection.active {
+            true => introspection.sub.or(introspection.username),
+            false => None,
+        };
+        let expires_in = introspection
+            .exp
+            .map(|exp| Duration::from_secs((exp - Utc::now().timestamp()).max(0) as u64))
+            .unwrap_or(CACHE_FOR);
+        self.cache.lock().unwrap().insert(
+            token.to_owned(),
+            (account.clone(), now + expires_in.min(CACHE_FOR)),
+        );
+        Ok(account)
+    }
+
+    async fn introspect(&self, token: &str) -> Result<Introspection, Error> {
+        debug!("Introspecting access token");
+        let credentials = format!("{}:{}", self.config.client_id, self.config.client_secret);
+        let request = Request::post(&self.config.introspection_url)
+            .header(
+                AUTHORIZATION,
+                format!("Basic {}", STANDARD.encode(credentials)),
+            )
+            .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(Body::from(serde_urlencoded::to_string([("token", token)])?))?;
+
+        let response = match timeout(TIMEOUT, self.client.request(request)).await {
+            Ok(response) => response.context("Could not reach introspection endpoint")?,
+            Err(_) => bail!("Introspection endpoint didn't answer in time"),
+        };
+        if !response.status().is_success() {
+            bail!("Introspection endpoint answered {}", response.status());
+        }
+        let body = body::to_bytes(response.into_body()).await?;
+        Ok(serde_json::from_slice(&body).context("Could not parse introspection response")?)
+    }
+}
@@ -0,0 +1,59 @@
+/// This module sets up the logger for the program.
+///
+/// Logs can either be written as plain text lines, or as one JSON object per
+/// line so they can be ingested by log aggregators such as Loki or
+/// Elasticsearch.
+///
+/// Logging goes through `tracing`, so each line carries the spans it was
+/// written in: the HTTP request, the Alexa request with its id, the intent,
+/// the AVR command and the telnet exchange. Each span is logged when it
+/// closes, with how long it took, so a single request's timing can be broken
+/// down. Commands are processed on their AVR's queue task, but their spans
+/// are still children of the request's, see `crate::queue`. Logs of the
+/// libraries using `log` are picked up too.
+use std::future::Future;
+use tracing::{info_span, Instrument};
+use tracing_subscriber::{fmt::format::FmtSpan, EnvFilter};
+
+/// Supported output formats for the logger
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl<'a> From<&'a str> for LogFormat {
+    /// Convert from string to appropriate `LogFormat`
+    fn from(s: &'a str) -> LogFormat {
+        match s {
+            "json" => LogFormat::Json,
+            _ => LogFormat::Text,
+        }
+    }
+}
+
+/// Initialize the logger with the requested format. Log level filtering is
+/// still controlled by `RUST_LOG`.
+pub fn init(format: LogFormat) {
+    let filter = EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| EnvFilter::new("alexa_avr_control=info"));
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_span_events(FmtSpan::CLOSE);
+
+    match format {
+        LogFormat::Text => builder.init(),
+        LogFormat::Json => builder
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .init(),
+    }
+}
+
+/// Run `future` in a span with the Alexa request id, so it's attached to any
+/// log lines written while it is processed.
+pub async fn with_request_id<F: Future>(id: String, future: F) -> F::Output {
+    future
+        .instrument(info_span!("alexa_request", request_id = %id))
+        .await
+}
@@ -1,44 +1,62 @@
 /// This program hosts a custom web service for processing requests for the
 /// Alexa AVR Control skill.   
 ///
-/// At a high level, the program will launch a thread to manage the telnet
-/// connection to the networked AVR device and another thread for the Rouille
-/// server, which will have a single route to receive json POST requests from
-/// the Alexa skill.   
+/// At a high level, the program runs on a tokio runtime. For each configured
+/// AVR, one task manages the telnet connection to the networked AVR device
+/// and a command queue task processes commands one at a time through an
+/// `AvrConnection` handle to the telnet task. A hyper server handles the
+/// single route to receive json POST requests from the Alexa skill, and the
+/// configured `Devices` are passed to the server and on to each request.   
 ///
 /// When requests are received from Alexa, the request will be verified,
 /// deserialized and processed into the approriate command needing to be sent
-/// to the AVR. The request thread will send a message to the telnet thread
-/// with the appropriate command via a crossbeam channel. The telnet thread
-/// blocks while waiting for these messages, and once received will write it
-/// over the telnet connection, then wait for a response back from the AVR.
-/// This response code is then sent back via crossbeam to the request thread
+/// to the AVR, which is added to that AVR's command queue. When processed,
+/// the command is converted to codes and sent to the telnet task via the
+/// `AvrConnection`, along with a oneshot channel for the response. The telnet
+/// task waits for these messages, and once received will write it over the
+/// telnet connection, then wait for a response back from the AVR. This
+/// response code is then sent back on the oneshot channel to the request task
 /// for futher processing. If the response from the AVR matches the expected
-/// response, verifying the requested change went through, the request thread
-/// will respond with a success message back to the user.
-use clap::{App, Arg};
-use crossbeam_channel::{bounded, Receiver, Sender};
-use env_logger::Env;
-use failure::Error;
-use lazy_static::{initialize, lazy_static};
-use log::error;
-
-mod avr;
-mod site;
-mod skill;
-mod speech;
-mod telnet;
+/// response, verifying the requested change went through, the request task
+/// will respond with a success message back to the user.   
+///
+/// Everything the AVR sends is also parsed into state change events by the
+/// telnet task, keeping a cache of the AVR's state up to date even when it's
+/// changed with the physical remote.
+use alexa_avr_control::{
+    access_log::AccessLog,
+    addr,
+    api::Api,
+    audit::Audit,
+    basic_auth::{self, BasicAuth},
+    config::{self, Config, DeviceConfig},
+    device, discovery, google, heartbeat,
+    history::History,
+    homekit,
+    linking::AccountLinking,
+    log_error, logging, memory, model,
+    quiet::QuietHours,
+    replay::{self, Recorder},
+    scheduler, setup, site, smart_home, socket, speech, stats,
+    stats::Stats,
+    status, telegram, telnet, tls, watchdog, webhook,
+};
+use clap::{App, Arg, SubCommand};
+use failure::{bail, format_err, Error};
+use std::{
+    future,
+    net::{Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+use tracing::{info, warn};
 
-lazy_static! {
-    /// Send messages from skills request thread to telnet thread
-    static ref CHANNEL_A: (Sender<String>, Receiver<String>) = { bounded(1) };
+/// How long to wait for AVRs to respond to SSDP discovery
+const DISCOVERY_WAIT: Duration = Duration::from_secs(3);
 
-    /// Send messages from telnet thread back to skills request thread
-    static ref CHANNEL_B: (Sender<String>, Receiver<String>) = { bounded(1) };
-}
-
-fn main() {
-    if let Err(e) = run() {
+#[tokio::main]
+async fn main() {
+    if let Err(e) = run().await {
         log_error(&e);
         std::process::exit(1);
     }
@@ -46,23 +64,23 @@ fn main() {
 
 /// Run the program...   
 ///
-/// Setup the logger, intialize the crossbeam channels, process command line
-/// arguments and kick off the telnet and Rouille threads.
-fn run() -> Result<(), Error> {
-    env_logger::from_env(Env::default().default_filter_or("alexa_avr_control=info")).init();
-
-    initialize(&CHANNEL_A);
-    initialize(&CHANNEL_B);
-
+/// Process command line arguments, load the config file, setup the logger and
+/// kick off the telnet and web service tasks.   
+///
+/// The AVR given by HOST and PORT is added before any in the config file,
+/// making it the default AVR. If neither gives an AVR, the first one found on
+/// the network via SSDP is used. Either way, `--avr-port` is its port when
+/// PORT isn't given.
+async fn run() -> Result<(), Error> {
     let matches = App::new("Alexa AVR Control")
                           .version("0.1.1")
                           .author("Cory F. <cforsstrom18@gmail.com>")
                           .about("A self hosted Alexa skill to control a network-enabled Pioneer AVR through telnet commands.")
-                          .arg(Arg::with_name("HOST").required(true)
+                          .arg(Arg::with_name("HOST")
                                                      .index(1)
-                                                     .help("Specify the host / ip of the AVR"))
-                          .arg(Arg::with_name("PORT").required(true)
-                                                     .index(2)
+                                                     .help("Specify the host / ip of the AVR, found via SSDP if not given, an IPv6 address in brackets to add a port")
+                                                     .validator(|h| addr::split_host(&h).map(|_| ()).map_err(|e| e.to_string())))
+                          .arg(Arg::with_name("PORT").index(2)
                                                      .help("Specify the telnet port for the AVR")
                                                      .validator(|p| {
                                                             let p = p.parse::<u16>().map_err(|_| "Port provided not valid");
@@ -71,6 +89,20 @@ fn run() -> Result<(), Error> {
                                                                 Err(e) => Err(e.to_owned())
                                                             }
                                                         }))
+                          .arg(Arg::with_name("avr-port").long("avr-port")
+                                                     .takes_value(true)
+                                                     .help("Specify the telnet port for the AVR when not given as PORT, 8102 on some models")
+                                                     .default_value("23")
+                                                     .validator(|p| {
+                                                            let p = p.parse::<u16>().map_err(|_| "Port provided not valid");
+                                                            match p {
+                                                                Ok(_) => Ok(()),
+                                                                Err(e) => Err(e.to_owned())
+                                                            }
+                                                        }))
+                          .arg(Arg::with_name("config").short("c").long("config")
+                                                     .takes_value(true)
+                                                     .help("Specify a config file, which can list several AVRs"))
                           .arg(Arg::with_name("port").short("p")
                                                      .takes_value(true)
                                                      .help("Specify the port to run the skill web service on")
@@ -82,21 +114,333 @@ fn run() -> Result<(), Error> {
                                                                 Err(e) => Err(e.to_owned())
                                                             }
                                                         }))
+                          .arg(Arg::with_name("bind").long("bind")
+                                                     .takes_value(true)
+                                                     .help("Specify the address to run the skill web service on, :: for both IPv4 and IPv6")
+                                                     .default_value("::")
+                                                     .validator(|a| addr::parse_ip(&a).map(|_| ()).map_err(|e| e.to_string())))
+                          .arg(Arg::with_name("reconnect-min").long("reconnect-min")
+                                                     .takes_value(true)
+                                                     .help("Specify the initial delay in seconds before reconnecting to the AVR")
+                                                     .default_value("1")
+                                                     .validator(|s| {
+                                                            let s = s.parse::<u64>().map_err(|_| "Delay provided not valid");
+                                                            match s {
+                                                                Ok(_) => Ok(()),
+                                                                Err(e) => Err(e.to_owned())
+                                                            }
+                                                        }))
+                          .arg(Arg::with_name("reconnect-max").long("reconnect-max")
+                                                     .takes_value(true)
+                                                     .help("Specify the maximum delay in seconds before reconnecting to the AVR")
+                                                     .default_value("60")
+                                                     .validator(|s| {
+                                                            let s = s.parse::<u64>().map_err(|_| "Delay provided not valid");
+                                                            match s {
+                                                                Ok(_) => Ok(()),
+                                                                Err(e) => Err(e.to_owned())
+                                                            }
+                                                        }))
+                          .arg(Arg::with_name("connect-timeout").long("connect-timeout")
+                                                     .takes_value(true)
+                                                     .help("Specify how many seconds to wait for the AVR to accept the connection")
+                                                     .default_value("5")
+                                                     .validator(|s| {
+                                                            let s = s.parse::<u64>().map_err(|_| "Timeout provided not valid");
+                                                            match s {
+                                                                Ok(_) => Ok(()),
+                                                                Err(e) => Err(e.to_owned())
+                                                            }
+                                                        }))
+                          .arg(Arg::with_name("log-format").long("log-format")
+                                                     .takes_value(true)
+                                                     .help("Specify the format of log output")
+                                                     .possible_values(&["text", "json"])
+                                                     .default_value("text"))
+                          .arg(Arg::with_name("dry-run").long("dry-run")
+                                                     .help("Log the commands that would be sent to the AVR without sending them"))
+                          .arg(Arg::with_name("insecure-skip-verify").long("insecure-skip-verify")
+                                                     .help("INSECURE, for development only: accept Alexa requests that aren't signed by Alexa, needs --bind to a loopback address"))
+                          .arg(Arg::with_name("record").long("record")
+                                                     .takes_value(true)
+                                                     .help("Record the codes sent to the AVR and its responses to a transcript file"))
+                          .subcommand(SubCommand::with_name("discover")
+                                                     .about("Search the network for AVRs and list them"))
+                          .subcommand(SubCommand::with_name("replay")
+                                                     .about("Act as an AVR, replaying the responses in a transcript file")
+                                                     .arg(Arg::with_name("TRANSCRIPT").required(true)
+                                                                                      .help("Specify the transcript file to replay"))
+                                                     .arg(Arg::with_name("PORT").default_value("2323")
+                                                                                .help("Specify the telnet port to listen on")
+                                                                                .validator(|p| {
+                                                                                    let p = p.parse::<u16>().map_err(|_| "Port provided not valid");
+                                                                                    match p {
+                                                                                        Ok(_) => Ok(()),
+                                                                                        Err(e) => Err(e.to_owned())
+                                                                                    }
+                                                                                })))
+                          .subcommand(SubCommand::with_name("export-model")
+                                                     .about("Print the skill's interaction model JSON, for the configured AVRs and features"))
+                          .subcommand(SubCommand::with_name("init")
+                                                     .about("Write an example config file, with every setting commented")
+                                                     .arg(Arg::with_name("FILE").default_value("config.toml")
+                                                                                .help("Specify the config file to write")))
+                          .subcommand(SubCommand::with_name("setup")
+                                                     .about("Find the AVR, test its inputs and write a config file for it, asking questions along the way")
+                                                     .arg(Arg::with_name("FILE").default_value("config.toml")
+                                                                                .help("Specify the config file to write")))
+                          .subcommand(SubCommand::with_name("status")
+                                                     .about("Query the state of each AVR and print it, without starting the web service")
+                                                     .arg(Arg::with_name("json").long("json")
+                                                                                .help("Print the state as JSON")))
+                          .subcommand(SubCommand::with_name("send")
+                                                     .about("Send a command to the running service on its control socket, like PowerOn or '{\"SetVolume\": 4}'")
+                                                     .arg(Arg::with_name("COMMAND").required(true)
+                                                                                   .help("Specify the command, as the control API takes it"))
+                                                     .arg(Arg::with_name("device").short("d").long("device")
+                                                                                  .takes_value(true)
+                                                                                  .help("Specify the AVR to send the command to, the first one if not given")))
+                          .subcommand(SubCommand::with_name("stats")
+                                                     .about("Print usage statistics of the web service running on the given port")
+                                                     .arg(Arg::with_name("user").short("u").long("user")
+                                                                                .takes_value(true)
+                                                                                .help("Specify the username and password, as USER:PASSWORD, if Basic auth is configured")))
+                          .subcommand(SubCommand::with_name("hash-password")
+                                                     .about("Print the hash of a password, for the Basic auth section of the config file")
+                                                     .arg(Arg::with_name("PASSWORD").required(true)
+                                                                                   .help("Specify the password to hash")))
                           .get_matches();
-    let avr_host = matches.value_of("HOST").unwrap();
-    let avr_port = matches.value_of("PORT").unwrap().parse::<u16>().unwrap();
-    let site_port = matches.value_of("port").unwrap();
+    let site_port = matches.value_of("port").unwrap().parse::<u16>().unwrap();
+    let site_ip = addr::parse_ip(matches.value_of("bind").unwrap())?;
+    let log_format = matches.value_of("log-format").unwrap();
+    let reconnect_min = matches
+        .value_of("reconnect-min")
+        .unwrap()
+        .parse::<u64>()
+        .unwrap();
+    let reconnect_max = matches
+        .value_of("reconnect-max")
+        .unwrap()
+        .parse::<u64>()
+        .unwrap();
+    let connect_timeout = matches
+        .value_of("connect-timeout")
+        .unwrap()
+        .parse::<u64>()
+        .unwrap();
+    let backoff = telnet::Backoff {
+        min: Duration::from_secs(reconnect_min),
+        max: Duration::from_secs(reconnect_max),
+        connect_timeout: Duration::from_secs(connect_timeout),
+    };
+    let avr_port = matches
+        .value_of("avr-port")
+        .unwrap()
+        .parse::<u16>()
+        .unwrap();
 
-    telnet::run(avr_host.to_owned(), avr_port)?;
-    site::run(site_port)?;
+    let dry_run = matches.is_present("dry-run");
+    let skip_verify = matches.is_present("insecure-skip-verify");
+    let recorder = matches
+        .value_of("record")
+        .map(Recorder::create)
+        .transpose()?;
 
-    Ok(())
-}
+    logging::init(log_format.into());
+
+    if matches.subcommand_matches("discover").is_some() {
+        for receiver in discovery::discover(DISCOVERY_WAIT).await? {
+            println!("{}\t{} ({})", receiver.host, receiver.name, receiver.model);
+        }
+        return Ok(());
+    }
+
+    if let Some(init) = matches.subcommand_matches("init") {
+        let path = init.value_of("FILE").unwrap();
+        config::init(path)?;
+        println!("Wrote example config file: {}", path);
+        return Ok(());
+    }
+
+    if let Some(setup) = matches.subcommand_matches("setup") {
+        let path = setup.value_of("FILE").unwrap();
+        return setup::run(path, avr_port, backoff, DISCOVERY_WAIT).await;
+    }
 
-/// Log any errors and causes
-pub fn log_error(e: &Error) {
-    error!("{}", e);
-    for cause in e.iter_causes() {
-        error!("Caused by: {}", cause);
+    if let Some(replay) = matches.subcommand_matches("replay") {
+        let exchanges = replay::load(replay.value_of("TRANSCRIPT").unwrap())?;
+        let port = replay.value_of("PORT").unwrap().parse::<u16>().unwrap();
+        replay::serve((Ipv6Addr::UNSPECIFIED, port).into(), exchanges).await?;
+        return future::pending().await;
+    }
+
+    if let Some(stats) = matches.subcommand_matches("stats") {
+        return stats::run(site_port, stats.value_of("user")).await;
+    }
+
+    if let Some(hash) = matches.subcommand_matches("hash-password") {
+        println!("{}", basic_auth::hash(hash.value_of("PASSWORD").unwrap()));
+        return Ok(());
+    }
+
+    let mut config = match matches.value_of("config") {
+        Some(path) => config::load(path)?,
+        None => Config::default(),
+    };
+    if let Some(send) = matches.subcommand_matches("send") {
+        let socket = match &config.socket {
+            Some(socket) => socket,
+            None => bail!("No control socket configured for the command to be sent on"),
+        };
+        let command = send.value_of("COMMAND").unwrap();
+        let command = serde_json::from_str(command)
+            .or_else(|_| serde_json::from_value(serde_json::Value::String(command.to_owned())))
+            .map_err(|e| format_err!("Command not valid: {}", e))?;
+        return socket::send(&socket.path, send.value_of("device"), command).await;
+    }
+    if let Some(host) = matches.value_of("HOST") {
+        let (host, host_port) = addr::split_host(host)?;
+        let port = matches
+            .value_of("PORT")
+            .map(|port| port.parse::<u16>().unwrap())
+            .or(host_port)
+            .unwrap_or(avr_port);
+        config
+            .devices
+            .insert(0, DeviceConfig::new("receiver", host, port));
+    }
+    if matches.subcommand_matches("export-model").is_some() {
+        println!("{}", serde_json::to_string_pretty(&model::export(&config))?);
+        return Ok(());
     }
+    if config.devices.is_empty() {
+        info!("No AVR configured, searching the network for one");
+        match discovery::discover(DISCOVERY_WAIT)
+            .await?
+            .into_iter()
+            .next()
+        {
+            Some(receiver) => config.devices.push(DeviceConfig::new(
+                "receiver",
+                &receiver.host.to_string(),
+                avr_port,
+            )),
+            None => bail!(
+                "No AVR configured or found, provide HOST and PORT or list them in the config file"
+            ),
+        }
+    }
+
+    if let Some(status) = matches.subcommand_matches("status") {
+        return status::run(config.devices, backoff, status.is_present("json")).await;
+    }
+
+    if dry_run {
+        info!("Dry run, commands won't be sent to the AVR");
+    }
+    if skip_verify {
+        if !site_ip.is_loopback() {
+            bail!("--insecure-skip-verify needs --bind to a loopback address, like 127.0.0.1");
+        }
+        warn!("INSECURE: Alexa requests aren't checked to be signed by Alexa");
+    }
+    let history = config
+        .history
+        .map(|history| History::open(&history.path, history.keep_days))
+        .transpose()?;
+    let quiet = config
+        .quiet_hours
+        .as_ref()
+        .map(QuietHours::new)
+        .transpose()?;
+    let audit = config
+        .audit
+        .map(|audit| Audit::open(&audit.path))
+        .transpose()?;
+    let devices = device::run(
+        config.devices,
+        backoff,
+        dry_run,
+        recorder,
+        history.clone(),
+        audit,
+        quiet,
+    )?;
+    webhook::run(config.webhooks, &devices)?;
+    scheduler::run(config.schedules, &devices)?;
+    if let Some(volume_memory) = config.volume_memory {
+        memory::run(volume_memory, &devices)?;
+    }
+    if let Some(timers) = config.timers {
+        scheduler::restore(timers, &devices)?;
+    }
+    if let Some(socket) = config.socket {
+        socket::run(socket, &devices)?;
+    }
+    if let Some(telegram) = config.telegram {
+        telegram::run(telegram, &devices)?;
+    }
+    if let Some(heartbeat) = config.heartbeat {
+        heartbeat::run(heartbeat, &devices, site_port)?;
+    }
+    if let Some(watchdog) = config.watchdog {
+        watchdog::run(watchdog, &devices)?;
+    }
+    if let Some(homekit) = config.homekit {
+        homekit::run(homekit, &devices).await?;
+    }
+    let smart_home = config
+        .smart_home
+        .map(|smart_home| smart_home::run(smart_home, &devices))
+        .transpose()?;
+    let google = config
+        .google
+        .map(|google| Arc::new(google::Google::new(google, &devices)));
+    let api_tls = config.api.as_ref().and_then(|api| api.tls.clone());
+    let api = config
+        .api
+        .map(|api| Api::new(api, &devices).map(Arc::new))
+        .transpose()?;
+    let basic_auth = config
+        .basic_auth
+        .map(|basic_auth| BasicAuth::new(basic_auth).map(Arc::new))
+        .transpose()?;
+    let linking = config
+        .account_linking
+        .map(|linking| AccountLinking::new(linking, &devices).map(Arc::new))
+        .transpose()?;
+    let access_log = config
+        .access_log
+        .map(|access_log| AccessLog::open(&access_log))
+        .transpose()?;
+    let services = site::Services {
+        devices,
+        speaker: speech::Speaker::new(&config.speech)?,
+        skill: Arc::new(config.skill),
+        smart_home,
+        google,
+        api,
+        basic_auth,
+        linking,
+        history,
+        access_log,
+        stats: Stats::new(),
+        verify: !skip_verify,
+    };
+    if let Some(tls) = api_tls {
+        let acceptor = tls::acceptor(&tls)?;
+        let (_, server) = site::bind_tls(
+            SocketAddr::new(site_ip, tls.port),
+            acceptor,
+            services.clone(),
+        )?;
+        tokio::spawn(async move {
+            if let Err(e) = server.await {
+                log_error(&e);
+            }
+        });
+    }
+    site::run(SocketAddr::new(site_ip, site_port), services).await?;
+
+    Ok(())
 }
@@ -0,0 +1,139 @@
+/// This module remembers the volume last used on each of an AVR's inputs, and
+/// can set it again when the input is changed back to, so game night can be
+/// loud and movies quiet without asking each time.
+///
+/// Volumes are kept on the same 1 to 10 scale as the Volume intent, by AVR
+/// name and input code, in a JSON file so they survive restarts:
+///
+/// ```json
+/// {"living room": {"25": 4, "49": 7}}
+/// ```
+///
+/// The volume is remembered whenever the AVR reports a change, whatever made
+/// it. Restoring is sent through the AVR's command queue like any other
+/// command, when the input changes from one to another, but not when the input
+/// is first reported after connecting.
+use crate::{
+    avr::AvrCommand,
+    config::VolumeMemoryConfig,
+    device::{Device, Devices},
+    events::AvrEvent,
+    queue::Source,
+};
+use failure::{Error, ResultExt};
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::broadcast;
+use tracing::{info, warn};
+
+/// Volume by input code, by AVR name
+type Volumes = BTreeMap<String, BTreeMap<String, u8>>;
+
+/// Remembered volumes and the file they're kept in. Cloned for each AVR.
+#[derive(Clone)]
+struct Memory {
+    path: String,
+    restore: bool,
+    volumes: Arc<Mutex<Volumes>>,
+}
+
+impl Memory {
+    /// Load the file at path, starting empty if it doesn't exist
+    fn load(config: VolumeMemoryConfig) -> Result<Memory, Error> {
+        let path = config.path;
+        let volumes = if Path::new(&path).exists() {
+            let contents = fs::read_to_string(&path)
+                .context(format!("Could not read volume memory: {}", path))?;
+            serde_json::from_str(&contents)
+                .context(format!("Could not parse volume memory: {}", path))?
+        } else {
+            Volumes::new()
+        };
+
+        Ok(Memory {
+            path,
+            restore: config.restore,
+            volumes: Arc::new(Mutex::new(volumes)),
+        })
+    }
+
+    /// Remember the volume used on the AVR's input, saving the file if it's
+    /// changed
+    fn remember(&self, device: &str, input: &str, volume: u8) -> Result<(), Error> {
+        let mut volumes = self.volumes.lock().unwrap();
+        let inputs = volumes.entry(device.to_owned()).or_default();
+        if inputs.insert(input.to_owned(), volume) == Some(volume) {
+            return Ok(());
+        }
+
+        let contents = serde_json::to_string_pretty(&*volumes)?;
+        fs::write(&self.path, contents)
+            .context(format!("Could not write volume memory: {}", self.path))?;
+        Ok(())
+    }
+
+    fn recall(&self, device: &str, input: &str) -> Option<u8> {
+        self.volumes
+            .lock()
+            .unwrap()
+            .get(device)
+            .and_then(|inputs| inputs.get(input))
+            .copied()
+    }
+}
+
+/// Load the remembered volumes and start a task for each AVR remembering, and
+/// if configured restoring, the volume of its inputs
+pub fn run(config: VolumeMemoryConfig, devices: &Devices) -> Result<(), Error> {
+    let memory = Memory::load(config)?;
+    for device in devices.iter() {
+        tokio::spawn(watch(device.clone(), memory.clone()));
+    }
+    Ok(())
+}
+
+/// Remember each volume the AVR reports for its current input, and restore the
+/// volume of each input it changes to
+async fn watch(device: Device, memory: Memory) {
+    let protocol = device.events.protocol().clone();
+    let mut events = device.events.subscribe();
+    let mut input = device.events.state().input;
+
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => return,
+        };
+
+        match event {
+            AvrEvent::Volume(level) => {
+                let volume = (protocol.volume_percent(level) + 5) / 10;
+                if let (Some(input), true) = (&input, volume > 0) {
+                    if let Err(e) = memory.remember(&device.name, input, volume) {
+                        warn!("{}", e);
+                    }
+                }
+            }
+            AvrEvent::Input(changed) => {
+                let previous = input.replace(changed.clone());
+                if !memory.restore || previous.is_none() || previous.as_ref() == Some(&changed) {
+                    continue;
+                }
+                if let Some(volume) = memory.recall(&device.name, &changed) {
+                    info!("Restoring volume {} for input {}", volume, changed);
+                    let cmd = AvrCommand::SetVolume(volume);
+                    if let Err(e) = device.queue.process(cmd, Source::Restore).await {
+                        warn!("Could not restore volume for input {}: {}", changed, e);
+                    }
+                }
+            }
+            AvrEvent::Connected(false) => input = None,
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,637 @@
+/// This module generates the skill's interaction model, for the
+/// `export-model` subcommand, so the model in the Alexa developer console can
+/// be kept in line with what this server handles.
+///
+/// The `DeviceName` slot type gets the names of the configured AVRs, and the
+/// intents only mention an AVR's name if any are configured. The `InputName`
+/// slot type gets the names of their inputs, and the `PresetName` slot type
+/// the names of their tuner presets, the Preset intent only being included if
+/// any are named. Likewise for the `SequenceName` slot type and the Sequence
+/// intent, with the names of their sequences, and the `CodeName` slot type
+/// and the SendCode intent, with the names of their raw codes. The
+/// `TransportKey` slot type names the keys of the transport controls, and the
+/// Mute intent takes an optional `AMAZON.DURATION` to mute for. The
+/// `TimerCommand` slot type names the commands the Timer intent can send, the
+/// `SpeakerSystem` slot type the speaker systems and the `VideoResolution`
+/// slot type the HDMI output resolutions. The Yes and No intents, used to
+/// answer confirmation prompts, are only included if the skill is configured
+/// to ask for confirmation or has the SpeakerSystem intent, which always asks,
+/// and intents the config turns off for the skill are left out.
+use crate::{
+    aliases::InputNames,
+    config::Config,
+    pioneer::Pioneer,
+    protocol::{self, AvrProtocol},
+};
+use serde_json::{json, Value};
+use std::collections::BTreeSet;
+
+/// Name the skill is invoked with
+const INVOCATION_NAME: &str = "avr";
+
+/// Interaction model for the config, as the JSON the developer console takes
+pub fn export(config: &Config) -> Value {
+    let named = !config.devices.is_empty();
+    let presets: BTreeSet<_> = config
+        .devices
+        .iter()
+        .flat_map(|device| device.presets.keys())
+        .collect();
+    let sequences: BTreeSet<_> = config
+        .devices
+        .iter()
+        .flat_map(|device| device.sequences.keys())
+        .collect();
+    let codes: BTreeSet<_> = config
+        .devices
+        .iter()
+        .flat_map(|device| device.raw_codes.keys())
+        .collect();
+    let confirm = config.skill.confirm_power_off
+        || config.skill.confirm_volume_jump.is_some()
+        || config.skill.intents.enabled("SpeakerSystem");
+
+    let mut intents = vec![
+        builtin("AMAZON.FallbackIntent", &[]),
+        builtin("AMAZON.CancelIntent", &["cancel"]),
+        builtin("AMAZON.HelpIntent", &["help"]),
+        builtin("AMAZON.StopIntent", &["stop"]),
+        builtin("AMAZON.NavigateHomeIntent", &["restart"]),
+    ];
+    if confirm {
+        intents.push(builtin("AMAZON.YesIntent", &[]));
+        intents.push(builtin("AMAZON.NoIntent", &[]));
+    }
+    let mut custom = vec![
+        intent(
+            "Volume",
+            vec![number_slot(
+                "Volume_slot",
+                &["{Volume_slot}", "to {Volume_slot}", "volume {Volume_slot}"],
+            )],
+            &[
+                "volume {Volume_slot}",
+                "set the volume",
+                "change the volume",
+            ],
+            &[
+                "volume {Volume_slot} on the {Device_slot} receiver",
+                "set the {Device_slot} receiver to volume {Volume_slot}",
+                "set the volume on the {Device_slot} receiver",
+            ],
+            named,
+        ),
+        intent(
+            "Input",
+            vec![
+                number_slot("Input_slot", &["{Input_slot}", "input {Input_slot}"]),
+                json!({ "name": "InputName_slot", "type": "InputName" }),
+            ],
+            &[
+                "input {Input_slot}",
+                "change the input",
+                "switch to {InputName_slot}",
+                "change to {InputName_slot}",
+                "change the input to {InputName_slot}",
+            ],
+            &[
+                "input {Input_slot} on the {Device_slot} receiver",
+                "change the input on the {Device_slot} receiver",
+                "switch the {Device_slot} receiver to {InputName_slot}",
+            ],
+            named,
+        ),
+        intent(
+            "Activity",
+            vec![
+                json!({ "name": "Input_slot", "type": "AMAZON.NUMBER" }),
+                json!({ "name": "InputName_slot", "type": "InputName" }),
+                json!({ "name": "Volume_slot", "type": "AMAZON.NUMBER" }),
+            ],
+            &[
+                "turn on and switch to {InputName_slot}",
+                "turn on and switch to {InputName_slot} at volume {Volume_slot}",
+                "turn on and change to input {Input_slot}",
+                "turn on and change to input {Input_slot} at volume {Volume_slot}",
+                "turn on at volume {Volume_slot}",
+                "switch to {InputName_slot} at volume {Volume_slot}",
+            ],
+            &[
+                "turn on the {Device_slot} receiver and switch to {InputName_slot}",
+                "turn on the {Device_slot} receiver and switch to {InputName_slot} at volume {Volume_slot}",
+            ],
+            named,
+        ),
+        intent(
+            "SwitchBack",
+            vec![],
+            &["switch back", "go back", "previous input", "last input"],
+            &[
+                "switch back on the {Device_slot} receiver",
+                "switch the {Device_slot} receiver back",
+            ],
+            named,
+        ),
+        intent(
+            "Timer",
+            vec![
+                json!({ "name": "Timer_slot", "type": "AMAZON.DURATION" }),
+                json!({ "name": "TimerCommand_slot", "type": "TimerCommand" }),
+            ],
+            &[
+                "{TimerCommand_slot} in {Timer_slot}",
+                "{TimerCommand_slot} the receiver in {Timer_slot}",
+                "in {Timer_slot} {TimerCommand_slot}",
+            ],
+            &["{TimerCommand_slot} the {Device_slot} receiver in {Timer_slot}"],
+            named,
+        ),
+        intent(
+            "CancelTimer",
+            vec![],
+            &["cancel the timer", "stop the timer", "never mind the timer"],
+            &["cancel the timer on the {Device_slot} receiver"],
+            named,
+        ),
+        intent(
+            "RestoreVolume",
+            vec![],
+            &[
+                "put the volume back",
+                "restore the volume",
+                "volume back to normal",
+            ],
+            &["put the volume back on the {Device_slot} receiver"],
+            named,
+        ),
+        intent(
+            "Undo",
+            vec![],
+            &["undo that", "undo", "take that back", "undo the last command"],
+            &["undo that on the {Device_slot} receiver"],
+            named,
+        ),
+        intent(
+            "Mute",
+            vec![json!({ "name": "Mute_slot", "type": "AMAZON.DURATION" })],
+            &["mute", "mute for {Mute_slot}", "mute it for {Mute_slot}"],
+            &[
+                "mute the {Device_slot} receiver",
+                "mute the {Device_slot} receiver for {Mute_slot}",
+            ],
+            named,
+        ),
+        intent(
+            "Unmute",
+            vec![],
+            &["unmute"],
+            &["unmute the {Device_slot} receiver"],
+            named,
+        ),
+        intent(
+            "On",
+            vec![],
+            &["power on", "on"],
+            &[
+                "turn on the {Device_slot} receiver",
+                "power on the {Device_slot} receiver",
+            ],
+            named,
+        ),
+        intent(
+            "Off",
+            vec![],
+            &["power off", "off"],
+            &[
+                "turn off the {Device_slot} receiver",
+                "power off the {Device_slot} receiver",
+            ],
+            named,
+        ),
+    ];
+    custom.push(intent(
+        "ListInputs",
+        vec![],
+        &[
+            "list inputs",
+            "what inputs are there",
+            "which inputs are there",
+        ],
+        &["list the inputs of the {Device_slot} receiver"],
+        named,
+    ));
+    custom.push(intent(
+        "Identify",
+        vec![],
+        &[
+            "what receiver is this",
+            "which receiver is this",
+            "what model is this",
+            "what firmware is it on",
+        ],
+        &["what receiver is the {Device_slot} receiver"],
+        named,
+    ));
+    custom.push(intent(
+        "KeepListening",
+        vec![],
+        &["keep listening", "stay open"],
+        &[],
+        named,
+    ));
+    custom.push(intent(
+        "AutoMode",
+        vec![],
+        &["auto mode", "switch to auto mode", "auto surround"],
+        &["switch the {Device_slot} receiver to auto mode"],
+        named,
+    ));
+    custom.push(intent(
+        "DirectMode",
+        vec![],
+        &["direct mode", "switch to direct mode"],
+        &["switch the {Device_slot} receiver to direct mode"],
+        named,
+    ));
+    custom.push(intent(
+        "ExtendedStereo",
+        vec![],
+        &["extended stereo", "switch to extended stereo"],
+        &["switch the {Device_slot} receiver to extended stereo"],
+        named,
+    ));
+    custom.push(intent(
+        "SpeakerSystem",
+        vec![json!({ "name": "SpeakerSystem_slot", "type": "SpeakerSystem" })],
+        &[
+            "set the speaker system to {SpeakerSystem_slot}",
+            "switch the speakers to {SpeakerSystem_slot}",
+            "assign the speakers to {SpeakerSystem_slot}",
+        ],
+        &["set the speaker system on the {Device_slot} receiver to {SpeakerSystem_slot}"],
+        named,
+    ));
+    custom.push(intent(
+        "SubwooferUp",
+        vec![],
+        &["subwoofer up", "turn up the subwoofer", "more bass"],
+        &["turn up the subwoofer on the {Device_slot} receiver"],
+        named,
+    ));
+    custom.push(intent(
+        "SubwooferDown",
+        vec![],
+        &["subwoofer down", "turn down the subwoofer", "less bass"],
+        &["turn down the subwoofer on the {Device_slot} receiver"],
+        named,
+    ));
+    custom.push(intent(
+        "LfeAttenuation",
+        vec![json!({ "name": "LfeAttenuation_slot", "type": "AMAZON.NUMBER" })],
+        &[
+            "set the LFE attenuation to {LfeAttenuation_slot} decibels",
+            "attenuate the LFE by {LfeAttenuation_slot} decibels",
+        ],
+        &["set the LFE attenuation on the {Device_slot} receiver to {LfeAttenuation_slot} decibels"],
+        named,
+    ));
+    custom.push(intent(
+        "VideoConverterOn",
+        vec![],
+        &["turn on the video converter", "video converter on"],
+        &["turn on the video converter on the {Device_slot} receiver"],
+        named,
+    ));
+    custom.push(intent(
+        "VideoConverterOff",
+        vec![],
+        &["turn off the video converter", "video converter off"],
+        &["turn off the video converter on the {Device_slot} receiver"],
+        named,
+    ));
+    custom.push(intent(
+        "VideoResolution",
+        vec![json!({ "name": "VideoResolution_slot", "type": "VideoResolution" })],
+        &[
+            "set the video resolution to {VideoResolution_slot}",
+            "output video at {VideoResolution_slot}",
+        ],
+        &["set the video resolution on the {Device_slot} receiver to {VideoResolution_slot}"],
+        named,
+    ));
+    custom.push(intent(
+        "NetworkStandbyOn",
+        vec![],
+        &["turn on network standby", "network standby on"],
+        &["turn on network standby on the {Device_slot} receiver"],
+        named,
+    ));
+    custom.push(intent(
+        "SoundRetrieverOn",
+        vec![],
+        &["turn on sound retriever", "sound retriever on"],
+        &["turn on sound retriever on the {Device_slot} receiver"],
+        named,
+    ));
+    custom.push(intent(
+        "SoundRetrieverOff",
+        vec![],
+        &["turn off sound retriever", "sound retriever off"],
+        &["turn off sound retriever on the {Device_slot} receiver"],
+        named,
+    ));
+    custom.push(intent(
+        "DialogEnhancementOn",
+        vec![],
+        &["turn on dialog enhancement", "dialog enhancement on"],
+        &["turn on dialog enhancement on the {Device_slot} receiver"],
+        named,
+    ));
+    custom.push(intent(
+        "DialogEnhancementOff",
+        vec![],
+        &["turn off dialog enhancement", "dialog enhancement off"],
+        &["turn off dialog enhancement on the {Device_slot} receiver"],
+        named,
+    ));
+    custom.push(intent(
+        "Pairing",
+        vec![],
+        &[
+            "bluetooth pairing",
+            "start bluetooth pairing",
+            "pairing mode",
+            "go into pairing mode",
+        ],
+        &[
+            "put the {Device_slot} receiver in pairing mode",
+            "put the {Device_slot} receiver in bluetooth pairing mode",
+        ],
+        named,
+    ));
+    custom.push(intent(
+        "Transport",
+        vec![json!({ "name": "Transport_slot", "type": "TransportKey" })],
+        &[
+            "{Transport_slot} the music",
+            "{Transport_slot} the song",
+            "{Transport_slot} track",
+        ],
+        &["{Transport_slot} the music on the {Device_slot} receiver"],
+        named,
+    ));
+    if !presets.is_empty() {
+        custom.push(intent(
+            "Preset",
+            vec![json!({ "name": "Preset_slot", "type": "PresetName" })],
+            &[
+                "play {Preset_slot} on the radio",
+                "put on {Preset_slot}",
+                "tune to {Preset_slot}",
+            ],
+            &[
+                "play {Preset_slot} on the {Device_slot} receiver",
+                "tune the {Device_slot} receiver to {Preset_slot}",
+            ],
+            named,
+        ));
+    }
+    if !sequences.is_empty() {
+        custom.push(intent(
+            "Sequence",
+            vec![json!({ "name": "Sequence_slot", "type": "SequenceName" })],
+            &[
+                "play {Sequence_slot}",
+                "start {Sequence_slot}",
+                "run {Sequence_slot}",
+            ],
+            &[
+                "run {Sequence_slot} on the {Device_slot} receiver",
+                "start {Sequence_slot} on the {Device_slot} receiver",
+            ],
+            named,
+        ));
+    }
+    if !codes.is_empty() {
+        custom.push(intent(
+            "SendCode",
+            vec![json!({ "name": "SendCode_slot", "type": "CodeName" })],
+            &["send {SendCode_slot}", "send the {SendCode_slot} code"],
+            &["send {SendCode_slot} to the {Device_slot} receiver"],
+            named,
+        ));
+    }
+    intents.extend(custom.into_iter().filter(|intent| {
+        config
+            .skill
+            .intents
+            .enabled(intent["name"].as_str().unwrap_or_default())
+    }));
+
+    let mut types = vec![
+        json!({ "name": "InputName", "values": input_names(config) }),
+        json!({ "name": "TransportKey", "values": transport_keys() }),
+        json!({ "name": "SpeakerSystem", "values": speaker_systems() }),
+        json!({ "name": "VideoResolution", "values": video_resolutions() }),
+        json!({ "name": "TimerCommand", "values": timer_commands() }),
+    ];
+    if !presets.is_empty() {
+        let names: Vec<_> = presets
+            .iter()
+            .map(|name| json!({ "name": { "value": name } }))
+            .collect();
+        types.push(json!({ "name": "PresetName", "values": names }));
+    }
+    if !sequences.is_empty() {
+        let names: Vec<_> = sequences
+            .iter()
+            .map(|name| json!({ "name": { "value": name } }))
+            .collect();
+        types.push(json!({ "name": "SequenceName", "values": names }));
+    }
+    if !codes.is_empty() {
+        let names: Vec<_> = codes
+            .iter()
+            .map(|name| json!({ "name": { "value": name } }))
+            .collect();
+        types.push(json!({ "name": "CodeName", "values": names }));
+    }
+    if named {
+        let names: Vec<_> = config
+            .devices
+            .iter()
+            .map(|device| json!({ "name": { "value": device.name } }))
+            .collect();
+        types.push(json!({ "name": "DeviceName", "values": names }));
+    }
+
+    // Volume and Input elicit their number when it's missing, Preset,
+    // Sequence and SendCode their name, Timer its duration and command,
+    // Transport its key, SpeakerSystem the system, LfeAttenuation its dB and
+    // VideoResolution the resolution
+    let dialog: Vec<_> = [
+        ("Volume", "Volume_slot", "AMAZON.NUMBER"),
+        ("Input", "Input_slot", "AMAZON.NUMBER"),
+        ("Preset", "Preset_slot", "PresetName"),
+        ("Sequence", "Sequence_slot", "SequenceName"),
+        ("Timer", "Timer_slot", "AMAZON.DURATION"),
+        ("SendCode", "SendCode_slot", "CodeName"),
+        ("Transport", "Transport_slot", "TransportKey"),
+        ("SpeakerSystem", "SpeakerSystem_slot", "SpeakerSystem"),
+        ("LfeAttenuation", "LfeAttenuation_slot", "AMAZON.NUMBER"),
+        ("VideoResolution", "VideoResolution_slot", "VideoResolution"),
+    ]
+    .iter()
+    .filter(|(name, _, _)| config.skill.intents.enabled(name))
+    .filter(|(name, _, _)| *name != "Preset" || !presets.is_empty())
+    .filter(|(name, _, _)| *name != "Sequence" || !sequences.is_empty())
+    .filter(|(name, _, _)| *name != "SendCode" || !codes.is_empty())
+    .map(|(name, slot, slot_type)| {
+        let mut slots = vec![dialog_slot(slot, slot_type)];
+        if *name == "Input" {
+            slots.push(dialog_slot("InputName_slot", "InputName"));
+        }
+        if *name == "Timer" {
+            slots.push(dialog_slot("TimerCommand_slot", "TimerCommand"));
+        }
+        if named {
+            slots.push(dialog_slot("Device_slot", "DeviceName"));
+        }
+        json!({
+            "name": name,
+            "confirmationRequired": false,
+            "prompts": {},
+            "slots": slots,
+        })
+    })
+    .collect();
+
+    json!({
+        "interactionModel": {
+            "languageModel": {
+                "invocationName": INVOCATION_NAME,
+                "intents": intents,
+                "types": types,
+            },
+            "dialog": {
+                "intents": dialog,
+                "delegationStrategy": "SKILL_RESPONSE",
+            },
+        }
+    })
+}
+
+/// Keys of the transport controls, with the other ways of saying them the
+/// skill understands
+fn transport_keys() -> Vec<Value> {
+    [
+        ("play", &["resume", "unpause", "continue"][..]),
+        ("pause", &[]),
+        ("stop", &[]),
+        ("next", &["skip"]),
+        ("previous", &["back"]),
+    ]
+    .iter()
+    .map(|(value, synonyms)| json!({ "name": { "value": value, "synonyms": synonyms } }))
+    .collect()
+}
+
+/// Speaker systems of the SpeakerSystem intent, as `crate::skill` takes them
+fn speaker_systems() -> Vec<Value> {
+    [
+        ("surround back", &["normal"][..]),
+        ("speaker b", &["b speakers"]),
+        ("bi-amp", &["bi amp", "front bi-amp"]),
+        ("zone 2", &["zone two"]),
+    ]
+    .iter()
+    .map(|(value, synonyms)| json!({ "name": { "value": value, "synonyms": synonyms } }))
+    .collect()
+}
+
+/// Resolutions of the VideoResolution intent, as `crate::skill` takes them
+fn video_resolutions() -> Vec<Value> {
+    [
+        ("auto", &["automatic"][..]),
+        ("pure", &["pass through", "source"]),
+        ("480p", &["480"]),
+        ("720p", &["720"]),
+        ("1080i", &[]),
+        ("1080p", &["1080", "full hd"]),
+        ("1080p24", &["1080p 24", "24p"]),
+    ]
+    .iter()
+    .map(|(value, synonyms)| json!({ "name": { "value": value, "synonyms": synonyms } }))
+    .collect()
+}
+
+/// Commands the Timer intent can send, as `crate::skill` takes them
+fn timer_commands() -> Vec<Value> {
+    [
+        ("turn off", &["off", "power off", "turn it off"][..]),
+        ("turn on", &["on", "power on", "turn it on"]),
+        ("mute", &[]),
+        ("unmute", &[]),
+        ("volume up", &["louder"]),
+        ("volume down", &["quieter"]),
+    ]
+    .iter()
+    .map(|(value, synonyms)| json!({ "name": { "value": value, "synonyms": synonyms } }))
+    .collect()
+}
+
+/// Names of the configured AVRs' inputs, or the Pioneer inputs if none are
+/// configured. Aliases are left out, as they're matched by this server, as are
+/// hidden inputs.
+fn input_names(config: &Config) -> Vec<Value> {
+    let mut names = BTreeSet::new();
+    if config.devices.is_empty() {
+        names.extend(Pioneer.inputs());
+    }
+    for device in &config.devices {
+        let inputs = protocol::from_config(device)
+            .and_then(|protocol| InputNames::new(&*protocol, &device.aliases, &device.hidden));
+        if let Ok(inputs) = inputs {
+            names.extend(inputs.visible().iter().map(|(_, name)| name.clone()));
+        }
+    }
+    names
+        .iter()
+        .map(|name| json!({ "name": { "value": name } }))
+        .collect()
+}
+
+fn builtin(name: &str, samples: &[&str]) -> Value {
+    json!({ "name": name, "samples": samples })
+}
+
+/// Custom intent, with `slots` and, if there are named AVRs, the slot naming
+/// the AVR and the samples using it
+fn intent(
+    name: &str,
+    mut slots: Vec<Value>,
+    samples: &[&str],
+    device_samples: &[&str],
+    named: bool,
+) -> Value {
+    let mut samples = samples.to_vec();
+    if named {
+        slots.push(json!({ "name": "Device_slot", "type": "DeviceName" }));
+        samples.extend_from_slice(device_samples);
+    }
+    json!({ "name": name, "slots": slots, "samples": samples })
+}
+
+fn number_slot(name: &str, samples: &[&str]) -> Value {
+    json!({ "name": name, "type": "AMAZON.NUMBER", "samples": samples })
+}
+
+fn dialog_slot(name: &str, slot_type: &str) -> Value {
+    json!({
+        "name": name,
+        "type": slot_type,
+        "elicitationRequired": false,
+        "confirmationRequired": false,
+        "prompts": {},
+    })
+}
@@ -0,0 +1,245 @@
+/// This module evens out loudness differences between an AVR's inputs, like a
+/// CD player 6 dB hotter than everything else.
+///
+/// Each input can be given an offset in dB, how much louder it is than the
+/// others. `Offsets` wraps the AVR's protocol, keeping track of the current
+/// input from the AVR's messages, and shifts the volume levels it reports by
+/// the current input's offset. Everything else only ever sees the shifted
+/// levels, so `SetVolume` steps the AVR to that much below the level it would
+/// otherwise set, and the volume reported to the skill, HomeKit and the rest is
+/// the same for every input at the same loudness.
+use crate::{
+    avr::{AvrCommand, AvrQuery, Key},
+    config::DeviceConfig,
+    events::AvrEvent,
+    protocol::{AvrProtocol, Message},
+};
+use failure::{bail, Error};
+use std::{
+    collections::BTreeMap,
+    convert::TryFrom,
+    sync::{Arc, Mutex},
+};
+use tracing::debug;
+
+/// The AVR's protocol, with volume levels shifted by the current input's
+/// offset
+pub struct Offsets {
+    inner: Arc<dyn AvrProtocol>,
+    /// Offset in volume levels, by input code
+    levels: BTreeMap<String, i16>,
+    input: Mutex<Option<String>>,
+}
+
+impl Offsets {
+    /// Wrap the protocol with the AVR's configured offsets, keyed by input
+    /// name or number, if it has any
+    pub fn wrap(
+        inner: Arc<dyn AvrProtocol>,
+        config: &DeviceConfig,
+    ) -> Result<Arc<dyn AvrProtocol>, Error> {
+        if config.offsets.is_empty() {
+            return Ok(inner);
+        }
+
+        let mut levels = BTreeMap::new();
+        for (input, db) in &config.offsets {
+            let n = inner.find_input(input);
+            let code = match n.and_then(|n| inner.input_code(n)) {
+                Some(code) => code,
+                None => bail!("No input named {:?} for volume offset", input),
+            };
+            let offset = (db / inner.level_db()).round() as i16;
+            debug!("Volume offset of {} levels for input {}", offset, code);
+            levels.insert(code, offset);
+        }
+
+        Ok(Arc::new(Offsets {
+            inner,
+            levels,
+            input: Mutex::new(None),
+        }))
+    }
+
+    /// Offset of the current input, in volume levels
+    fn offset(&self) -> i16 {
+        let input = self.input.lock().unwrap();
+        input
+            .as_ref()
+            .and_then(|input| self.levels.get(input))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Keep track of the input, and shift the volume level the AVR reported
+    fn adjust(&self, event: AvrEvent) -> AvrEvent {
+        match event {
+            AvrEvent::Input(input) => {
+                *self.input.lock().unwrap() = Some(input.clone());
+                AvrEvent::Input(input)
+            }
+            AvrEvent::Volume(level) => {
+                let shifted = i16::from(level) + self.offset();
+                AvrEvent::Volume(shifted.clamp(0, i16::from(u8::MAX)) as u8)
+            }
+            event => event,
+        }
+    }
+}
+
+impl AvrProtocol for Offsets {
+    fn supports(&self, cmd: &AvrCommand) -> bool {
+        self.inner.supports(cmd)
+    }
+
+    fn code(&self, cmd: &AvrCommand) -> String {
+        self.inner.code(cmd)
+    }
+
+    fn query_code(&self, query: &AvrQuery) -> String {
+        self.inner.query_code(query)
+    }
+
+    fn expected(&self, cmd: &AvrCommand) -> String {
+        self.inner.expected(cmd)
+    }
+
+    /// The volume is confirmed with the shifted level the AVR reports
+    fn matches(&self, cmd: &AvrCommand, response: &str) -> bool {
+        match cmd {
+            AvrCommand::SetVolume(n) => self
+                .parse_events(response)
+                .contains(&AvrEvent::Volume(self.volume_level(*n))),
+            _ => self.inner.matches(cmd, response),
+        }
+    }
+
+    fn volume_level(&self, n: u8) -> u8 {
+        self.inner.volume_level(n)
+    }
+
+    fn volume_step(&self) -> u8 {
+        self.inner.volume_step()
+    }
+
+    fn level_db(&self) -> f32 {
+        self.inner.level_db()
+    }
+
+    fn volume_percent(&self, level: u8) -> u8 {
+        self.inner.volume_percent(level)
+    }
+
+    /// dB of the level the AVR is actually at, before the shift
+    fn volume_db(&self, level: u8) -> Option<f32> {
+        let actual = i16::from(level) - self.offset();
+        self.inner
+            .volume_db(actual.clamp(0, i16::from(u8::MAX)) as u8)
+    }
+
+    /// Shifted like the levels the AVR reports
+    fn db_level(&self, db: f32) -> Option<u8> {
+        let shifted = i16::from(self.inner.db_level(db)?) + self.offset();
+        u8::try_from(shifted).ok()
+    }
+
+    /// The level the AVR is actually set to, before the shift
+    fn volume_code(&self, level: u8) -> Option<String> {
+        let actual = i16::from(level) - self.offset();
+        self.inner
+            .volume_code(actual.clamp(0, i16::from(u8::MAX)) as u8)
+    }
+
+    fn zone2_volume_code(&self, db: f32) -> Option<String> {
+        self.inner.zone2_volume_code(db)
+    }
+
+    fn inputs(&self) -> Vec<String> {
+        self.inner.inputs()
+    }
+
+    fn input_number(&self, input: &str) -> Option<u8> {
+        self.inner.input_number(input)
+    }
+
+    fn input_code(&self, n: u8) -> Option<String> {
+        self.inner.input_code(n)
+    }
+
+    fn input_name(&self, input: &str) -> Option<String> {
+        self.inner.input_name(input)
+    }
+
+    fn tuner_input(&self) -> Option<String> {
+        self.inner.tuner_input()
+    }
+
+    fn preset_code(&self, preset: &str) -> Option<String> {
+        self.inner.preset_code(preset)
+    }
+
+    fn listening_mode_code(&self, mode: &str) -> Option<String> {
+        self.inner.listening_mode_code(mode)
+    }
+
+    fn listening_mode_named(&self, name: &str) -> Option<String> {
+        self.inner.listening_mode_named(name)
+    }
+
+    fn speaker_system_code(&self, system: &str) -> Option<String> {
+        self.inner.speaker_system_code(system)
+    }
+
+    fn speaker_system_named(&self, name: &str) -> Option<String> {
+        self.inner.speaker_system_named(name)
+    }
+
+    fn video_resolution_code(&self, resolution: &str) -> Option<String> {
+        self.inner.video_resolution_code(resolution)
+    }
+
+    fn video_resolution_named(&self, name: &str) -> Option<String> {
+        self.inner.video_resolution_named(name)
+    }
+
+    fn subwoofer_level_code(&self, level: u8) -> Option<String> {
+        self.inner.subwoofer_level_code(level)
+    }
+
+    fn subwoofer_step(&self) -> u8 {
+        self.inner.subwoofer_step()
+    }
+
+    fn lfe_attenuation_code(&self, db: u8) -> Option<String> {
+        self.inner.lfe_attenuation_code(db)
+    }
+
+    fn transport(&self, input: &str, key: Key) -> Option<AvrCommand> {
+        self.inner.transport(input, key)
+    }
+
+    fn headphones_code(&self) -> Option<String> {
+        self.inner.headphones_code()
+    }
+
+    fn audio_status_code(&self) -> Option<String> {
+        self.inner.audio_status_code()
+    }
+
+    fn signal_present(&self, response: &str) -> Option<bool> {
+        self.inner.signal_present(response)
+    }
+
+    fn parse_event(&self, message: &str) -> Option<AvrEvent> {
+        self.inner
+            .parse_event(message)
+            .map(|event| self.adjust(event))
+    }
+
+    fn classify(&self, message: &str) -> Message {
+        match self.inner.classify(message) {
+            Message::Event(event) => Message::Event(self.adjust(event)),
+            message => message,
+        }
+    }
+}
@@ -0,0 +1,388 @@
+/// This module generates the OpenAPI 3 document describing the local HTTP
+/// API, served at `/api/openapi.json`, so Home Assistant and other
+/// integrations can generate a client for it.
+///
+/// Only the routes that are enabled are described, the same way
+/// `crate::site` routes requests: `/api/command` and `/api/raw` with the
+/// control API configured, `/api/history` with a history kept, and `/stats`
+/// and `/health` always. The
+/// Alexa, Smart Home and Google routes aren't included, as they're called by
+/// the assistants with their own request formats.
+use crate::{
+    avr::{AvrCommand, Key},
+    site::Services,
+};
+use serde_json::{json, Map, Value};
+
+/// The OpenAPI document for the routes enabled in `services`
+pub fn spec(services: &Services) -> Value {
+    let mut paths = Map::new();
+    if services.api.is_some() {
+        paths.insert("/api/command".to_owned(), command());
+        paths.insert("/api/raw".to_owned(), raw());
+    }
+    if services.history.is_some() {
+        paths.insert("/api/history".to_owned(), history());
+    }
+    paths.insert("/stats".to_owned(), stats());
+    paths.insert("/health".to_owned(), health());
+    paths.insert("/api/openapi.json".to_owned(), openapi());
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "alexa-avr-control",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "paths": paths,
+        "components": {
+            "schemas": schemas(),
+            "securitySchemes": {
+                "bearer": { "type": "http", "scheme": "bearer" },
+            },
+        },
+    })
+}
+
+/// Response with the `Error` schema
+fn error(description: &str) -> Value {
+    json!({
+        "description": description,
+        "content": {
+            "application/json": { "schema": { "$ref": "#/components/schemas/Error" } },
+        },
+    })
+}
+
+fn command() -> Value {
+    json!({
+        "post": {
+            "operationId": "sendCommand",
+            "summary": "Send a command to an AVR through its command queue",
+            "security": [{ "bearer": [] }],
+            "requestBody": {
+                "required": true,
+                "content": {
+                    "application/json": {
+                        "schema": { "$ref": "#/components/schemas/CommandRequest" },
+                    },
+                },
+            },
+            "responses": {
+                "204": { "description": "The AVR confirmed the command" },
+                "400": error("bad_request"),
+                "401": error("unauthorized"),
+                "404": error("unknown_device"),
+                "409": error("power_off or power_on"),
+                "422": error("unsupported"),
+                "500": error("internal"),
+                "502": error("validation_failed"),
+                "503": error("busy"),
+                "504": error("timeout or network_standby"),
+            },
+        },
+    })
+}
+
+fn raw() -> Value {
+    json!({
+        "post": {
+            "operationId": "sendRawCode",
+            "summary": "Send one of the raw codes configured for an AVR, as it is",
+            "security": [{ "bearer": [] }],
+            "requestBody": {
+                "required": true,
+                "content": {
+                    "application/json": {
+                        "schema": { "$ref": "#/components/schemas/RawRequest" },
+                    },
+                },
+            },
+            "responses": {
+                "200": {
+                    "description": "What the AVR answered, each message on its own line",
+                    "content": {
+                        "application/json": {
+                            "schema": { "$ref": "#/components/schemas/RawResponse" },
+                        },
+                    },
+                },
+                "400": error("bad_request"),
+                "401": error("unauthorized"),
+                "403": error("not_allowed"),
+                "404": error("unknown_device"),
+                "500": error("internal"),
+                "504": error("timeout"),
+            },
+        },
+    })
+}
+
+fn history() -> Value {
+    json!({
+        "get": {
+            "operationId": "getHistory",
+            "summary": "Latest commands processed, newest first",
+            "parameters": [{
+                "name": "limit",
+                "in": "query",
+                "schema": { "type": "integer", "minimum": 0, "default": 50 },
+            }],
+            "responses": {
+                "200": {
+                    "description": "History entries",
+                    "content": {
+                        "application/json": {
+                            "schema": {
+                                "type": "array",
+                                "items": { "$ref": "#/components/schemas/HistoryEntry" },
+                            },
+                        },
+                    },
+                },
+                "500": error("internal"),
+            },
+        },
+    })
+}
+
+fn stats() -> Value {
+    json!({
+        "get": {
+            "operationId": "getStats",
+            "summary": "Usage statistics since the web service started",
+            "responses": {
+                "200": {
+                    "description": "Usage statistics",
+                    "content": {
+                        "application/json": {
+                            "schema": { "$ref": "#/components/schemas/Stats" },
+                        },
+                    },
+                },
+            },
+        },
+    })
+}
+
+fn health() -> Value {
+    let content = json!({
+        "application/json": {
+            "schema": { "$ref": "#/components/schemas/Health" },
+        },
+    });
+    json!({
+        "get": {
+            "operationId": "getHealth",
+            "summary": "Whether every AVR is connected, and what each says it is",
+            "responses": {
+                "200": { "description": "Every AVR is connected", "content": content },
+                "503": { "description": "An AVR isn't connected", "content": content },
+            },
+        },
+    })
+}
+
+fn openapi() -> Value {
+    json!({
+        "get": {
+            "operationId": "getOpenApi",
+            "summary": "This document",
+            "responses": {
+                "200": {
+                    "description": "OpenAPI document",
+                    "content": { "application/json": { "schema": { "type": "object" } } },
+                },
+            },
+        },
+    })
+}
+
+/// Schema of `AvrCommand` as serialized, a string for commands without a
+/// value, otherwise an object with the value keyed by the command
+fn avr_command() -> Value {
+    let unit: Vec<_> = [
+        AvrCommand::PowerOn,
+        AvrCommand::PowerOff,
+        AvrCommand::Mute,
+        AvrCommand::Unmute,
+        AvrCommand::VolumeUp,
+        AvrCommand::VolumeDown,
+        AvrCommand::BluetoothPairing,
+        AvrCommand::SoundRetrieverOn,
+        AvrCommand::SoundRetrieverOff,
+        AvrCommand::DialogEnhancementOn,
+        AvrCommand::DialogEnhancementOff,
+        AvrCommand::SubwooferUp,
+        AvrCommand::SubwooferDown,
+        AvrCommand::VideoConverterOn,
+        AvrCommand::VideoConverterOff,
+        AvrCommand::NetworkStandbyOn,
+    ]
+    .iter()
+    .map(|cmd| serde_json::to_value(cmd).unwrap_or_default())
+    .collect();
+    let keys: Vec<_> = [
+        Key::Up,
+        Key::Down,
+        Key::Left,
+        Key::Right,
+        Key::Enter,
+        Key::Return,
+        Key::Play,
+        Key::Pause,
+        Key::Stop,
+        Key::Previous,
+        Key::Next,
+    ]
+    .iter()
+    .map(|key| serde_json::to_value(key).unwrap_or_default())
+    .collect();
+    let with_value = |name: &str, value: Value| {
+        json!({
+            "type": "object",
+            "required": [name],
+            "properties": { name: value },
+            "additionalProperties": false,
+        })
+    };
+
+    json!({
+        "oneOf": [
+            { "type": "string", "enum": unit },
+            with_value("SetVolume", json!({ "type": "integer", "minimum": 1, "maximum": 10 })),
+            with_value("SetVolumeDb", json!({ "type": "number", "example": -30.5 })),
+            with_value("ChangeInput", json!({ "type": "integer", "minimum": 1 })),
+            with_value("SelectPreset", json!({ "type": "string", "example": "A2" })),
+            with_value("SetListeningMode", json!({ "type": "string", "example": "0112" })),
+            with_value("SetSpeakerSystem", json!({ "type": "string", "example": "04" })),
+            with_value("SetLfeAttenuation", json!({ "type": "integer", "example": 10 })),
+            with_value("SetVideoResolution", json!({ "type": "string", "example": "06" })),
+            with_value("Gallery", json!({ "type": "string", "enum": keys })),
+            with_value("Ipod", json!({ "type": "string", "enum": keys })),
+            with_value("Adapter", json!({ "type": "string", "enum": keys })),
+        ],
+    })
+}
+
+fn schemas() -> Value {
+    let count = json!({
+        "type": "object",
+        "properties": {
+            "requests": { "type": "integer" },
+            "succeeded": { "type": "integer" },
+            "failed": { "type": "integer" },
+        },
+    });
+
+    json!({
+        "AvrCommand": avr_command(),
+        "CommandRequest": {
+            "type": "object",
+            "required": ["command"],
+            "properties": {
+                "device": {
+                    "type": "string",
+                    "description": "Name of the AVR, the first one if not given",
+                },
+                "command": { "$ref": "#/components/schemas/AvrCommand" },
+            },
+        },
+        "RawRequest": {
+            "type": "object",
+            "required": ["code"],
+            "properties": {
+                "device": {
+                    "type": "string",
+                    "description": "Name of the AVR, the first one if not given",
+                },
+                "code": { "type": "string", "example": "?SPK" },
+            },
+        },
+        "RawResponse": {
+            "type": "object",
+            "properties": {
+                "response": { "type": "string" },
+            },
+        },
+        "Error": {
+            "type": "object",
+            "required": ["error"],
+            "properties": {
+                "error": {
+                    "type": "object",
+                    "required": ["code", "message"],
+                    "properties": {
+                        "code": {
+                            "type": "string",
+                            "enum": [
+                                "bad_request", "unauthorized", "unknown_device", "power_off",
+                                "power_on", "unsupported", "busy", "timeout", "validation_failed",
+                                "network_standby", "not_allowed", "internal",
+                            ],
+                        },
+                        "message": { "type": "string" },
+                    },
+                },
+            },
+        },
+        "HistoryEntry": {
+            "type": "object",
+            "properties": {
+                "time": { "type": "string", "format": "date-time" },
+                "device": { "type": "string" },
+                "source": {
+                    "type": "string",
+                    "enum": ["alexa", "google", "homekit", "schedule", "idle", "restore", "api", "socket", "cli", "telegram"],
+                },
+                "command": { "$ref": "#/components/schemas/AvrCommand" },
+                "response": { "type": "string" },
+                "error": { "type": "string", "nullable": true },
+                "latency_ms": { "type": "integer" },
+            },
+        },
+        "Stats": {
+            "type": "object",
+            "properties": {
+                "started": { "type": "string", "format": "date-time" },
+                "uptime_secs": { "type": "integer" },
+                "total": count,
+                "success_rate": { "type": "number", "nullable": true },
+                "intents": { "type": "object", "additionalProperties": count },
+                "latency": {
+                    "type": "object",
+                    "additionalProperties": {
+                        "type": "object",
+                        "additionalProperties": {
+                            "type": "object",
+                            "properties": {
+                                "count": { "type": "integer" },
+                                "p50_ms": { "type": "integer" },
+                                "p95_ms": { "type": "integer" },
+                                "p99_ms": { "type": "integer" },
+                            },
+                        },
+                    },
+                },
+            },
+        },
+        "Health": {
+            "type": "object",
+            "properties": {
+                "healthy": { "type": "boolean" },
+                "devices": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "properties": {
+                            "name": { "type": "string" },
+                            "connected": { "type": "boolean" },
+                            "model": { "type": "string", "nullable": true },
+                            "firmware": { "type": "string", "nullable": true },
+                        },
+                    },
+                },
+            },
+        },
+    })
+}
@@ -0,0 +1,501 @@
+/// This module contains the `AvrProtocol` implementation for Pioneer AVRs.
+use crate::{
+    avr::{AvrCommand, AvrQuery, Key},
+    events::AvrEvent,
+    protocol::{AvrProtocol, Message},
+    state::{self, Response},
+};
+
+pub struct Pioneer;
+
+/// Input code of the Adapter Port
+const ADAPTER_PORT: &str = "33";
+
+/// Input code and name for each input number, input `n` being at index `n - 1`
+const INPUTS: [(&str, &str); 23] = [
+    ("25", "BD"),
+    ("49", "Game"),
+    ("19", "HDMI 1"),
+    ("15", "DVR/BDR"),
+    ("10", "Video 1"),
+    ("14", "Video 2"),
+    ("05", "TV/SAT"),
+    ("20", "HDMI 2"),
+    ("21", "HDMI 3"),
+    ("22", "HDMI 4"),
+    ("23", "HDMI 5"),
+    ("24", "HDMI 6"),
+    ("26", "Home Media Gallery"),
+    ("17", "iPod/USB"),
+    ("01", "CD"),
+    ("03", "CD-R/Tape"),
+    ("02", "Tuner"),
+    ("00", "Phono"),
+    ("12", "Multi Channel In"),
+    ("33", "Adapter Port"),
+    ("27", "Sirius"),
+    ("31", "HDMI (cyclic)"),
+    ("04", "DVD"),
+];
+
+impl AvrProtocol for Pioneer {
+    fn supports(&self, cmd: &AvrCommand) -> bool {
+        match cmd {
+            AvrCommand::SelectPreset(preset) => self.preset_code(preset).is_some(),
+            AvrCommand::Ipod(key) => ipod_code(*key).is_some(),
+            AvrCommand::Adapter(key) => adapter_code(*key).is_some(),
+            AvrCommand::SetListeningMode(mode) => self.listening_mode_code(mode).is_some(),
+            AvrCommand::SetSpeakerSystem(system) => self.speaker_system_code(system).is_some(),
+            AvrCommand::SetLfeAttenuation(db) => self.lfe_attenuation_code(*db).is_some(),
+            AvrCommand::SetVideoResolution(resolution) => {
+                self.video_resolution_code(resolution).is_some()
+            }
+            AvrCommand::SetVolumeDb(db) => self.db_level(*db).is_some(),
+            _ => true,
+        }
+    }
+
+    fn code(&self, cmd: &AvrCommand) -> String {
+        match cmd {
+            AvrCommand::SetVolume(n) => get_volume_code(self.volume_level(*n)),
+            AvrCommand::SetVolumeDb(db) => get_volume_code(self.db_level(*db).unwrap_or_default()),
+            AvrCommand::ChangeInput(n) => get_input_code(*n),
+            AvrCommand::PowerOn => "PO\r".to_owned(),
+            AvrCommand::PowerOff => "PF\r".to_owned(),
+            AvrCommand::Mute => "MO\r".to_owned(),
+            AvrCommand::Unmute => "MF\r".to_owned(),
+            AvrCommand::VolumeDown => "VD\r\n".to_owned(),
+            AvrCommand::VolumeUp => "VU\r\n".to_owned(),
+            // Switch to the tuner first, presets can't be selected otherwise
+            AvrCommand::SelectPreset(preset) => format!(
+                "{}FN\r{}PR\r",
+                self.tuner_input().unwrap_or_default(),
+                self.preset_code(preset).unwrap_or_default()
+            ),
+            AvrCommand::Gallery(key) => format!("{}NW\r", gallery_code(*key)),
+            AvrCommand::Ipod(key) => format!("{}IP\r", ipod_code(*key).unwrap_or_default()),
+            AvrCommand::Adapter(key) => {
+                format!("{}BT\r", adapter_code(*key).unwrap_or_default())
+            }
+            // Pairing only starts once the Adapter Port is selected
+            AvrCommand::BluetoothPairing => format!("{}FN\r99BT\r", ADAPTER_PORT),
+            AvrCommand::SoundRetrieverOn => "1ATA\r".to_owned(),
+            AvrCommand::SoundRetrieverOff => "0ATA\r".to_owned(),
+            AvrCommand::DialogEnhancementOn => "1ATG\r".to_owned(),
+            AvrCommand::DialogEnhancementOff => "0ATG\r".to_owned(),
+            AvrCommand::SetListeningMode(mode) => {
+                format!("{}SR\r", self.listening_mode_code(mode).unwrap_or_default())
+            }
+            AvrCommand::SetSpeakerSystem(system) => format!(
+                "{}SSF\r",
+                self.speaker_system_code(system).unwrap_or_default()
+            ),
+            // Stepped from the current level, see `subwoofer_level_code`
+            AvrCommand::SubwooferUp | AvrCommand::SubwooferDown => String::new(),
+            AvrCommand::SetLfeAttenuation(db) => format!(
+                "{}ATL\r",
+                self.lfe_attenuation_code(*db).unwrap_or_default()
+            ),
+            AvrCommand::VideoConverterOn => "1VTB\r".to_owned(),
+            AvrCommand::VideoConverterOff => "0VTB\r".to_owned(),
+            AvrCommand::NetworkStandbyOn => "1STJ\r".to_owned(),
+            AvrCommand::SetVideoResolution(resolution) => format!(
+                "{}VTC\r",
+                self.video_resolution_code(resolution).unwrap_or_default()
+            ),
+        }
+    }
+
+    fn query_code(&self, query: &AvrQuery) -> String {
+        match query {
+            AvrQuery::Volume => "?V\r".to_owned(),
+            AvrQuery::Mute => "?M\r".to_owned(),
+            AvrQuery::Power => "?P\r".to_owned(),
+            AvrQuery::Input => "?F\r".to_owned(),
+            AvrQuery::Preset => "?PR\r".to_owned(),
+            AvrQuery::SoundRetriever => "?ATA\r".to_owned(),
+            AvrQuery::DialogEnhancement => "?ATG\r".to_owned(),
+            AvrQuery::ListeningMode => "?S\r".to_owned(),
+            AvrQuery::SpeakerSystem => "?SSF\r".to_owned(),
+            AvrQuery::SubwooferLevel => "?SW_CLV\r".to_owned(),
+            AvrQuery::LfeAttenuation => "?ATL\r".to_owned(),
+            AvrQuery::Headphones => "?HP\r".to_owned(),
+            AvrQuery::VideoConverter => "?VTB\r".to_owned(),
+            AvrQuery::VideoResolution => "?VTC\r".to_owned(),
+            AvrQuery::NetworkStandby => "?STJ\r".to_owned(),
+            AvrQuery::Model => "?RGD\r".to_owned(),
+            AvrQuery::Firmware => "?SSI\r".to_owned(),
+            AvrQuery::InputLabel(n) => self
+                .input_code(*n)
+                .map(|input| format!("?RGB{}\r", input))
+                .unwrap_or_default(),
+        }
+    }
+
+    fn expected(&self, cmd: &AvrCommand) -> String {
+        match cmd {
+            AvrCommand::SetVolume(n) => format!("VOL{:0>3}\r\n", self.volume_level(*n)),
+            AvrCommand::SetVolumeDb(db) => {
+                format!("VOL{:0>3}\r\n", self.db_level(*db).unwrap_or_default())
+            }
+            AvrCommand::ChangeInput(n) => {
+                format!("FN{}\r\n", self.input_code(*n).unwrap_or_default())
+            }
+            AvrCommand::Mute => "MUT0\r\n".to_owned(),
+            AvrCommand::Unmute => "MUT1\r\n".to_owned(),
+            AvrCommand::PowerOn => "PWR0\r\n".to_owned(),
+            AvrCommand::PowerOff => "PWR2\r\n".to_owned(),
+            AvrCommand::VolumeDown => "VOL".to_owned(),
+            AvrCommand::VolumeUp => "VOL".to_owned(),
+            AvrCommand::SelectPreset(preset) => {
+                format!("PR{}\r\n", self.preset_code(preset).unwrap_or_default())
+            }
+            AvrCommand::SoundRetrieverOn => "ATA1\r\n".to_owned(),
+            AvrCommand::SoundRetrieverOff => "ATA0\r\n".to_owned(),
+            AvrCommand::DialogEnhancementOn => "ATG1\r\n".to_owned(),
+            AvrCommand::DialogEnhancementOff => "ATG0\r\n".to_owned(),
+            AvrCommand::SetListeningMode(mode) => format!(
+                "SR{}\r\n",
+                self.listening_mode_code(mode).unwrap_or_default()
+            ),
+            AvrCommand::SetSpeakerSystem(system) => format!(
+                "SSF{}\r\n",
+                self.speaker_system_code(system).unwrap_or_default()
+            ),
+            AvrCommand::SubwooferUp => "CLVSW_".to_owned(),
+            AvrCommand::SubwooferDown => "CLVSW_".to_owned(),
+            AvrCommand::SetLfeAttenuation(db) => format!(
+                "ATL{}\r\n",
+                self.lfe_attenuation_code(*db).unwrap_or_default()
+            ),
+            AvrCommand::VideoConverterOn => "VTB1\r\n".to_owned(),
+            AvrCommand::VideoConverterOff => "VTB0\r\n".to_owned(),
+            AvrCommand::NetworkStandbyOn => "STJ1\r\n".to_owned(),
+            AvrCommand::SetVideoResolution(resolution) => format!(
+                "VTC{}\r\n",
+                self.video_resolution_code(resolution).unwrap_or_default()
+            ),
+            AvrCommand::Gallery(_)
+            | AvrCommand::Ipod(_)
+            | AvrCommand::Adapter(_)
+            | AvrCommand::BluetoothPairing => String::new(),
+        }
+    }
+
+    /// Commands without a query are confirmed by the response not being an
+    /// error, as the AVR reports no state change for them
+    fn matches(&self, cmd: &AvrCommand, response: &str) -> bool {
+        let mut messages = response.split_terminator("\r\n");
+        if cmd.query_type().is_none() {
+            return !messages
+                .any(|message| matches!(state::parse(message), Some(Response::Error(_))));
+        }
+        let expected = self.expected(cmd);
+        messages.any(|message| format!("{}\r\n", message).starts_with(&expected))
+    }
+
+    /// 161 is equal to 0.0dB and I don't want to set any higher via this
+    /// skill, so I've set this as the ceiling.
+    fn volume_level(&self, n: u8) -> u8 {
+        let ceiling = 101.0;
+        let weight = f32::from(n) / 10.0;
+        (weight * ceiling).ceil() as u8
+    }
+
+    fn volume_step(&self) -> u8 {
+        2
+    }
+
+    /// Each level is 0.5dB, from -80.0dB at level 1
+    fn level_db(&self) -> f32 {
+        0.5
+    }
+
+    /// Level 161 is 0.0dB
+    fn volume_db(&self, level: u8) -> Option<f32> {
+        Some((f32::from(level) - 161.0) * self.level_db())
+    }
+
+    /// Rounded to the half dB, up to level 185, +12.0dB
+    fn db_level(&self, db: f32) -> Option<u8> {
+        let level = (db / self.level_db()).round() + 161.0;
+        if (0.0..=185.0).contains(&level) {
+            Some(level as u8)
+        } else {
+            None
+        }
+    }
+
+    /// Three digit level, like "051VL"
+    fn volume_code(&self, level: u8) -> Option<String> {
+        Some(get_volume_code(level))
+    }
+
+    /// Zone 2 levels are 1dB, from -80.0dB at level 1 to 0.0dB at level 81,
+    /// with level 0 silent
+    fn zone2_volume_code(&self, db: f32) -> Option<String> {
+        let level = (db + 81.0).round().clamp(0.0, 81.0) as u8;
+        Some(format!("{:02}ZV\r", level))
+    }
+
+    /// Listening modes are four digit codes, like "0112" for Extended Stereo
+    fn listening_mode_code(&self, mode: &str) -> Option<String> {
+        let mode = mode.trim();
+        if mode.len() != 4 || !mode.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        Some(mode.to_owned())
+    }
+
+    /// Auto surround is "0005", direct "0006" and extended stereo "0112"
+    fn listening_mode_named(&self, name: &str) -> Option<String> {
+        match name {
+            "auto" => Some("0005".to_owned()),
+            "direct" => Some("0006".to_owned()),
+            "extended stereo" => Some("0112".to_owned()),
+            _ => None,
+        }
+    }
+
+    /// Speaker systems are two digit codes, like "02" for Speaker B or "04"
+    /// for the surround back speakers assigned to Zone 2
+    fn speaker_system_code(&self, system: &str) -> Option<String> {
+        let system = system.trim();
+        if system.len() != 2 || !system.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        Some(system.to_owned())
+    }
+
+    /// The surround back speakers, the B speakers, the front speakers
+    /// bi-amped, or the surround back speakers assigned to Zone 2
+    fn speaker_system_named(&self, name: &str) -> Option<String> {
+        let code = match name.to_lowercase().as_str() {
+            "surround back" | "normal" => "00",
+            "speaker b" | "b speakers" => "02",
+            "bi-amp" | "bi amp" | "front bi-amp" => "03",
+            "zone 2" | "zone two" => "04",
+            _ => return None,
+        };
+        Some(code.to_owned())
+    }
+
+    /// Subwoofer levels are 26 to 74, 50 being 0.0dB
+    fn subwoofer_level_code(&self, level: u8) -> Option<String> {
+        if !(26..=74).contains(&level) {
+            return None;
+        }
+        Some(format!("SW_{}CLV\r", level))
+    }
+
+    /// Each level is 0.5dB, so step a whole dB
+    fn subwoofer_step(&self) -> u8 {
+        2
+    }
+
+    /// 0 to 20dB in 5dB steps, as 0 to 4
+    fn lfe_attenuation_code(&self, db: u8) -> Option<String> {
+        if db > 20 || db % 5 != 0 {
+            return None;
+        }
+        Some((db / 5).to_string())
+    }
+
+    /// Resolutions are two digit codes: "00" auto, "01" pure, passing the
+    /// source's through, then "03" 480p, "04" 720p, "05" 1080i, "06" 1080p
+    /// and "07" 1080p at 24 frames
+    fn video_resolution_code(&self, resolution: &str) -> Option<String> {
+        match resolution.trim() {
+            code @ ("00" | "01" | "03" | "04" | "05" | "06" | "07") => Some(code.to_owned()),
+            _ => None,
+        }
+    }
+
+    /// Auto, pure passing the source's resolution through, or one of the
+    /// fixed ones
+    fn video_resolution_named(&self, name: &str) -> Option<String> {
+        let code = match name.to_lowercase().as_str() {
+            "auto" | "automatic" => "00",
+            "pure" | "pass through" | "source" => "01",
+            "480p" | "480" => "03",
+            "720p" | "720" => "04",
+            "1080i" => "05",
+            "1080p" | "1080" | "full hd" => "06",
+            "1080p24" | "1080p 24" | "24p" => "07",
+            _ => return None,
+        };
+        Some(code.to_owned())
+    }
+
+    /// The iPod/USB input, the Adapter Port and the Home Media Gallery
+    fn transport(&self, input: &str, key: Key) -> Option<AvrCommand> {
+        match input {
+            "17" if ipod_code(key).is_some() => Some(AvrCommand::Ipod(key)),
+            ADAPTER_PORT if adapter_code(key).is_some() => Some(AvrCommand::Adapter(key)),
+            "26" => Some(AvrCommand::Gallery(key)),
+            _ => None,
+        }
+    }
+
+    fn inputs(&self) -> Vec<String> {
+        INPUTS.iter().map(|(_, name)| (*name).to_owned()).collect()
+    }
+
+    fn input_number(&self, input: &str) -> Option<u8> {
+        INPUTS
+            .iter()
+            .position(|(code, _)| *code == input)
+            .map(|i| i as u8 + 1)
+    }
+
+    fn input_code(&self, n: u8) -> Option<String> {
+        let i = usize::from(n).checked_sub(1)?;
+        INPUTS.get(i).map(|(code, _)| (*code).to_owned())
+    }
+
+    /// Presets are classes A to G, of 9 presets each, reported as the class
+    /// then the number padded to two digits, like "A02"
+    fn preset_code(&self, preset: &str) -> Option<String> {
+        let preset = preset.trim();
+        let class = preset.chars().next()?.to_ascii_uppercase();
+        let n = preset.get(1..)?.parse::<u8>().ok()?;
+        if !('A'..='G').contains(&class) || !(1..=9).contains(&n) {
+            return None;
+        }
+        Some(format!("{}{:02}", class, n))
+    }
+
+    fn headphones_code(&self) -> Option<String> {
+        Some(self.query_code(&AvrQuery::Headphones))
+    }
+
+    fn audio_status_code(&self) -> Option<String> {
+        Some("?AST\r".to_owned())
+    }
+
+    /// The audio status shows a signal if any input channel is present
+    fn signal_present(&self, response: &str) -> Option<bool> {
+        response
+            .split_terminator("\r\n")
+            .find_map(|message| match state::parse(message) {
+                Some(Response::AudioStatus(status)) => Some(status.signal_present()),
+                _ => None,
+            })
+    }
+
+    /// Besides state changes, the AVR sends "R" as a heartbeat, and answers a
+    /// code it can't execute with an error such as "E04", or "B00" if it's
+    /// busy.
+    fn classify(&self, message: &str) -> Message {
+        match state::parse(message) {
+            Some(Response::Heartbeat) => Message::Heartbeat,
+            Some(Response::Error(code)) => Message::Error(code),
+            response => match response.and_then(event) {
+                Some(event) => Message::Event(event),
+                None => Message::Other(message.trim().to_owned()),
+            },
+        }
+    }
+
+    /// Parse a single message from the AVR, such as "PWR0" or "VOL101".
+    /// Messages that aren't a state change, like the "R" heartbeat, are
+    /// ignored.
+    fn parse_event(&self, message: &str) -> Option<AvrEvent> {
+        state::parse(message).and_then(event)
+    }
+}
+
+/// State change the response reports, if it's one
+fn event(response: Response) -> Option<AvrEvent> {
+    match response {
+        Response::Power(on) => Some(AvrEvent::Power(on)),
+        Response::Volume(level) => Some(AvrEvent::Volume(level)),
+        Response::Mute(mute) => Some(AvrEvent::Mute(mute)),
+        Response::Input(input) => Some(AvrEvent::Input(input)),
+        Response::Preset(preset) => Some(AvrEvent::Preset(preset)),
+        Response::SoundRetriever(on) => Some(AvrEvent::SoundRetriever(on)),
+        Response::DialogEnhancement(on) => Some(AvrEvent::DialogEnhancement(on)),
+        Response::ListeningMode(mode) => Some(AvrEvent::ListeningMode(mode)),
+        Response::SpeakerSystem(system) => Some(AvrEvent::SpeakerSystem(system)),
+        Response::ChannelLevel { channel, level } if channel == "SW" => {
+            Some(AvrEvent::SubwooferLevel(level))
+        }
+        Response::LfeAttenuation(db) => Some(AvrEvent::LfeAttenuation(db)),
+        Response::Headphones(plugged) => Some(AvrEvent::Headphones(plugged)),
+        Response::VideoConverter(on) => Some(AvrEvent::VideoConverter(on)),
+        Response::VideoResolution(resolution) => Some(AvrEvent::VideoResolution(resolution)),
+        Response::NetworkStandby(on) => Some(AvrEvent::NetworkStandby(on)),
+        Response::InputLabel {
+            input,
+            renamed,
+            name,
+        } => Some(AvrEvent::InputLabel(input, Some(name).filter(|_| renamed))),
+        Response::Model(model) => Some(AvrEvent::Model(model)),
+        Response::Firmware(firmware) => Some(AvrEvent::Firmware(firmware)),
+        _ => None,
+    }
+}
+
+/// Operation code of the key on the Home Media Gallery, sent as "30NW"
+fn gallery_code(key: Key) -> &'static str {
+    match key {
+        Key::Play => "10",
+        Key::Pause => "11",
+        Key::Previous => "12",
+        Key::Next => "13",
+        Key::Stop => "20",
+        Key::Up => "26",
+        Key::Down => "27",
+        Key::Right => "28",
+        Key::Left => "29",
+        Key::Enter => "30",
+        Key::Return => "31",
+    }
+}
+
+/// Operation code of the key on the iPod/USB input, sent as "00IP". Only
+/// its transport controls are supported.
+fn ipod_code(key: Key) -> Option<&'static str> {
+    match key {
+        Key::Play => Some("00"),
+        Key::Pause => Some("01"),
+        Key::Stop => Some("02"),
+        Key::Previous => Some("03"),
+        Key::Next => Some("04"),
+        _ => None,
+    }
+}
+
+/// Operation code of the key on the Adapter Port, for a Bluetooth adapter,
+/// sent as "10BT". Only its transport controls are supported.
+fn adapter_code(key: Key) -> Option<&'static str> {
+    match key {
+        Key::Play => Some("10"),
+        Key::Pause => Some("11"),
+        Key::Stop => Some("12"),
+        Key::Previous => Some("13"),
+        Key::Next => Some("14"),
+        _ => None,
+    }
+}
+
+/// Convert AVR volume level to volume code.   
+///
+/// Must be padded to three digits: "{:0>3}"
+fn get_volume_code(volume: u8) -> String {
+    let mut volume = format!("{:0>3}", volume);
+    volume.push_str("VL\r");
+    volume
+}
+
+/// Convert input to AVR input code.
+fn get_input_code(n: u8) -> String {
+    let code = usize::from(n)
+        .checked_sub(1)
+        .and_then(|i| INPUTS.get(i))
+        .map(|(code, _)| *code)
+        .unwrap_or(""); // Should never be reached
+    let mut code = code.to_owned();
+    code.push_str("FN\r");
+    code
+}
@@ -0,0 +1,55 @@
+/// This module resolves the spoken name of a tuner preset to the preset.
+///
+/// Presets are named in the config, like "NPR" for A2, so "play NPR on the
+/// radio" can switch to the tuner and select it. Names are matched the same
+/// way as input names, ignoring case, spaces and punctuation, with the
+/// closest name taken if it's close enough.
+use crate::{aliases, avr::AvrCommand, protocol::AvrProtocol};
+use failure::{bail, Error};
+use std::collections::BTreeMap;
+
+/// Named presets of an AVR: the normalized name, the name as configured, and
+/// the preset
+#[derive(Clone, Debug, Default)]
+pub struct Presets {
+    presets: Vec<(String, String, String)>,
+}
+
+impl Presets {
+    /// The configured presets, preset by name, checking the AVR has each
+    pub fn new(
+        protocol: &dyn AvrProtocol,
+        presets: &BTreeMap<String, String>,
+    ) -> Result<Presets, Error> {
+        let mut named = vec![];
+        for (name, preset) in presets {
+            if !protocol.supports(&AvrCommand::SelectPreset(preset.clone())) {
+                bail!("Not a tuner preset of the AVR: {:?} for {:?}", preset, name);
+            }
+            named.push((aliases::normalize(name), name.clone(), preset.clone()));
+        }
+        Ok(Presets { presets: named })
+    }
+
+    /// Names of the presets, as configured
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.presets.iter().map(|(_, name, _)| name.as_str())
+    }
+
+    /// Name of the preset, if it's named
+    pub fn name(&self, preset: &str) -> Option<&str> {
+        self.presets
+            .iter()
+            .find(|(_, _, named)| named.eq_ignore_ascii_case(preset))
+            .map(|(_, name, _)| name.as_str())
+    }
+
+    /// Preset going by the spoken name, or the closest to it
+    pub fn resolve(&self, spoken: &str) -> Option<String> {
+        let names = self
+            .presets
+            .iter()
+            .map(|(name, _, preset)| (name.as_str(), preset));
+        aliases::closest(names, spoken).cloned()
+    }
+}
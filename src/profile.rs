@@ -0,0 +1,287 @@
+/// This module limits an AVR to what its model actually has, so commands it
+/// can't execute are turned down before they're sent, rather than timing out.
+///
+/// A profile lists the model's inputs, by name or number, whether it has a
+/// Zone 2, and the commands it has besides power, volume, mute and input.
+/// Anything left out of a profile isn't limited. Profiles for some models are
+/// built in, and any other can be given as the path of a TOML file, like:
+///
+/// ```toml
+/// inputs = ["BD", "DVD", "TV/SAT", "HDMI 1", "HDMI 2", "iPod/USB", "Tuner"]
+/// zone2 = false
+/// commands = ["SelectPreset", "Ipod", "SoundRetrieverOn", "SoundRetrieverOff"]
+/// ```
+///
+/// Inputs the model doesn't have are also hidden, see `crate::aliases`.
+use crate::{
+    avr::{AvrCommand, AvrQuery, Key},
+    config::DeviceConfig,
+    events::AvrEvent,
+    protocol::{AvrProtocol, Message},
+};
+use failure::{bail, Error, ResultExt};
+use serde::Deserialize;
+use serde_json::Value;
+use std::{fs, sync::Arc};
+use tracing::info;
+
+/// Built in profiles, by model name
+const BUILT_IN: [(&str, &str); 2] = [("VSX-1021", VSX_1021), ("SC-LX901", SC_LX901)];
+
+const VSX_1021: &str = r#"
+inputs = [
+    "BD", "DVD", "TV/SAT", "DVR/BDR", "Video 1", "HDMI 1", "HDMI 2", "HDMI 3",
+    "HDMI 4", "HDMI 5", "Home Media Gallery", "iPod/USB", "CD", "Tuner",
+    "Adapter Port", "HDMI (cyclic)",
+]
+zone2 = true
+commands = [
+    "SelectPreset", "Gallery", "Ipod", "Adapter", "BluetoothPairing",
+    "SoundRetrieverOn", "SoundRetrieverOff", "SetListeningMode", "SetSpeakerSystem",
+    "SubwooferUp", "SubwooferDown", "SetLfeAttenuation", "VideoConverterOn",
+    "VideoConverterOff", "SetVideoResolution", "NetworkStandbyOn",
+]
+"#;
+
+const SC_LX901: &str = r#"
+inputs = [
+    "BD", "Game", "DVD", "TV/SAT", "DVR/BDR", "HDMI 1", "HDMI 2", "HDMI 3",
+    "HDMI 4", "HDMI 5", "HDMI 6", "iPod/USB", "CD", "Tuner", "Phono",
+    "Multi Channel In", "HDMI (cyclic)",
+]
+zone2 = true
+commands = [
+    "SelectPreset", "Ipod", "SoundRetrieverOn", "SoundRetrieverOff",
+    "DialogEnhancementOn", "DialogEnhancementOff", "SetListeningMode",
+    "SetSpeakerSystem", "SubwooferUp", "SubwooferDown", "SetLfeAttenuation",
+    "VideoConverterOn", "VideoConverterOff", "SetVideoResolution", "NetworkStandbyOn",
+]
+"#;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProfileFile {
+    inputs: Option<Vec<String>>,
+    zone2: Option<bool>,
+    commands: Option<Vec<String>>,
+}
+
+/// The AVR's protocol, limited to the inputs, Zone 2 and commands its model
+/// has
+pub struct Profile {
+    inner: Arc<dyn AvrProtocol>,
+    /// Numbers of the inputs the model has
+    inputs: Option<Vec<u8>>,
+    zone2: Option<bool>,
+    commands: Option<Vec<String>>,
+}
+
+impl Profile {
+    /// Wrap the protocol with the AVR's configured profile, a built in
+    /// model's name or the path of a profile file, if it has one
+    pub fn wrap(
+        inner: Arc<dyn AvrProtocol>,
+        config: &DeviceConfig,
+    ) -> Result<Arc<dyn AvrProtocol>, Error> {
+        let name = match &config.profile {
+            Some(name) => name,
+            None => return Ok(inner),
+        };
+        let file: ProfileFile = match BUILT_IN
+            .iter()
+            .find(|(model, _)| model.eq_ignore_ascii_case(name))
+        {
+            Some((_, profile)) => toml::from_str(profile)?,
+            None => {
+                let contents = fs::read_to_string(name)
+                    .context(format!("Could not read profile file: {}", name))?;
+                toml::from_str(&contents)
+                    .context(format!("Could not parse profile file: {}", name))?
+            }
+        };
+        info!("Using the {} profile for {}", name, config.name);
+
+        let inputs = match file.inputs {
+            Some(inputs) => {
+                let mut numbers = vec![];
+                for input in &inputs {
+                    match inner.find_input(input) {
+                        Some(n) => numbers.push(n),
+                        None => bail!("No input named {:?} in profile {}", input, name),
+                    }
+                }
+                Some(numbers)
+            }
+            None => None,
+        };
+
+        Ok(Arc::new(Profile {
+            inner,
+            inputs,
+            zone2: file.zone2,
+            commands: file.commands,
+        }))
+    }
+}
+
+/// Name of the command, as in the config, like "SelectPreset"
+fn command_name(cmd: &AvrCommand) -> Option<String> {
+    match serde_json::to_value(cmd).ok()? {
+        Value::String(name) => Some(name),
+        Value::Object(command) => command.keys().next().cloned(),
+        _ => None,
+    }
+}
+
+impl AvrProtocol for Profile {
+    /// Inputs and commands the model doesn't have aren't supported
+    fn supports(&self, cmd: &AvrCommand) -> bool {
+        let has = match (cmd, &self.inputs, &self.commands) {
+            (AvrCommand::ChangeInput(n), Some(inputs), _) => inputs.contains(n),
+            (cmd, _, _) if cmd.is_basic() => true,
+            (cmd, _, Some(commands)) => command_name(cmd).is_some_and(|name| {
+                commands
+                    .iter()
+                    .any(|command| command.eq_ignore_ascii_case(&name))
+            }),
+            _ => true,
+        };
+        has && self.inner.supports(cmd)
+    }
+
+    fn code(&self, cmd: &AvrCommand) -> String {
+        self.inner.code(cmd)
+    }
+
+    fn query_code(&self, query: &AvrQuery) -> String {
+        self.inner.query_code(query)
+    }
+
+    fn expected(&self, cmd: &AvrCommand) -> String {
+        self.inner.expected(cmd)
+    }
+
+    fn matches(&self, cmd: &AvrCommand, response: &str) -> bool {
+        self.inner.matches(cmd, response)
+    }
+
+    fn volume_level(&self, n: u8) -> u8 {
+        self.inner.volume_level(n)
+    }
+
+    fn volume_step(&self) -> u8 {
+        self.inner.volume_step()
+    }
+
+    fn level_db(&self) -> f32 {
+        self.inner.level_db()
+    }
+
+    fn volume_percent(&self, level: u8) -> u8 {
+        self.inner.volume_percent(level)
+    }
+
+    fn volume_db(&self, level: u8) -> Option<f32> {
+        self.inner.volume_db(level)
+    }
+
+    /// Only if the model has a Zone 2
+    fn db_level(&self, db: f32) -> Option<u8> {
+        self.inner.db_level(db)
+    }
+
+    fn volume_code(&self, level: u8) -> Option<String> {
+        self.inner.volume_code(level)
+    }
+
+    fn zone2_volume_code(&self, db: f32) -> Option<String> {
+        match self.zone2 {
+            Some(false) => None,
+            _ => self.inner.zone2_volume_code(db),
+        }
+    }
+
+    fn inputs(&self) -> Vec<String> {
+        self.inner.inputs()
+    }
+
+    fn input_number(&self, input: &str) -> Option<u8> {
+        self.inner.input_number(input)
+    }
+
+    fn input_code(&self, n: u8) -> Option<String> {
+        self.inner.input_code(n)
+    }
+
+    fn input_name(&self, input: &str) -> Option<String> {
+        self.inner.input_name(input)
+    }
+
+    fn tuner_input(&self) -> Option<String> {
+        self.inner.tuner_input()
+    }
+
+    fn preset_code(&self, preset: &str) -> Option<String> {
+        self.inner.preset_code(preset)
+    }
+
+    fn listening_mode_code(&self, mode: &str) -> Option<String> {
+        self.inner.listening_mode_code(mode)
+    }
+
+    fn listening_mode_named(&self, name: &str) -> Option<String> {
+        self.inner.listening_mode_named(name)
+    }
+
+    fn speaker_system_code(&self, system: &str) -> Option<String> {
+        self.inner.speaker_system_code(system)
+    }
+
+    fn speaker_system_named(&self, name: &str) -> Option<String> {
+        self.inner.speaker_system_named(name)
+    }
+
+    fn video_resolution_code(&self, resolution: &str) -> Option<String> {
+        self.inner.video_resolution_code(resolution)
+    }
+
+    fn video_resolution_named(&self, name: &str) -> Option<String> {
+        self.inner.video_resolution_named(name)
+    }
+
+    fn subwoofer_level_code(&self, level: u8) -> Option<String> {
+        self.inner.subwoofer_level_code(level)
+    }
+
+    fn subwoofer_step(&self) -> u8 {
+        self.inner.subwoofer_step()
+    }
+
+    fn lfe_attenuation_code(&self, db: u8) -> Option<String> {
+        self.inner.lfe_attenuation_code(db)
+    }
+
+    fn transport(&self, input: &str, key: Key) -> Option<AvrCommand> {
+        self.inner.transport(input, key)
+    }
+
+    fn headphones_code(&self) -> Option<String> {
+        self.inner.headphones_code()
+    }
+
+    fn audio_status_code(&self) -> Option<String> {
+        self.inner.audio_status_code()
+    }
+
+    fn signal_present(&self, response: &str) -> Option<bool> {
+        self.inner.signal_present(response)
+    }
+
+    fn parse_event(&self, message: &str) -> Option<AvrEvent> {
+        self.inner.parse_event(message)
+    }
+
+    fn classify(&self, message: &str) -> Message {
+        self.inner.classify(message)
+    }
+}
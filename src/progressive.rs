@@ -0,0 +1,130 @@
+/// This module sends progressive responses through the Alexa Directives API,
+/// so Alexa says something like "Turning it on..." while a slow command is
+/// still being processed, instead of going quiet until the final response.
+///
+/// Alexa gives the skill 8 seconds to respond, which powering on the AVR and
+/// stepping its volume can come close to. A progressive response keeps the
+/// user informed, but doesn't extend that time.
+///
+/// The Directives API endpoint and the token to use with it are in the
+/// request's `context.System`, and the directive must carry the request's id.
+use failure::{bail, Error};
+use hyper::{
+    client::HttpConnector,
+    header::{AUTHORIZATION, CONTENT_TYPE},
+    Body, Client, Method, Request,
+};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use serde::Deserialize;
+use serde_json::json;
+use std::sync::Arc;
+use tracing::{debug, warn};
+
+pub type HttpsClient = Client<HttpsConnector<HttpConnector>>;
+
+/// Part of the Alexa request needed to send directives, which
+/// `alexa_sdk::Request` doesn't have
+#[derive(Deserialize)]
+struct Envelope {
+    context: Context,
+}
+
+#[derive(Deserialize)]
+struct Context {
+    #[serde(rename = "System")]
+    system: System,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct System {
+    api_endpoint: Option<String>,
+    api_access_token: Option<String>,
+}
+
+/// Where to send the progressive response for a request, and what with
+struct Target {
+    endpoint: String,
+    token: String,
+    request_id: String,
+}
+
+/// Sends progressive responses for one request. Nothing is sent if the
+/// request didn't come with a Directives API endpoint and token.
+#[derive(Clone)]
+pub struct Progress {
+    client: HttpsClient,
+    target: Option<Arc<Target>>,
+}
+
+impl Progress {
+    /// Client shared by every request's `Progress`
+    pub fn client() -> HttpsClient {
+        let connector = HttpsConnectorBuilder::new()
+            .with_webpki_roots()
+            .https_only()
+            .enable_http1()
+            .build();
+        Client::builder().build(connector)
+    }
+
+    /// Progress for the raw Alexa request body
+    pub fn new(client: &HttpsClient, body: &[u8], request_id: &str) -> Progress {
+        let target = serde_json::from_slice::<Envelope>(body)
+            .ok()
+            .and_then(|envelope| {
+                let system = envelope.context.system;
+                Some(Target {
+                    endpoint: system.api_endpoint?,
+                    token: system.api_access_token?,
+                    request_id: request_id.to_owned(),
+                })
+            })
+            .map(Arc::new);
+
+        Progress {
+            client: client.clone(),
+            target,
+        }
+    }
+
+    /// Send the speech as a progressive response in the background, so the
+    /// command isn't held up. Failures are only logged, as the final response
+    /// still gets through.
+    pub fn speak(&self, speech: String) {
+        let target = match &self.target {
+            Some(target) => target.clone(),
+            None => return,
+        };
+        let client = self.client.clone();
+
+        tokio::spawn(async move {
+            match send(&client, &target, &speech).await {
+                Ok(()) => debug!("Progressive response sent: {:?}", speech),
+                Err(e) => warn!("Could not send progressive response: {}", e),
+            }
+        });
+    }
+}
+
+async fn send(client: &HttpsClient, target: &Target, speech: &str) -> Result<(), Error> {
+    let directive = json!({
+        "header": { "requestId": target.request_id },
+        "directive": { "type": "VoicePlayer.Speak", "speech": speech },
+    });
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(format!(
+            "{}/v1/directives",
+            target.endpoint.trim_end_matches('/')
+        ))
+        .header(AUTHORIZATION, format!("Bearer {}", target.token))
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(directive.to_string()))?;
+
+    let response = client.request(request).await?;
+    if !response.status().is_success() {
+        bail!("Directives API returned {}", response.status());
+    }
+    Ok(())
+}
@@ -0,0 +1,255 @@
+/// This module defines the `AvrProtocol` trait, which describes how to talk to
+/// a given brand of AVR over telnet.   
+///
+/// `crate::avr` contains the brand independent logic for sending a command
+/// and validating it went through, using the protocol to get the actual codes
+/// to send and the responses to expect. Supporting another brand only needs a
+/// new implementation of this trait, added to `from_config`, or a table file
+/// for the `table` protocol.
+use crate::{
+    avr::{AvrCommand, AvrQuery, Key},
+    config::DeviceConfig,
+    curve::Curve,
+    events::AvrEvent,
+    offset::Offsets,
+    pioneer::Pioneer,
+    profile::Profile,
+    table::Table,
+};
+use failure::{bail, Error};
+use std::sync::Arc;
+
+/// Message received from the AVR, classified by what it means
+#[derive(Clone, Debug, PartialEq)]
+pub enum Message {
+    /// Sent periodically while the connection is idle, meaning nothing
+    Heartbeat,
+    /// State change, either answering a code or made some other way
+    Event(AvrEvent),
+    /// Error the AVR reported in response to a code
+    Error(String),
+    /// Anything else
+    Other(String),
+}
+
+pub trait AvrProtocol: Send + Sync {
+    /// Whether the AVR can be sent the command. By default, only the power,
+    /// volume, mute and input commands every AVR has.
+    fn supports(&self, cmd: &AvrCommand) -> bool {
+        cmd.is_basic()
+    }
+
+    /// Convert command to the appropriate telnet code supported by the AVR
+    fn code(&self, cmd: &AvrCommand) -> String;
+
+    /// Convert query to the appropriate telnet code supported by the AVR
+    fn query_code(&self, query: &AvrQuery) -> String;
+
+    /// Response expected from the command's query once the command has been
+    /// executed.
+    fn expected(&self, cmd: &AvrCommand) -> String;
+
+    /// Whether the query response confirms the command was executed. By
+    /// default, one of the CRLF terminated messages in the response must start
+    /// with `expected`.
+    fn matches(&self, cmd: &AvrCommand, response: &str) -> bool {
+        let expected = self.expected(cmd);
+        response
+            .split_terminator("\r\n")
+            .any(|message| format!("{}\r\n", message).starts_with(&expected))
+    }
+
+    /// Convert volume of 1 - 10 to the AVR's volume level
+    fn volume_level(&self, n: u8) -> u8;
+
+    /// How much the AVR's volume level changes with each `VolumeUp` or
+    /// `VolumeDown`
+    fn volume_step(&self) -> u8;
+
+    /// How many dB the AVR's volume changes with each volume level
+    fn level_db(&self) -> f32 {
+        1.0
+    }
+
+    /// The AVR's volume level in dB, if it can be told
+    fn volume_db(&self, _level: u8) -> Option<f32> {
+        None
+    }
+
+    /// The AVR's volume level nearest to `db`, if it can be told and the AVR
+    /// has one that close
+    fn db_level(&self, _db: f32) -> Option<u8> {
+        None
+    }
+
+    /// Code setting the AVR's volume straight to `level`, if it has one.
+    /// Models that don't take it still get there by stepping.
+    fn volume_code(&self, _level: u8) -> Option<String> {
+        None
+    }
+
+    /// Code setting the AVR's Zone 2 volume to `db`, if it has a Zone 2
+    fn zone2_volume_code(&self, _db: f32) -> Option<String> {
+        None
+    }
+
+    /// Names of the AVR's inputs, input `n` being at index `n - 1`
+    fn inputs(&self) -> Vec<String>;
+
+    /// Number of the input reported by `AvrEvent::Input`
+    fn input_number(&self, input: &str) -> Option<u8>;
+
+    /// Input reported by `AvrEvent::Input` for input number `n`
+    fn input_code(&self, n: u8) -> Option<String>;
+
+    /// Number of the input going by its name, ignoring case, or its number,
+    /// the ways inputs are given in the config
+    fn find_input(&self, input: &str) -> Option<u8> {
+        let inputs = self.inputs();
+        match input.parse::<u8>() {
+            Ok(n) if n >= 1 && usize::from(n) <= inputs.len() => Some(n),
+            _ => inputs
+                .iter()
+                .position(|name| name.eq_ignore_ascii_case(input))
+                .map(|i| i as u8 + 1),
+        }
+    }
+
+    /// Input reported by `AvrEvent::Input` for the tuner, if the AVR has one
+    fn tuner_input(&self) -> Option<String> {
+        self.find_input("Tuner").and_then(|n| self.input_code(n))
+    }
+
+    /// Tuner preset reported by `AvrEvent::Preset` for a preset as given in
+    /// the config, like "A2", if the AVR has presets and it's one of them
+    fn preset_code(&self, _preset: &str) -> Option<String> {
+        None
+    }
+
+    /// Listening mode reported by `AvrEvent::ListeningMode` for a listening
+    /// mode as given in the config, like "0112", if the AVR has it
+    fn listening_mode_code(&self, _mode: &str) -> Option<String> {
+        None
+    }
+
+    /// Listening mode going by the name the listening mode intents use,
+    /// "auto", "direct" or "extended stereo", as `listening_mode_code` takes
+    /// it, if the AVR has it
+    fn listening_mode_named(&self, _name: &str) -> Option<String> {
+        None
+    }
+
+    /// Speaker system reported by `AvrEvent::SpeakerSystem` for a speaker
+    /// system as given in the config, like "04", if the AVR has it
+    fn speaker_system_code(&self, _system: &str) -> Option<String> {
+        None
+    }
+
+    /// Speaker system going by the spoken name, like "zone 2", as
+    /// `speaker_system_code` takes it, if the AVR has it
+    fn speaker_system_named(&self, _name: &str) -> Option<String> {
+        None
+    }
+
+    /// Code setting the subwoofer's channel level to `level`, if the AVR has
+    /// that level
+    fn subwoofer_level_code(&self, _level: u8) -> Option<String> {
+        None
+    }
+
+    /// How much the subwoofer's channel level changes with each `SubwooferUp`
+    /// or `SubwooferDown`
+    fn subwoofer_step(&self) -> u8 {
+        1
+    }
+
+    /// Value of the code attenuating the LFE channel by `db`, if the AVR can
+    fn lfe_attenuation_code(&self, _db: u8) -> Option<String> {
+        None
+    }
+
+    /// HDMI output resolution reported by `AvrEvent::VideoResolution` for a
+    /// resolution as given in the config, like "06", if the AVR has it
+    fn video_resolution_code(&self, _resolution: &str) -> Option<String> {
+        None
+    }
+
+    /// HDMI output resolution going by the spoken name, like "1080p", as
+    /// `video_resolution_code` takes it, if the AVR has it
+    fn video_resolution_named(&self, _name: &str) -> Option<String> {
+        None
+    }
+
+    /// Command pressing the key of the transport controls of the input
+    /// reported by `AvrEvent::Input`, if it has any
+    fn transport(&self, _input: &str, _key: Key) -> Option<AvrCommand> {
+        None
+    }
+
+    /// Name of the input reported by `AvrEvent::Input`
+    fn input_name(&self, input: &str) -> Option<String> {
+        self.input_number(input)
+            .and_then(|n| self.inputs().get(usize::from(n) - 1).cloned())
+    }
+
+    /// Convert the AVR's volume level to a percentage of the highest volume
+    /// this skill sets
+    fn volume_percent(&self, level: u8) -> u8 {
+        let max = u32::from(self.volume_level(10)).max(1);
+        (u32::from(level) * 100 / max).min(100) as u8
+    }
+
+    /// Code asking whether headphones are plugged in, if the AVR reports it
+    fn headphones_code(&self) -> Option<String> {
+        None
+    }
+
+    /// Code asking for the AVR's audio status, if it reports whether the
+    /// current input has a signal
+    fn audio_status_code(&self) -> Option<String> {
+        None
+    }
+
+    /// Whether the audio status response shows a signal on the current input,
+    /// if it can be told
+    fn signal_present(&self, _response: &str) -> Option<bool> {
+        None
+    }
+
+    /// Parse a single message received from the AVR into a state change
+    /// event, if it is one
+    fn parse_event(&self, message: &str) -> Option<AvrEvent>;
+
+    /// Classify a single message received from the AVR. By default, any
+    /// message that isn't a state change is `Message::Other`.
+    fn classify(&self, message: &str) -> Message {
+        match self.parse_event(message) {
+            Some(event) => Message::Event(event),
+            None => Message::Other(message.to_owned()),
+        }
+    }
+
+    /// Parse data received from the AVR, which can contain several messages,
+    /// into state change events
+    fn parse_events(&self, data: &str) -> Vec<AvrEvent> {
+        data.split("\r\n")
+            .filter_map(|message| self.parse_event(message))
+            .collect()
+    }
+}
+
+/// Get the protocol named in the AVR's config, loading its table file for the
+/// `table` protocol, limited to its model's profile and with its volume curve
+/// and the volume offsets of its inputs applied
+pub fn from_config(config: &DeviceConfig) -> Result<Arc<dyn AvrProtocol>, Error> {
+    let protocol: Arc<dyn AvrProtocol> = match config.protocol.as_str() {
+        "pioneer" => Arc::new(Pioneer),
+        "table" => match &config.table {
+            Some(path) => Arc::new(Table::load(path)?),
+            None => bail!("No table file given for AVR: {}", config.name),
+        },
+        name => bail!("Unknown AVR protocol: {:?}", name),
+    };
+    let protocol = Curve::wrap(Profile::wrap(protocol, config)?, config)?;
+    Offsets::wrap(protocol, config)
+}
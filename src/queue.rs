@@ -0,0 +1,522 @@
+/// This module contains the command queue that sits between the skill and the
+/// telnet task.
+///
+/// Commands are processed against the AVR one at a time. While a command is
+/// being processed, new commands wait in the queue. When picking the next
+/// command, power commands get priority over everything else, and all waiting
+/// `SetVolume` and `SetVolumeDb` requests are coalesced so only the latest
+/// value is set, with every caller getting that result back. Callers that
+/// would rather not wait can use `try_process`, which fails if any command is
+/// already queued.
+///
+/// A command that fails because the AVR didn't answer or confirm it can be
+/// retried, per the AVR's `RetryConfig`, before the failure is sent back.
+/// A power on command the AVR hasn't confirmed yet has its power polled until
+/// it's on, and the commands after it wait for the AVR to settle, per its
+/// `PowerOnConfig`.
+/// During `QuietHours`, volume commands are kept to the volume ceiling.
+/// Every command processed is added to the `History`, if one is kept, and to
+/// the `Audit` log along with who asked for it, if that's kept. How long the
+/// AVR took to carry out the ones that worked is added to its `Latencies`.
+///
+/// Changing to an input with commands to send on selecting it sends them
+/// right after, before any other queued command. If one of them fails, the
+/// input is changed back to the one before, and the failure is sent back.
+///
+/// Each command is processed in a span that's a child of the one it was added
+/// to the queue from, so it shows up under the request that asked for it.
+use crate::{
+    audit::{self, Audit},
+    avr::{self, AvrCommand, AvrError, AvrQuery},
+    config::{PowerOnConfig, RetryConfig},
+    history::{Entry, History},
+    latency::Latencies,
+    log_error,
+    quiet::QuietHours,
+    telnet::AvrConnection,
+};
+use failure::{err_msg, Error};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::{
+    sync::{
+        mpsc::{self, UnboundedReceiver, UnboundedSender},
+        oneshot,
+    },
+    time::sleep,
+};
+use tracing::{debug, info, info_span, warn, Instrument, Span};
+
+type Reply = oneshot::Sender<Result<(), Error>>;
+
+/// How often the AVR's power is polled while waiting for it to turn on
+const POWER_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// What a command came from, as recorded in the history
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Source {
+    Alexa,
+    Google,
+    Homekit,
+    Schedule,
+    Idle,
+    Restore,
+    Api,
+    Socket,
+    Cli,
+    Telegram,
+}
+
+/// Command waiting to be processed, along with what it came from, who asked
+/// for it if known, the span it was added from, and the channel to send the
+/// result back on
+struct QueuedCommand {
+    cmd: AvrCommand,
+    source: Source,
+    user: Option<String>,
+    span: Span,
+    reply: Reply,
+}
+
+/// Command picked to be processed next, with the channels of every caller
+/// waiting on it
+struct Next {
+    cmd: AvrCommand,
+    source: Source,
+    user: Option<String>,
+    span: Span,
+    replies: Vec<Reply>,
+}
+
+/// Handle to the command queue, used by the skill to process commands. Cloned
+/// for each request.
+#[derive(Clone)]
+pub struct CommandQueue {
+    commands: UnboundedSender<QueuedCommand>,
+    queued: Arc<AtomicUsize>,
+    last_command: Arc<Mutex<Instant>>,
+}
+
+impl CommandQueue {
+    /// Add command to the queue and wait for it to be processed
+    pub async fn process(&self, cmd: AvrCommand, source: Source) -> Result<(), Error> {
+        self.queued.fetch_add(1, Ordering::SeqCst);
+        self.send(cmd, source)?.await?
+    }
+
+    /// Add command to the queue, returning the future to wait for it to be
+    /// processed, unless a command is already queued or being processed, in
+    /// which case it fails with `AvrError::Busy` straight away.
+    pub fn try_process(
+        &self,
+        cmd: AvrCommand,
+        source: Source,
+    ) -> Result<impl Future<Output = Result<(), Error>>, Error> {
+        if self.queued.fetch_add(1, Ordering::SeqCst) > 0 {
+            self.queued.fetch_sub(1, Ordering::SeqCst);
+            debug!("Queue busy, rejecting: {:?}", cmd);
+            return Err(AvrError::Busy.into());
+        }
+        let result = self.send(cmd, source)?;
+        Ok(async move { result.await? })
+    }
+
+    /// When the last command was added to the queue, or the queue started if
+    /// none has been
+    pub fn last_command(&self) -> Instant {
+        *self.last_command.lock().unwrap()
+    }
+
+    /// Send the command to the queue task, already counted in `queued`,
+    /// attributed to the user the calling task is processing a request from
+    fn send(
+        &self,
+        cmd: AvrCommand,
+        source: Source,
+    ) -> Result<oneshot::Receiver<Result<(), Error>>, Error> {
+        let (reply, result) = oneshot::channel();
+        *self.last_command.lock().unwrap() = Instant::now();
+        let queued = QueuedCommand {
+            cmd,
+            source,
+            user: audit::current_user(),
+            span: Span::current(),
+            reply,
+        };
+        if self.commands.send(queued).is_err() {
+            self.queued.fetch_sub(1, Ordering::SeqCst);
+            return Err(err_msg("Command queue is closed"));
+        }
+        Ok(result)
+    }
+}
+
+/// Where the commands processed against an AVR are recorded: the history and
+/// audit log, if they're kept, and the AVR's latencies
+#[derive(Clone)]
+pub struct Records {
+    pub history: Option<History>,
+    pub audit: Option<Audit>,
+    pub latency: Latencies,
+}
+
+/// Spawn a new task to process queued commands against the named AVR,
+/// returning the `CommandQueue` handle to add commands to it.
+pub fn run(
+    name: String,
+    conn: AvrConnection,
+    retry: RetryConfig,
+    power_on: PowerOnConfig,
+    records: Records,
+    quiet: Option<QuietHours>,
+    on_select: BTreeMap<u8, Vec<AvrCommand>>,
+) -> CommandQueue {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    let queued = Arc::new(AtomicUsize::new(0));
+    let processor = Processor {
+        name,
+        conn,
+        retry,
+        power_on,
+        records,
+        quiet,
+        on_select,
+    };
+
+    tokio::spawn(process_queue(receiver, processor, queued.clone()));
+
+    CommandQueue {
+        commands: sender,
+        queued,
+        last_command: Arc::new(Mutex::new(Instant::now())),
+    }
+}
+
+/// Everything needed to process commands against an AVR
+struct Processor {
+    name: String,
+    conn: AvrConnection,
+    retry: RetryConfig,
+    power_on: PowerOnConfig,
+    records: Records,
+    quiet: Option<QuietHours>,
+    /// Commands to send on selecting an input, by input number
+    on_select: BTreeMap<u8, Vec<AvrCommand>>,
+}
+
+/// Wait for commands, then process them one at a time until the queue is
+/// empty. `queued` counts the commands not yet answered.
+async fn process_queue(
+    mut receiver: UnboundedReceiver<QueuedCommand>,
+    processor: Processor,
+    queued: Arc<AtomicUsize>,
+) {
+    let mut pending = vec![];
+
+    loop {
+        if pending.is_empty() {
+            match receiver.recv().await {
+                Some(queued) => pending.push(queued),
+                None => return,
+            }
+        }
+        while let Ok(queued) = receiver.try_recv() {
+            pending.push(queued);
+        }
+
+        let Next {
+            cmd,
+            source,
+            user,
+            span,
+            replies,
+        } = next_command(&mut pending);
+        let span = info_span!(
+            parent: &span,
+            "avr_command",
+            device = %processor.name,
+            command = ?cmd
+        );
+        let result = processor
+            .process(cmd, source, user.as_deref())
+            .instrument(span)
+            .await;
+        let answered = replies.len();
+        send_result(result, replies);
+        queued.fetch_sub(answered, Ordering::SeqCst);
+    }
+}
+
+impl Processor {
+    /// Process the command, along with the commands to send on selecting the
+    /// input it changes to, if any. If one of those fails, the input is
+    /// changed back. `user` is who asked for it, if known.
+    async fn process(
+        &self,
+        cmd: AvrCommand,
+        source: Source,
+        user: Option<&str>,
+    ) -> Result<(), Error> {
+        let commands = match cmd {
+            AvrCommand::ChangeInput(n) => self.on_select.get(&n),
+            _ => None,
+        };
+        let commands = match commands {
+            Some(commands) => commands,
+            None => return self.process_one(cmd, source, user).await,
+        };
+
+        let protocol = self.conn.protocol();
+        let previous = self
+            .conn
+            .events()
+            .state()
+            .input
+            .and_then(|input| protocol.input_number(&input));
+        self.process_one(cmd, source, user).await?;
+
+        for on_select in commands {
+            let e = match self.process_one(on_select.clone(), source, user).await {
+                Ok(()) => continue,
+                Err(e) => e,
+            };
+            warn!("Could not send {:?} on selecting input: {}", on_select, e);
+            if let Some(previous) = previous {
+                info!("Changing back to input {}", previous);
+                let back = AvrCommand::ChangeInput(previous);
+                if let Err(e) = self.process_one(back, source, user).await {
+                    log_error(&e);
+                }
+            }
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Process the command, kept to the volume ceiling during quiet hours,
+    /// then add it to the history and the audit log
+    async fn process_one(
+        &self,
+        cmd: AvrCommand,
+        source: Source,
+        user: Option<&str>,
+    ) -> Result<(), Error> {
+        let cmd = match &self.quiet {
+            Some(quiet) => match quiet.limit(cmd, &self.conn) {
+                Some(cmd) => cmd,
+                None => return Ok(()),
+            },
+            None => cmd,
+        };
+        let before = self.conn.events().state();
+        let start = Instant::now();
+        let result = self.process_with_retry(&cmd).await;
+        if result.is_ok() && cmd == AvrCommand::PowerOn && self.power_on.settle > 0 {
+            debug!("Waiting {}ms for the AVR to settle", self.power_on.settle);
+            sleep(Duration::from_millis(self.power_on.settle)).await;
+        }
+
+        if let Some(audit) = &self.records.audit {
+            let entry = audit::Entry::new(
+                &self.name,
+                source,
+                user.map(str::to_owned),
+                cmd.clone(),
+                before.into(),
+                self.conn.events().state().into(),
+                result.as_ref().map(|_| ()),
+            );
+            if let Err(e) = audit.record(&entry) {
+                log_error(&e);
+            }
+        }
+
+        if let Some(history) = &self.records.history {
+            let entry = Entry::new(
+                &self.name,
+                source,
+                cmd,
+                result.as_ref().map(String::as_str),
+                start.elapsed(),
+            );
+            if let Err(e) = history.record(&entry) {
+                log_error(&e);
+            }
+        }
+        result.map(|_| ())
+    }
+
+    /// Process the command, retrying it if the AVR didn't answer or confirm
+    /// it, as many times as configured. A power command finding the AVR
+    /// already in the requested state on a retry went through on an earlier
+    /// attempt, and power on timing out while the AVR's address refuses the
+    /// connection or doesn't answer is taken for network standby being off.
+    async fn process_with_retry(&self, cmd: &AvrCommand) -> Result<String, Error> {
+        let retry = self.retry;
+        let mut attempt = 0;
+
+        loop {
+            let start = Instant::now();
+            let e = match avr::process(cmd.clone(), &self.conn).await {
+                Ok(response) => {
+                    let latency = start.elapsed();
+                    self.records.latency.record(&self.name, cmd, latency);
+                    return Ok(response);
+                }
+                Err(e) => e,
+            };
+            if *cmd == AvrCommand::PowerOn
+                && matches!(
+                    e.downcast_ref::<AvrError>(),
+                    Some(AvrError::Timeout) | Some(AvrError::ResponseDoesntMatch { .. })
+                )
+            {
+                if let Some(response) = self.await_power_on(start).await {
+                    let latency = start.elapsed();
+                    self.records.latency.record(&self.name, cmd, latency);
+                    return Ok(response);
+                }
+            }
+            match e.downcast_ref::<AvrError>() {
+                Some(AvrError::PowerAlreadyOn) | Some(AvrError::PowerAlreadyOff) if attempt > 0 => {
+                    return Ok(String::new())
+                }
+                Some(AvrError::Timeout) | Some(AvrError::ResponseDoesntMatch { .. })
+                    if attempt < retry.attempts => {}
+                // An AVR whose address refuses the connection or doesn't
+                // answer once it's in standby has its network turned off
+                // with it
+                Some(AvrError::Timeout)
+                    if *cmd == AvrCommand::PowerOn
+                        && self.conn.events().state().connected != Some(true)
+                        && self.conn.events().link().unreachable =>
+                {
+                    warn!(
+                        "Could not reach {} to turn it on, its network standby setting appears \
+                         to be off. Turn it on with NetworkStandbyOn while the AVR is on.",
+                        self.name
+                    );
+                    return Err(AvrError::NetworkStandbyOff.into());
+                }
+                _ => return Err(e),
+            }
+
+            let delay = Duration::from_millis(retry.delay.saturating_mul(1 << attempt.min(16)));
+            attempt += 1;
+            warn!(
+                "Retrying {:?} in {:?}, attempt {} of {}: {}",
+                cmd, delay, attempt, retry.attempts, e
+            );
+            sleep(delay).await;
+        }
+    }
+
+    /// Poll the AVR's power until it reports being on, for up to `max_wait`
+    /// after the power on command was `sent`, returning its answer. `None` if
+    /// it didn't turn on in time.
+    async fn await_power_on(&self, sent: Instant) -> Option<String> {
+        let deadline = sent + Duration::from_millis(self.power_on.max_wait);
+        while Instant::now() + POWER_POLL_INTERVAL < deadline {
+            sleep(POWER_POLL_INTERVAL).await;
+            match self.conn.query(AvrQuery::Power).await {
+                Ok(response)
+                    if self
+                        .conn
+                        .protocol()
+                        .matches(&AvrCommand::PowerOn, &response) =>
+                {
+                    info!("AVR reported being on after {:?}", sent.elapsed());
+                    return Some(response);
+                }
+                Ok(_) => debug!("AVR not on yet, polling again"),
+                Err(e) => debug!("Could not poll AVR's power: {}", e),
+            }
+        }
+        warn!(
+            "AVR didn't report being on within {}ms",
+            self.power_on.max_wait
+        );
+        None
+    }
+}
+
+/// Take the next command to process out of the pending commands.
+///
+/// Power commands are taken first, otherwise the oldest command. If it sets
+/// the volume, all other pending commands setting it are removed and the
+/// latest value is used instead, along with its source, user and span.
+fn next_command(pending: &mut Vec<QueuedCommand>) -> Next {
+    let index = pending
+        .iter()
+        .position(|queued| queued.cmd.is_power())
+        .unwrap_or(0);
+    let QueuedCommand {
+        mut cmd,
+        mut source,
+        mut user,
+        mut span,
+        reply,
+    } = pending.remove(index);
+    let mut replies = vec![reply];
+
+    let sets_volume =
+        |cmd: &AvrCommand| matches!(cmd, AvrCommand::SetVolume(_) | AvrCommand::SetVolumeDb(_));
+    if sets_volume(&cmd) {
+        let mut i = 0;
+        while i < pending.len() {
+            if sets_volume(&pending[i].cmd) {
+                let queued = pending.remove(i);
+                debug!("Coalescing {:?} into {:?}", cmd, queued.cmd);
+                cmd = queued.cmd;
+                source = queued.source;
+                user = queued.user;
+                span = queued.span;
+                replies.push(queued.reply);
+            } else {
+                i += 1;
+            }
+        }
+    }
+
+    Next {
+        cmd,
+        source,
+        user,
+        span,
+        replies,
+    }
+}
+
+/// Send the result to every caller waiting on it.
+///
+/// `Error` can't be cloned, so coalesced callers get a copy: `AvrError` keeps
+/// its type so the right speech can be chosen, anything else is converted to
+/// a message.
+fn send_result(result: Result<(), Error>, replies: Vec<Reply>) {
+    let mut replies = replies.into_iter();
+    let first = replies.next();
+
+    for reply in replies {
+        let copy = match &result {
+            Ok(()) => Ok(()),
+            Err(e) => match e.downcast_ref::<AvrError>() {
+                Some(e) => Err(e.clone().into()),
+                None => Err(err_msg(e.to_string())),
+            },
+        };
+        let _ = reply.send(copy);
+    }
+
+    if let Some(reply) = first {
+        let _ = reply.send(result);
+    }
+}
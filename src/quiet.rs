@@ -0,0 +1,107 @@
+/// This module keeps the volume down during the configured quiet hours.
+///
+/// Between `start` and `end`, local time, the volume can't be set above
+/// `max_volume`, on the same 1 to 10 scale as the Volume intent. Each AVR's
+/// command queue lowers a `SetVolume` above it to the ceiling, and drops a
+/// `VolumeUp` that would go over it, whatever the command came from. The
+/// skill also says why the volume isn't what was asked for.
+use crate::{avr::AvrCommand, config::QuietHoursConfig, telnet::AvrConnection};
+use chrono::{Local, NaiveTime, Timelike};
+use failure::{bail, format_err, Error};
+use tracing::info;
+
+/// Parsed quiet hours
+#[derive(Clone, Copy, Debug)]
+pub struct QuietHours {
+    start: NaiveTime,
+    end: NaiveTime,
+    max_volume: u8,
+}
+
+impl QuietHours {
+    pub fn new(config: &QuietHoursConfig) -> Result<QuietHours, Error> {
+        let parse = |time: &str| match NaiveTime::parse_from_str(time, "%H:%M") {
+            Ok(time) => Ok(time),
+            Err(_) => Err(format_err!(
+                "Invalid time for quiet hours, expected HH:MM: {}",
+                time
+            )),
+        };
+        if config.max_volume < 1 || config.max_volume > 10 {
+            bail!("Quiet hours max_volume not between 1 and 10");
+        }
+
+        Ok(QuietHours {
+            start: parse(&config.start)?,
+            end: parse(&config.end)?,
+            max_volume: config.max_volume,
+        })
+    }
+
+    /// Whether `time` is within quiet hours, which can span midnight
+    fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+
+    /// Highest volume allowed right now, if it's quiet hours
+    pub fn ceiling(&self) -> Option<u8> {
+        if self.contains(Local::now().time()) {
+            Some(self.max_volume)
+        } else {
+            None
+        }
+    }
+
+    /// When quiet hours start, to be spoken, like "10pm" or "10:30pm"
+    pub fn start(&self) -> String {
+        let (pm, hour) = self.start.hour12();
+        let suffix = if pm { "pm" } else { "am" };
+        match self.start.minute() {
+            0 => format!("{}{}", hour, suffix),
+            minute => format!("{}:{:02}{}", hour, minute, suffix),
+        }
+    }
+
+    /// The command kept to the ceiling, if it's quiet hours. `None` if it's a
+    /// `VolumeUp` that would go over it, so it mustn't be sent.
+    pub fn limit(&self, cmd: AvrCommand, conn: &AvrConnection) -> Option<AvrCommand> {
+        let max = match self.ceiling() {
+            Some(max) => max,
+            None => return Some(cmd),
+        };
+
+        match cmd {
+            AvrCommand::SetVolume(n) if n > max => {
+                info!("Quiet hours, lowering volume {} to {}", n, max);
+                Some(AvrCommand::SetVolume(max))
+            }
+            AvrCommand::SetVolumeDb(db) => {
+                let protocol = conn.protocol();
+                match protocol.db_level(db) {
+                    Some(level) if level > protocol.volume_level(max) => {
+                        info!("Quiet hours, lowering volume {} dB to {}", db, max);
+                        Some(AvrCommand::SetVolume(max))
+                    }
+                    _ => Some(cmd),
+                }
+            }
+            AvrCommand::VolumeUp => {
+                let protocol = conn.protocol();
+                let level = conn.events().state().volume.unwrap_or_default();
+                if u16::from(level) + u16::from(protocol.volume_step())
+                    > u16::from(protocol.volume_level(max))
+                {
+                    info!("Quiet hours, not turning volume up past {}", max);
+                    None
+                } else {
+                    Some(cmd)
+                }
+            }
+            cmd => Some(cmd),
+        }
+    }
+}
@@ -0,0 +1,69 @@
+/// This module sends codes to an AVR as they are, for trying out codes this
+/// skill doesn't know, like undocumented ones.
+///
+/// Only the codes configured for the AVR can be sent, none by default, each
+/// under a name, like "speaker status" for `?SPK`. The Alexa intent takes the
+/// name, matched the same way as input names, and the control API's
+/// `/api/raw` route the code itself. Either way the code is sent straight to
+/// the AVR, not through its command queue, and whatever it answers within the
+/// response window is sent back.
+use crate::{aliases, telnet::AvrConnection};
+use failure::{Error, Fail};
+use std::collections::BTreeMap;
+use tracing::info;
+
+/// Error sending a raw code
+#[derive(Fail, Debug)]
+pub enum RawError {
+    #[fail(display = "Code isn't one that can be sent: {:?}", code)]
+    NotAllowed { code: String },
+}
+
+/// Codes that can be sent to an AVR, along with their normalized names.
+/// Cloned for each request.
+#[derive(Clone)]
+pub struct RawCodes {
+    codes: Vec<(String, String)>,
+    conn: AvrConnection,
+}
+
+impl RawCodes {
+    /// The configured codes, by name, to be sent on the AVR's connection
+    pub fn new(codes: &BTreeMap<String, String>, conn: AvrConnection) -> RawCodes {
+        let codes = codes
+            .iter()
+            .map(|(name, code)| (aliases::normalize(name), code.trim().to_owned()))
+            .collect();
+        RawCodes { codes, conn }
+    }
+
+    /// Code going by the spoken name, or the closest to it
+    pub fn resolve(&self, spoken: &str) -> Option<&str> {
+        let names = self
+            .codes
+            .iter()
+            .map(|(normalized, code)| (normalized.as_str(), code.as_str()));
+        aliases::closest(names, spoken)
+    }
+
+    /// Send the code to the AVR, if it's one of the configured codes,
+    /// returning its response with each message on its own line. Nothing is
+    /// sent in dry run mode.
+    pub async fn send(&self, code: &str) -> Result<String, Error> {
+        let code = code.trim();
+        if !self.codes.iter().any(|(_, allowed)| allowed == code) {
+            return Err(RawError::NotAllowed {
+                code: code.to_owned(),
+            }
+            .into());
+        }
+        if self.conn.dry_run() {
+            info!("Dry run, not sending raw code: {:?}", code);
+            return Ok(String::new());
+        }
+
+        info!("Sending raw code: {:?}", code);
+        let response = self.conn.send(&format!("{}\r", code), None).await?;
+        Ok(response.trim_end().replace("\r\n", "\n"))
+    }
+}
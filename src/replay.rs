@@ -0,0 +1,165 @@
+/// This module records the codes sent to the AVR over telnet and the
+/// responses received back, and replays those recordings as a stand-in AVR.
+///
+/// A transcript is a file with one `Exchange` per line, as JSON. The telnet
+/// task appends every exchange to it when started with a `Recorder`. Serving
+/// a transcript with `serve` listens for telnet connections like an AVR would,
+/// and answers each code written to it with the recorded response, so the
+/// skill can be run against a real AVR's behaviour without the AVR. The tests
+/// in `tests/` use this to check `avr.rs` against transcripts of real AVRs.
+use failure::{Error, Fail, ResultExt};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    fs::{self, File, OpenOptions},
+    io::Write,
+    net::SocketAddr,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tracing::{debug, info, warn};
+
+/// Code written to the AVR and the response it sent back
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Exchange {
+    pub code: String,
+    pub response: String,
+}
+
+/// Appends each exchange with the AVR to a transcript file. Cloned for each
+/// telnet task.
+#[derive(Clone)]
+pub struct Recorder {
+    file: Arc<Mutex<File>>,
+}
+
+impl Recorder {
+    /// Open the transcript file, creating it if needed. Exchanges are added to
+    /// the end of an existing transcript.
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Recorder, Error> {
+        let path = path.as_ref();
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| e.context(format!("Could not open transcript: {}", path.display())))?;
+        info!("Recording AVR exchanges to: {}", path.display());
+
+        Ok(Recorder {
+            file: Arc::new(Mutex::new(file)),
+        })
+    }
+
+    /// Add the exchange to the end of the transcript
+    pub fn record(&self, code: &str, response: &str) -> Result<(), Error> {
+        let mut line = serde_json::to_string(&Exchange {
+            code: code.to_owned(),
+            response: response.to_owned(),
+        })?;
+        line.push('\n');
+
+        self.file
+            .lock()
+            .unwrap()
+            .write_all(line.as_bytes())
+            .context("Could not write to transcript")?;
+        Ok(())
+    }
+}
+
+/// Load the exchanges of a transcript file, in order
+pub fn load<P: AsRef<Path>>(path: P) -> Result<Vec<Exchange>, Error> {
+    let path = path.as_ref();
+    let transcript = fs::read_to_string(path)
+        .map_err(|e| e.context(format!("Could not read transcript: {}", path.display())))?;
+
+    let mut exchanges = vec![];
+    for line in transcript.lines().filter(|line| !line.trim().is_empty()) {
+        exchanges.push(
+            serde_json::from_str(line)
+                .map_err(|e| e.context(format!("Invalid transcript: {}", path.display())))?,
+        );
+    }
+    Ok(exchanges)
+}
+
+/// Listen on `addr` as a stand-in AVR, replaying the exchanges in order, and
+/// return the address it's listening on. Port 0 picks any free port.
+///
+/// Each code written to it is answered with the response of the next
+/// exchange, as long as the code matches the one recorded. Anything else, or
+/// anything written once the transcript is used up, gets no response, which
+/// the skill will treat as the AVR not responding. Connections are accepted
+/// one at a time, carrying on where the last one left off.
+pub async fn serve(addr: SocketAddr, exchanges: Vec<Exchange>) -> Result<SocketAddr, Error> {
+    let listener = crate::addr::listener(addr)
+        .and_then(|listener| Ok(TcpListener::from_std(listener)?))
+        .context("Could not listen for telnet connections")?;
+    let addr = listener.local_addr()?;
+    info!("Replaying {} AVR exchanges on: {}", exchanges.len(), addr);
+
+    tokio::spawn(async move {
+        let mut exchanges = VecDeque::from(exchanges);
+        loop {
+            match listener.accept().await {
+                Ok((conn, peer)) => {
+                    debug!("Replay connection from: {}", peer);
+                    if let Err(e) = replay(conn, &mut exchanges).await {
+                        debug!("Replay connection closed: {}", e);
+                    }
+                }
+                Err(e) => warn!("Could not accept replay connection: {}", e),
+            }
+        }
+    });
+
+    Ok(addr)
+}
+
+/// Answer codes written to the connection until it's closed. Codes can be
+/// split over several reads, so they're collected until they're as long as
+/// the code expected next.
+async fn replay(mut conn: TcpStream, exchanges: &mut VecDeque<Exchange>) -> Result<(), Error> {
+    let mut buf = [0; 256];
+    let mut received = String::new();
+
+    loop {
+        let n = conn.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        received.push_str(&String::from_utf8_lossy(&buf[..n]));
+
+        let expected = match exchanges.front() {
+            Some(exchange) => exchange.code.clone(),
+            None => {
+                warn!("Transcript used up, not answering: {:?}", received);
+                received.clear();
+                continue;
+            }
+        };
+        if received.len() < expected.len() && expected.starts_with(received.as_str()) {
+            continue;
+        }
+
+        if received == expected {
+            if let Some(exchange) = exchanges.pop_front() {
+                debug!(
+                    "Replaying response to {:?}: {:?}",
+                    exchange.code, exchange.response
+                );
+                conn.write_all(exchange.response.as_bytes()).await?;
+            }
+        } else {
+            warn!(
+                "Code doesn't match transcript, expected {:?} but got: {:?}",
+                expected, received
+            );
+        }
+        received.clear();
+    }
+}
@@ -0,0 +1,68 @@
+/// This module resolves the AVR's host to its addresses.
+///
+/// The host is resolved again on every connection attempt, so an AVR that
+/// gets a new address from DHCP is found again once the old one stops
+/// answering. Hosts ending in `.local` are resolved with mDNS if the system
+/// resolver can't resolve them. IPv6 literals can be given in brackets.
+use crate::addr;
+use failure::{bail, Error, Fail, ResultExt};
+use mdns_sd::{HostnameResolutionEvent, ServiceDaemon};
+use std::net::SocketAddr;
+use tokio::net;
+use tracing::debug;
+
+/// How long to wait for an mDNS response, in milliseconds
+const MDNS_TIMEOUT: u64 = 3_000;
+
+/// Resolve host to the addresses to try connecting to, in order
+pub async fn resolve(host: &str, port: u16) -> Result<Vec<SocketAddr>, Error> {
+    let host = addr::unbracket(host);
+    let system = net::lookup_host((host, port)).await;
+    let is_mdns = host.trim_end_matches('.').ends_with(".local");
+
+    match system {
+        Ok(addrs) => {
+            let addrs: Vec<_> = addrs.collect();
+            if !addrs.is_empty() || !is_mdns {
+                return Ok(addrs);
+            }
+        }
+        Err(e) if !is_mdns => {
+            return Err(e
+                .context(format!("Could not resolve AVR host: {}", host))
+                .into())
+        }
+        Err(e) => debug!("System resolver couldn't resolve {}: {}", host, e),
+    }
+
+    resolve_mdns(host, port).await
+}
+
+/// Resolve a `.local` host by querying for it with mDNS
+async fn resolve_mdns(host: &str, port: u16) -> Result<Vec<SocketAddr>, Error> {
+    let hostname = format!("{}.", host.trim_end_matches('.'));
+    debug!("Resolving via mDNS: {}", hostname);
+
+    let daemon = ServiceDaemon::new().context("Could not start mDNS resolver")?;
+    let events = daemon
+        .resolve_hostname(&hostname, Some(MDNS_TIMEOUT))
+        .context(format!("Could not resolve AVR host via mDNS: {}", host))?;
+
+    let mut addrs = vec![];
+    while let Ok(event) = events.recv_async().await {
+        match event {
+            HostnameResolutionEvent::AddressesFound(_, found) => {
+                addrs.extend(found.into_iter().map(|ip| SocketAddr::new(ip, port)));
+                break;
+            }
+            HostnameResolutionEvent::SearchTimeout(_) => break,
+            _ => {}
+        }
+    }
+    let _ = daemon.shutdown();
+
+    if addrs.is_empty() {
+        bail!("Could not resolve AVR host via mDNS: {}", host);
+    }
+    Ok(addrs)
+}
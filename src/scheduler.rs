@@ -0,0 +1,360 @@
+/// This module sends commands to the AVRs at set times of day, configured as
+/// `[[schedule]]` jobs, such as turning the receiver off at 23:30 on
+/// weekdays or switching to the tuner at 07:00.
+///
+/// Each enabled job gets a task that sleeps until the job's next time, in
+/// local time, and then sends its command through the AVR's command queue,
+/// the same as a command from the skill, so it's retried and added to the
+/// history the same way.
+///
+/// Commands can also be sent once after a delay, like the unmute at the end of
+/// a timed mute, or a command on a timer, like turning the receiver off in 45
+/// minutes, see `Timers`. With a `[timers]` file, commands on a timer are kept
+/// in it, so they're still sent after a restart:
+///
+/// ```json
+/// {"living room": {"at": 1571227200, "command": "PowerOff"}}
+/// ```
+use crate::{
+    avr::AvrCommand,
+    config::{ScheduleConfig, TimersConfig},
+    device::{Device, Devices},
+    events::{AvrEvent, EventBus},
+    queue::{CommandQueue, Source},
+};
+use chrono::{DateTime, Datelike, Duration, Local, NaiveTime, TimeZone, Weekday};
+use failure::{bail, format_err, Error, ResultExt};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    convert::TryFrom,
+    fs, future,
+    path::Path,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time,
+};
+use tokio::{sync::broadcast, task::JoinHandle, time::sleep};
+use tracing::{info, warn};
+
+/// Parsed schedule job
+struct Job {
+    name: String,
+    at: NaiveTime,
+    days: Vec<Weekday>,
+    command: AvrCommand,
+}
+
+impl Job {
+    /// First time the job runs after `after`
+    fn next_run(&self, after: DateTime<Local>) -> Option<DateTime<Local>> {
+        // A week and a day covers every day of the week, even if the time has
+        // already passed today
+        (0..=7)
+            .filter_map(|days| after.date_naive().checked_add_signed(Duration::days(days)))
+            .filter(|date| self.days.is_empty() || self.days.contains(&date.weekday()))
+            .filter_map(|date| {
+                Local
+                    .from_local_datetime(&date.and_time(self.at))
+                    .earliest()
+            })
+            .find(|time| *time > after)
+    }
+}
+
+/// Check the schedule and start a task for each enabled job
+pub fn run(schedules: Vec<ScheduleConfig>, devices: &Devices) -> Result<(), Error> {
+    let mut jobs = vec![];
+
+    for schedule in schedules {
+        let at = match NaiveTime::parse_from_str(&schedule.at, "%H:%M") {
+            Ok(at) => at,
+            Err(_) => bail!(
+                "Invalid time for schedule {:?}, expected HH:MM: {}",
+                schedule.name,
+                schedule.at
+            ),
+        };
+        let mut days = vec![];
+        for day in &schedule.days {
+            days.extend(parse_days(day).ok_or_else(|| {
+                format_err!("Unknown day for schedule {:?}: {}", schedule.name, day)
+            })?);
+        }
+        let device = match devices.get(schedule.device.as_deref()) {
+            Some(device) => device.clone(),
+            None => bail!(
+                "No AVR named {:?} for schedule {:?}",
+                schedule.device.unwrap_or_default(),
+                schedule.name
+            ),
+        };
+
+        if !schedule.enabled {
+            info!("Schedule {:?} is disabled", schedule.name);
+            continue;
+        }
+        let job = Job {
+            name: schedule.name,
+            at,
+            days,
+            command: schedule.command,
+        };
+        jobs.push((job, device));
+    }
+
+    for (job, device) in jobs {
+        tokio::spawn(schedule(job, device));
+    }
+
+    Ok(())
+}
+
+/// Days a `days` entry stands for: a day of the week, `weekdays` or
+/// `weekends`
+fn parse_days(day: &str) -> Option<Vec<Weekday>> {
+    use Weekday::*;
+
+    match day.to_lowercase().as_str() {
+        "weekdays" => Some(vec![Mon, Tue, Wed, Thu, Fri]),
+        "weekends" => Some(vec![Sat, Sun]),
+        day => Weekday::from_str(day).ok().map(|day| vec![day]),
+    }
+}
+
+/// Send the job's command to the AVR each time it's due
+async fn schedule(job: Job, device: Device) {
+    let mut after = Local::now();
+
+    while let Some(next) = job.next_run(after) {
+        info!(
+            "Schedule {:?} next runs at {}",
+            job.name,
+            next.format("%a %Y-%m-%d %H:%M")
+        );
+        sleep((next - Local::now()).to_std().unwrap_or_default()).await;
+
+        info!(
+            "Running schedule {:?}: {:?} on {}",
+            job.name, job.command, device.name
+        );
+        if let Err(e) = device
+            .queue
+            .process(job.command.clone(), Source::Schedule)
+            .await
+        {
+            warn!("Schedule {:?} failed: {}", job.name, e);
+        }
+
+        after = next.max(Local::now());
+    }
+}
+
+/// Commands waiting to be sent to an AVR once, after a delay: the unmute
+/// ending a timed mute, and a command on a timer, along with the file timers
+/// are kept in, if any. Cloned for each request, sharing the waiting commands.
+#[derive(Clone, Default)]
+pub struct Timers {
+    unmute: Arc<Mutex<Option<JoinHandle<()>>>>,
+    timer: Arc<Mutex<Option<JoinHandle<()>>>>,
+    file: Arc<Mutex<Option<Arc<TimerFile>>>>,
+}
+
+/// Command on a timer, sent at `at`, in seconds since the Unix epoch
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct Timer {
+    at: i64,
+    command: AvrCommand,
+}
+
+/// Commands on a timer by AVR name, and the JSON file they're kept in. Shared
+/// by the AVRs.
+struct TimerFile {
+    path: String,
+    timers: Mutex<BTreeMap<String, Timer>>,
+}
+
+impl TimerFile {
+    /// Keep the AVR's timer, or remove it if `None`, then write the file
+    fn save(&self, device: &str, timer: Option<Timer>) -> Result<(), Error> {
+        let mut timers = self.timers.lock().unwrap();
+        match timer {
+            Some(timer) => timers.insert(device.to_owned(), timer),
+            None => timers.remove(device),
+        };
+
+        let contents = serde_json::to_string_pretty(&*timers)?;
+        fs::write(&self.path, contents)
+            .context(format!("Could not write timers: {}", self.path))?;
+        Ok(())
+    }
+}
+
+impl Timers {
+    /// Unmute the AVR after `delay`, in place of any unmute already waiting.
+    /// It's cancelled if the AVR is unmuted before then, whatever unmuted it.
+    pub fn unmute_after(&self, delay: time::Duration, queue: CommandQueue, events: &EventBus) {
+        let mut events = events.subscribe();
+        let task = tokio::spawn(async move {
+            tokio::select! {
+                _ = sleep(delay) => {}
+                _ = unmuted(&mut events) => {
+                    info!("Unmuted before the timed mute ended");
+                    return;
+                }
+            }
+            info!("Timed mute ended, unmuting");
+            if let Err(e) = queue.process(AvrCommand::Unmute, Source::Schedule).await {
+                warn!("Could not unmute after timed mute: {}", e);
+            }
+        });
+
+        if let Some(waiting) = self.unmute.lock().unwrap().replace(task) {
+            waiting.abort();
+        }
+    }
+
+    /// Cancel the unmute waiting to be sent, if any
+    pub fn cancel_unmute(&self) {
+        if let Some(waiting) = self.unmute.lock().unwrap().take() {
+            waiting.abort();
+        }
+    }
+
+    /// Send the command to the AVR at `at`, in place of any command already
+    /// on a timer
+    pub fn start(&self, device: &Device, at: DateTime<Local>, command: AvrCommand) {
+        info!(
+            "Timer for {}: {:?} at {}",
+            device.name,
+            command,
+            at.format("%a %Y-%m-%d %H:%M:%S")
+        );
+        self.set(
+            device,
+            Timer {
+                at: at.timestamp(),
+                command,
+            },
+        );
+    }
+
+    /// Cancel the command on a timer, returning whether there was one
+    pub fn cancel(&self, device: &Device) -> bool {
+        match self.timer.lock().unwrap().take() {
+            Some(waiting) => waiting.abort(),
+            None => return false,
+        }
+        info!("Timer for {} cancelled", device.name);
+        self.save(&device.name, None);
+        true
+    }
+
+    fn set(&self, device: &Device, timer: Timer) {
+        let task = tokio::spawn(run_timer(self.clone(), device.clone(), timer.clone()));
+        if let Some(waiting) = self.timer.lock().unwrap().replace(task) {
+            waiting.abort();
+        }
+        self.save(&device.name, Some(timer));
+    }
+
+    /// Keep the AVR's timer in the file, if there is one
+    fn save(&self, device: &str, timer: Option<Timer>) {
+        if let Some(file) = &*self.file.lock().unwrap() {
+            if let Err(e) = file.save(device, timer) {
+                warn!("{}", e);
+            }
+        }
+    }
+}
+
+/// Wait for the timer, then send its command through the AVR's command queue
+async fn run_timer(timers: Timers, device: Device, timer: Timer) {
+    let wait = u64::try_from(timer.at - Local::now().timestamp()).unwrap_or_default();
+    sleep(time::Duration::from_secs(wait)).await;
+
+    timers.timer.lock().unwrap().take();
+    timers.save(&device.name, None);
+    info!("Timer up, sending {:?} to {}", timer.command, device.name);
+    if let Err(e) = device.queue.process(timer.command, Source::Schedule).await {
+        warn!("Timer for {} failed: {}", device.name, e);
+    }
+}
+
+/// Keep the commands on a timer in the configured file, and start the timers
+/// left in it by the last run. Those already due are sent right away.
+pub fn restore(config: TimersConfig, devices: &Devices) -> Result<(), Error> {
+    let path = config.path;
+    let saved: BTreeMap<String, Timer> = if Path::new(&path).exists() {
+        let contents =
+            fs::read_to_string(&path).context(format!("Could not read timers: {}", path))?;
+        serde_json::from_str(&contents).context(format!("Could not parse timers: {}", path))?
+    } else {
+        BTreeMap::new()
+    };
+
+    let file = Arc::new(TimerFile {
+        path,
+        timers: Mutex::default(),
+    });
+    for device in devices.iter() {
+        *device.timers.file.lock().unwrap() = Some(file.clone());
+    }
+    for (name, timer) in saved {
+        match devices.get(Some(&name)) {
+            Some(device) => device.timers.set(device, timer),
+            None => warn!("No AVR named {:?} for timer {:?}", name, timer.command),
+        }
+    }
+    Ok(())
+}
+
+/// Wait for the AVR to report it's unmuted
+async fn unmuted(events: &mut broadcast::Receiver<AvrEvent>) {
+    loop {
+        match events.recv().await {
+            Ok(AvrEvent::Mute(false)) => return,
+            Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+            Err(broadcast::error::RecvError::Closed) => future::pending().await,
+        }
+    }
+}
+
+/// Length of an ISO 8601 duration, the way Alexa gives `AMAZON.DURATION` slot
+/// values, like `PT5M` or `PT1H30M`. Years, months and weeks aren't taken.
+pub fn parse_duration(value: &str) -> Option<time::Duration> {
+    let value = value.trim().strip_prefix('P')?;
+    let (date, time) = match value.split_once('T') {
+        Some((date, time)) => (date, Some(time)),
+        None => (value, None),
+    };
+
+    let mut seconds = 0;
+    let mut parse = |part: &str, units: &[(char, u64)]| -> Option<()> {
+        let mut number = String::new();
+        for c in part.chars() {
+            if c.is_ascii_digit() {
+                number.push(c);
+                continue;
+            }
+            let (_, unit) = units.iter().find(|(name, _)| *name == c)?;
+            seconds += number.parse::<u64>().ok()? * unit;
+            number.clear();
+        }
+        if number.is_empty() {
+            Some(())
+        } else {
+            None
+        }
+    };
+    parse(date, &[('D', 86_400)])?;
+    parse(
+        time.unwrap_or_default(),
+        &[('H', 3_600), ('M', 60), ('S', 1)],
+    )?;
+
+    match seconds {
+        0 => None,
+        seconds => Some(time::Duration::from_secs(seconds)),
+    }
+}
@@ -0,0 +1,99 @@
+/// This module runs named sequences of commands, like the keys to press on the
+/// Home Media Gallery's menu to get to an internet radio favorite.
+///
+/// Sequences are named in the config, and run with the Sequence intent, like
+/// "play internet radio favorite three". Each command is sent through the AVR's
+/// command queue in turn, waiting between them for on-screen menus to catch
+/// up, and the sequence stops at the first command that fails. Names are
+/// matched the same way as input names.
+use crate::{
+    aliases,
+    avr::AvrCommand,
+    protocol::AvrProtocol,
+    queue::{CommandQueue, Source},
+};
+use failure::{bail, Error};
+use std::{collections::BTreeMap, time::Duration};
+use tokio::time::sleep;
+use tracing::info;
+
+/// Delay between the commands of a sequence, if not configured
+const DEFAULT_DELAY: Duration = Duration::from_millis(1_000);
+
+/// Named sequences of an AVR: the normalized name, the name as configured,
+/// and the commands
+#[derive(Clone, Debug, Default)]
+pub struct Sequences {
+    sequences: Vec<(String, String, Vec<AvrCommand>)>,
+    delay: Duration,
+}
+
+impl Sequences {
+    /// The configured sequences, commands by name, checking the AVR supports
+    /// every command. `delay` is in milliseconds.
+    pub fn new(
+        protocol: &dyn AvrProtocol,
+        sequences: &BTreeMap<String, Vec<AvrCommand>>,
+        delay: Option<u64>,
+    ) -> Result<Sequences, Error> {
+        let mut named = vec![];
+        for (name, commands) in sequences {
+            if commands.is_empty() {
+                bail!("No commands in sequence {:?}", name);
+            }
+            if let Some(cmd) = commands.iter().find(|cmd| !protocol.supports(cmd)) {
+                bail!("AVR doesn't support {:?} in sequence {:?}", cmd, name);
+            }
+            named.push((aliases::normalize(name), name.clone(), commands.clone()));
+        }
+
+        Ok(Sequences {
+            sequences: named,
+            delay: delay.map_or(DEFAULT_DELAY, Duration::from_millis),
+        })
+    }
+
+    /// Names of the sequences, as configured
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.sequences.iter().map(|(_, name, _)| name.as_str())
+    }
+
+    /// Name as configured of the sequence going by the spoken name, or the
+    /// closest to it
+    pub fn resolve(&self, spoken: &str) -> Option<&str> {
+        let names = self
+            .sequences
+            .iter()
+            .map(|(normalized, name, _)| (normalized.as_str(), name.as_str()));
+        aliases::closest(names, spoken)
+    }
+
+    /// Commands of the sequence named as configured
+    pub fn commands(&self, name: &str) -> Option<&[AvrCommand]> {
+        self.sequences
+            .iter()
+            .find(|(_, named, _)| named == name)
+            .map(|(_, _, commands)| commands.as_slice())
+    }
+
+    /// Send the named sequence's commands through the queue in turn, stopping
+    /// at the first that fails. The commands are given, as they can be
+    /// adjusted for whoever runs it, like a volume lowered to their limit.
+    pub async fn run(
+        &self,
+        name: &str,
+        commands: &[AvrCommand],
+        queue: &CommandQueue,
+        source: Source,
+    ) -> Result<(), Error> {
+        info!("Running sequence {:?}", name);
+
+        for (i, cmd) in commands.iter().enumerate() {
+            if i > 0 {
+                sleep(self.delay).await;
+            }
+            queue.process(cmd.clone(), source).await?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,219 @@
+/// This module implements the `setup` subcommand, a wizard writing the config
+/// file for an AVR by asking questions, rather than editing it by hand.
+///
+/// The AVR is found on the network, see `crate::discovery`, or its host asked
+/// for if none is. Once connected, it's turned on and muted, and each input
+/// is selected in turn, asking whether the AVR actually changed to it. Inputs
+/// the AVR rejects or that didn't change are hidden, and the others can be
+/// given a friendlier name to ask for them by. The AVR is put back on the
+/// input it was on, unmuted, and the config file is written, with every other
+/// setting explained as `init` writes them.
+use crate::{
+    avr::{self, AvrCommand, AvrQuery},
+    config::{self, Config},
+    discovery,
+    events::EventBus,
+    pioneer::Pioneer,
+    protocol::AvrProtocol,
+    telnet::{self, AvrConnection, Backoff},
+};
+use failure::{bail, Error, ResultExt};
+use std::{
+    collections::BTreeMap,
+    io::{self, Write},
+    path::Path,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{task, time::sleep};
+
+/// How long the AVR gets to turn on before giving up
+const POWER_ON_WAIT: Duration = Duration::from_secs(10);
+
+/// How long to poll the power between, while it's turning on
+const POWER_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Ask the questions and write the config file to path, unless there's a
+/// file there already. Discovery waits `wait` for AVRs to answer.
+pub async fn run(path: &str, port: u16, backoff: Backoff, wait: Duration) -> Result<(), Error> {
+    if Path::new(path).exists() {
+        bail!("Config file already exists: {}", path);
+    }
+
+    let (host, found_name) = find(wait).await?;
+    let port = ask_number("Telnet port of the AVR", port).await?;
+    let name = ask(
+        "Name to ask for the AVR by",
+        found_name.as_deref().unwrap_or("receiver"),
+    )
+    .await?;
+
+    let protocol: Arc<dyn AvrProtocol> = Arc::new(Pioneer);
+    let conn = telnet::run(
+        host.clone(),
+        port,
+        backoff,
+        protocol.clone(),
+        EventBus::new(protocol),
+        false,
+        None,
+    );
+    avr::refresh(&conn)
+        .await
+        .with_context(|_| format!("Could not connect to AVR: {}:{}", host, port))?;
+    power_on(&conn).await?;
+
+    let (hidden, aliases) = test_inputs(&conn).await?;
+
+    let contents = config::generated(&name, &host, port, &hidden, &aliases);
+    toml::from_str::<Config>(&contents).context("Generated config file is invalid")?;
+    std::fs::write(path, contents).context(format!("Could not write config file: {}", path))?;
+    println!("Wrote config file: {}", path);
+    Ok(())
+}
+
+/// Host of the AVR, picked from those found on the network or typed in, and
+/// its name on the network if it was found
+async fn find(wait: Duration) -> Result<(String, Option<String>), Error> {
+    println!("Searching the network for AVRs...");
+    let receivers = discovery::discover(wait).await?;
+    if receivers.is_empty() {
+        println!("No AVRs found. Make sure it's on and on the same network.");
+        let host = ask("Host or IP address of the AVR", "").await?;
+        if host.is_empty() {
+            bail!("No host given for the AVR");
+        }
+        return Ok((host, None));
+    }
+
+    for (i, receiver) in receivers.iter().enumerate() {
+        println!(
+            "  {}. {} ({}) at {}",
+            i + 1,
+            receiver.name,
+            receiver.model,
+            receiver.host
+        );
+    }
+    let choice = ask("Number of the AVR to set up, or its host", "1").await?;
+    match choice.parse::<usize>() {
+        Ok(n) if (1..=receivers.len()).contains(&n) => {
+            let receiver = &receivers[n - 1];
+            Ok((receiver.host.to_string(), Some(receiver.name.clone())))
+        }
+        _ => Ok((choice, None)),
+    }
+}
+
+/// Turn the AVR on if it's off, waiting until it reports being on
+async fn power_on(conn: &AvrConnection) -> Result<(), Error> {
+    if conn.events().state().power != Some(false) {
+        return Ok(());
+    }
+    println!("Turning the AVR on...");
+    // It often doesn't answer until it's done turning on
+    let _ = avr::process(AvrCommand::PowerOn, conn).await;
+
+    let mut waited = Duration::from_secs(0);
+    while conn.events().state().power != Some(true) {
+        if waited >= POWER_ON_WAIT {
+            bail!("AVR didn't turn on");
+        }
+        sleep(POWER_POLL_INTERVAL).await;
+        waited += POWER_POLL_INTERVAL;
+        let _ = conn.query(AvrQuery::Power).await;
+    }
+    Ok(())
+}
+
+/// Select each input in turn, muted, asking whether the AVR changed to it and
+/// what to call it. Returns the inputs to hide, and the friendly names of the
+/// others, keyed by input name. The AVR is put back the way it was.
+async fn test_inputs(
+    conn: &AvrConnection,
+) -> Result<(Vec<String>, BTreeMap<String, Vec<String>>), Error> {
+    let before = conn.events().state();
+    let original = before
+        .input
+        .as_ref()
+        .and_then(|input| conn.protocol().input_number(input));
+    if before.mute != Some(true) {
+        avr::process(AvrCommand::Mute, conn).await?;
+    }
+
+    println!("Selecting each input in turn, muted, check the AVR's display.");
+    let mut hidden = vec![];
+    let mut aliases = BTreeMap::new();
+    for (i, input) in conn.protocol().inputs().iter().enumerate() {
+        let n = i as u8 + 1;
+        if let Err(e) = avr::process(AvrCommand::ChangeInput(n), conn).await {
+            println!("{} is missing, hiding it: {}", input, e);
+            hidden.push(input.clone());
+            continue;
+        }
+        if !ask_yes(&format!("Did the input change to {}?", input)).await? {
+            hidden.push(input.clone());
+            continue;
+        }
+        let alias = ask(&format!("Name to ask for {} by, if not that", input), "").await?;
+        if !alias.is_empty() && !alias.eq_ignore_ascii_case(input) {
+            aliases.insert(input.clone(), vec![alias]);
+        }
+    }
+
+    if let Some(n) = original {
+        avr::process(AvrCommand::ChangeInput(n), conn).await?;
+    }
+    if before.mute != Some(true) {
+        avr::process(AvrCommand::Unmute, conn).await?;
+    }
+    Ok((hidden, aliases))
+}
+
+/// Ask the question, answered with `y` or `n`
+async fn ask_yes(question: &str) -> Result<bool, Error> {
+    loop {
+        match ask(&format!("{} [y/n]", question), "").await?.as_str() {
+            "y" | "Y" | "yes" => return Ok(true),
+            "n" | "N" | "no" => return Ok(false),
+            _ => println!("Please answer y or n."),
+        }
+    }
+}
+
+/// Ask for a number, `default` if none is given
+async fn ask_number(question: &str, default: u16) -> Result<u16, Error> {
+    loop {
+        match ask(question, &default.to_string()).await?.parse() {
+            Ok(n) => return Ok(n),
+            Err(_) => println!("Please give a number."),
+        }
+    }
+}
+
+/// Ask the question on the terminal and read the answer, trimmed, `default`
+/// if it's left empty
+async fn ask(question: &str, default: &str) -> Result<String, Error> {
+    let prompt = if default.is_empty() {
+        format!("{}: ", question)
+    } else {
+        format!("{} [{}]: ", question, default)
+    };
+    let default = default.to_owned();
+
+    task::spawn_blocking(move || -> Result<String, Error> {
+        print!("{}", prompt);
+        io::stdout().flush()?;
+        let mut answer = String::new();
+        if io::stdin().read_line(&mut answer)? == 0 {
+            bail!("No answer given");
+        }
+        let answer = answer.trim();
+        Ok(if answer.is_empty() {
+            default
+        } else {
+            answer.to_owned()
+        })
+    })
+    .await?
+}
@@ -1,81 +1,675 @@
-/// This module contains the server that will start for the Alexa web service.   
+/// This module contains the server that will start for the Alexa web service.
 ///
 /// All requests will be verified using `alexa_verifier` then processed and
-/// responded to using the `crate::skill` module.
-use crate::skill::process_request;
+/// responded to using the `crate::skill` module. Smart Home directives and
+/// Google Assistant fulfillment requests are accepted too when configured,
+/// and handled by `crate::smart_home` and `crate::google`, as are commands
+/// and raw codes for the local control API, handled by `crate::api`. If a
+/// command history is kept, it's served at `/api/history`. Usage statistics
+/// are served at `/stats`, whether the AVRs are connected at `/health`, and
+/// the OpenAPI document for these routes at `/api/openapi.json`, see
+/// `crate::openapi`.
+///
+/// The `/api` routes answer errors with a JSON body with a stable code, see
+/// `crate::api::ApiError`. So does the Alexa route, with `debug` set in the
+/// skill config, for requests it can't process.
+///
+/// Verification is turned off by the tests in `tests/`, so they can post
+/// unsigned requests, and by `--insecure-skip-verify`, which is refused
+/// unless the web service only listens on a loopback address.
+///
+/// Alexa requests delivered twice are only processed once, and replayed ones
+/// not at all, see `crate::debounce`.
+///
+/// Every request is added to the access log, if one is kept, see
+/// `crate::access_log`, and processed in an `http` span, see
+/// `crate::logging`.
+use crate::{
+    access_log::{AccessLog, Pending},
+    addr,
+    api::{Api, ApiError},
+    audit,
+    basic_auth::BasicAuth,
+    config::SkillConfig,
+    debounce::Debounce,
+    device::Devices,
+    google::Google,
+    history::History,
+    identity::Identity,
+    linking::AccountLinking,
+    log_error, logging, openapi,
+    progressive::{HttpsClient, Progress},
+    skill::{self, process_request, SkillResponse},
+    smart_home::SmartHome,
+    speech::Speaker,
+    stats::Stats,
+    users,
+};
 use alexa_verifier::RequestVerifier;
-use log::{debug, error, info};
-use rouille::{router, Request, Response};
-use std::io::Read;
+use failure::Error;
+use hyper::{
+    body,
+    header::{HeaderValue, CONTENT_TYPE, WWW_AUTHENTICATE},
+    server::conn::{AddrStream, Http},
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    convert::Infallible, fmt::Debug, future::Future, net::SocketAddr, sync::Arc, time::Duration,
+};
+use tokio::{net::TcpListener, task};
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, error, info, info_span, warn, Instrument, Span};
+
+/// Everything the routes need to process requests. Cloned for each request.
+#[derive(Clone)]
+pub struct Services {
+    pub devices: Devices,
+    pub speaker: Speaker,
+    pub skill: Arc<SkillConfig>,
+    pub smart_home: Option<Arc<SmartHome>>,
+    pub google: Option<Arc<Google>>,
+    pub api: Option<Arc<Api>>,
+    pub basic_auth: Option<Arc<BasicAuth>>,
+    pub linking: Option<Arc<AccountLinking>>,
+    pub history: Option<History>,
+    pub access_log: Option<AccessLog>,
+    pub stats: Stats,
+    /// Whether Alexa requests must be signed by Alexa
+    pub verify: bool,
+}
 
-/// Only one route is needed to accept json POST request from Alexa.   
+/// Only one route is needed to accept json POST request from Alexa, plus
+/// one each for Smart Home directives, Google Assistant and API commands if
+/// configured, one to GET the command history if it's kept, one to GET usage
+/// statistics, one to GET the AVRs' health, and one to GET the OpenAPI
+/// document. `client_cert` is whether the request came over TLS with a
+/// verified client certificate, which stands in for the API token, as do
+/// Basic credentials. With those configured, only the health check is served
+/// without them. `peer` is the client's address, for the access log.
 ///
 /// All other routes will return 404
-fn note_routes(request: &Request, verifier: &RequestVerifier) -> Response {
-    router!(request,
-        (POST) (/) => {
-            info!("Request received...");
-
-            // Get raw body from request
-            let mut body = request.data().unwrap();
-            let mut body_bytes: Vec<u8> = vec![];
-            body.read_to_end(&mut body_bytes).unwrap();
-
-            // Extract headers needed for request verification
-            let signature_cert_chain_url = request.header("SignatureCertChainUrl").unwrap_or("");
-            let signature = request.header("Signature").unwrap_or("");
-
-            // Deserialize the request, returning 400 on de error
-            let _request = serde_json::from_slice::<alexa_sdk::Request>(&body_bytes);
-            if let Err(e) = _request {
-                error!("Could not deserialize request");
-                error!("{:?}", e);
-                let response = Response::empty_400();
+async fn note_routes(
+    request: Request<Body>,
+    verifier: Arc<RequestVerifier>,
+    client: HttpsClient,
+    debounce: Debounce<Arc<SkillResponse>>,
+    services: Services,
+    client_cert: bool,
+    peer: SocketAddr,
+) -> Result<Response<Body>, Infallible> {
+    let access_log = services.access_log.clone();
+    let pending = Pending::new(&request, peer);
+    let verified = client_cert
+        || services
+            .basic_auth
+            .as_ref()
+            .is_some_and(|basic_auth| basic_auth.authorized(&header(&request, "Authorization")));
+    let guarded = services.basic_auth.is_some() && !verified;
+
+    let response = match (request.method(), request.uri().path()) {
+        (&Method::POST, "/") => alexa_request(request, verifier, client, debounce, services).await,
+        (&Method::POST, "/smart-home") if services.smart_home.is_some() => {
+            directive(request, services.smart_home.unwrap()).await
+        }
+        (&Method::POST, "/google") if services.google.is_some() => {
+            google_request(request, services.google.unwrap()).await
+        }
+        (&Method::POST, "/api/command") if services.api.is_some() => {
+            api_command(request, services.api.unwrap(), verified).await
+        }
+        (&Method::POST, "/api/raw") if services.api.is_some() => {
+            api_raw(request, services.api.unwrap(), verified).await
+        }
+        (&Method::GET, "/api/history" | "/stats" | "/api/openapi.json") if guarded => {
+            basic_challenge()
+        }
+        (&Method::GET, "/api/history") if services.history.is_some() => {
+            history(&request, &services.history.unwrap())
+        }
+        (&Method::GET, "/stats") => json(&services.stats.summary(&services.devices)),
+        (&Method::GET, "/health") => health(&services.devices).await,
+        (&Method::GET, "/api/openapi.json") => json(&openapi::spec(&services)),
+        _ => empty(StatusCode::NOT_FOUND),
+    };
+    if let Some(access_log) = access_log {
+        access_log.record(pending, &response);
+    }
+    Ok(response)
+}
+
+/// Span for processing a request from `peer`, see `crate::logging`
+fn http_span(request: &Request<Body>, peer: SocketAddr) -> Span {
+    info_span!(
+        "http",
+        method = %request.method(),
+        path = %request.uri().path(),
+        client = %peer.ip().to_canonical()
+    )
+}
+
+/// Verify, deserialize and process the json POST request from Alexa. `client`
+/// is used to send progressive responses while it's processed, and `debounce`
+/// holds the responses of recent requests, in case one is delivered again or
+/// replayed.
+async fn alexa_request(
+    request: Request<Body>,
+    verifier: Arc<RequestVerifier>,
+    client: HttpsClient,
+    debounce: Debounce<Arc<SkillResponse>>,
+    services: Services,
+) -> Response<Body> {
+    info!("Request received...");
+
+    // Extract headers needed for request verification
+    let signature_cert_chain_url = header(&request, "SignatureCertChainUrl");
+    let signature = header(&request, "Signature");
+
+    // Get raw body from request
+    let body_bytes = match body::to_bytes(request.into_body()).await {
+        Ok(body_bytes) => body_bytes.to_vec(),
+        Err(e) => {
+            error!("Could not read request body");
+            error!("{:?}", e);
+            return bad_request(&services, "bad_request", "Could not read request body");
+        }
+    };
+
+    // Deserialize the request, returning 400 on de error
+    let request = match serde_json::from_slice::<alexa_sdk::Request>(&body_bytes) {
+        Ok(request) => request,
+        Err(e) => {
+            error!("Could not deserialize request");
+            error!("{:?}", e);
+            let response = bad_request(&services, "bad_request", e.to_string());
+            info!("Sending back response...");
+            debug!("{:?}", response);
+            return response;
+        }
+    };
+
+    let request_id = request.body.request_id.clone();
+    let progress = Progress::new(&client, &body_bytes, &request_id);
+    logging::with_request_id(request_id.clone(), async move {
+        debug!("{:?}", request);
+        let user = users::identify(&services.skill.users, &body_bytes).cloned();
+        let access_token = users::access_token(&body_bytes);
+        let user_id = users::id(&body_bytes);
+        let allowed = users::allowed(&services.skill.allowed_accounts, &body_bytes);
+
+        // Verify the request came from Alexa, 400 if not. Verification may
+        // need to download the signing certificate, so run it off the
+        // runtime's worker threads.
+        if services.verify {
+            let timestamp = request.body.timestamp.clone();
+            let verified = task::spawn_blocking(move || {
+                verifier
+                    .verify(
+                        &signature_cert_chain_url,
+                        &signature,
+                        &body_bytes,
+                        &timestamp,
+                        None,
+                    )
+                    .is_ok()
+            })
+            .await
+            .unwrap_or(false);
+            if !verified {
+                error!("Could not validate request came from Alexa");
+                let response = bad_request(
+                    &services,
+                    "verification_failed",
+                    "Could not validate request came from Alexa",
+                );
                 info!("Sending back response...");
                 debug!("{:?}", response);
                 return response;
-            }
-            let request = _request.unwrap();
-            debug!("{:?}", request);
+            };
+            debug!("Request is validated...");
+        }
 
-            // Verify the request came from Alexa, 400 if not
-            if verifier
-                .verify(
-                    signature_cert_chain_url,
-                    signature,
-                    &body_bytes,
-                    request.body.timestamp.as_str(),
-                    None
-                ).is_err() {
-                    error!("Could not validate request came from Alexa");
-                    let response = Response::empty_400();
+        // Accounts not on the allowlist are turned away politely
+        if !allowed {
+            let speaker = services.speaker.with_locale(request.body.locale.as_deref());
+            let response = json(&skill::account_not_allowed(&speaker));
+            info!("Sending back response...");
+            debug!("{:?}", response);
+            return response;
+        }
+
+        // With account linking, only the AVRs of the account the access token
+        // belongs to can be controlled, and anyone else is asked to link theirs
+        let devices = match &services.linking {
+            Some(linking) => match linking
+                .devices(access_token.as_deref(), &services.devices)
+                .await
+            {
+                Ok(Some(devices)) => devices,
+                result => {
+                    let speaker = services.speaker.with_locale(request.body.locale.as_deref());
+                    let response = match result {
+                        Err(e) => {
+                            log_error(&e);
+                            json(&skill::account_error(&speaker))
+                        }
+                        Ok(_) => json(&skill::link_account(&speaker)),
+                    };
                     info!("Sending back response...");
                     debug!("{:?}", response);
                     return response;
-                };
-            debug!("Request is validated...");
+                }
+            },
+            None => services.devices.clone(),
+        };
 
-            // Process and get response from `crate::skill` module, then
-            // serialize
-            let response = Response::json(&process_request(request));
+        // Process and get response from `crate::skill` module, then
+        // serialize. Commands sent are attributed to the user in the audit
+        // log.
+        let processed = audit::as_user(user_id, async {
+            Arc::new(
+                process_request(
+                    request,
+                    user.as_ref(),
+                    &devices,
+                    &services.speaker,
+                    &services.skill,
+                    &progress,
+                    &services.stats,
+                )
+                .await,
+            )
+        });
+        let response = match debounce.run(&request_id, processed).await {
+            Some(response) => json(&*response),
+            None => bad_request(&services, "replayed", "Request was already processed"),
+        };
 
-            // Send back response
-            info!("Sending back response...");
-            debug!("{:?}", response);
+        // Send back response
+        info!("Sending back response...");
+        debug!("{:?}", response);
+        response
+    })
+    .await
+}
+
+/// Check the secret, then deserialize and process the Smart Home directive
+async fn directive(request: Request<Body>, smart_home: Arc<SmartHome>) -> Response<Body> {
+    info!("Directive received...");
+
+    if !smart_home.authorized(&header(&request, "Authorization")) {
+        error!("Directive did not have the Smart Home secret");
+        return empty(StatusCode::UNAUTHORIZED);
+    }
+    let directive = match read_json(request).await {
+        Ok(directive) => directive,
+        Err(e) => return empty(e.status),
+    };
+
+    let response = json(&smart_home.process_directive(directive).await);
+    info!("Sending back response...");
+    debug!("{:?}", response);
+    response
+}
+
+/// Check the token, then deserialize and process the Google Assistant
+/// request
+async fn google_request(request: Request<Body>, google: Arc<Google>) -> Response<Body> {
+    info!("Google request received...");
+
+    if !google.authorized(&header(&request, "Authorization")) {
+        error!("Google request did not have the configured token");
+        return empty(StatusCode::UNAUTHORIZED);
+    }
+    let request = match read_json(request).await {
+        Ok(request) => request,
+        Err(e) => return empty(e.status),
+    };
+
+    let response = json(&google.process_request(request).await);
+    info!("Sending back response...");
+    debug!("{:?}", response);
+    response
+}
+
+/// Check the token, then deserialize and process the API command. Answers
+/// with an empty 204 once the command is done, or the JSON error.
+async fn api_command(request: Request<Body>, api: Arc<Api>, verified: bool) -> Response<Body> {
+    info!("API command received...");
+
+    if !api.authorized(&header(&request, "Authorization"), verified) {
+        error!("API command did not have the configured token");
+        return api_error(&ApiError::new(
+            StatusCode::UNAUTHORIZED,
+            "unauthorized",
+            "Missing or wrong bearer token",
+        ));
+    }
+    let request = match read_json(request).await {
+        Ok(request) => request,
+        Err(e) => return api_error(&e),
+    };
+
+    let response = match api.command(request).await {
+        Ok(()) => empty(StatusCode::NO_CONTENT),
+        Err(e) => api_error(&e),
+    };
+    info!("Sending back response...");
+    debug!("{:?}", response);
+    response
+}
+
+/// Send the raw code posted to `/api/raw`, answering with the AVR's response
+async fn api_raw(request: Request<Body>, api: Arc<Api>, verified: bool) -> Response<Body> {
+    info!("API raw code received...");
+
+    if !api.authorized(&header(&request, "Authorization"), verified) {
+        error!("API raw code did not have the configured token");
+        return api_error(&ApiError::new(
+            StatusCode::UNAUTHORIZED,
+            "unauthorized",
+            "Missing or wrong bearer token",
+        ));
+    }
+    let request = match read_json(request).await {
+        Ok(request) => request,
+        Err(e) => return api_error(&e),
+    };
+
+    let response = match api.raw(request).await {
+        Ok(response) => json(&response),
+        Err(e) => api_error(&e),
+    };
+    info!("Sending back response...");
+    debug!("{:?}", response);
+    response
+}
+
+/// The latest entries of the command history, newest first. The `limit` query
+/// parameter sets how many, 50 by default.
+fn history(request: &Request<Body>, history: &History) -> Response<Body> {
+    let limit = request
+        .uri()
+        .query()
+        .and_then(|query| serde_urlencoded::from_str::<HistoryQuery>(query).ok())
+        .and_then(|query| query.limit)
+        .unwrap_or(50);
+
+    match history.latest(limit) {
+        Ok(entries) => json(&entries),
+        Err(e) => {
+            log_error(&e);
+            api_error(&ApiError::from_error(&e))
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct HistoryQuery {
+    limit: Option<usize>,
+}
+
+/// Read and deserialize the json body, or the 400 error to send back if it
+/// can't be
+async fn read_json<T: DeserializeOwned + Debug>(request: Request<Body>) -> Result<T, ApiError> {
+    let body_bytes = match body::to_bytes(request.into_body()).await {
+        Ok(body_bytes) => body_bytes,
+        Err(e) => {
+            error!("Could not read request body");
+            error!("{:?}", e);
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "bad_request",
+                "Could not read request body",
+            ));
+        }
+    };
+
+    match serde_json::from_slice(&body_bytes) {
+        Ok(request) => {
+            debug!("{:?}", request);
+            Ok(request)
+        }
+        Err(e) => {
+            error!("Could not deserialize request");
+            error!("{:?}", e);
+            Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "bad_request",
+                e.to_string(),
+            ))
+        }
+    }
+}
+
+/// Get header value as an owned string, empty if not present
+fn header(request: &Request<Body>, name: &str) -> String {
+    request
+        .headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("")
+        .to_owned()
+}
+
+/// Whether every AVR is connected, along with what each says it is
+#[derive(Serialize)]
+struct Health {
+    healthy: bool,
+    devices: Vec<DeviceHealth>,
+}
+
+#[derive(Serialize)]
+struct DeviceHealth {
+    name: String,
+    connected: bool,
+    #[serde(flatten)]
+    identity: Identity,
+}
+
+/// Whether every AVR is connected, with the model and firmware version of
+/// each, asking those connected that haven't said yet. 503 if any isn't
+/// connected.
+async fn health(devices: &Devices) -> Response<Body> {
+    let mut health = Health {
+        healthy: true,
+        devices: vec![],
+    };
+    for device in devices.iter() {
+        let connected = device.events.state().connected == Some(true);
+        let identity = if connected {
+            device.identity.identify().await.unwrap_or_else(|e| {
+                log_error(&e);
+                device.identity.known()
+            })
+        } else {
+            device.identity.known()
+        };
+        health.healthy &= connected;
+        health.devices.push(DeviceHealth {
+            name: device.name.clone(),
+            connected,
+            identity,
+        });
+    }
+
+    let mut response = json(&health);
+    if !health.healthy && response.status() == StatusCode::OK {
+        *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+    }
+    response
+}
+
+/// Empty response with the given status code
+fn empty(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    response
+}
+
+/// 401 asking the browser for the Basic credentials
+fn basic_challenge() -> Response<Body> {
+    let mut response = empty(StatusCode::UNAUTHORIZED);
+    response.headers_mut().insert(
+        WWW_AUTHENTICATE,
+        HeaderValue::from_static("Basic realm=\"alexa-avr-control\""),
+    );
+    response
+}
+
+/// JSON response with the error and its status code
+fn api_error(e: &ApiError) -> Response<Body> {
+    let mut response = json(e);
+    if response.status() == StatusCode::OK {
+        *response.status_mut() = e.status;
+    }
+    response
+}
+
+/// 400 response for an Alexa request that can't be processed, with the JSON
+/// error in debug mode, empty otherwise
+fn bad_request(
+    services: &Services,
+    code: &'static str,
+    message: impl Into<String>,
+) -> Response<Body> {
+    if services.skill.debug {
+        api_error(&ApiError::new(StatusCode::BAD_REQUEST, code, message))
+    } else {
+        empty(StatusCode::BAD_REQUEST)
+    }
+}
+
+/// Serialize the Alexa response to a json response
+fn json<T: Serialize>(response: &T) -> Response<Body> {
+    match serde_json::to_vec(response) {
+        Ok(body) => {
+            let mut response = Response::new(Body::from(body));
             response
-    },
-        _ => Response::empty_404()
-    )
+                .headers_mut()
+                .insert(CONTENT_TYPE, "application/json".parse().unwrap());
+            response
+        }
+        Err(e) => {
+            error!("Could not serialize response");
+            error!("{:?}", e);
+            empty(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
 }
 
-/// Use the specified port to run the web service.   
+/// Run the web service on the specified address, see `crate::addr` for how
+/// `::` listens on both IPv4 and IPv6.
+pub async fn run(addr: SocketAddr, services: Services) -> Result<(), Error> {
+    let (_, server) = bind(addr, services)?;
+    server.await
+}
+
+/// Bind the web service to `addr`, returning the address it's bound to, with
+/// the port picked if `addr`'s is 0, and the future that runs it.
 ///
-/// `alexa_verifier::RequestVerifier` needs to be mutexed for safe acces, as
-/// it contains a certificate cache.
-pub fn run(port: &str) -> std::io::Result<()> {
-    let verifier = RequestVerifier::new();
-
-    let addrs = format!("0.0.0.0:{}", port);
-    info!("Starting server on {}", addrs);
-    rouille::start_server(addrs, move |request| note_routes(&request, &verifier));
+/// `alexa_verifier::RequestVerifier` is shared between all request tasks, as
+/// it contains a certificate cache, as is the client for progressive
+/// responses. Each request gets a clone of the `Services`.
+pub fn bind(
+    addr: SocketAddr,
+    services: Services,
+) -> Result<(SocketAddr, impl Future<Output = Result<(), Error>>), Error> {
+    let verifier = Arc::new(RequestVerifier::new());
+    let client = Progress::client();
+    let debounce = Debounce::new(
+        Duration::from_millis(services.skill.debounce),
+        Duration::from_secs(services.skill.replay_window),
+    );
+
+    let service = make_service_fn(move |conn: &AddrStream| {
+        let peer = conn.remote_addr();
+        let verifier = verifier.clone();
+        let client = client.clone();
+        let debounce = debounce.clone();
+        let services = services.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let span = http_span(&request, peer);
+                note_routes(
+                    request,
+                    verifier.clone(),
+                    client.clone(),
+                    debounce.clone(),
+                    services.clone(),
+                    false,
+                    peer,
+                )
+                .instrument(span)
+            }))
+        }
+    });
+
+    let server = Server::from_tcp(addr::listener(addr)?)?.serve(service);
+    let addr = server.local_addr();
+    info!("Starting server on {}", addr);
+
+    Ok((addr, async move {
+        server.await?;
+        Ok(())
+    }))
+}
+
+/// Bind the web service to `addr` over TLS, with `acceptor` requiring a
+/// client certificate, returning the address it's bound to and the future
+/// that runs it. Requests on it are let through the control API without the
+/// token, see `crate::tls`.
+pub fn bind_tls(
+    addr: SocketAddr,
+    acceptor: TlsAcceptor,
+    services: Services,
+) -> Result<(SocketAddr, impl Future<Output = Result<(), Error>>), Error> {
+    let verifier = Arc::new(RequestVerifier::new());
+    let client = Progress::client();
+    let debounce = Debounce::new(
+        Duration::from_millis(services.skill.debounce),
+        Duration::from_secs(services.skill.replay_window),
+    );
+
+    let listener = TcpListener::from_std(addr::listener(addr)?)?;
+    let addr = listener.local_addr()?;
+    info!("Starting TLS server with client certificates on {}", addr);
+
+    Ok((addr, async move {
+        loop {
+            let (conn, peer) = listener.accept().await?;
+            let acceptor = acceptor.clone();
+            let verifier = verifier.clone();
+            let client = client.clone();
+            let debounce = debounce.clone();
+            let services = services.clone();
+
+            tokio::spawn(async move {
+                let stream = match acceptor.accept(conn).await {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        warn!("TLS handshake with {} failed: {}", peer, e);
+                        return;
+                    }
+                };
+                let service = service_fn(move |request| {
+                    let span = http_span(&request, peer);
+                    note_routes(
+                        request,
+                        verifier.clone(),
+                        client.clone(),
+                        debounce.clone(),
+                        services.clone(),
+                        true,
+                        peer,
+                    )
+                    .instrument(span)
+                });
+                if let Err(e) = Http::new().serve_connection(stream, service).await {
+                    debug!("TLS connection from {} ended: {}", peer, e);
+                }
+            });
+        }
+    }))
 }
@@ -1,18 +1,73 @@
 /// This module contains all of the logic for processing Alexa requests and
 /// returning the appropriate response.   
 ///
-/// Once the request's intent is determined, this will call `avr::process()`
-/// along with the appropriate `AvrCommand` to be executed.
+/// Once the request's intent is determined, this will add the appropriate
+/// `AvrCommand` to the `CommandQueue` of the requested AVR to be executed.
 use crate::{
-    avr::{self, AvrCommand, AvrError},
-    log_error, speech,
+    avr::{AvrCommand, AvrError, Key},
+    config::{SkillConfig, UserConfig, WhenBusy},
+    device::{Device, Devices},
+    identity::Identity,
+    log_error,
+    progressive::Progress,
+    queue::Source,
+    scheduler,
+    speech::Speaker,
+    stats::Stats,
+    undo::{self, Snapshot},
 };
 use alexa_sdk::{
     request::{IntentType, ReqType},
+    response::Reprompt,
     Request, Response,
 };
+use chrono::Local;
 use failure::{ensure, Error, Fail};
-use log::info;
+use serde::{ser, Serialize, Serializer};
+use serde_json::{json, Value};
+use std::time::Duration;
+use tracing::{info, info_span, Instrument};
+
+/// Session attributes carrying the command waiting for confirmation, and the
+/// AVR it's for
+const PENDING_COMMAND: &str = "pending_command";
+const PENDING_DEVICE: &str = "pending_device";
+
+/// Session attribute keeping the session open after each command, once asked
+/// to keep listening
+const KEEP_OPEN: &str = "keep_open";
+
+/// Response to send back, along with any dialog directives, which
+/// `alexa_sdk::Response` has no field for, and a card its `Card` can't
+/// express, like the one for linking the account
+pub struct SkillResponse {
+    response: Response,
+    directives: Vec<Value>,
+    card: Option<Value>,
+}
+
+impl From<Response> for SkillResponse {
+    fn from(response: Response) -> SkillResponse {
+        SkillResponse {
+            response,
+            directives: vec![],
+            card: None,
+        }
+    }
+}
+
+impl Serialize for SkillResponse {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut value = serde_json::to_value(&self.response).map_err(ser::Error::custom)?;
+        if !self.directives.is_empty() {
+            value["response"]["directives"] = json!(self.directives);
+        }
+        if let Some(card) = &self.card {
+            value["response"]["card"] = card.clone();
+        }
+        value.serialize(serializer)
+    }
+}
 
 /// Custom intents defined for this skill
 enum UserIntent {
@@ -22,6 +77,35 @@ enum UserIntent {
     On,
     Off,
     Input,
+    Activity,
+    SwitchBack,
+    RestoreVolume,
+    Undo,
+    Timer,
+    CancelTimer,
+    ListInputs,
+    Preset,
+    Sequence,
+    SendCode,
+    Identify,
+    Transport,
+    Pairing,
+    SoundRetrieverOn,
+    SoundRetrieverOff,
+    DialogEnhancementOn,
+    DialogEnhancementOff,
+    AutoMode,
+    DirectMode,
+    ExtendedStereo,
+    SpeakerSystem,
+    SubwooferUp,
+    SubwooferDown,
+    LfeAttenuation,
+    VideoConverterOn,
+    VideoConverterOff,
+    VideoResolution,
+    NetworkStandbyOn,
+    KeepListening,
     Other,
 }
 
@@ -35,6 +119,35 @@ impl<'a> From<&'a str> for UserIntent {
             "On" => UserIntent::On,
             "Off" => UserIntent::Off,
             "Input" => UserIntent::Input,
+            "Activity" => UserIntent::Activity,
+            "SwitchBack" => UserIntent::SwitchBack,
+            "RestoreVolume" => UserIntent::RestoreVolume,
+            "Undo" => UserIntent::Undo,
+            "Timer" => UserIntent::Timer,
+            "CancelTimer" => UserIntent::CancelTimer,
+            "ListInputs" => UserIntent::ListInputs,
+            "Preset" => UserIntent::Preset,
+            "Sequence" => UserIntent::Sequence,
+            "SendCode" => UserIntent::SendCode,
+            "Identify" => UserIntent::Identify,
+            "Transport" => UserIntent::Transport,
+            "Pairing" => UserIntent::Pairing,
+            "SoundRetrieverOn" => UserIntent::SoundRetrieverOn,
+            "SoundRetrieverOff" => UserIntent::SoundRetrieverOff,
+            "DialogEnhancementOn" => UserIntent::DialogEnhancementOn,
+            "DialogEnhancementOff" => UserIntent::DialogEnhancementOff,
+            "AutoMode" => UserIntent::AutoMode,
+            "DirectMode" => UserIntent::DirectMode,
+            "ExtendedStereo" => UserIntent::ExtendedStereo,
+            "SpeakerSystem" => UserIntent::SpeakerSystem,
+            "SubwooferUp" => UserIntent::SubwooferUp,
+            "SubwooferDown" => UserIntent::SubwooferDown,
+            "LfeAttenuation" => UserIntent::LfeAttenuation,
+            "VideoConverterOn" => UserIntent::VideoConverterOn,
+            "VideoConverterOff" => UserIntent::VideoConverterOff,
+            "VideoResolution" => UserIntent::VideoResolution,
+            "NetworkStandbyOn" => UserIntent::NetworkStandbyOn,
+            "KeepListening" => UserIntent::KeepListening,
             _ => UserIntent::Other,
         }
     }
@@ -52,44 +165,127 @@ impl From<&String> for UserIntent {
 /// LaunchRequests are left open, waiting for an appropriate Intent to be
 /// requested. SessionEndedRequests doesn't need a verbal response, just
 /// silently end. Other requests types aren't supported by this skill, it
-/// will just send back "Hmm."
-pub fn process_request(request: Request) -> Response {
+/// will just send back "Hmm." Responses are spoken in the request's locale.
+///
+/// Launches and intents are counted in `stats`. The settings of the `user`
+/// who sent the request apply to it, if they have any. Intents are processed
+/// in an `intent` span, see `crate::logging`.
+pub async fn process_request(
+    request: Request,
+    user: Option<&UserConfig>,
+    devices: &Devices,
+    speaker: &Speaker,
+    config: &SkillConfig,
+    progress: &Progress,
+    stats: &Stats,
+) -> SkillResponse {
     let reqtype = request.reqtype();
     info!("Request Type: {:?}", reqtype);
+    let speaker = &speaker.with_locale(request.body.locale.as_deref());
 
     match reqtype {
-        ReqType::IntentRequest => process_intent(request),
-        ReqType::LaunchRequest => open_hello(),
-        ReqType::SessionEndedRequest => end_silent(),
-        _ => end_hmm(),
+        ReqType::IntentRequest => {
+            let span = info_span!("intent", intent = ?request.intent());
+            process_intent(request, user, devices, speaker, config, progress, stats)
+                .instrument(span)
+                .await
+        }
+        ReqType::LaunchRequest => {
+            stats.record("LaunchRequest", true);
+            open_hello(speaker).into()
+        }
+        ReqType::SessionEndedRequest => end_silent().into(),
+        _ => end_hmm(speaker).into(),
     }
 }
 
+/// Response using `Speaker::link_account` that ends, with the card linking
+/// the account in the Alexa app, see `crate::linking`
+pub fn link_account(speaker: &Speaker) -> SkillResponse {
+    SkillResponse {
+        response: Response::new(true).speech(speaker.link_account()),
+        directives: vec![],
+        card: Some(json!({ "type": "LinkAccount" })),
+    }
+}
+
+/// Response using `Speaker::account_not_allowed` that ends, for accounts that
+/// aren't allowed to use the skill
+pub fn account_not_allowed(speaker: &Speaker) -> SkillResponse {
+    Response::new(true)
+        .speech(speaker.account_not_allowed())
+        .into()
+}
+
+/// Response using `Speaker::account_error` that ends, for when the linked
+/// account couldn't be checked
+pub fn account_error(speaker: &Speaker) -> SkillResponse {
+    Response::new(true).speech(speaker.account_error()).into()
+}
+
 /// Processes intent from IntentRequests.
 ///
 /// If it is one of the skills custom intents `IntentType::User`, it will
 /// be passed along for futher processing.   
 ///
+/// Yes and No answer a confirmation prompt, processing or dropping the command
+/// waiting for it.   
+///
+/// With `keep_open` in the config, or once asked to keep listening, answers to
+/// commands leave the session open for the next one.   
+///
 /// If an error occurs while processing the custom intent, it will be
-/// logged and the appropriate response will be generated.
-fn process_intent(request: Request) -> Response {
+/// logged and the appropriate response will be generated. A missing slot
+/// value is asked for instead, which doesn't count as the intent failing.
+async fn process_intent(
+    request: Request,
+    user: Option<&UserConfig>,
+    devices: &Devices,
+    speaker: &Speaker,
+    config: &SkillConfig,
+    progress: &Progress,
+    stats: &Stats,
+) -> SkillResponse {
     let intent = request.intent();
     info!("Intent: {:?}", intent);
+    let name = match &intent {
+        IntentType::User(s) => s.clone(),
+        intent => format!("AMAZON.{:?}Intent", intent),
+    };
+
+    let command = matches!(intent, IntentType::User(_) | IntentType::Yes);
+    let keep_open = config.keep_open || request.attribute_value(KEEP_OPEN).is_some();
 
     let response_result = match intent {
-        IntentType::User(s) => process_user_intent(s, request),
-        IntentType::Help => Ok(open_help()),
-        IntentType::Cancel => Ok(end_ok()),
-        IntentType::Stop => Ok(end_ok()),
-        IntentType::NavigateHome => Ok(end_ok()),
-        _ => Ok(end_hmm()),
+        IntentType::User(s) => {
+            process_user_intent(s, &request, user, devices, speaker, config, progress).await
+        }
+        IntentType::Yes => confirmed(&request, user, devices, speaker, config, progress).await,
+        IntentType::No => Ok(end_cancelled(speaker)),
+        IntentType::Help => Ok(open_help(speaker)),
+        IntentType::Cancel => Ok(end_ok(speaker)),
+        IntentType::Stop => Ok(end_ok(speaker)),
+        IntentType::NavigateHome => Ok(end_ok(speaker)),
+        _ => Ok(end_hmm(speaker)),
     };
 
     match response_result {
-        Ok(response) => response,
+        Ok(response) => {
+            stats.record(&name, true);
+            if command && keep_open && response.body.should_end_session {
+                return open_next(response, speaker).into();
+            }
+            response.into()
+        }
         Err(e) => {
+            if let Some(SkillError::MissingSlot { slot }) = e.downcast_ref::<SkillError>() {
+                info!("Eliciting slot: {}", slot);
+                stats.record(&name, true);
+                return open_elicit(speaker, &request, slot);
+            }
+            stats.record(&name, false);
             log_error(&e);
-            verbalize_error(e)
+            verbalize_error(speaker, e).into()
         }
     }
 }
@@ -97,22 +293,214 @@ fn process_intent(request: Request) -> Response {
 /// Process the custom intent further, getting slot values for applicable
 /// intents.   
 ///
+/// All intents can name the AVR to control in the Device slot, otherwise the
+/// first configured AVR is used. Return `SkillError::Device` if there's no
+/// AVR with that name.   
+///
+/// Return `SkillError::NotEnabled` if the intent isn't enabled in the config,
+/// for the AVR or at all, and `SkillError::NotAllowed` if the user isn't
+/// allowed it.
+///
 /// Volume and Input require a slot value, those are passed for further
-/// processing. All other intents can directly call their respective
-/// function.
-fn process_user_intent(mut s: String, request: Request) -> Result<Response, Error> {
+/// processing. The input can also be named, returning `SkillError::InputName`
+/// if no input goes by that name. Preset names a tuner preset, returning
+/// `SkillError::PresetName` if none goes by that name, and Sequence a
+/// sequence of commands, returning `SkillError::SequenceName` likewise.
+/// SendCode sends one of the AVR's raw codes, by name, returning
+/// `SkillError::CodeName` if none goes by that name. Identify says the AVR's
+/// model and firmware version. Timer puts a command on
+/// a timer, and CancelTimer cancels it. Activity turns the AVR on and changes
+/// its input and volume in one go. Undo puts back what the last command
+/// changed. KeepListening keeps the session open after
+/// each command. Transport presses a key of the current
+/// input's transport controls. All other intents can directly call their
+/// respective function.
+async fn process_user_intent(
+    s: String,
+    request: &Request,
+    user: Option<&UserConfig>,
+    devices: &Devices,
+    speaker: &Speaker,
+    config: &SkillConfig,
+    progress: &Progress,
+) -> Result<Response, Error> {
+    if !config.intents.enabled(&s) {
+        return Err(SkillError::NotEnabled { intent: s }.into());
+    }
+    if let Some(intents) = user.and_then(|user| user.intents.as_ref()) {
+        if !intents.contains(&s) {
+            return Err(SkillError::NotAllowed { intent: s }.into());
+        }
+    }
+
     let user_intent = UserIntent::from(&s);
-    s.push_str("_slot");
-    let maybe_slot_value = request.slot_value(&s);
+    let maybe_slot_value = request.slot_value(&format!("{}_slot", s));
+
+    let device_name = request.slot_value("Device_slot").filter(|name| name != "?");
+    let device = devices
+        .get(device_name.as_deref())
+        .ok_or_else(|| SkillError::Device {
+            name: device_name.clone().unwrap_or_default(),
+        })?;
+    info!("Device: {}", device.name);
+    if !device.intents.enabled(&s) {
+        return Err(SkillError::NotEnabled { intent: s }.into());
+    }
 
     match user_intent {
-        UserIntent::Volume => volume(maybe_slot_value),
-        UserIntent::Input => input(maybe_slot_value),
-        UserIntent::Mute => mute(),
-        UserIntent::Unmute => unmute(),
-        UserIntent::On => on(),
-        UserIntent::Off => off(),
-        _ => Ok(end_hmm()),
+        UserIntent::Volume => {
+            volume(maybe_slot_value, user, device, speaker, config, progress).await
+        }
+        UserIntent::Input => {
+            let name = request
+                .slot_value("InputName_slot")
+                .filter(|name| name != "?");
+            let slot_value = match (maybe_slot_value, name) {
+                (Some(value), _) => Some(value),
+                (None, Some(name)) => match device.inputs.resolve(&name) {
+                    Some(n) => Some(n.to_string()),
+                    None => return Err(SkillError::InputName { name }.into()),
+                },
+                (None, None) => user
+                    .and_then(|user| user.default_input)
+                    .map(|input| input.to_string()),
+            };
+            input(slot_value, device, speaker, config, progress).await
+        }
+        UserIntent::Activity => activity(request, user, device, speaker, config, progress).await,
+        UserIntent::SwitchBack => switch_back(user, device, speaker, config, progress).await,
+        UserIntent::RestoreVolume => restore_volume(device, speaker, config, progress).await,
+        UserIntent::Undo => undo(user, device, speaker, config, progress).await,
+        UserIntent::Timer => {
+            let name = request
+                .slot_value("TimerCommand_slot")
+                .filter(|name| name != "?");
+            timer(maybe_slot_value, name, user, device, speaker, config)
+        }
+        UserIntent::CancelTimer => match device.timers.cancel(device) {
+            true => Ok(end_timer_cancelled(speaker)),
+            false => Err(SkillError::NoTimer.into()),
+        },
+        UserIntent::ListInputs => Ok(end_list_inputs(speaker, device)),
+        UserIntent::Preset => preset(maybe_slot_value, device, speaker, config, progress).await,
+        UserIntent::Sequence => sequence(maybe_slot_value, user, device, speaker, config).await,
+        UserIntent::SendCode => send_code(maybe_slot_value, device, speaker).await,
+        UserIntent::Identify => identify(device, speaker).await,
+        UserIntent::Transport => {
+            transport(maybe_slot_value, device, speaker, config, progress).await
+        }
+        UserIntent::Mute => match maybe_slot_value {
+            Some(value) => timed_mute(value, device, speaker, config, progress).await,
+            None => {
+                device.timers.cancel_unmute();
+                process(AvrCommand::Mute, device, speaker, config, progress).await
+            }
+        },
+        UserIntent::Unmute => process(AvrCommand::Unmute, device, speaker, config, progress).await,
+        UserIntent::On => process(AvrCommand::PowerOn, device, speaker, config, progress).await,
+        UserIntent::Off => process(AvrCommand::PowerOff, device, speaker, config, progress).await,
+        UserIntent::AutoMode => listening_mode("auto", device, speaker, config, progress).await,
+        UserIntent::DirectMode => listening_mode("direct", device, speaker, config, progress).await,
+        UserIntent::ExtendedStereo => {
+            listening_mode("extended stereo", device, speaker, config, progress).await
+        }
+        UserIntent::SpeakerSystem => {
+            speaker_system(maybe_slot_value, device, speaker, config, progress).await
+        }
+        UserIntent::SubwooferUp => {
+            process(AvrCommand::SubwooferUp, device, speaker, config, progress).await
+        }
+        UserIntent::SubwooferDown => {
+            process(AvrCommand::SubwooferDown, device, speaker, config, progress).await
+        }
+        UserIntent::LfeAttenuation => {
+            lfe_attenuation(maybe_slot_value, device, speaker, config, progress).await
+        }
+        UserIntent::VideoConverterOn => {
+            process(
+                AvrCommand::VideoConverterOn,
+                device,
+                speaker,
+                config,
+                progress,
+            )
+            .await
+        }
+        UserIntent::VideoConverterOff => {
+            process(
+                AvrCommand::VideoConverterOff,
+                device,
+                speaker,
+                config,
+                progress,
+            )
+            .await
+        }
+        UserIntent::VideoResolution => {
+            video_resolution(maybe_slot_value, device, speaker, config, progress).await
+        }
+        UserIntent::NetworkStandbyOn => {
+            process(
+                AvrCommand::NetworkStandbyOn,
+                device,
+                speaker,
+                config,
+                progress,
+            )
+            .await
+        }
+        UserIntent::SoundRetrieverOn => {
+            process(
+                AvrCommand::SoundRetrieverOn,
+                device,
+                speaker,
+                config,
+                progress,
+            )
+            .await
+        }
+        UserIntent::SoundRetrieverOff => {
+            process(
+                AvrCommand::SoundRetrieverOff,
+                device,
+                speaker,
+                config,
+                progress,
+            )
+            .await
+        }
+        UserIntent::DialogEnhancementOn => {
+            process(
+                AvrCommand::DialogEnhancementOn,
+                device,
+                speaker,
+                config,
+                progress,
+            )
+            .await
+        }
+        UserIntent::DialogEnhancementOff => {
+            process(
+                AvrCommand::DialogEnhancementOff,
+                device,
+                speaker,
+                config,
+                progress,
+            )
+            .await
+        }
+        UserIntent::Pairing => {
+            process(
+                AvrCommand::BluetoothPairing,
+                device,
+                speaker,
+                config,
+                progress,
+            )
+            .await
+        }
+        UserIntent::KeepListening => Ok(open_listening(speaker)),
+        _ => Ok(end_hmm(speaker)),
     }
 }
 
@@ -122,21 +510,55 @@ fn process_user_intent(mut s: String, request: Request) -> Result<Response, Erro
 /// Return `SkillError::Volume` if value can't be validated to notify user of
 /// the correct use of this intent.   
 ///
-/// Slot values are never None, request will pass as "?" if it is an unkown
-/// value.   
+/// Return `SkillError::MissingSlot` if no value was given, so it can be
+/// asked for. The request will pass as "?" if it is an unkown value.   
+///
+/// A volume above the user's `max_volume` is lowered to it, and they're told
+/// so, unless quiet hours keep it lower still.   
 ///
 /// `SkillError::Response` is mapped to errors returned by `avr::process`, so
 /// the user is appropriately notified that their request didn't succeed.
-fn volume(slot_value: Option<String>) -> Result<Response, Error> {
-    let value = slot_value.unwrap();
+async fn volume(
+    slot_value: Option<String>,
+    user: Option<&UserConfig>,
+    device: &Device,
+    speaker: &Speaker,
+    config: &SkillConfig,
+    progress: &Progress,
+) -> Result<Response, Error> {
+    let value = slot_value.ok_or_else(|| SkillError::MissingSlot {
+        slot: "Volume_slot".to_owned(),
+    })?;
     info!("Slot Value: {}", value);
 
     let value =
         validate_volume_value(value).map_err(|inner| Error::from(SkillError::Volume { inner }))?;
     info!("Got valid volume value: {}", value);
 
-    avr::process(AvrCommand::SetVolume(value))?;
-    Ok(end_ok())
+    let limit = user
+        .and_then(|user| user.max_volume)
+        .filter(|max| value > *max);
+    let cmd = AvrCommand::SetVolume(limit.unwrap_or(value));
+    match limit {
+        Some(max) if !needs_confirmation(&cmd, device, config) => {
+            info!("Lowering volume {} to the user's limit of {}", value, max);
+            let response = execute(cmd, device, speaker, config, progress).await?;
+            if quieter_than(device, max) {
+                Ok(response)
+            } else {
+                Ok(end_user_volume_limit(speaker, max))
+            }
+        }
+        _ => process(cmd, device, speaker, config, progress).await,
+    }
+}
+
+/// Whether quiet hours keep the volume lower than `max` right now
+fn quieter_than(device: &Device, max: u8) -> bool {
+    device
+        .quiet
+        .and_then(|quiet| quiet.ceiling())
+        .is_some_and(|ceiling| ceiling < max)
 }
 
 /// Validate volume value is an integer between 1 and 10.
@@ -150,17 +572,32 @@ fn validate_volume_value(value: String) -> Result<u8, Error> {
 /// 1 and 22.
 ///
 /// Return `SkillError::Input` if value can't be validated to notify user of
-/// the correct use of this intent.
-fn input(slot_value: Option<String>) -> Result<Response, Error> {
-    let value = slot_value.unwrap();
+/// the correct use of this intent, or `SkillError::MissingSlot` if no value
+/// was given.
+async fn input(
+    slot_value: Option<String>,
+    device: &Device,
+    speaker: &Speaker,
+    config: &SkillConfig,
+    progress: &Progress,
+) -> Result<Response, Error> {
+    let value = slot_value.ok_or_else(|| SkillError::MissingSlot {
+        slot: "Input_slot".to_owned(),
+    })?;
     info!("Slot Value: {}", value);
 
     let value =
         validate_input_value(value).map_err(|inner| Error::from(SkillError::Input { inner }))?;
     info!("Got valid input value: {}", value);
 
-    avr::process(AvrCommand::ChangeInput(value))?;
-    Ok(end_ok())
+    process(
+        AvrCommand::ChangeInput(value),
+        device,
+        speaker,
+        config,
+        progress,
+    )
+    .await
 }
 
 /// Validate input value is an integer between 1 and 22.
@@ -170,38 +607,762 @@ fn validate_input_value(value: String) -> Result<u8, Error> {
     Ok(int)
 }
 
-/// Process `AvrCommand::Mute`
-fn mute() -> Result<Response, Error> {
-    avr::process(AvrCommand::Mute)?;
-    Ok(end_ok())
+/// Turn the AVR on, then change to the input and set the volume given in the
+/// request, if any, one after the other, like "turn on and switch to BD at
+/// volume 5". The input is given by number or name, as for the Input intent,
+/// and the volume is kept to the user's `max_volume`, telling them so as the
+/// Volume intent does. All the slot values are checked before anything is
+/// sent, as is that the On, Input and Volume intents of the commands are
+/// enabled and allowed, and once every command is done, the state they left
+/// the AVR in is read back as the single confirmation.
+async fn activity(
+    request: &Request,
+    user: Option<&UserConfig>,
+    device: &Device,
+    speaker: &Speaker,
+    config: &SkillConfig,
+    progress: &Progress,
+) -> Result<Response, Error> {
+    let slot = |name: &str| request.slot_value(name).filter(|value| value != "?");
+    let input = match (slot("Input_slot"), slot("InputName_slot")) {
+        (Some(value), _) => {
+            Some(validate_input_value(value).map_err(|inner| SkillError::Input { inner })?)
+        }
+        (None, Some(name)) => Some(
+            device
+                .inputs
+                .resolve(&name)
+                .ok_or(SkillError::InputName { name })?,
+        ),
+        (None, None) => None,
+    };
+    let volume = match slot("Volume_slot") {
+        Some(value) => {
+            Some(validate_volume_value(value).map_err(|inner| SkillError::Volume { inner })?)
+        }
+        None => None,
+    };
+    let limit = user
+        .and_then(|user| user.max_volume)
+        .filter(|max| volume.is_some_and(|value| value > *max));
+    let volume = volume.map(|value| limit.unwrap_or(value));
+    info!("Activity: input {:?}, volume {:?}", input, volume);
+    let before = device.events.state();
+
+    let mut commands = vec![];
+    if device.events.state().power != Some(true) {
+        commands.push(AvrCommand::PowerOn);
+    }
+    commands.extend(input.map(AvrCommand::ChangeInput));
+    commands.extend(volume.map(AvrCommand::SetVolume));
+    for cmd in &commands {
+        check_command(cmd, user, device, config)?;
+    }
+
+    let mut done = vec![];
+    for cmd in commands {
+        if let Some(speech) = speaker.progress(&cmd) {
+            progress.speak(speech);
+        }
+        match device.queue.process(cmd.clone(), Source::Alexa).await {
+            Ok(()) => done.push(cmd),
+            Err(e) if matches!(e.downcast_ref(), Some(AvrError::PowerAlreadyOn)) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    if let Some(snapshot) = Snapshot::of(&done, &before, &device.events.state()) {
+        device.undo.push(snapshot);
+    }
+    match limit {
+        Some(max) if !quieter_than(device, max) => Ok(end_user_volume_limit(speaker, max)),
+        _ => Ok(end_done_activity(speaker, device)),
+    }
+}
+
+/// Change back to the input before the current one, like a TV remote's last
+/// channel button. Return `SkillError::NoPreviousInput` if the input hasn't
+/// changed since this server started, and the Input intent's errors if it
+/// isn't enabled or allowed.
+async fn switch_back(
+    user: Option<&UserConfig>,
+    device: &Device,
+    speaker: &Speaker,
+    config: &SkillConfig,
+    progress: &Progress,
+) -> Result<Response, Error> {
+    let protocol = device.events.protocol();
+    let n = device
+        .events
+        .state()
+        .previous_input
+        .and_then(|input| protocol.input_number(&input))
+        .ok_or(SkillError::NoPreviousInput)?;
+    info!("Switching back to input: {}", n);
+
+    let cmd = AvrCommand::ChangeInput(n);
+    check_command(&cmd, user, device, config)?;
+    process(cmd, device, speaker, config, progress).await
+}
+
+/// Put the volume back to what it was before it was last set or muted,
+/// unmuting if it wasn't muted then. The level is set in dB if the AVR can,
+/// for the exact level, otherwise on the 1 to 10 scale. Return
+/// `SkillError::NoPreviousVolume` if the volume hasn't been set or muted since
+/// this server started.
+async fn restore_volume(
+    device: &Device,
+    speaker: &Speaker,
+    config: &SkillConfig,
+    progress: &Progress,
+) -> Result<Response, Error> {
+    let state = device.events.state();
+    let previous = state.previous_volume.ok_or(SkillError::NoPreviousVolume)?;
+    info!("Restoring volume: {:?}", previous);
+
+    let protocol = device.events.protocol();
+    let cmd = match previous.level {
+        Some(level) => undo::volume_command(&**protocol, level),
+        None if previous.muted => AvrCommand::Mute,
+        None => AvrCommand::Unmute,
+    };
+    if previous.level.is_some() && !previous.muted && state.mute == Some(true) {
+        device
+            .queue
+            .process(AvrCommand::Unmute, Source::Alexa)
+            .await?;
+    }
+
+    process(cmd, device, speaker, config, progress).await
+}
+
+/// Put back what the most recent change made through the skill changed, as far
+/// as it's different now, reading back the state that leaves the AVR in.
+/// Return `SkillError::NothingToUndo` if nothing was changed since this server
+/// started, or it's all been undone. The intents of the commands putting it
+/// back must be enabled and allowed, otherwise nothing is sent and the change
+/// is left to be undone.
+async fn undo(
+    user: Option<&UserConfig>,
+    device: &Device,
+    speaker: &Speaker,
+    config: &SkillConfig,
+    progress: &Progress,
+) -> Result<Response, Error> {
+    let snapshot = device.undo.pop().ok_or(SkillError::NothingToUndo)?;
+    info!("Undoing: {:?}", snapshot);
+
+    let commands = snapshot.commands(&device.events.state(), &**device.events.protocol());
+    if let Err(e) = commands
+        .iter()
+        .try_for_each(|cmd| check_command(cmd, user, device, config))
+    {
+        device.undo.push(snapshot);
+        return Err(e);
+    }
+    for cmd in commands {
+        if let Some(speech) = speaker.progress(&cmd) {
+            progress.speak(speech);
+        }
+        device.queue.process(cmd, Source::Alexa).await?;
+    }
+    Ok(end_done_undo(speaker, device))
+}
+
+/// Mute for the duration in the slot value, then unmute, unless it's unmuted
+/// before then. Return `SkillError::Duration` if the duration can't be read.
+async fn timed_mute(
+    slot_value: String,
+    device: &Device,
+    speaker: &Speaker,
+    config: &SkillConfig,
+    progress: &Progress,
+) -> Result<Response, Error> {
+    info!("Slot Value: {}", slot_value);
+    let duration =
+        scheduler::parse_duration(&slot_value).ok_or(SkillError::Duration { value: slot_value })?;
+
+    execute(AvrCommand::Mute, device, speaker, config, progress).await?;
+    device
+        .timers
+        .unmute_after(duration, device.queue.clone(), &device.events);
+    Ok(end_done_mute_for(speaker, duration))
+}
+
+/// Send the command named in `name` after the duration in the slot value, in
+/// place of any command already on a timer.
+///
+/// Return `SkillError::MissingSlot` if either wasn't given, so it can be asked
+/// for, `SkillError::Duration` if the duration can't be read, or
+/// `SkillError::TimerCommand` if no command goes by that name. The command's
+/// own intent must be enabled and allowed, as it's checked now rather than
+/// when the timer's up.
+fn timer(
+    slot_value: Option<String>,
+    name: Option<String>,
+    user: Option<&UserConfig>,
+    device: &Device,
+    speaker: &Speaker,
+    config: &SkillConfig,
+) -> Result<Response, Error> {
+    let value = slot_value.ok_or_else(|| SkillError::MissingSlot {
+        slot: "Timer_slot".to_owned(),
+    })?;
+    let name = name.ok_or_else(|| SkillError::MissingSlot {
+        slot: "TimerCommand_slot".to_owned(),
+    })?;
+    info!("Slot Values: {}, {}", value, name);
+
+    let duration = scheduler::parse_duration(&value).ok_or(SkillError::Duration { value })?;
+    let cmd = timer_command(&name).ok_or(SkillError::TimerCommand { name })?;
+    check_command(&cmd, user, device, config)?;
+    if !device.events.protocol().supports(&cmd) {
+        return Err(AvrError::Unsupported { command: cmd }.into());
+    }
+
+    let at = Local::now() + chrono::Duration::from_std(duration)?;
+    device.timers.start(device, at, cmd);
+    Ok(end_done_timer(speaker, duration))
+}
+
+/// Command a timer can send going by the spoken name
+fn timer_command(name: &str) -> Option<AvrCommand> {
+    match name.to_lowercase().as_str() {
+        "turn off" | "off" | "power off" | "turn it off" => Some(AvrCommand::PowerOff),
+        "turn on" | "on" | "power on" | "turn it on" => Some(AvrCommand::PowerOn),
+        "mute" => Some(AvrCommand::Mute),
+        "unmute" => Some(AvrCommand::Unmute),
+        "volume up" | "louder" => Some(AvrCommand::VolumeUp),
+        "volume down" | "quieter" => Some(AvrCommand::VolumeDown),
+        _ => None,
+    }
+}
+
+/// Switch to the tuner and select the preset named in the slot value.
+///
+/// Return `SkillError::MissingSlot` if no name was given, so it can be asked
+/// for, or `SkillError::PresetName` if no preset goes by that name.
+async fn preset(
+    slot_value: Option<String>,
+    device: &Device,
+    speaker: &Speaker,
+    config: &SkillConfig,
+    progress: &Progress,
+) -> Result<Response, Error> {
+    let name = slot_value
+        .filter(|name| name != "?")
+        .ok_or_else(|| SkillError::MissingSlot {
+            slot: "Preset_slot".to_owned(),
+        })?;
+    info!("Slot Value: {}", name);
+
+    let preset = device
+        .presets
+        .resolve(&name)
+        .ok_or(SkillError::PresetName { name })?;
+    info!("Selecting preset: {}", preset);
+
+    process(
+        AvrCommand::SelectPreset(preset),
+        device,
+        speaker,
+        config,
+        progress,
+    )
+    .await
+}
+
+/// Run the sequence of commands named in the slot value, one after the other.
+/// A volume above the user's `max_volume` is lowered to it, telling them so
+/// as the Volume intent does, and every command's intent is checked to be
+/// enabled and allowed before anything is sent.
+///
+/// Return `SkillError::MissingSlot` if no name was given, so it can be asked
+/// for, or `SkillError::SequenceName` if no sequence goes by that name.
+async fn sequence(
+    slot_value: Option<String>,
+    user: Option<&UserConfig>,
+    device: &Device,
+    speaker: &Speaker,
+    config: &SkillConfig,
+) -> Result<Response, Error> {
+    let name = slot_value
+        .filter(|name| name != "?")
+        .ok_or_else(|| SkillError::MissingSlot {
+            slot: "Sequence_slot".to_owned(),
+        })?;
+    info!("Slot Value: {}", name);
+
+    let sequence = device
+        .sequences
+        .resolve(&name)
+        .ok_or(SkillError::SequenceName { name })?;
+
+    let protocol = device.events.protocol();
+    let max = user.and_then(|user| user.max_volume);
+    let mut limit = None;
+    let commands: Vec<_> = device
+        .sequences
+        .commands(sequence)
+        .unwrap_or_default()
+        .iter()
+        .map(|cmd| {
+            let level = match cmd {
+                AvrCommand::SetVolume(n) => Some(protocol.volume_level(*n)),
+                AvrCommand::SetVolumeDb(db) => protocol.db_level(*db),
+                _ => None,
+            };
+            match max {
+                Some(max) if level.is_some_and(|level| level > protocol.volume_level(max)) => {
+                    info!("Lowering {:?} to the user's limit of {}", cmd, max);
+                    limit = Some(max);
+                    AvrCommand::SetVolume(max)
+                }
+                _ => cmd.clone(),
+            }
+        })
+        .collect();
+    for cmd in &commands {
+        check_command(cmd, user, device, config)?;
+    }
+
+    device
+        .sequences
+        .run(sequence, &commands, &device.queue, Source::Alexa)
+        .await?;
+    match limit {
+        Some(max) if !quieter_than(device, max) => Ok(end_user_volume_limit(speaker, max)),
+        _ => Ok(end_done_sequence(speaker, sequence, device)),
+    }
+}
+
+/// Send the raw code named in the slot value to the AVR, reading back what it
+/// answers.
+///
+/// Return `SkillError::MissingSlot` if no name was given, so it can be asked
+/// for, or `SkillError::CodeName` if no code goes by that name.
+async fn send_code(
+    slot_value: Option<String>,
+    device: &Device,
+    speaker: &Speaker,
+) -> Result<Response, Error> {
+    let name = slot_value
+        .filter(|name| name != "?")
+        .ok_or_else(|| SkillError::MissingSlot {
+            slot: "SendCode_slot".to_owned(),
+        })?;
+    info!("Slot Value: {}", name);
+
+    let code = device
+        .raw
+        .resolve(&name)
+        .ok_or(SkillError::CodeName { name })?;
+    let response = device.raw.send(code).await?;
+    Ok(end_done_raw(speaker, &response))
+}
+
+/// Say the AVR's model and firmware version, asking it for them if it hasn't
+/// said yet
+async fn identify(device: &Device, speaker: &Speaker) -> Result<Response, Error> {
+    let identity = device.identity.identify().await?;
+    info!("Identity: {:?}", identity);
+    Ok(end_identity(speaker, &identity))
+}
+
+/// Press the key named in the slot value on the transport controls of the
+/// current input, like pausing the iPod/USB input.
+///
+/// Return `SkillError::MissingSlot` if no key was given, or none goes by that
+/// name, so it can be asked for, or `SkillError::NoTransport` if the current
+/// input has no transport controls, or it isn't known.
+async fn transport(
+    slot_value: Option<String>,
+    device: &Device,
+    speaker: &Speaker,
+    config: &SkillConfig,
+    progress: &Progress,
+) -> Result<Response, Error> {
+    let key = slot_value
+        .as_deref()
+        .and_then(transport_key)
+        .ok_or_else(|| SkillError::MissingSlot {
+            slot: "Transport_slot".to_owned(),
+        })?;
+    info!("Slot Value: {:?}", key);
+
+    let input = device.events.state().input;
+    let cmd = input
+        .as_deref()
+        .and_then(|input| device.events.protocol().transport(input, key))
+        .ok_or(SkillError::NoTransport)?;
+    process(cmd, device, speaker, config, progress).await
 }
 
-/// Process `AvrCommand::Unmute`
-fn unmute() -> Result<Response, Error> {
-    avr::process(AvrCommand::Unmute)?;
-    Ok(end_ok())
+/// Key of the transport controls going by the spoken name
+fn transport_key(name: &str) -> Option<Key> {
+    match name.to_lowercase().as_str() {
+        "play" | "resume" | "unpause" | "continue" => Some(Key::Play),
+        "pause" => Some(Key::Pause),
+        "stop" => Some(Key::Stop),
+        "next" | "skip" => Some(Key::Next),
+        "previous" | "back" => Some(Key::Previous),
+        _ => None,
+    }
 }
 
-/// Process `AvrCommand::PowerOn`
-fn on() -> Result<Response, Error> {
-    avr::process(AvrCommand::PowerOn)?;
-    Ok(end_ok())
+/// Switch to the listening mode going by the shorthand intent's name. Return
+/// `AvrError::Unsupported` if the AVR's protocol has no mode by that name.
+async fn listening_mode(
+    name: &str,
+    device: &Device,
+    speaker: &Speaker,
+    config: &SkillConfig,
+    progress: &Progress,
+) -> Result<Response, Error> {
+    let mode = match device.events.protocol().listening_mode_named(name) {
+        Some(mode) => mode,
+        None => {
+            let command = AvrCommand::SetListeningMode(name.to_owned());
+            return Err(AvrError::Unsupported { command }.into());
+        }
+    };
+    info!("Listening mode: {} ({})", name, mode);
+    process(
+        AvrCommand::SetListeningMode(mode),
+        device,
+        speaker,
+        config,
+        progress,
+    )
+    .await
+}
+
+/// Change the speaker system to the one named. It's always confirmed first.
+/// Return `SkillError::MissingSlot` if none was given, so it can be asked
+/// for, or `AvrError::Unsupported` if the AVR's protocol has none by that
+/// name.
+async fn speaker_system(
+    slot_value: Option<String>,
+    device: &Device,
+    speaker: &Speaker,
+    config: &SkillConfig,
+    progress: &Progress,
+) -> Result<Response, Error> {
+    let name = slot_value
+        .filter(|name| name != "?")
+        .ok_or_else(|| SkillError::MissingSlot {
+            slot: "SpeakerSystem_slot".to_owned(),
+        })?;
+    let system = match device.events.protocol().speaker_system_named(&name) {
+        Some(system) => system,
+        None => {
+            let command = AvrCommand::SetSpeakerSystem(name);
+            return Err(AvrError::Unsupported { command }.into());
+        }
+    };
+    info!("Speaker system: {} ({})", name, system);
+    process(
+        AvrCommand::SetSpeakerSystem(system),
+        device,
+        speaker,
+        config,
+        progress,
+    )
+    .await
+}
+
+/// Attenuate the LFE channel by the dB given, as a whole number.
+///
+/// Return `SkillError::MissingSlot` if no number was given, so it can be asked
+/// for, or `SkillError::LfeAttenuation` if it isn't one of the AVR's steps.
+async fn lfe_attenuation(
+    slot_value: Option<String>,
+    device: &Device,
+    speaker: &Speaker,
+    config: &SkillConfig,
+    progress: &Progress,
+) -> Result<Response, Error> {
+    let db = slot_value
+        .and_then(|value| value.trim().trim_start_matches('-').parse().ok())
+        .ok_or_else(|| SkillError::MissingSlot {
+            slot: "LfeAttenuation_slot".to_owned(),
+        })?;
+    info!("LFE attenuation: {}", db);
+    if device.events.protocol().lfe_attenuation_code(db).is_none() {
+        return Err(SkillError::LfeAttenuation { db }.into());
+    }
+    process(
+        AvrCommand::SetLfeAttenuation(db),
+        device,
+        speaker,
+        config,
+        progress,
+    )
+    .await
+}
+
+/// Change the HDMI output resolution to the one named. Return
+/// `SkillError::MissingSlot` if none was given, so it can be asked for, or
+/// `AvrError::Unsupported` if the AVR's protocol has none by that name.
+async fn video_resolution(
+    slot_value: Option<String>,
+    device: &Device,
+    speaker: &Speaker,
+    config: &SkillConfig,
+    progress: &Progress,
+) -> Result<Response, Error> {
+    let name = slot_value
+        .filter(|name| name != "?")
+        .ok_or_else(|| SkillError::MissingSlot {
+            slot: "VideoResolution_slot".to_owned(),
+        })?;
+    let resolution = match device.events.protocol().video_resolution_named(&name) {
+        Some(resolution) => resolution,
+        None => {
+            let command = AvrCommand::SetVideoResolution(name);
+            return Err(AvrError::Unsupported { command }.into());
+        }
+    };
+    info!("Video resolution: {} ({})", name, resolution);
+    process(
+        AvrCommand::SetVideoResolution(resolution),
+        device,
+        speaker,
+        config,
+        progress,
+    )
+    .await
+}
+
+/// Intent that sends the command on its own, `None` if none does. Commands
+/// sent on behalf of another intent are checked against it too, so they
+/// can't do what that intent isn't enabled or allowed to.
+fn command_intent(cmd: &AvrCommand) -> Option<&'static str> {
+    match cmd {
+        AvrCommand::PowerOn => Some("On"),
+        AvrCommand::PowerOff => Some("Off"),
+        AvrCommand::Mute => Some("Mute"),
+        AvrCommand::Unmute => Some("Unmute"),
+        AvrCommand::ChangeInput(_) => Some("Input"),
+        AvrCommand::SetVolume(_)
+        | AvrCommand::SetVolumeDb(_)
+        | AvrCommand::VolumeUp
+        | AvrCommand::VolumeDown => Some("Volume"),
+        AvrCommand::SetSpeakerSystem(_) => Some("SpeakerSystem"),
+        _ => None,
+    }
+}
+
+/// Return `SkillError::NotEnabled` if the intent sending the command isn't
+/// enabled in the config, for the AVR or at all, and `SkillError::NotAllowed`
+/// if the user isn't allowed it
+fn check_command(
+    cmd: &AvrCommand,
+    user: Option<&UserConfig>,
+    device: &Device,
+    config: &SkillConfig,
+) -> Result<(), Error> {
+    let intent = match command_intent(cmd) {
+        Some(intent) => intent,
+        None => return Ok(()),
+    };
+    if !config.intents.enabled(intent) || !device.intents.enabled(intent) {
+        return Err(SkillError::NotEnabled {
+            intent: intent.to_owned(),
+        }
+        .into());
+    }
+    if let Some(intents) = user.and_then(|user| user.intents.as_ref()) {
+        if !intents.iter().any(|allowed| allowed == intent) {
+            return Err(SkillError::NotAllowed {
+                intent: intent.to_owned(),
+            }
+            .into());
+        }
+    }
+    Ok(())
+}
+
+/// Ask to confirm the command first if the config requires it, otherwise
+/// process it. Speaker commands are skipped while headphones are plugged in,
+/// if the config says so.
+async fn process(
+    cmd: AvrCommand,
+    device: &Device,
+    speaker: &Speaker,
+    config: &SkillConfig,
+    progress: &Progress,
+) -> Result<Response, Error> {
+    if config.skip_speakers_with_headphones
+        && cmd.is_speaker()
+        && device.events.state().headphones == Some(true)
+    {
+        info!("Headphones plugged in, skipping: {:?}", cmd);
+        return Ok(end_headphones(speaker));
+    }
+    if needs_confirmation(&cmd, device, config) {
+        info!("Asking to confirm: {:?}", cmd);
+        return Ok(open_confirm(speaker, &cmd, device));
+    }
+    execute(cmd, device, speaker, config, progress).await
+}
+
+/// Power off needs confirming if configured, as does a volume change bigger
+/// than `confirm_volume_jump` steps from the current volume. A speaker system
+/// change always does, as a mistaken one leaves speakers silent.
+fn needs_confirmation(cmd: &AvrCommand, device: &Device, config: &SkillConfig) -> bool {
+    match cmd {
+        AvrCommand::PowerOff => config.confirm_power_off,
+        AvrCommand::SetSpeakerSystem(_) => true,
+        AvrCommand::SetVolume(n) => {
+            let protocol = device.events.protocol();
+            let current = device
+                .events
+                .state()
+                .volume
+                .map(|level| (protocol.volume_percent(level) + 5) / 10);
+            match (config.confirm_volume_jump, current) {
+                (Some(threshold), Some(current)) => {
+                    (i16::from(*n) - i16::from(current)).abs() > i16::from(threshold)
+                }
+                _ => false,
+            }
+        }
+        _ => false,
+    }
+}
+
+/// Process the command waiting for confirmation, carried in the session
+/// attributes, once its intent is checked again to be enabled and allowed
+async fn confirmed(
+    request: &Request,
+    user: Option<&UserConfig>,
+    devices: &Devices,
+    speaker: &Speaker,
+    config: &SkillConfig,
+    progress: &Progress,
+) -> Result<Response, Error> {
+    let cmd = request
+        .attribute_value(PENDING_COMMAND)
+        .and_then(|cmd| serde_json::from_str::<AvrCommand>(cmd).ok());
+    let cmd = match cmd {
+        Some(cmd) => cmd,
+        None => return Ok(end_hmm(speaker)),
+    };
+
+    let device_name = request.attribute_value(PENDING_DEVICE);
+    let device = devices
+        .get(device_name.map(String::as_str))
+        .ok_or_else(|| SkillError::Device {
+            name: device_name.cloned().unwrap_or_default(),
+        })?;
+    info!("Confirmed: {:?} for device: {}", cmd, device.name);
+    check_command(&cmd, user, device, config)?;
+
+    execute(cmd, device, speaker, config, progress).await
+}
+
+/// Process the command, then respond with `Speaker::done`, or
+/// `Speaker::quiet_hours` if the volume was kept down. Slow commands get a
+/// progressive response first. If the AVR is busy with another request, the
+/// command waits its turn or is rejected, depending on the config.
+async fn execute(
+    cmd: AvrCommand,
+    device: &Device,
+    speaker: &Speaker,
+    config: &SkillConfig,
+    progress: &Progress,
+) -> Result<Response, Error> {
+    // The queue lowers the volume to the ceiling during quiet hours
+    let quieted = match (&cmd, &device.quiet) {
+        (AvrCommand::SetVolume(n), Some(quiet)) => quiet
+            .ceiling()
+            .filter(|max| n > max)
+            .map(|max| (quiet.start(), max)),
+        _ => None,
+    };
+
+    let before = device.events.state();
+    match config.when_busy {
+        WhenBusy::Queue => {
+            if let Some(speech) = speaker.progress(&cmd) {
+                progress.speak(speech);
+            }
+            device.queue.process(cmd.clone(), Source::Alexa).await?;
+        }
+        // Only promise progress once the command is accepted
+        WhenBusy::Reject => {
+            let processed = device.queue.try_process(cmd.clone(), Source::Alexa)?;
+            if let Some(speech) = speaker.progress(&cmd) {
+                progress.speak(speech);
+            }
+            processed.await?;
+        }
+    }
+    let after = device.events.state();
+    if let Some(snapshot) = Snapshot::of(std::slice::from_ref(&cmd), &before, &after) {
+        device.undo.push(snapshot);
+    }
+    match quieted {
+        Some((start, max)) => Ok(end_quiet_hours(speaker, &start, max)),
+        None => Ok(end_done(speaker, &cmd, device)),
+    }
+}
+
+/// Response using `Speaker::hello` that is left open
+fn open_hello(speaker: &Speaker) -> Response {
+    Response::new(false).speech(speaker.hello())
+}
+
+/// Response using `Speaker::confirm` that is left open for the answer, with
+/// the command and AVR kept in the session attributes
+fn open_confirm(speaker: &Speaker, cmd: &AvrCommand, device: &Device) -> Response {
+    let mut response = Response::new(false).speech(speaker.confirm(cmd));
+    if let Ok(cmd) = serde_json::to_string(cmd) {
+        response.add_attribute(PENDING_COMMAND, &cmd);
+    }
+    response.add_attribute(PENDING_DEVICE, &device.name);
+    response
+}
+
+/// Response using `Speaker::elicit` that is left open, with a
+/// `Dialog.ElicitSlot` directive asking for the missing slot value. The answer
+/// comes back as the same intent with the slot filled in.
+fn open_elicit(speaker: &Speaker, request: &Request, slot: &str) -> SkillResponse {
+    let mut directive = json!({
+        "type": "Dialog.ElicitSlot",
+        "slotToElicit": slot,
+    });
+    if let Some(intent) = &request.body.intent {
+        directive["updatedIntent"] = serde_json::to_value(intent).unwrap_or_default();
+    }
+
+    SkillResponse {
+        response: Response::new(false).speech(speaker.elicit(slot)),
+        directives: vec![directive],
+        card: None,
+    }
 }
 
-/// Process `AvrCommand::PowerOff`
-fn off() -> Result<Response, Error> {
-    avr::process(AvrCommand::PowerOff)?;
-    Ok(end_ok())
+/// Response using `Speaker::listening` that is left open, keeping the session
+/// open after each command from then on
+fn open_listening(speaker: &Speaker) -> Response {
+    open_next(Response::new(true).speech(speaker.listening()), speaker)
 }
 
-/// Response using `speech::hello` that is left open
-fn open_hello() -> Response {
-    Response::new(false).speech(speech::hello())
+/// The response to a command, left open for the next one, asking with
+/// `Speaker::anything_else` if nothing is said
+fn open_next(mut response: Response, speaker: &Speaker) -> Response {
+    response.body.should_end_session = false;
+    response.body.reprompt = Some(Reprompt {
+        output_speech: speaker.anything_else(),
+    });
+    response.add_attribute(KEEP_OPEN, "true");
+    response
 }
 
-/// Response using `speech::help` that is left open
-fn open_help() -> Response {
-    Response::new(false).speech(speech::help())
+/// Response using `Speaker::help` that is left open
+fn open_help(speaker: &Speaker) -> Response {
+    Response::new(false).speech(speaker.help())
 }
 
 /// Silent response that ends
@@ -209,44 +1370,223 @@ fn end_silent() -> Response {
     Response::end()
 }
 
-/// Response using `speech::ok` that ends
-fn end_ok() -> Response {
-    Response::new(true).speech(speech::ok())
+/// Response using `Speaker::ok` that ends
+fn end_ok(speaker: &Speaker) -> Response {
+    Response::new(true).speech(speaker.ok())
 }
 
-/// Response using `speech::hmm` that ends
-fn end_hmm() -> Response {
-    Response::new(true).speech(speech::hmm())
+/// Response using `Speaker::done` that ends
+fn end_done(speaker: &Speaker, cmd: &AvrCommand, device: &Device) -> Response {
+    Response::new(true).speech(speaker.done(cmd, device))
 }
 
-/// Response using `speech::volume_error` that notifies user their Volume
+/// Response using `Speaker::list_inputs` that ends, naming the inputs that
+/// aren't hidden
+fn end_list_inputs(speaker: &Speaker, device: &Device) -> Response {
+    let visible = device.inputs.visible();
+    let names: Vec<_> = visible.iter().map(|(_, name)| name.as_str()).collect();
+    Response::new(true).speech(speaker.list_inputs(&names))
+}
+
+/// Response using `Speaker::done_activity` that ends
+fn end_done_activity(speaker: &Speaker, device: &Device) -> Response {
+    Response::new(true).speech(speaker.done_activity(device))
+}
+
+/// Response using `Speaker::done_undo` that ends
+fn end_done_undo(speaker: &Speaker, device: &Device) -> Response {
+    Response::new(true).speech(speaker.done_undo(device))
+}
+
+/// Response using `Speaker::done_sequence` that ends
+fn end_done_sequence(speaker: &Speaker, name: &str, device: &Device) -> Response {
+    Response::new(true).speech(speaker.done_sequence(name, device))
+}
+
+/// Response using `Speaker::done_mute_for` that ends
+fn end_done_mute_for(speaker: &Speaker, duration: Duration) -> Response {
+    Response::new(true).speech(speaker.done_mute_for(duration))
+}
+
+/// Response using `Speaker::done_timer` that ends
+fn end_done_timer(speaker: &Speaker, duration: Duration) -> Response {
+    Response::new(true).speech(speaker.done_timer(duration))
+}
+
+/// Response using `Speaker::timer_cancelled` that ends
+fn end_timer_cancelled(speaker: &Speaker) -> Response {
+    Response::new(true).speech(speaker.timer_cancelled())
+}
+
+/// Response using `Speaker::done_raw` that ends
+fn end_done_raw(speaker: &Speaker, response: &str) -> Response {
+    Response::new(true).speech(speaker.done_raw(response))
+}
+
+/// Response using `Speaker::identity` that ends
+fn end_identity(speaker: &Speaker, identity: &Identity) -> Response {
+    Response::new(true).speech(speaker.identity(identity))
+}
+
+/// Response using `Speaker::quiet_hours` that ends
+fn end_quiet_hours(speaker: &Speaker, start: &str, volume: u8) -> Response {
+    Response::new(true).speech(speaker.quiet_hours(start, volume))
+}
+
+/// Response using `Speaker::headphones` that ends
+fn end_headphones(speaker: &Speaker) -> Response {
+    Response::new(true).speech(speaker.headphones())
+}
+
+/// Response using `Speaker::user_volume_limit` that ends
+fn end_user_volume_limit(speaker: &Speaker, volume: u8) -> Response {
+    Response::new(true).speech(speaker.user_volume_limit(volume))
+}
+
+/// Response using `Speaker::cancelled` that ends
+fn end_cancelled(speaker: &Speaker) -> Response {
+    Response::new(true).speech(speaker.cancelled())
+}
+
+/// Response using `Speaker::hmm` that ends
+fn end_hmm(speaker: &Speaker) -> Response {
+    Response::new(true).speech(speaker.hmm())
+}
+
+/// Response using `Speaker::volume_error` that notifies user their Volume
 /// intent request contained an incorrect slot value.
-fn end_volume_error() -> Response {
-    Response::new(true).speech(speech::volume_error())
+fn end_volume_error(speaker: &Speaker) -> Response {
+    Response::new(true).speech(speaker.volume_error())
 }
 
-/// Response using `speech::input_error` that notifies user their Input
+/// Response using `Speaker::input_error` that notifies user their Input
 /// intent request contained an incorrect slot value.
-fn end_input_error() -> Response {
-    Response::new(true).speech(speech::input_error())
+fn end_input_error(speaker: &Speaker) -> Response {
+    Response::new(true).speech(speaker.input_error())
+}
+
+/// Response using `Speaker::device_error` that notifies user there's no AVR
+/// with the name they requested.
+fn end_device_error(speaker: &Speaker, name: &str) -> Response {
+    Response::new(true).speech(speaker.device_error(name))
+}
+
+/// Response using `Speaker::input_name_error` that notifies user there's no
+/// input with the name they requested.
+fn end_input_name_error(speaker: &Speaker, name: &str) -> Response {
+    Response::new(true).speech(speaker.input_name_error(name))
 }
 
-/// Response using `speech::response_error` that notifies user their request
+/// Response using `Speaker::preset_name_error` that notifies user there's no
+/// preset with the name they requested.
+fn end_preset_name_error(speaker: &Speaker, name: &str) -> Response {
+    Response::new(true).speech(speaker.preset_name_error(name))
+}
+
+/// Response using `Speaker::sequence_name_error` that notifies user there's
+/// no sequence with the name they requested.
+fn end_sequence_name_error(speaker: &Speaker, name: &str) -> Response {
+    Response::new(true).speech(speaker.sequence_name_error(name))
+}
+
+/// Response using `Speaker::code_name_error` that notifies user there's no
+/// raw code with the name they requested.
+fn end_code_name_error(speaker: &Speaker, name: &str) -> Response {
+    Response::new(true).speech(speaker.code_name_error(name))
+}
+
+/// Response using `Speaker::timer_command_error` that notifies user the
+/// command they asked for can't be put on a timer.
+fn end_timer_command_error(speaker: &Speaker, name: &str) -> Response {
+    Response::new(true).speech(speaker.timer_command_error(name))
+}
+
+/// Response using `Speaker::lfe_attenuation_error` that notifies user the AVR
+/// can't attenuate the LFE by the dB they asked for.
+fn end_lfe_attenuation_error(speaker: &Speaker, db: u8) -> Response {
+    Response::new(true).speech(speaker.lfe_attenuation_error(db))
+}
+
+/// Response using `Speaker::no_timer` that notifies user there's no timer to
+/// cancel.
+fn end_no_timer(speaker: &Speaker) -> Response {
+    Response::new(true).speech(speaker.no_timer())
+}
+
+/// Response using `Speaker::no_transport` that notifies user the current
+/// input has no transport controls.
+fn end_no_transport(speaker: &Speaker) -> Response {
+    Response::new(true).speech(speaker.no_transport())
+}
+
+/// Response using `Speaker::no_previous_volume` that notifies user there's no
+/// volume to put back.
+fn end_no_previous_volume(speaker: &Speaker) -> Response {
+    Response::new(true).speech(speaker.no_previous_volume())
+}
+
+/// Response using `Speaker::nothing_to_undo` that notifies user there's no
+/// change to undo.
+fn end_nothing_to_undo(speaker: &Speaker) -> Response {
+    Response::new(true).speech(speaker.nothing_to_undo())
+}
+
+/// Response using `Speaker::no_previous_input` that notifies user there's no
+/// input to switch back to.
+fn end_no_previous_input(speaker: &Speaker) -> Response {
+    Response::new(true).speech(speaker.no_previous_input())
+}
+
+/// Response using `Speaker::response_error` that notifies user their request
 /// didn't succeed because of some error communicating with the AVR.
-fn end_response_error() -> Response {
-    Response::new(true).speech(speech::response_error())
+fn end_response_error(speaker: &Speaker) -> Response {
+    Response::new(true).speech(speaker.response_error())
+}
+
+fn end_error_power_already_off(speaker: &Speaker) -> Response {
+    Response::new(true).speech(speaker.error_power_already_off())
+}
+
+fn end_error_power_already_on(speaker: &Speaker) -> Response {
+    Response::new(true).speech(speaker.error_power_already_on())
+}
+
+fn end_error_turn_power_on(speaker: &Speaker) -> Response {
+    Response::new(true).speech(speaker.error_turn_power_on())
+}
+
+fn end_error_busy(speaker: &Speaker) -> Response {
+    Response::new(true).speech(speaker.error_busy())
+}
+
+/// Response using `Speaker::error_network_standby` that notifies user the AVR
+/// can't be turned on remotely until network standby is turned on
+fn end_error_network_standby(speaker: &Speaker) -> Response {
+    Response::new(true).speech(speaker.error_network_standby())
+}
+
+/// Response using `Speaker::not_allowed` that notifies user they can't use
+/// the intent
+fn end_not_allowed(speaker: &Speaker) -> Response {
+    Response::new(true).speech(speaker.not_allowed())
 }
 
-fn end_error_power_already_off() -> Response {
-    Response::new(true).speech(speech::error_power_already_off())
+/// Response using `Speaker::not_enabled` that notifies user the intent is
+/// turned off
+fn end_not_enabled(speaker: &Speaker) -> Response {
+    Response::new(true).speech(speaker.not_enabled())
 }
 
-fn end_error_power_already_on() -> Response {
-    Response::new(true).speech(speech::error_power_already_on())
+/// Response using `Speaker::unsupported` that notifies user the AVR can't be
+/// sent the command
+fn end_unsupported(speaker: &Speaker) -> Response {
+    Response::new(true).speech(speaker.unsupported())
 }
 
-fn end_error_turn_power_on() -> Response {
-    Response::new(true).speech(speech::error_turn_power_on())
+/// Response using `Speaker::unsupported_input` that notifies user the AVR
+/// doesn't have the input
+fn end_unsupported_input(speaker: &Speaker, n: u8) -> Response {
+    Response::new(true).speech(speaker.unsupported_input(n))
 }
 
 /// Error for this module, mainly used to determine appropriate speech to
@@ -257,24 +1597,77 @@ enum SkillError {
     Volume { inner: Error },
     #[fail(display = "Input error: {}", inner)]
     Input { inner: Error },
+    #[fail(display = "No AVR named: {:?}", name)]
+    Device { name: String },
+    #[fail(display = "No input named: {:?}", name)]
+    InputName { name: String },
+    #[fail(display = "No preset named: {:?}", name)]
+    PresetName { name: String },
+    #[fail(display = "No sequence named: {:?}", name)]
+    SequenceName { name: String },
+    #[fail(display = "No raw code named: {:?}", name)]
+    CodeName { name: String },
+    #[fail(display = "Not a duration: {:?}", value)]
+    Duration { value: String },
+    #[fail(display = "No command to put on a timer named: {:?}", name)]
+    TimerCommand { name: String },
+    #[fail(display = "LFE can't be attenuated by: {}dB", db)]
+    LfeAttenuation { db: u8 },
+    #[fail(display = "No timer to cancel")]
+    NoTimer,
+    #[fail(display = "No previous input to switch back to")]
+    NoPreviousInput,
+    #[fail(display = "No previous volume to put back")]
+    NoPreviousVolume,
+    #[fail(display = "No change to undo")]
+    NothingToUndo,
+    #[fail(display = "No transport controls on the current input")]
+    NoTransport,
+    #[fail(display = "No value given for slot: {}", slot)]
+    MissingSlot { slot: String },
+    #[fail(display = "User not allowed intent: {}", intent)]
+    NotAllowed { intent: String },
+    #[fail(display = "Intent not enabled: {}", intent)]
+    NotEnabled { intent: String },
 }
 
-fn verbalize_error(e: Error) -> Response {
+fn verbalize_error(speaker: &Speaker, e: Error) -> Response {
     match e.downcast::<SkillError>() {
         Ok(e) => match e {
-            SkillError::Volume { .. } => end_volume_error(),
-            SkillError::Input { .. } => end_input_error(),
+            SkillError::Volume { .. } => end_volume_error(speaker),
+            SkillError::Input { .. } => end_input_error(speaker),
+            SkillError::Device { name } => end_device_error(speaker, &name),
+            SkillError::InputName { name } => end_input_name_error(speaker, &name),
+            SkillError::PresetName { name } => end_preset_name_error(speaker, &name),
+            SkillError::SequenceName { name } => end_sequence_name_error(speaker, &name),
+            SkillError::CodeName { name } => end_code_name_error(speaker, &name),
+            SkillError::TimerCommand { name } => end_timer_command_error(speaker, &name),
+            SkillError::LfeAttenuation { db } => end_lfe_attenuation_error(speaker, db),
+            SkillError::NoTimer => end_no_timer(speaker),
+            SkillError::NoPreviousInput => end_no_previous_input(speaker),
+            SkillError::NoPreviousVolume => end_no_previous_volume(speaker),
+            SkillError::NothingToUndo => end_nothing_to_undo(speaker),
+            SkillError::NoTransport => end_no_transport(speaker),
+            SkillError::MissingSlot { .. } | SkillError::Duration { .. } => end_hmm(speaker),
+            SkillError::NotAllowed { .. } => end_not_allowed(speaker),
+            SkillError::NotEnabled { .. } => end_not_enabled(speaker),
         },
         Err(e) => {
             if let Ok(e) = e.downcast::<AvrError>() {
                 match e {
-                    AvrError::PowerAlreadyOn => end_error_power_already_on(),
-                    AvrError::PowerAlreadyOff => end_error_power_already_off(),
-                    AvrError::PowerOffCantProcess => end_error_turn_power_on(),
-                    _ => end_response_error(),
+                    AvrError::PowerAlreadyOn => end_error_power_already_on(speaker),
+                    AvrError::PowerAlreadyOff => end_error_power_already_off(speaker),
+                    AvrError::PowerOffCantProcess => end_error_turn_power_on(speaker),
+                    AvrError::Busy => end_error_busy(speaker),
+                    AvrError::NetworkStandbyOff => end_error_network_standby(speaker),
+                    AvrError::Unsupported {
+                        command: AvrCommand::ChangeInput(n),
+                    } => end_unsupported_input(speaker, n),
+                    AvrError::Unsupported { .. } => end_unsupported(speaker),
+                    _ => end_response_error(speaker),
                 }
             } else {
-                end_response_error()
+                end_response_error(speaker)
             }
         }
     }
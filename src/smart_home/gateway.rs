@@ -0,0 +1,202 @@
+/// This module sends events to the Alexa event gateway.
+///
+/// Sending events needs an access token for the user, from Login with Amazon.
+/// Alexa sends a grant code in the `AcceptGrant` directive when the skill is
+/// enabled, which is exchanged for an access and refresh token. The refresh
+/// token is kept in the storage file, so the grant survives restarts.
+use crate::config::SmartHomeConfig;
+use failure::{bail, Error, ResultExt};
+use hyper::{
+    body,
+    client::HttpConnector,
+    header::{AUTHORIZATION, CONTENT_TYPE},
+    Body, Client, Method, Request, StatusCode,
+};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    fs,
+    path::Path,
+    time::{Duration, Instant},
+};
+use tokio::sync::Mutex;
+use tracing::{debug, info};
+
+const TOKEN_URL: &str = "https://api.amazon.com/auth/o2/token";
+
+/// Refresh the access token this long before it expires
+const EXPIRY_MARGIN: Duration = Duration::from_secs(60);
+
+#[derive(Serialize, Deserialize, Default)]
+struct Storage {
+    refresh_token: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    refresh_token: String,
+    expires_in: u64,
+}
+
+struct Tokens {
+    refresh: Option<String>,
+    access: Option<(String, Instant)>,
+}
+
+pub struct Gateway {
+    config: SmartHomeConfig,
+    client: Client<HttpsConnector<HttpConnector>>,
+    tokens: Mutex<Tokens>,
+}
+
+impl Gateway {
+    /// Load the refresh token from the storage file, if the skill has been
+    /// enabled before
+    pub fn new(config: SmartHomeConfig) -> Result<Gateway, Error> {
+        let storage = if Path::new(&config.storage).exists() {
+            let contents = fs::read_to_string(&config.storage).context(format!(
+                "Could not read Smart Home storage: {}",
+                config.storage
+            ))?;
+            serde_json::from_str(&contents).context(format!(
+                "Could not parse Smart Home storage: {}",
+                config.storage
+            ))?
+        } else {
+            Storage::default()
+        };
+
+        let connector = HttpsConnectorBuilder::new()
+            .with_webpki_roots()
+            .https_only()
+            .enable_http1()
+            .build();
+
+        Ok(Gateway {
+            config,
+            client: Client::builder().build(connector),
+            tokens: Mutex::new(Tokens {
+                refresh: storage.refresh_token,
+                access: None,
+            }),
+        })
+    }
+
+    /// Exchange the grant code from an `AcceptGrant` directive for tokens
+    pub async fn accept_grant(&self, code: &str) -> Result<(), Error> {
+        let response = self
+            .token_request(&[("grant_type", "authorization_code"), ("code", code)])
+            .await?;
+        let mut tokens = self.tokens.lock().await;
+        self.update(&mut tokens, response)?;
+        info!("Smart Home grant accepted, ChangeReport events enabled");
+        Ok(())
+    }
+
+    /// Send event to the event gateway, adding the access token to its
+    /// endpoint. Nothing is sent if the skill hasn't been enabled yet.
+    pub async fn send(&self, mut event: Value) -> Result<(), Error> {
+        let token = match self.access_token().await? {
+            Some(token) => token,
+            None => {
+                debug!("No Smart Home grant yet, event not sent");
+                return Ok(());
+            }
+        };
+        event["event"]["endpoint"]["scope"]["type"] = "BearerToken".into();
+        event["event"]["endpoint"]["scope"]["token"] = token.clone().into();
+
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(&self.config.gateway)
+            .header(AUTHORIZATION, format!("Bearer {}", token))
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(event.to_string()))?;
+        let response = self.client.request(request).await?;
+        let status = response.status();
+
+        if status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN {
+            self.tokens.lock().await.access = None;
+        }
+        if !status.is_success() {
+            let body = body::to_bytes(response.into_body()).await?;
+            bail!(
+                "Event gateway returned {}: {}",
+                status,
+                String::from_utf8_lossy(&body)
+            );
+        }
+
+        debug!("Event sent to event gateway: {}", event["event"]["header"]);
+        Ok(())
+    }
+
+    /// Current access token, refreshed if it expired
+    async fn access_token(&self) -> Result<Option<String>, Error> {
+        let mut tokens = self.tokens.lock().await;
+        if let Some((token, expires)) = &tokens.access {
+            if Instant::now() + EXPIRY_MARGIN < *expires {
+                return Ok(Some(token.clone()));
+            }
+        }
+        let refresh = match &tokens.refresh {
+            Some(refresh) => refresh.clone(),
+            None => return Ok(None),
+        };
+
+        let response = self
+            .token_request(&[("grant_type", "refresh_token"), ("refresh_token", &refresh)])
+            .await?;
+        let token = response.access_token.clone();
+        self.update(&mut tokens, response)?;
+        Ok(Some(token))
+    }
+
+    async fn token_request(&self, params: &[(&str, &str)]) -> Result<TokenResponse, Error> {
+        let mut params = params.to_vec();
+        params.push(("client_id", &self.config.client_id));
+        params.push(("client_secret", &self.config.client_secret));
+
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(TOKEN_URL)
+            .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(Body::from(serde_urlencoded::to_string(&params)?))?;
+        let response = self.client.request(request).await?;
+        let status = response.status();
+        let body = body::to_bytes(response.into_body()).await?;
+        if !status.is_success() {
+            bail!(
+                "Login with Amazon returned {}: {}",
+                status,
+                String::from_utf8_lossy(&body)
+            );
+        }
+
+        Ok(serde_json::from_slice(&body).context("Could not parse Login with Amazon tokens")?)
+    }
+
+    /// Keep the new tokens, saving the refresh token if it changed
+    fn update(&self, tokens: &mut Tokens, response: TokenResponse) -> Result<(), Error> {
+        let expires = Instant::now() + Duration::from_secs(response.expires_in);
+        tokens.access = Some((response.access_token, expires));
+
+        if tokens.refresh.as_ref() != Some(&response.refresh_token) {
+            tokens.refresh = Some(response.refresh_token);
+            let storage = Storage {
+                refresh_token: tokens.refresh.clone(),
+            };
+            fs::write(
+                &self.config.storage,
+                serde_json::to_string_pretty(&storage)?,
+            )
+            .context(format!(
+                "Could not write Smart Home storage: {}",
+                self.config.storage
+            ))?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,339 @@
+/// This module implements the optional Alexa Smart Home interface, so the AVRs
+/// also show up as devices in the Alexa app.
+///
+/// Smart Home skills can only be hosted on Lambda, so the skill's Lambda
+/// function forwards its directives here, sending the configured `secret` as
+/// a bearer token. Each AVR is discovered as an endpoint with power, volume,
+/// mute and input control, and any change to its state, like from the
+/// physical remote, is pushed to Alexa as a ChangeReport event so the Alexa
+/// app stays accurate.
+use crate::{
+    config::SmartHomeConfig,
+    device::{Device, Devices},
+    events::AvrEvent,
+    log_error,
+};
+use chrono::{SecondsFormat, Utc};
+use failure::Error;
+use gateway::Gateway;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{mem, sync::Arc};
+use tokio::sync::broadcast;
+use tracing::info;
+use uuid::Uuid;
+
+mod gateway;
+
+/// Directive forwarded from the skill's Lambda function
+#[derive(Deserialize, Debug)]
+pub struct Request {
+    directive: Directive,
+}
+
+#[derive(Deserialize, Debug)]
+struct Directive {
+    header: Header,
+    endpoint: Option<Value>,
+    #[serde(default)]
+    payload: Value,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Header {
+    namespace: String,
+    name: String,
+    correlation_token: Option<String>,
+}
+
+pub struct SmartHome {
+    secret: String,
+    gateway: Gateway,
+    devices: Devices,
+}
+
+impl SmartHome {
+    /// Whether the Authorization header has the configured secret
+    pub fn authorized(&self, authorization: &str) -> bool {
+        authorization == format!("Bearer {}", self.secret)
+    }
+
+    /// Process the directive, returning the response event
+    pub async fn process_directive(&self, request: Request) -> Value {
+        let directive = request.directive;
+        info!(
+            "Directive: {}.{}",
+            directive.header.namespace, directive.header.name
+        );
+
+        match (
+            directive.header.namespace.as_str(),
+            directive.header.name.as_str(),
+        ) {
+            ("Alexa.Authorization", "AcceptGrant") => self.accept_grant(&directive).await,
+            ("Alexa.Discovery", "Discover") => self.discover(),
+            _ => error_response(&directive, "INVALID_DIRECTIVE", "Directive not supported"),
+        }
+    }
+
+    /// Alexa sends a grant code when the skill is enabled, needed to send
+    /// events to the event gateway
+    async fn accept_grant(&self, directive: &Directive) -> Value {
+        let code = directive.payload["grant"]["code"].as_str().unwrap_or("");
+
+        match self.gateway.accept_grant(code).await {
+            Ok(()) => json!({
+                "event": {
+                    "header": header("Alexa.Authorization", "AcceptGrant.Response", None),
+                    "payload": {},
+                }
+            }),
+            Err(e) => {
+                log_error(&e);
+                json!({
+                    "event": {
+                        "header": header("Alexa.Authorization", "ErrorResponse", None),
+                        "payload": {
+                            "type": "ACCEPT_GRANT_FAILED",
+                            "message": e.to_string(),
+                        },
+                    }
+                })
+            }
+        }
+    }
+
+    /// Every AVR is an endpoint, with its inputs from its protocol
+    fn discover(&self) -> Value {
+        let endpoints: Vec<_> = self.devices.iter().map(endpoint).collect();
+
+        json!({
+            "event": {
+                "header": header("Alexa.Discovery", "Discover.Response", None),
+                "payload": {
+                    "endpoints": endpoints,
+                },
+            }
+        })
+    }
+}
+
+/// Start the Smart Home interface, with a task for each AVR sending its
+/// state changes to Alexa
+pub fn run(config: SmartHomeConfig, devices: &Devices) -> Result<Arc<SmartHome>, Error> {
+    let smart_home = Arc::new(SmartHome {
+        secret: config.secret.clone(),
+        gateway: Gateway::new(config)?,
+        devices: devices.clone(),
+    });
+
+    for device in smart_home.devices.iter() {
+        tokio::spawn(report_changes(device.clone(), smart_home.clone()));
+    }
+
+    info!("Smart Home interface enabled");
+    Ok(smart_home)
+}
+
+/// Send a ChangeReport event for each change to the AVR's state
+async fn report_changes(device: Device, smart_home: Arc<SmartHome>) {
+    let mut events = device.events.subscribe();
+
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => return,
+        };
+
+        if let Some(report) = change_report(&device, &event) {
+            if let Err(e) = smart_home.gateway.send(report).await {
+                log_error(&e.context("Could not send ChangeReport").into());
+            }
+        }
+    }
+}
+
+/// Discovered endpoint for the AVR
+fn endpoint(device: &Device) -> Value {
+    let inputs: Vec<_> = device
+        .inputs
+        .visible()
+        .iter()
+        .map(|(_, name)| json!({ "name": name }))
+        .collect();
+
+    let mut input_controller = capability("Alexa.InputController", &["input"]);
+    input_controller["inputs"] = json!(inputs);
+
+    json!({
+        "endpointId": endpoint_id(&device.name),
+        "manufacturerName": "Alexa AVR Control",
+        "description": "AVR controlled over telnet",
+        "friendlyName": device.name,
+        "displayCategories": ["SPEAKER"],
+        "capabilities": [
+            {
+                "type": "AlexaInterface",
+                "interface": "Alexa",
+                "version": "3",
+            },
+            capability("Alexa.PowerController", &["powerState"]),
+            capability("Alexa.Speaker", &["volume", "muted"]),
+            input_controller,
+            capability("Alexa.EndpointHealth", &["connectivity"]),
+        ],
+    })
+}
+
+/// Capability with the given properties, which are reported with
+/// ChangeReport events
+fn capability(interface: &str, properties: &[&str]) -> Value {
+    let supported: Vec<_> = properties
+        .iter()
+        .map(|name| json!({ "name": name }))
+        .collect();
+
+    json!({
+        "type": "AlexaInterface",
+        "interface": interface,
+        "version": "3",
+        "properties": {
+            "supported": supported,
+            "proactivelyReported": true,
+            "retrievable": false,
+        },
+    })
+}
+
+/// ChangeReport for the event, with the rest of the AVR's known state as
+/// context
+fn change_report(device: &Device, event: &AvrEvent) -> Option<Value> {
+    let change = property(device, event)?;
+    let context: Vec<_> = device
+        .events
+        .state()
+        .events()
+        .iter()
+        .filter(|other| mem::discriminant(*other) != mem::discriminant(event))
+        .filter_map(|other| property(device, other))
+        .collect();
+
+    Some(json!({
+        "event": {
+            "header": header("Alexa", "ChangeReport", None),
+            "endpoint": {
+                "endpointId": endpoint_id(&device.name),
+            },
+            "payload": {
+                "change": {
+                    "cause": {
+                        "type": "PHYSICAL_INTERACTION",
+                    },
+                    "properties": [change],
+                },
+            },
+        },
+        "context": {
+            "properties": context,
+        },
+    }))
+}
+
+/// Property reported to Alexa for the state in the event
+fn property(device: &Device, event: &AvrEvent) -> Option<Value> {
+    let protocol = device.events.protocol();
+    let (namespace, name, value) = match event {
+        AvrEvent::Power(on) => (
+            "Alexa.PowerController",
+            "powerState",
+            json!(if *on { "ON" } else { "OFF" }),
+        ),
+        AvrEvent::Volume(level) => (
+            "Alexa.Speaker",
+            "volume",
+            json!(protocol.volume_percent(*level)),
+        ),
+        AvrEvent::Mute(mute) => ("Alexa.Speaker", "muted", json!(mute)),
+        AvrEvent::Input(input) => (
+            "Alexa.InputController",
+            "input",
+            json!(device.input_name(input)?),
+        ),
+        AvrEvent::Preset(_)
+        | AvrEvent::SoundRetriever(_)
+        | AvrEvent::DialogEnhancement(_)
+        | AvrEvent::ListeningMode(_)
+        | AvrEvent::SpeakerSystem(_)
+        | AvrEvent::SubwooferLevel(_)
+        | AvrEvent::LfeAttenuation(_)
+        | AvrEvent::Headphones(_)
+        | AvrEvent::VideoConverter(_)
+        | AvrEvent::VideoResolution(_)
+        | AvrEvent::NetworkStandby(_)
+        | AvrEvent::InputLabel(..)
+        | AvrEvent::Model(_)
+        | AvrEvent::Firmware(_) => return None,
+        AvrEvent::Connected(connected) => (
+            "Alexa.EndpointHealth",
+            "connectivity",
+            json!({ "value": if *connected { "OK" } else { "UNREACHABLE" } }),
+        ),
+    };
+
+    Some(json!({
+        "namespace": namespace,
+        "name": name,
+        "value": value,
+        "timeOfSample": Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+        "uncertaintyInMilliseconds": 0,
+    }))
+}
+
+/// Error response to the directive
+fn error_response(directive: &Directive, error: &str, message: &str) -> Value {
+    json!({
+        "event": {
+            "header": header(
+                "Alexa",
+                "ErrorResponse",
+                directive.header.correlation_token.as_deref(),
+            ),
+            "endpoint": directive.endpoint,
+            "payload": {
+                "type": error,
+                "message": message,
+            },
+        }
+    })
+}
+
+/// Header of an event sent to Alexa, with the directive's correlation token
+/// if it's a response
+fn header(namespace: &str, name: &str, correlation_token: Option<&str>) -> Value {
+    let mut header = json!({
+        "namespace": namespace,
+        "name": name,
+        "messageId": Uuid::new_v4().to_string(),
+        "payloadVersion": "3",
+    });
+    if let Some(token) = correlation_token {
+        header["correlationToken"] = json!(token);
+    }
+    header
+}
+
+/// Endpoint id for the AVR, its name with any characters Alexa doesn't allow
+/// replaced
+fn endpoint_id(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || "_-=#;:?@&".contains(c) {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,148 @@
+/// This module listens on a Unix domain socket for commands from scripts on
+/// the same host, without opening the HTTP port or needing a token.
+///
+/// Each line sent is a JSON command, the same as the body of the control
+/// API's `/api/command`, and is answered with a line once it's processed:
+///
+/// ```json
+/// {"device": "living room", "command": {"SetVolume": 4}}
+/// {"ok": true}
+/// {"command": "Dance"}
+/// {"error": {"code": "bad_request", "message": "unknown variant `Dance`, ..."}, "ok": false}
+/// ```
+///
+/// The socket is created only readable and writable by the user running the
+/// service, which is what stands in for authentication. The `send`
+/// subcommand sends its command on the socket too, marked with `"cli": true`
+/// so it's recorded as coming from the command line.
+use crate::{
+    api::{self, ApiError, CommandRequest},
+    avr::AvrCommand,
+    config::SocketConfig,
+    device::Devices,
+    queue::Source,
+};
+use failure::{bail, Error, ResultExt};
+use hyper::StatusCode;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{fs, os::unix::fs::PermissionsExt, path::Path};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+};
+use tracing::{debug, info, warn};
+
+/// Command sent on the socket, `cli` if it's from the `send` subcommand
+#[derive(Deserialize, Debug)]
+struct SocketRequest {
+    #[serde(flatten)]
+    request: CommandRequest,
+    #[serde(default)]
+    cli: bool,
+}
+
+/// Bind the socket, replacing any left over from a previous run, and start a
+/// task accepting connections on it
+pub fn run(config: SocketConfig, devices: &Devices) -> Result<(), Error> {
+    let path = config.path;
+    if Path::new(&path).exists() {
+        fs::remove_file(&path).context(format!("Could not remove old socket: {}", path))?;
+    }
+    let listener = UnixListener::bind(&path).context(format!("Could not bind socket: {}", path))?;
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o600))
+        .context(format!("Could not set permissions of socket: {}", path))?;
+    info!("Listening for commands on {}", path);
+
+    let devices = devices.clone();
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    tokio::spawn(serve(stream, devices.clone()));
+                }
+                Err(e) => warn!("Could not accept socket connection: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Process each line sent on the connection as a command, answering each in
+/// turn, until it's closed
+async fn serve(stream: UnixStream, devices: Devices) {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    loop {
+        let line = match lines.next_line().await {
+            Ok(Some(line)) => line,
+            Ok(None) => return,
+            Err(e) => {
+                warn!("Could not read from socket: {}", e);
+                return;
+            }
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        debug!("Socket command: {:?}", line);
+
+        let reply = match serde_json::from_str::<SocketRequest>(&line) {
+            Ok(SocketRequest { request, cli }) => {
+                let source = if cli { Source::Cli } else { Source::Socket };
+                api::process(&devices, request, source).await
+            }
+            Err(e) => Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "bad_request",
+                e.to_string(),
+            )),
+        };
+        let mut reply = reply_json(reply).to_string();
+        reply.push('\n');
+        if let Err(e) = writer.write_all(reply.as_bytes()).await {
+            warn!("Could not write to socket: {}", e);
+            return;
+        }
+    }
+}
+
+/// `{"ok": true}`, or `{"ok": false}` along with the error
+fn reply_json(result: Result<(), ApiError>) -> Value {
+    match result {
+        Ok(()) => json!({ "ok": true }),
+        Err(e) => {
+            let mut reply = serde_json::to_value(&e).unwrap_or_else(|_| json!({}));
+            reply["ok"] = json!(false);
+            reply
+        }
+    }
+}
+
+/// Send the command to the service listening on the socket at `path`, for
+/// `device` or the first AVR, and wait for it to be processed
+pub async fn send(path: &str, device: Option<&str>, command: AvrCommand) -> Result<(), Error> {
+    let stream = UnixStream::connect(path)
+        .await
+        .context(format!("Could not connect to socket: {}", path))?;
+    let (reader, mut writer) = stream.into_split();
+
+    let mut request = json!({ "device": device, "command": command, "cli": true }).to_string();
+    request.push('\n');
+    writer.write_all(request.as_bytes()).await?;
+
+    let reply = match BufReader::new(reader).lines().next_line().await? {
+        Some(reply) => serde_json::from_str::<Value>(&reply)?,
+        None => bail!("Socket closed without answering"),
+    };
+    if reply["ok"] != json!(true) {
+        bail!(
+            "{}",
+            reply["error"]["message"]
+                .as_str()
+                .unwrap_or("Command failed")
+        );
+    }
+    Ok(())
+}
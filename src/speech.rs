@@ -1,42 +1,772 @@
-/// All Alexa speech options go here
+/// All Alexa speech options go here.
+///
+/// Phrases are looked up by key in the speech table for the request's locale.
+/// en-US is built in, and tables for other locales can be added as TOML files
+/// in the configured `locales` directory, named after the locale, like
+/// `de-DE.toml`, or just its language, like `de.toml`:
+///
+/// ```toml
+/// ok = ["Okay.", "Erledigt.", "Gemacht."]
+/// volume_error = "Die Lautstärke muss zwischen {min} und {max} liegen."
+/// ```
+///
+/// A phrase can be a list of variations, one of which is picked at random
+/// each time. Phrases missing from a table, and locales without one, fall
+/// back to en-US, which can itself be overridden with an `en-US.toml` table.
+///
+/// Phrases are written as SSML, with pauses, emphasis and numbers spoken as
+/// numbers. Unless SSML is enabled in the config, the markup is stripped and
+/// they're sent as plain text.
+use crate::{
+    avr::AvrCommand,
+    config::{SpeechConfig, Verbosity},
+    device::Device,
+    identity::Identity,
+};
 use alexa_sdk::response::Speech;
+use failure::{Error, ResultExt};
+use rand::seq::SliceRandom;
+use regex::Regex;
+use serde::Deserialize;
+use std::{collections::HashMap, fs, path::Path, sync::Arc, time::Duration};
+use tracing::{debug, info, warn};
 
-pub fn hello() -> Speech {
-    Speech::plain("What can I do for you?")
-}
+/// Built in en-US speech table. `{}` placeholders are filled in by the
+/// `Speaker` method for the phrase.
+const EN_US: [(&str, &str); 99] = [
+    ("hello", "What can I do for you?"),
+    ("ok", "Ok."),
+    ("listening", "Ok, I'm listening."),
+    ("anything_else", "Anything else?"),
+    ("done_volume", "Ok, volume {volume}."),
+    ("done_volume_db", "Ok, volume {db} decibels."),
+    ("done_input", "Ok, input {input}."),
+    ("done_preset", "Ok, {preset} on the radio."),
+    ("done_sequence", "Ok, {sequence}."),
+    ("done_raw", "It answered {response}."),
+    ("done_raw_silent", "It didn't answer."),
+    ("identity", "It's a {model}, on firmware {firmware}."),
+    ("identity_model", "It's a {model}."),
+    ("identity_unknown", "It didn't say which model it is."),
+    ("done_mute", "Ok, muted."),
+    ("done_mute_for", "Ok, muted for {duration}."),
+    ("done_timer", "Ok, in {duration}."),
+    ("timer_cancelled", "Ok, the timer's cancelled."),
+    ("done_undo", "Ok, that's undone."),
+    ("done_unmute", "Ok, unmuted."),
+    ("done_power_on", "Ok, it's on."),
+    ("done_power_off", "Ok, it's off."),
+    ("done_pairing", "Ok, it's ready to pair."),
+    ("done_sound_retriever_on", "Ok, Sound Retriever is on."),
+    ("done_sound_retriever_off", "Ok, Sound Retriever is off."),
+    ("done_dialog_enhancement_on", "Ok, Dialog Enhancement is on."),
+    ("done_dialog_enhancement_off", "Ok, Dialog Enhancement is off."),
+    ("done_listening_mode", "Ok, listening mode changed."),
+    ("done_speaker_system", "Ok, speaker system changed."),
+    ("done_subwoofer_up", "Ok, more bass."),
+    ("done_subwoofer_down", "Ok, less bass."),
+    ("done_lfe_attenuation", "Ok, LFE attenuated by {db} decibels."),
+    ("done_video_converter_on", "Ok, the video converter is on."),
+    ("done_video_converter_off", "Ok, the video converter is off."),
+    ("done_video_resolution", "Ok, video resolution changed."),
+    (
+        "done_network_standby_on",
+        "Ok, network standby is on, so it can be turned on from standby.",
+    ),
+    ("done_state", "Ok, {state}."),
+    ("state_power_on", "power is on"),
+    ("state_power_off", "power is off"),
+    ("state_input", "input is now {input}"),
+    ("state_volume", "volume is {volume}"),
+    ("state_volume_db", "volume is {volume}, that's {db} decibels"),
+    ("minus", "minus"),
+    ("state_muted", "it's muted"),
+    ("and", "and"),
+    ("second", "a second"),
+    ("seconds", "{seconds} seconds"),
+    ("minute", "a minute"),
+    ("minutes", "{minutes} minutes"),
+    ("list_inputs", "The inputs are {inputs}."),
+    ("confirm_power_off", "Are you sure you want to turn it off?"),
+    (
+        "confirm_volume",
+        "That's a big jump. <break time=\"200ms\"/> Set the volume to {volume}?",
+    ),
+    (
+        "confirm_speaker_system",
+        "That changes which speakers play. <break time=\"200ms\"/> Are you sure?",
+    ),
+    ("cancelled", "Ok, never mind."),
+    ("progress_power_on", "Turning it on..."),
+    ("progress_volume", "Setting the volume..."),
+    ("elicit_volume", "To what level?"),
+    ("elicit_input", "Which input?"),
+    ("elicit_preset", "Which preset?"),
+    ("elicit_sequence", "Which one?"),
+    ("elicit_code", "Which code?"),
+    ("elicit_timer", "In how long?"),
+    ("elicit_timer_command", "What should it do then?"),
+    ("elicit_transport", "Play, pause, stop, next or previous?"),
+    ("elicit_speaker_system", "Which speaker system?"),
+    ("elicit_lfe_attenuation", "By how many decibels?"),
+    ("elicit_video_resolution", "Which resolution?"),
+    ("hmm", "Hmm."),
+    (
+        "help",
+        "Try commands such as: <break time=\"300ms\"/> on, off, mute, unmute, volume {volume}, input {input}.",
+    ),
+    ("volume_error", "Volume must be between {min} and {max}."),
+    ("input_error", "Input must be between {min} and {max}."),
+    ("device_error", "I don't know a receiver called {name}."),
+    ("input_name_error", "I don't know an input called {name}."),
+    ("preset_name_error", "I don't know a preset called {name}."),
+    ("sequence_name_error", "I don't know a sequence called {name}."),
+    ("code_name_error", "I don't know a code called {name}."),
+    ("no_previous_input", "There's no input to switch back to."),
+    ("no_previous_volume", "There's no volume to put back."),
+    ("nothing_to_undo", "There's nothing to undo."),
+    ("no_timer", "There's no timer to cancel."),
+    ("timer_command_error", "I can't {name} on a timer."),
+    (
+        "lfe_attenuation_error",
+        "The receiver can't attenuate the LFE by {db} decibels.",
+    ),
+    ("no_transport", "There's no music to control on this input."),
+    ("response_error", "Don't think it worked..."),
+    (
+        "error_power_already_off",
+        "Power is <emphasis>already</emphasis> off.",
+    ),
+    (
+        "error_power_already_on",
+        "Power is <emphasis>already</emphasis> on.",
+    ),
+    (
+        "error_turn_power_on",
+        "Turn power on <emphasis>first</emphasis>.",
+    ),
+    (
+        "error_busy",
+        "It's still busy with another request, try again in a moment.",
+    ),
+    (
+        "error_network_standby",
+        "I can't reach the receiver in standby, its network standby looks to be off. Turn it on by hand, then ask me to turn on network standby.",
+    ),
+    (
+        "quiet_hours",
+        "It's after {time}, keeping the volume at {volume}.",
+    ),
+    (
+        "user_volume_limit",
+        "Keeping the volume at {volume}, that's as loud as it goes for you.",
+    ),
+    (
+        "headphones",
+        "Headphones are plugged in, so the speakers are off. Unplug them first.",
+    ),
+    ("not_allowed", "Sorry, you can't do that."),
+    (
+        "link_account",
+        "To control your receiver, link your account in the Alexa app.",
+    ),
+    (
+        "account_not_allowed",
+        "Sorry, this skill isn't set up for your account.",
+    ),
+    (
+        "account_error",
+        "Sorry, I couldn't check your account, try again in a moment.",
+    ),
+    ("not_enabled", "Sorry, that's not enabled."),
+    ("unsupported", "Sorry, this receiver can't do that."),
+    ("unsupported_input", "Sorry, this receiver doesn't have input {input}."),
+];
 
-pub fn ok() -> Speech {
-    Speech::plain("Ok.")
-}
+const DEFAULT_LOCALE: &str = "en-US";
 
-pub fn hmm() -> Speech {
-    Speech::plain("Hmm.")
-}
+/// Variations of the phrase for each key
+type Table = HashMap<String, Vec<String>>;
 
-pub fn help() -> Speech {
-    Speech::plain("Try commands such as: on, off, mute, unmute, volume 2, input3.")
+/// Phrase in a speech table file, either a single phrase or a list of
+/// variations
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Phrases {
+    One(String),
+    Many(Vec<String>),
 }
 
-pub fn volume_error() -> Speech {
-    Speech::plain("Volume must be between 1 and 10.")
+/// Builds the skill's speech in the request's locale, as SSML or plain text
+/// depending on the config
+#[derive(Clone)]
+pub struct Speaker {
+    ssml: bool,
+    verbosity: Verbosity,
+    tables: Arc<HashMap<String, Table>>,
+    locale: String,
 }
 
-pub fn input_error() -> Speech {
-    Speech::plain("Input must be between 1 and 22.")
+impl Speaker {
+    /// Load the speech tables from the configured `locales` directory
+    pub fn new(config: &SpeechConfig) -> Result<Speaker, Error> {
+        let mut tables = HashMap::new();
+
+        if let Some(dir) = &config.locales {
+            let entries =
+                fs::read_dir(dir).context(format!("Could not read locales directory: {}", dir))?;
+            for entry in entries {
+                let path = entry?.path();
+                if path.extension().and_then(|ext| ext.to_str()) != Some("toml") {
+                    continue;
+                }
+                let (locale, table) = load_table(&path)?;
+                info!("Loaded speech table for locale: {}", locale);
+                tables.insert(locale, table);
+            }
+        }
+
+        Ok(Speaker {
+            ssml: config.ssml,
+            verbosity: config.verbosity,
+            tables: Arc::new(tables),
+            locale: DEFAULT_LOCALE.to_owned(),
+        })
+    }
+
+    /// Speaker for the request's locale, en-US if not given
+    pub fn with_locale(&self, locale: Option<&str>) -> Speaker {
+        let locale = locale.unwrap_or(DEFAULT_LOCALE);
+        debug!("Locale: {}", locale);
+        Speaker {
+            locale: locale.to_owned(),
+            ..self.clone()
+        }
+    }
+
+    pub fn hello(&self) -> Speech {
+        self.phrase("hello", &[])
+    }
+
+    pub fn ok(&self) -> Speech {
+        self.phrase("ok", &[])
+    }
+
+    pub fn listening(&self) -> Speech {
+        self.phrase("listening", &[])
+    }
+
+    pub fn anything_else(&self) -> Speech {
+        self.phrase("anything_else", &[])
+    }
+
+    /// Confirm the command is done, as configured by `verbosity`
+    pub fn done(&self, cmd: &AvrCommand, device: &Device) -> Speech {
+        match self.verbosity {
+            Verbosity::Terse => self.ok(),
+            Verbosity::Normal => self.done_command(cmd, device),
+            Verbosity::Verbose => self.done_state(device),
+        }
+    }
+
+    /// Repeat the command back
+    fn done_command(&self, cmd: &AvrCommand, device: &Device) -> Speech {
+        match cmd {
+            AvrCommand::SetVolume(n) => {
+                self.phrase("done_volume", &[("volume", number(i32::from(*n)))])
+            }
+            AvrCommand::SetVolumeDb(db) => {
+                self.phrase("done_volume_db", &[("db", self.decibels(*db))])
+            }
+            AvrCommand::ChangeInput(n) => {
+                let inputs = device.events.protocol().inputs();
+                let input = device
+                    .inputs
+                    .name(*n, &inputs)
+                    .map(|name| escape(&name))
+                    .unwrap_or_else(|| number(i32::from(*n)));
+                self.phrase("done_input", &[("input", input)])
+            }
+            AvrCommand::SelectPreset(preset) => {
+                let name = device.presets.name(preset).unwrap_or(preset);
+                self.phrase("done_preset", &[("preset", escape(name))])
+            }
+            AvrCommand::Mute => self.phrase("done_mute", &[]),
+            AvrCommand::Unmute => self.phrase("done_unmute", &[]),
+            AvrCommand::PowerOn => self.phrase("done_power_on", &[]),
+            AvrCommand::PowerOff => self.phrase("done_power_off", &[]),
+            AvrCommand::VolumeUp
+            | AvrCommand::VolumeDown
+            | AvrCommand::Gallery(_)
+            | AvrCommand::Ipod(_)
+            | AvrCommand::Adapter(_) => self.ok(),
+            AvrCommand::BluetoothPairing => self.phrase("done_pairing", &[]),
+            AvrCommand::SoundRetrieverOn => self.phrase("done_sound_retriever_on", &[]),
+            AvrCommand::SoundRetrieverOff => self.phrase("done_sound_retriever_off", &[]),
+            AvrCommand::DialogEnhancementOn => self.phrase("done_dialog_enhancement_on", &[]),
+            AvrCommand::DialogEnhancementOff => self.phrase("done_dialog_enhancement_off", &[]),
+            AvrCommand::SetListeningMode(_) => self.phrase("done_listening_mode", &[]),
+            AvrCommand::SetSpeakerSystem(_) => self.phrase("done_speaker_system", &[]),
+            AvrCommand::SubwooferUp => self.phrase("done_subwoofer_up", &[]),
+            AvrCommand::SubwooferDown => self.phrase("done_subwoofer_down", &[]),
+            AvrCommand::SetLfeAttenuation(db) => {
+                self.phrase("done_lfe_attenuation", &[("db", number(i32::from(*db)))])
+            }
+            AvrCommand::VideoConverterOn => self.phrase("done_video_converter_on", &[]),
+            AvrCommand::VideoConverterOff => self.phrase("done_video_converter_off", &[]),
+            AvrCommand::SetVideoResolution(_) => self.phrase("done_video_resolution", &[]),
+            AvrCommand::NetworkStandbyOn => self.phrase("done_network_standby_on", &[]),
+        }
+    }
+
+    /// Read back the AVR's state, as reported by the AVR after the command
+    fn done_state(&self, device: &Device) -> Speech {
+        let state = device.events.state();
+        let protocol = device.events.protocol();
+        let mut parts = vec![];
+
+        match state.power {
+            Some(false) => parts.push(self.lookup("state_power_off")),
+            Some(true) => parts.push(self.lookup("state_power_on")),
+            None => {}
+        }
+        if state.power != Some(false) {
+            if let Some(input) = state.input.and_then(|input| device.input_name(&input)) {
+                parts.push(self.fill("state_input", &[("input", escape(&input))]));
+            }
+            if let Some(level) = state.volume {
+                let volume = number((i32::from(protocol.volume_percent(level)) + 5) / 10);
+                parts.push(match protocol.volume_db(level) {
+                    Some(db) => self.fill(
+                        "state_volume_db",
+                        &[("volume", volume), ("db", self.decibels(db))],
+                    ),
+                    None => self.fill("state_volume", &[("volume", volume)]),
+                });
+            }
+            if state.mute == Some(true) {
+                parts.push(self.lookup("state_muted"));
+            }
+        }
+
+        match self.join(&parts) {
+            Some(state) => self.speak(&self.fill("done_state", &[("state", state)])),
+            None => self.ok(),
+        }
+    }
+
+    /// Name the inputs
+    pub fn list_inputs(&self, names: &[&str]) -> Speech {
+        let names: Vec<_> = names.iter().map(|name| escape(name)).collect();
+        match self.join(&names) {
+            Some(inputs) => self.phrase("list_inputs", &[("inputs", inputs)]),
+            None => self.ok(),
+        }
+    }
+
+    /// dB to the half, like "minus 18.5"
+    fn decibels(&self, db: f32) -> String {
+        let magnitude = format!(
+            "<say-as interpret-as=\"cardinal\">{}</say-as>",
+            (db.abs() * 2.0).round() / 2.0
+        );
+        if db <= -0.25 {
+            format!("{} {}", self.lookup("minus"), magnitude)
+        } else {
+            magnitude
+        }
+    }
+
+    /// The duration like "5 minutes", rounded up to the minute, or in seconds
+    /// if it's under a minute
+    fn duration(&self, duration: Duration) -> String {
+        match duration.as_secs() {
+            0..=1 => self.lookup("second"),
+            seconds @ 2..=59 => self.fill("seconds", &[("seconds", number(seconds as i32))]),
+            seconds => match seconds.div_ceil(60) {
+                1 => self.lookup("minute"),
+                minutes => self.fill("minutes", &[("minutes", number(minutes as i32))]),
+            },
+        }
+    }
+
+    /// Join the parts into a list, like "a, b and c"
+    fn join(&self, parts: &[String]) -> Option<String> {
+        match parts.split_last()? {
+            (last, []) => Some(last.clone()),
+            (last, rest) => Some(format!(
+                "{} {} {}",
+                rest.join(", "),
+                self.lookup("and"),
+                last
+            )),
+        }
+    }
+
+    /// Ask to confirm the command before it's processed
+    pub fn confirm(&self, cmd: &AvrCommand) -> Speech {
+        match cmd {
+            AvrCommand::PowerOff => self.phrase("confirm_power_off", &[]),
+            AvrCommand::SetVolume(n) => {
+                self.phrase("confirm_volume", &[("volume", number(i32::from(*n)))])
+            }
+            AvrCommand::SetSpeakerSystem(_) => self.phrase("confirm_speaker_system", &[]),
+            _ => self.hmm(),
+        }
+    }
+
+    /// Say the command is under way, for commands slow enough to need a
+    /// progressive response. It's sent as text rather than `Speech`, SSML or
+    /// plain as configured.
+    pub fn progress(&self, cmd: &AvrCommand) -> Option<String> {
+        let key = match cmd {
+            AvrCommand::PowerOn => "progress_power_on",
+            AvrCommand::SetVolume(_) => "progress_volume",
+            _ => return None,
+        };
+        let ssml = self.lookup(key);
+        if self.ssml {
+            Some(format!("<speak>{}</speak>", ssml))
+        } else {
+            Some(plain(&ssml))
+        }
+    }
+
+    /// Ask for the value of the slot missing from the request
+    pub fn elicit(&self, slot: &str) -> Speech {
+        match slot {
+            "Volume_slot" => self.phrase("elicit_volume", &[]),
+            "Input_slot" => self.phrase("elicit_input", &[]),
+            "Preset_slot" => self.phrase("elicit_preset", &[]),
+            "Sequence_slot" => self.phrase("elicit_sequence", &[]),
+            "SendCode_slot" => self.phrase("elicit_code", &[]),
+            "Timer_slot" => self.phrase("elicit_timer", &[]),
+            "TimerCommand_slot" => self.phrase("elicit_timer_command", &[]),
+            "Transport_slot" => self.phrase("elicit_transport", &[]),
+            "SpeakerSystem_slot" => self.phrase("elicit_speaker_system", &[]),
+            "LfeAttenuation_slot" => self.phrase("elicit_lfe_attenuation", &[]),
+            "VideoResolution_slot" => self.phrase("elicit_video_resolution", &[]),
+            _ => self.hmm(),
+        }
+    }
+
+    pub fn cancelled(&self) -> Speech {
+        self.phrase("cancelled", &[])
+    }
+
+    pub fn hmm(&self) -> Speech {
+        self.phrase("hmm", &[])
+    }
+
+    pub fn help(&self) -> Speech {
+        self.phrase("help", &[("volume", number(2)), ("input", number(3))])
+    }
+
+    pub fn volume_error(&self) -> Speech {
+        self.phrase("volume_error", &[("min", number(1)), ("max", number(10))])
+    }
+
+    pub fn input_error(&self) -> Speech {
+        self.phrase("input_error", &[("min", number(1)), ("max", number(22))])
+    }
+
+    pub fn device_error(&self, name: &str) -> Speech {
+        self.phrase("device_error", &[("name", escape(name))])
+    }
+
+    pub fn input_name_error(&self, name: &str) -> Speech {
+        self.phrase("input_name_error", &[("name", escape(name))])
+    }
+
+    pub fn preset_name_error(&self, name: &str) -> Speech {
+        self.phrase("preset_name_error", &[("name", escape(name))])
+    }
+
+    /// Confirm the named sequence is done, as configured by `verbosity`
+    pub fn done_sequence(&self, name: &str, device: &Device) -> Speech {
+        match self.verbosity {
+            Verbosity::Terse => self.ok(),
+            Verbosity::Normal => self.phrase("done_sequence", &[("sequence", escape(name))]),
+            Verbosity::Verbose => self.done_state(device),
+        }
+    }
+
+    /// Confirm the activity is done, reading back the state it left the AVR
+    /// in unless `verbosity` is terse
+    pub fn done_activity(&self, device: &Device) -> Speech {
+        match self.verbosity {
+            Verbosity::Terse => self.ok(),
+            Verbosity::Normal | Verbosity::Verbose => self.done_state(device),
+        }
+    }
+
+    /// Confirm the change is undone, as configured by `verbosity`
+    pub fn done_undo(&self, device: &Device) -> Speech {
+        match self.verbosity {
+            Verbosity::Terse => self.ok(),
+            Verbosity::Normal => self.phrase("done_undo", &[]),
+            Verbosity::Verbose => self.done_state(device),
+        }
+    }
+
+    pub fn sequence_name_error(&self, name: &str) -> Speech {
+        self.phrase("sequence_name_error", &[("name", escape(name))])
+    }
+
+    /// Confirm the AVR is muted for the duration
+    pub fn done_mute_for(&self, duration: Duration) -> Speech {
+        let duration = self.duration(duration);
+        self.phrase("done_mute_for", &[("duration", duration)])
+    }
+
+    /// Confirm the command is on a timer for the duration
+    pub fn done_timer(&self, duration: Duration) -> Speech {
+        let duration = self.duration(duration);
+        self.phrase("done_timer", &[("duration", duration)])
+    }
+
+    pub fn timer_cancelled(&self) -> Speech {
+        self.phrase("timer_cancelled", &[])
+    }
+
+    /// Read back the AVR's answer to a raw code, spelling out each message
+    pub fn done_raw(&self, response: &str) -> Speech {
+        let messages: Vec<_> = response
+            .lines()
+            .filter(|message| !message.trim().is_empty())
+            .map(|message| {
+                format!(
+                    "<say-as interpret-as=\"characters\">{}</say-as>",
+                    escape(message.trim())
+                )
+            })
+            .collect();
+        if messages.is_empty() {
+            return self.phrase("done_raw_silent", &[]);
+        }
+        let response = messages.join("<break time=\"300ms\"/>");
+        self.phrase("done_raw", &[("response", response)])
+    }
+
+    /// Name the AVR's model, and its firmware version if it said
+    pub fn identity(&self, identity: &Identity) -> Speech {
+        match (&identity.model, &identity.firmware) {
+            (Some(model), Some(firmware)) => self.phrase(
+                "identity",
+                &[("model", escape(model)), ("firmware", escape(firmware))],
+            ),
+            (Some(model), None) => self.phrase("identity_model", &[("model", escape(model))]),
+            (None, _) => self.phrase("identity_unknown", &[]),
+        }
+    }
+
+    pub fn code_name_error(&self, name: &str) -> Speech {
+        self.phrase("code_name_error", &[("name", escape(name))])
+    }
+
+    pub fn no_previous_input(&self) -> Speech {
+        self.phrase("no_previous_input", &[])
+    }
+
+    pub fn no_previous_volume(&self) -> Speech {
+        self.phrase("no_previous_volume", &[])
+    }
+
+    pub fn nothing_to_undo(&self) -> Speech {
+        self.phrase("nothing_to_undo", &[])
+    }
+
+    pub fn no_timer(&self) -> Speech {
+        self.phrase("no_timer", &[])
+    }
+
+    pub fn timer_command_error(&self, name: &str) -> Speech {
+        self.phrase("timer_command_error", &[("name", escape(name))])
+    }
+
+    pub fn lfe_attenuation_error(&self, db: u8) -> Speech {
+        self.phrase("lfe_attenuation_error", &[("db", number(i32::from(db)))])
+    }
+
+    pub fn no_transport(&self) -> Speech {
+        self.phrase("no_transport", &[])
+    }
+
+    pub fn response_error(&self) -> Speech {
+        self.phrase("response_error", &[])
+    }
+
+    pub fn error_power_already_off(&self) -> Speech {
+        self.phrase("error_power_already_off", &[])
+    }
+
+    pub fn error_power_already_on(&self) -> Speech {
+        self.phrase("error_power_already_on", &[])
+    }
+
+    pub fn error_turn_power_on(&self) -> Speech {
+        self.phrase("error_turn_power_on", &[])
+    }
+
+    pub fn error_busy(&self) -> Speech {
+        self.phrase("error_busy", &[])
+    }
+
+    pub fn error_network_standby(&self) -> Speech {
+        self.phrase("error_network_standby", &[])
+    }
+
+    /// Say the volume was kept to the quiet hours ceiling
+    pub fn quiet_hours(&self, start: &str, volume: u8) -> Speech {
+        self.phrase(
+            "quiet_hours",
+            &[
+                ("time", escape(start)),
+                ("volume", number(i32::from(volume))),
+            ],
+        )
+    }
+
+    /// Say the volume was kept to the user's ceiling
+    pub fn user_volume_limit(&self, volume: u8) -> Speech {
+        self.phrase(
+            "user_volume_limit",
+            &[("volume", number(i32::from(volume)))],
+        )
+    }
+
+    /// Say a speaker command was skipped as headphones are plugged in
+    pub fn headphones(&self) -> Speech {
+        self.phrase("headphones", &[])
+    }
+
+    pub fn not_allowed(&self) -> Speech {
+        self.phrase("not_allowed", &[])
+    }
+
+    pub fn link_account(&self) -> Speech {
+        self.phrase("link_account", &[])
+    }
+
+    pub fn account_not_allowed(&self) -> Speech {
+        self.phrase("account_not_allowed", &[])
+    }
+
+    pub fn account_error(&self) -> Speech {
+        self.phrase("account_error", &[])
+    }
+
+    pub fn not_enabled(&self) -> Speech {
+        self.phrase("not_enabled", &[])
+    }
+
+    pub fn unsupported(&self) -> Speech {
+        self.phrase("unsupported", &[])
+    }
+
+    pub fn unsupported_input(&self, n: u8) -> Speech {
+        self.phrase("unsupported_input", &[("input", number(i32::from(n)))])
+    }
+
+    /// Look up the phrase for the locale, filling in its placeholders, then
+    /// speak it
+    fn phrase(&self, key: &str, args: &[(&str, String)]) -> Speech {
+        self.speak(&self.fill(key, args))
+    }
+
+    /// Look up the phrase for the locale, filling in its placeholders
+    fn fill(&self, key: &str, args: &[(&str, String)]) -> String {
+        let mut phrase = self.lookup(key);
+        for (name, value) in args {
+            phrase = phrase.replace(&format!("{{{}}}", name), value);
+        }
+        phrase
+    }
+
+    /// Phrase from the table for the locale, or for its language, falling
+    /// back to en-US and then the built in phrase. If the table has
+    /// variations of the phrase, one is picked at random.
+    fn lookup(&self, key: &str) -> String {
+        let language = self.locale.split('-').next().unwrap_or_default();
+
+        [self.locale.as_str(), language, DEFAULT_LOCALE]
+            .iter()
+            .filter_map(|locale| self.tables.get(*locale))
+            .find_map(|table| table.get(key))
+            .and_then(|phrases| phrases.choose(&mut rand::thread_rng()))
+            .map(String::as_str)
+            .or_else(|| {
+                EN_US
+                    .iter()
+                    .find(|(known, _)| *known == key)
+                    .map(|(_, phrase)| *phrase)
+            })
+            .unwrap_or_default()
+            .to_owned()
+    }
+
+    /// Speak the SSML phrase, as plain text if SSML isn't enabled
+    fn speak(&self, ssml: &str) -> Speech {
+        if self.ssml {
+            Speech::ssml(&format!("<speak>{}</speak>", ssml))
+        } else {
+            Speech::plain(&plain(ssml))
+        }
+    }
 }
 
-pub fn response_error() -> Speech {
-    Speech::plain("Don't think it worked...")
+/// Load a locale's speech table, named after the locale
+fn load_table(path: &Path) -> Result<(String, Table), Error> {
+    let display = path.display();
+    let locale = path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or_default()
+        .to_owned();
+    let contents =
+        fs::read_to_string(path).context(format!("Could not read speech table: {}", display))?;
+    let phrases: HashMap<String, Phrases> =
+        toml::from_str(&contents).context(format!("Could not parse speech table: {}", display))?;
+
+    let mut table = Table::new();
+    for (key, phrases) in phrases {
+        if !EN_US.iter().any(|(known, _)| *known == key) {
+            warn!("Unknown phrase in speech table: {}: {}", display, key);
+        }
+        let phrases = match phrases {
+            Phrases::One(phrase) => vec![phrase],
+            Phrases::Many(phrases) => phrases,
+        };
+        table.insert(key, phrases);
+    }
+
+    Ok((locale, table))
 }
 
-pub fn error_power_already_off() -> Speech {
-    Speech::plain("Power is already off.")
+/// Number spoken as a number, rather than digit by digit
+fn number(n: i32) -> String {
+    format!("<say-as interpret-as=\"cardinal\">{}</say-as>", n)
 }
 
-pub fn error_power_already_on() -> Speech {
-    Speech::plain("Power is already on.")
+/// Escape text that isn't SSML, like an AVR name
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
 }
 
-pub fn error_turn_power_on() -> Speech {
-    Speech::plain("Turn power on first.")
+/// Strip the SSML markup, leaving the text to speak
+fn plain(ssml: &str) -> String {
+    let tags = Regex::new(r"<[^>]*>").unwrap();
+    let spaces = Regex::new(r"\s+").unwrap();
+
+    let text = tags.replace_all(ssml, "");
+    let text = spaces.replace_all(text.trim(), " ");
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
 }
@@ -0,0 +1,228 @@
+/// This module parses the messages a Pioneer AVR sends into typed responses,
+/// so `crate::pioneer` never has to slice strings itself.
+///
+/// Each message is a prefix naming what it reports, followed by the value:
+///
+/// - `PWR0` power on, `PWR1` or `PWR2` off
+/// - `VOL081` volume level, from 0 to 185 in 0.5dB steps
+/// - `MUT0` muted, `MUT1` not
+/// - `FN19` input code
+/// - `PRA06` tuner preset, its class then number
+/// - `ATA1` Sound Retriever on, `ATA0` off
+/// - `ATG1` Dialog Enhancement on, `ATG0` off
+/// - `FL` front panel display, two hex digits of flags then the text as hex
+/// - `SR0101` listening mode code
+/// - `SSF04` speaker system code
+/// - `CLVSW_50` channel level, the channel padded to three characters then
+///   the level, from 26 to 74 in 0.5dB steps with 50 being 0.0dB
+/// - `ATL2` LFE attenuation, 0 to 4 for 0 to 20dB in 5dB steps
+/// - `HP1` headphones plugged in, `HP0` not
+/// - `VTB1` video converter on, `VTB0` off
+/// - `VTC06` HDMI output resolution code
+/// - `STJ1` network standby on, `STJ0` off
+/// - `RGB191PS5` input label, the input code, `1` if it was renamed or `0`
+///   if not, then its name
+/// - `AST` audio status, see `AudioStatus`
+/// - `RGD<101><VSX-1021>` model name, after a code for the model
+/// - `SSI"1-038-07"` firmware version
+/// - `R` heartbeat
+/// - `E04` or `B00` errors
+use std::str;
+
+/// Message from a Pioneer AVR
+#[derive(Clone, Debug, PartialEq)]
+pub enum Response {
+    Power(bool),
+    Volume(u8),
+    Mute(bool),
+    Input(String),
+    Preset(String),
+    SoundRetriever(bool),
+    DialogEnhancement(bool),
+    Display(String),
+    ListeningMode(String),
+    SpeakerSystem(String),
+    ChannelLevel {
+        channel: String,
+        level: u8,
+    },
+    LfeAttenuation(u8),
+    Headphones(bool),
+    VideoConverter(bool),
+    VideoResolution(String),
+    NetworkStandby(bool),
+    InputLabel {
+        input: String,
+        renamed: bool,
+        name: String,
+    },
+    AudioStatus(AudioStatus),
+    Model(String),
+    Firmware(String),
+    Heartbeat,
+    Error(String),
+}
+
+/// Audio status of the current input
+#[derive(Clone, Debug, PartialEq)]
+pub struct AudioStatus {
+    /// Input signal type, like 01 for PCM
+    pub signal: String,
+    /// Input sampling frequency, like 02 for 48kHz
+    pub frequency: String,
+    /// Whether each input channel is present, starting with L, C, R, SL, SR,
+    /// SBL, SBC, SBR and LFE
+    pub channels: Vec<bool>,
+}
+
+impl AudioStatus {
+    /// Whether any of the input channels has a signal
+    pub fn signal_present(&self) -> bool {
+        self.channels.iter().take(9).any(|present| *present)
+    }
+}
+
+/// Parse a single message, ignoring surrounding whitespace. `None` if it
+/// isn't one of the known messages, or its value is invalid.
+pub fn parse(message: &str) -> Option<Response> {
+    let message = message.trim();
+
+    if message == "R" {
+        Some(Response::Heartbeat)
+    } else if is_error(message) {
+        Some(Response::Error(message.to_owned()))
+    } else if let Some(power) = message.strip_prefix("PWR") {
+        match power {
+            "0" => Some(Response::Power(true)),
+            "1" | "2" => Some(Response::Power(false)),
+            _ => None,
+        }
+    } else if let Some(volume) = message.strip_prefix("VOL") {
+        volume.parse().ok().map(Response::Volume)
+    } else if let Some(mute) = message.strip_prefix("MUT") {
+        match mute {
+            "0" => Some(Response::Mute(true)),
+            "1" => Some(Response::Mute(false)),
+            _ => None,
+        }
+    } else if let Some(input) = message.strip_prefix("FN") {
+        Some(Response::Input(input.to_owned()))
+    } else if let Some(preset) = message.strip_prefix("PR") {
+        Some(Response::Preset(preset.to_owned()))
+    } else if let Some(on) = message.strip_prefix("ATA") {
+        parse_toggle(on).map(Response::SoundRetriever)
+    } else if let Some(on) = message.strip_prefix("ATG") {
+        parse_toggle(on).map(Response::DialogEnhancement)
+    } else if let Some(display) = message.strip_prefix("FL") {
+        parse_display(display).map(Response::Display)
+    } else if let Some(mode) = message.strip_prefix("SR") {
+        Some(Response::ListeningMode(mode.to_owned()))
+    } else if let Some(system) = message.strip_prefix("SSF") {
+        Some(Response::SpeakerSystem(system.to_owned()))
+    } else if let Some(level) = message.strip_prefix("CLV") {
+        parse_channel_level(level)
+    } else if let Some(att) = message.strip_prefix("ATL") {
+        att.parse::<u8>()
+            .ok()
+            .filter(|att| *att <= 4)
+            .map(|att| Response::LfeAttenuation(att * 5))
+    } else if let Some(plugged) = message.strip_prefix("HP") {
+        parse_toggle(plugged).map(Response::Headphones)
+    } else if let Some(on) = message.strip_prefix("VTB") {
+        parse_toggle(on).map(Response::VideoConverter)
+    } else if let Some(resolution) = message.strip_prefix("VTC") {
+        Some(Response::VideoResolution(resolution.to_owned()))
+    } else if let Some(on) = message.strip_prefix("STJ") {
+        parse_toggle(on).map(Response::NetworkStandby)
+    } else if let Some(label) = message.strip_prefix("RGB") {
+        parse_input_label(label)
+    } else if let Some(status) = message.strip_prefix("AST") {
+        parse_audio_status(status).map(Response::AudioStatus)
+    } else if let Some(model) = message.strip_prefix("RGD") {
+        parse_model(model).map(Response::Model)
+    } else {
+        message
+            .strip_prefix("SSI")
+            .map(|firmware| Response::Firmware(firmware.trim_matches('"').to_owned()))
+    }
+}
+
+/// Channel and level, like "SW_50"
+fn parse_channel_level(value: &str) -> Option<Response> {
+    let channel = value.get(..3)?;
+    let level = value.get(3..)?.parse().ok()?;
+    Some(Response::ChannelLevel {
+        channel: channel.trim_end_matches('_').to_owned(),
+        level,
+    })
+}
+
+/// "1" for on, "0" for off
+fn parse_toggle(value: &str) -> Option<bool> {
+    match value {
+        "1" => Some(true),
+        "0" => Some(false),
+        _ => None,
+    }
+}
+
+/// Input code, two digits, the renamed flag, then the name
+fn parse_input_label(label: &str) -> Option<Response> {
+    Some(Response::InputLabel {
+        input: label.get(0..2)?.to_owned(),
+        renamed: parse_toggle(label.get(2..3)?)?,
+        name: label.get(3..)?.trim().to_owned(),
+    })
+}
+
+/// Model name, the last part that isn't the model's code, whether or not it's
+/// in angle brackets
+fn parse_model(model: &str) -> Option<String> {
+    model
+        .split(['<', '>'])
+        .map(str::trim)
+        .rfind(|part| !part.is_empty() && !part.bytes().all(|b| b.is_ascii_digit()))
+        .map(str::to_owned)
+}
+
+/// An error such as "E04", or "B00" if the AVR is busy
+fn is_error(message: &str) -> bool {
+    message.len() == 3
+        && (message.starts_with('E') || message.starts_with('B'))
+        && message[1..].bytes().all(|b| b.is_ascii_digit())
+}
+
+/// Text of the front panel display, each character as two hex digits after
+/// the two of the flags
+fn parse_display(display: &str) -> Option<String> {
+    let hex = display.get(2..)?.as_bytes();
+    let text = hex
+        .chunks(2)
+        .map(|pair| {
+            let pair = str::from_utf8(pair).ok()?;
+            let byte = u8::from_str_radix(pair, 16).ok()?;
+            Some(char::from(byte))
+        })
+        .collect::<Option<String>>()?;
+    Some(text.trim().to_owned())
+}
+
+/// Signal type and frequency, two digits each, then a flag for each input
+/// channel
+fn parse_audio_status(status: &str) -> Option<AudioStatus> {
+    let signal = status.get(0..2)?.to_owned();
+    let frequency = status.get(2..4)?.to_owned();
+    let channels = status[4..]
+        .chars()
+        .map(|flag| match flag {
+            '0' => Some(false),
+            '1' => Some(true),
+            _ => None,
+        })
+        .collect::<Option<Vec<_>>>()?;
+    Some(AudioStatus {
+        signal,
+        frequency,
+        channels,
+    })
+}
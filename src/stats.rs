@@ -0,0 +1,183 @@
+/// This module counts how the skill is used: how often each intent is
+/// requested and how often it works, along with how long the web service has
+/// been up. The summary also has how long each AVR takes to carry out each
+/// type of command, see `crate::latency`.
+///
+/// The counts are kept in memory, so they start over on restart. The web
+/// service serves a `Summary` at `/stats`, which the `stats` subcommand
+/// fetches from the running service and prints.
+use crate::{device::Devices, latency::Percentiles};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{SecondsFormat, Utc};
+use failure::{bail, Error, ResultExt};
+use hyper::{body, header::AUTHORIZATION, Body, Client, Request};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+/// Requests for an intent, and how many of them worked or failed
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Count {
+    pub requests: u64,
+    pub succeeded: u64,
+    pub failed: u64,
+}
+
+impl Count {
+    fn add(&mut self, succeeded: bool) {
+        self.requests += 1;
+        if succeeded {
+            self.succeeded += 1;
+        } else {
+            self.failed += 1;
+        }
+    }
+
+    /// Percentage of the requests that worked, if there were any
+    pub fn success_rate(&self) -> Option<f64> {
+        if self.requests == 0 {
+            return None;
+        }
+        Some(self.succeeded as f64 * 100.0 / self.requests as f64)
+    }
+}
+
+/// Counts of every intent requested since the web service started. Cloned for
+/// each request.
+#[derive(Clone)]
+pub struct Stats {
+    started: Instant,
+    started_at: String,
+    intents: Arc<Mutex<BTreeMap<String, Count>>>,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Stats::new()
+    }
+}
+
+impl Stats {
+    pub fn new() -> Stats {
+        Stats {
+            started: Instant::now(),
+            started_at: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+            intents: Arc::new(Mutex::new(BTreeMap::new())),
+        }
+    }
+
+    /// Count a request for the intent, and whether it worked
+    pub fn record(&self, intent: &str, succeeded: bool) {
+        self.intents
+            .lock()
+            .unwrap()
+            .entry(intent.to_owned())
+            .or_default()
+            .add(succeeded);
+    }
+
+    /// Summary of the counts so far, along with the latencies of `devices`
+    pub fn summary(&self, devices: &Devices) -> Summary {
+        let intents = self.intents.lock().unwrap().clone();
+        let mut total = Count::default();
+        for count in intents.values() {
+            total.requests += count.requests;
+            total.succeeded += count.succeeded;
+            total.failed += count.failed;
+        }
+
+        Summary {
+            started: self.started_at.clone(),
+            uptime_secs: self.started.elapsed().as_secs(),
+            success_rate: total.success_rate(),
+            total,
+            intents,
+            latency: devices
+                .iter()
+                .map(|device| (device.name.clone(), device.latency.summary()))
+                .collect(),
+        }
+    }
+}
+
+/// Usage since the web service started, as served at `/stats`
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Summary {
+    pub started: String,
+    pub uptime_secs: u64,
+    pub total: Count,
+    pub success_rate: Option<f64>,
+    pub intents: BTreeMap<String, Count>,
+    /// Percentiles of each AVR's latencies, by AVR name then command
+    #[serde(default)]
+    pub latency: BTreeMap<String, BTreeMap<String, Percentiles>>,
+}
+
+/// Fetch the summary from the web service running on `port` on this machine
+/// and print it, sending `user` as Basic credentials if given
+pub async fn run(port: u16, user: Option<&str>) -> Result<(), Error> {
+    let mut request = Request::get(format!("http://127.0.0.1:{}/stats", port));
+    if let Some(user) = user {
+        request = request.header(AUTHORIZATION, format!("Basic {}", STANDARD.encode(user)));
+    }
+    let response = Client::new()
+        .request(request.body(Body::empty())?)
+        .await
+        .context(format!("Could not reach the web service on port {}", port))?;
+    if !response.status().is_success() {
+        bail!("Web service returned {}", response.status());
+    }
+    let body = body::to_bytes(response.into_body()).await?;
+    let summary: Summary = serde_json::from_slice(&body)?;
+
+    let rate = |count: &Count| match count.success_rate() {
+        Some(rate) => format!("{:.0}%", rate),
+        None => "-".to_owned(),
+    };
+    println!(
+        "Up since {} ({})",
+        summary.started,
+        uptime(summary.uptime_secs)
+    );
+    println!(
+        "{} requests, {} ok, {} failed, {} success rate",
+        summary.total.requests,
+        summary.total.succeeded,
+        summary.total.failed,
+        rate(&summary.total)
+    );
+    for (intent, count) in &summary.intents {
+        println!(
+            "  {:<28}{:>6} requests{:>6} failed{:>6}",
+            intent,
+            count.requests,
+            count.failed,
+            rate(count)
+        );
+    }
+    for (device, commands) in &summary.latency {
+        println!("{} latency", device);
+        for (command, latency) in commands {
+            println!(
+                "  {:<28}{:>6} times  p50 {}ms  p95 {}ms  p99 {}ms",
+                command, latency.count, latency.p50_ms, latency.p95_ms, latency.p99_ms
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Uptime as days, hours and minutes
+fn uptime(secs: u64) -> String {
+    let (days, hours, minutes) = (secs / 86_400, secs % 86_400 / 3_600, secs % 3_600 / 60);
+    if days > 0 {
+        format!("{}d {}h {}m", days, hours, minutes)
+    } else if hours > 0 {
+        format!("{}h {}m", hours, minutes)
+    } else {
+        format!("{}m", minutes)
+    }
+}
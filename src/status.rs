@@ -0,0 +1,142 @@
+/// This module implements the `status` subcommand, which connects to each
+/// configured AVR, queries its state and prints a summary, without starting
+/// the web service.
+///
+/// The queries are sent through a telnet task like the skill's, so their
+/// responses end up in the AVR's cached state, which is what gets printed.
+use crate::{
+    avr,
+    config::DeviceConfig,
+    events::{CachedState, EventBus},
+    log_error,
+    protocol::{self, AvrProtocol},
+    telnet::{self, Backoff},
+};
+use failure::{Error, ResultExt};
+use serde_json::{json, Value};
+use std::sync::Arc;
+
+/// Query every AVR and print its state, as a JSON array if `json` is set
+pub async fn run(configs: Vec<DeviceConfig>, backoff: Backoff, json: bool) -> Result<(), Error> {
+    let mut statuses = vec![];
+
+    for config in configs {
+        let protocol = protocol::from_config(&config)?;
+        let events = EventBus::new(protocol.clone());
+        let conn = telnet::run(
+            config.host.clone(),
+            config.port,
+            backoff.with_connect_timeout(config.connect_timeout),
+            protocol.clone(),
+            events.clone(),
+            false,
+            None,
+        );
+
+        let result = avr::refresh(&conn)
+            .await
+            .with_context(|_| format!("Could not query AVR: {}", config.name));
+        if let Err(e) = result {
+            log_error(&e.into());
+        }
+
+        statuses.push(Status {
+            config,
+            protocol,
+            state: events.state(),
+        });
+    }
+
+    if json {
+        let statuses: Vec<_> = statuses.iter().map(Status::json).collect();
+        println!("{}", serde_json::to_string_pretty(&statuses)?);
+    } else {
+        for status in &statuses {
+            status.print();
+        }
+    }
+    Ok(())
+}
+
+/// State of one AVR, as far as it answered the queries
+struct Status {
+    config: DeviceConfig,
+    protocol: Arc<dyn AvrProtocol>,
+    state: CachedState,
+}
+
+impl Status {
+    fn input_name(&self) -> Option<String> {
+        self.state
+            .input
+            .as_ref()
+            .and_then(|input| self.protocol.input_name(input))
+    }
+
+    fn print(&self) {
+        let state = &self.state;
+        let on_off = |on: Option<bool>| match on {
+            Some(true) => "on".to_owned(),
+            Some(false) => "off".to_owned(),
+            None => "unknown".to_owned(),
+        };
+
+        println!(
+            "{} ({}:{})",
+            self.config.name, self.config.host, self.config.port
+        );
+        println!(
+            "  connected: {}",
+            if state.connected == Some(true) {
+                "yes"
+            } else {
+                "no"
+            }
+        );
+        println!("  power:     {}", on_off(state.power));
+        println!(
+            "  volume:    {}",
+            state
+                .volume
+                .map(|level| format!("{} ({}%)", level, self.protocol.volume_percent(level)))
+                .unwrap_or_else(|| "unknown".to_owned())
+        );
+        println!("  mute:      {}", on_off(state.mute));
+        println!(
+            "  input:     {}",
+            match (&state.input, self.input_name()) {
+                (Some(input), Some(name)) => format!("{} ({})", name, input),
+                (Some(input), None) => input.clone(),
+                _ => "unknown".to_owned(),
+            }
+        );
+        // Only AVRs whose protocol can ask report it
+        if let Some(plugged) = state.headphones {
+            println!(
+                "  headphones: {}",
+                if plugged {
+                    "plugged in"
+                } else {
+                    "not plugged in"
+                }
+            );
+        }
+    }
+
+    fn json(&self) -> Value {
+        let state = &self.state;
+        json!({
+            "device": self.config.name,
+            "host": self.config.host,
+            "port": self.config.port,
+            "connected": state.connected == Some(true),
+            "power": state.power,
+            "volume": state.volume,
+            "volume_percent": state.volume.map(|level| self.protocol.volume_percent(level)),
+            "mute": state.mute,
+            "input": state.input,
+            "input_name": self.input_name(),
+            "headphones": state.headphones,
+        })
+    }
+}
@@ -0,0 +1,449 @@
+/// This module contains the `AvrProtocol` implementation driven entirely by a
+/// table file, for AVRs without their own implementation.
+///
+/// The table file is TOML, giving the code sent for each command, a regex the
+/// query response must match to confirm it, the query codes, and regexes to
+/// parse the AVR's messages into state change events. Codes and expected
+/// regexes can contain `{volume}` and `{input}`, replaced with the AVR volume
+/// level and the input's code. `level_db` is how many dB each volume level is,
+/// used for the inputs' volume offsets. An optional `[listening_modes]`
+/// section gives the code setting a listening mode, with `{mode}` replaced
+/// by the mode, its query and event regex, and the modes the listening mode
+/// intents switch to by name. For example, for a Denon AVR:
+///
+/// ```toml
+/// volume_max = 60
+/// volume_step = 1
+/// volume_width = 2
+/// level_db = 1.0
+/// inputs = ["BD", "GAME", "SAT/CBL", "DVD", "TV", "MPLAY", "TUNER", "AUX1"]
+///
+/// [commands]
+/// power_on = { code = "PWON\r", expected = "^PWON$" }
+/// power_off = { code = "PWSTANDBY\r", expected = "^PWSTANDBY$" }
+/// mute = { code = "MUON\r", expected = "^MUON$" }
+/// unmute = { code = "MUOFF\r", expected = "^MUOFF$" }
+/// volume_up = { code = "MVUP\r", expected = "^MV\\d+$" }
+/// volume_down = { code = "MVDOWN\r", expected = "^MV\\d+$" }
+/// set_volume = { code = "MV{volume}\r", expected = "^MV{volume}$" }
+/// change_input = { code = "SI{input}\r", expected = "^SI{input}$" }
+///
+/// [queries]
+/// power = "PW?\r"
+/// volume = "MV?\r"
+/// mute = "MU?\r"
+/// input = "SI?\r"
+///
+/// [events]
+/// power_on = "^PWON$"
+/// power_off = "^PWSTANDBY$"
+/// volume = "^MV(\\d+)$"
+/// mute_on = "^MUON$"
+/// mute_off = "^MUOFF$"
+/// input = "^SI(.+)$"
+///
+/// [listening_modes]
+/// code = "MS{mode}\r"
+/// expected = "^MS{mode}$"
+/// query = "MS?\r"
+/// event = "^MS(.+)$"
+///
+/// [listening_modes.names]
+/// auto = "AUTO"
+/// direct = "DIRECT"
+/// "extended stereo" = "MCH STEREO"
+/// ```
+use crate::{
+    avr::{AvrCommand, AvrQuery},
+    events::AvrEvent,
+    protocol::AvrProtocol,
+};
+use failure::{Error, ResultExt};
+use regex::Regex;
+use serde::Deserialize;
+use std::{collections::BTreeMap, fs};
+
+#[derive(Deserialize)]
+struct TableFile {
+    #[serde(default = "default_volume_max")]
+    volume_max: u8,
+    #[serde(default = "default_volume_step")]
+    volume_step: u8,
+    #[serde(default)]
+    volume_width: usize,
+    #[serde(default = "default_level_db")]
+    level_db: f32,
+    #[serde(default)]
+    inputs: Vec<String>,
+    commands: Commands,
+    queries: Queries,
+    events: Events,
+    listening_modes: Option<ListeningModes>,
+}
+
+fn default_volume_max() -> u8 {
+    100
+}
+
+fn default_volume_step() -> u8 {
+    1
+}
+
+fn default_level_db() -> f32 {
+    1.0
+}
+
+#[derive(Deserialize)]
+struct Commands {
+    power_on: CommandEntry,
+    power_off: CommandEntry,
+    mute: CommandEntry,
+    unmute: CommandEntry,
+    volume_up: CommandEntry,
+    volume_down: CommandEntry,
+    set_volume: CommandEntry,
+    change_input: CommandEntry,
+}
+
+/// Code template to send for a command, and the regex its query response must
+/// match
+#[derive(Deserialize)]
+struct CommandEntry {
+    code: String,
+    expected: String,
+}
+
+/// Code setting a listening mode and the regex its query response must
+/// match, the query and the event regex capturing the mode, and the modes by
+/// the names the listening mode intents use
+#[derive(Deserialize)]
+struct ListeningModes {
+    #[serde(flatten)]
+    command: CommandEntry,
+    query: String,
+    event: String,
+    #[serde(default)]
+    names: BTreeMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct Queries {
+    power: String,
+    volume: String,
+    mute: String,
+    input: String,
+}
+
+#[derive(Deserialize)]
+struct Events {
+    power_on: String,
+    power_off: String,
+    volume: String,
+    mute_on: String,
+    mute_off: String,
+    input: String,
+}
+
+/// Event regexes, compiled once when the table is loaded
+struct EventPatterns {
+    power_on: Regex,
+    power_off: Regex,
+    volume: Regex,
+    mute_on: Regex,
+    mute_off: Regex,
+    input: Regex,
+    listening_mode: Option<Regex>,
+}
+
+pub struct Table {
+    table: TableFile,
+    events: EventPatterns,
+}
+
+impl Table {
+    /// Read and parse the table file at path, checking all of its regexes
+    /// compile
+    pub fn load(path: &str) -> Result<Table, Error> {
+        let contents =
+            fs::read_to_string(path).context(format!("Could not read table file: {}", path))?;
+        let table: TableFile =
+            toml::from_str(&contents).context(format!("Could not parse table file: {}", path))?;
+
+        let commands = &table.commands;
+        for entry in &[
+            &commands.power_on,
+            &commands.power_off,
+            &commands.mute,
+            &commands.unmute,
+            &commands.volume_up,
+            &commands.volume_down,
+            &commands.set_volume,
+            &commands.change_input,
+        ] {
+            compile(&fill(&entry.expected, "0", "0", "0"))?;
+        }
+        if let Some(modes) = &table.listening_modes {
+            compile(&fill(&modes.command.expected, "0", "0", "0"))?;
+        }
+
+        let events = EventPatterns {
+            power_on: compile(&table.events.power_on)?,
+            power_off: compile(&table.events.power_off)?,
+            volume: compile(&table.events.volume)?,
+            mute_on: compile(&table.events.mute_on)?,
+            mute_off: compile(&table.events.mute_off)?,
+            input: compile(&table.events.input)?,
+            listening_mode: match &table.listening_modes {
+                Some(modes) => Some(compile(&modes.event)?),
+                None => None,
+            },
+        };
+
+        Ok(Table { table, events })
+    }
+
+    /// Entry for the command, if the table has one. Only the commands every
+    /// AVR supports do, and listening modes if the table has them.
+    fn entry(&self, cmd: &AvrCommand) -> Option<&CommandEntry> {
+        let commands = &self.table.commands;
+        match cmd {
+            AvrCommand::SetVolume(_) => Some(&commands.set_volume),
+            AvrCommand::ChangeInput(_) => Some(&commands.change_input),
+            AvrCommand::PowerOn => Some(&commands.power_on),
+            AvrCommand::PowerOff => Some(&commands.power_off),
+            AvrCommand::Mute => Some(&commands.mute),
+            AvrCommand::Unmute => Some(&commands.unmute),
+            AvrCommand::VolumeDown => Some(&commands.volume_down),
+            AvrCommand::VolumeUp => Some(&commands.volume_up),
+            AvrCommand::SetListeningMode(_) => self
+                .table
+                .listening_modes
+                .as_ref()
+                .map(|modes| &modes.command),
+            AvrCommand::SelectPreset(_)
+            | AvrCommand::Gallery(_)
+            | AvrCommand::Ipod(_)
+            | AvrCommand::Adapter(_)
+            | AvrCommand::BluetoothPairing
+            | AvrCommand::SoundRetrieverOn
+            | AvrCommand::SoundRetrieverOff
+            | AvrCommand::DialogEnhancementOn
+            | AvrCommand::DialogEnhancementOff
+            | AvrCommand::SetSpeakerSystem(_)
+            | AvrCommand::SubwooferUp
+            | AvrCommand::SubwooferDown
+            | AvrCommand::SetLfeAttenuation(_)
+            | AvrCommand::VideoConverterOn
+            | AvrCommand::VideoConverterOff
+            | AvrCommand::SetVideoResolution(_)
+            | AvrCommand::NetworkStandbyOn
+            | AvrCommand::SetVolumeDb(_) => None,
+        }
+    }
+
+    /// Volume, input and listening mode codes to fill the command's templates
+    /// with
+    fn values(&self, cmd: &AvrCommand) -> (String, String, String) {
+        let volume = match cmd {
+            AvrCommand::SetVolume(n) => format!(
+                "{:0>width$}",
+                self.volume_level(*n),
+                width = self.table.volume_width
+            ),
+            _ => String::new(),
+        };
+        let input = match cmd {
+            AvrCommand::ChangeInput(n) => self
+                .table
+                .inputs
+                .get(usize::from(*n).wrapping_sub(1))
+                .cloned()
+                .unwrap_or_default(),
+            _ => String::new(),
+        };
+        let mode = match cmd {
+            AvrCommand::SetListeningMode(mode) => mode.trim().to_owned(),
+            _ => String::new(),
+        };
+        (volume, input, mode)
+    }
+}
+
+impl AvrProtocol for Table {
+    fn supports(&self, cmd: &AvrCommand) -> bool {
+        match cmd {
+            AvrCommand::SetListeningMode(mode) => self.listening_mode_code(mode).is_some(),
+            _ => cmd.is_basic(),
+        }
+    }
+
+    fn code(&self, cmd: &AvrCommand) -> String {
+        let (volume, input, mode) = self.values(cmd);
+        self.entry(cmd)
+            .map(|entry| fill(&entry.code, &volume, &input, &mode))
+            .unwrap_or_default()
+    }
+
+    fn query_code(&self, query: &AvrQuery) -> String {
+        let queries = &self.table.queries;
+        match query {
+            AvrQuery::Volume => queries.volume.clone(),
+            AvrQuery::Mute => queries.mute.clone(),
+            AvrQuery::Power => queries.power.clone(),
+            AvrQuery::Input => queries.input.clone(),
+            AvrQuery::ListeningMode => self
+                .table
+                .listening_modes
+                .as_ref()
+                .map(|modes| modes.query.clone())
+                .unwrap_or_default(),
+            AvrQuery::Preset
+            | AvrQuery::SoundRetriever
+            | AvrQuery::DialogEnhancement
+            | AvrQuery::SpeakerSystem
+            | AvrQuery::SubwooferLevel
+            | AvrQuery::LfeAttenuation
+            | AvrQuery::Headphones
+            | AvrQuery::VideoConverter
+            | AvrQuery::VideoResolution
+            | AvrQuery::NetworkStandby
+            | AvrQuery::InputLabel(_)
+            | AvrQuery::Model
+            | AvrQuery::Firmware => String::new(),
+        }
+    }
+
+    /// The expected regex, with values escaped
+    fn expected(&self, cmd: &AvrCommand) -> String {
+        let (volume, input, mode) = self.values(cmd);
+        self.entry(cmd)
+            .map(|entry| {
+                fill(
+                    &entry.expected,
+                    &regex::escape(&volume),
+                    &regex::escape(&input),
+                    &regex::escape(&mode),
+                )
+            })
+            .unwrap_or_default()
+    }
+
+    /// Any message in the response matching the expected regex confirms the
+    /// command
+    fn matches(&self, cmd: &AvrCommand, response: &str) -> bool {
+        match Regex::new(&self.expected(cmd)) {
+            Ok(expected) => messages(response).any(|message| expected.is_match(message)),
+            Err(_) => false,
+        }
+    }
+
+    fn volume_level(&self, n: u8) -> u8 {
+        let weight = f32::from(n) / 10.0;
+        (weight * f32::from(self.table.volume_max)).ceil() as u8
+    }
+
+    fn volume_step(&self) -> u8 {
+        self.table.volume_step.max(1)
+    }
+
+    fn level_db(&self) -> f32 {
+        self.table.level_db
+    }
+
+    /// Only the modes named in the table can be set
+    fn listening_mode_code(&self, mode: &str) -> Option<String> {
+        let mode = mode.trim();
+        self.table
+            .listening_modes
+            .as_ref()?
+            .names
+            .values()
+            .find(|named| named.as_str() == mode)
+            .cloned()
+    }
+
+    fn listening_mode_named(&self, name: &str) -> Option<String> {
+        self.table
+            .listening_modes
+            .as_ref()?
+            .names
+            .get(name)
+            .cloned()
+    }
+
+    fn inputs(&self) -> Vec<String> {
+        self.table.inputs.clone()
+    }
+
+    fn input_number(&self, input: &str) -> Option<u8> {
+        self.table
+            .inputs
+            .iter()
+            .position(|code| code == input)
+            .map(|i| i as u8 + 1)
+    }
+
+    fn input_code(&self, n: u8) -> Option<String> {
+        let i = usize::from(n).checked_sub(1)?;
+        self.table.inputs.get(i).cloned()
+    }
+
+    fn parse_event(&self, message: &str) -> Option<AvrEvent> {
+        let message = message.trim();
+        let events = &self.events;
+
+        if events.power_on.is_match(message) {
+            Some(AvrEvent::Power(true))
+        } else if events.power_off.is_match(message) {
+            Some(AvrEvent::Power(false))
+        } else if events.mute_on.is_match(message) {
+            Some(AvrEvent::Mute(true))
+        } else if events.mute_off.is_match(message) {
+            Some(AvrEvent::Mute(false))
+        } else if let Some(captures) = events
+            .listening_mode
+            .as_ref()
+            .and_then(|pattern| pattern.captures(message))
+        {
+            captures
+                .get(1)
+                .map(|mode| AvrEvent::ListeningMode(mode.as_str().to_owned()))
+        } else if let Some(captures) = events.volume.captures(message) {
+            captures
+                .get(1)
+                .and_then(|volume| volume.as_str().parse::<u8>().ok())
+                .map(AvrEvent::Volume)
+        } else {
+            events
+                .input
+                .captures(message)
+                .and_then(|captures| captures.get(1))
+                .map(|input| AvrEvent::Input(input.as_str().to_owned()))
+        }
+    }
+
+    /// AVRs differ on line endings, so split on either CR or LF
+    fn parse_events(&self, data: &str) -> Vec<AvrEvent> {
+        messages(data)
+            .filter_map(|message| self.parse_event(message))
+            .collect()
+    }
+}
+
+/// Split data received from the AVR into its non-empty messages
+fn messages(data: &str) -> impl Iterator<Item = &str> {
+    data.split(['\r', '\n'])
+        .filter(|message| !message.is_empty())
+}
+
+/// Replace `{volume}`, `{input}` and `{mode}` in template
+fn fill(template: &str, volume: &str, input: &str, mode: &str) -> String {
+    template
+        .replace("{volume}", volume)
+        .replace("{input}", input)
+        .replace("{mode}", mode)
+}
+
+fn compile(pattern: &str) -> Result<Regex, Error> {
+    Ok(Regex::new(pattern).context(format!("Invalid regex in table file: {:?}", pattern))?)
+}
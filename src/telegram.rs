@@ -0,0 +1,284 @@
+/// This module runs the optional Telegram bot, for controlling the AVRs from
+/// a chat.
+///
+/// The bot long polls Telegram for messages, and only answers those from the
+/// allowlisted `chats`. Commands are converted to `AvrCommand`s and sent
+/// through the AVR's command queue, like the skill's:
+///
+/// - `/on`, `/off`, `/mute`, `/unmute`, `/up` and `/down`
+/// - `/vol 5`, from 1 to 10
+/// - `/input BD`, by number, name or alias
+/// - `/status`
+///
+/// The chats are also told when the connection to an AVR is lost, and when
+/// it's back.
+use crate::{
+    avr::AvrCommand,
+    config::TelegramConfig,
+    device::{Device, Devices},
+    events::AvrEvent,
+    queue::Source,
+};
+use failure::{bail, format_err, Error};
+use hyper::{body, client::HttpConnector, header::CONTENT_TYPE, Body, Client, Method, Request};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{sync::Arc, time::Duration};
+use tokio::{sync::broadcast, time::sleep};
+use tracing::{debug, info, warn};
+
+type HttpsClient = Client<HttpsConnector<HttpConnector>>;
+
+/// How long Telegram holds a request for updates open, in seconds
+const POLL_TIMEOUT: u64 = 30;
+
+/// Delay before polling again after a failure
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Deserialize)]
+struct Updates {
+    ok: bool,
+    #[serde(default)]
+    result: Vec<Update>,
+    description: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Update {
+    update_id: i64,
+    message: Option<Message>,
+}
+
+#[derive(Deserialize)]
+struct Message {
+    chat: Chat,
+    text: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Chat {
+    id: i64,
+}
+
+/// Bot's token and allowlisted chats, with the client to call the Bot API
+#[derive(Clone)]
+struct Bot {
+    config: Arc<TelegramConfig>,
+    client: HttpsClient,
+}
+
+/// Start the bot, polling for commands and alerting on connection changes
+pub fn run(config: TelegramConfig, devices: &Devices) -> Result<(), Error> {
+    if let Some(name) = &config.device {
+        if devices.get(Some(name)).is_none() {
+            bail!("No AVR named {:?} for Telegram", name);
+        }
+    }
+    if config.chats.is_empty() {
+        warn!("No chats allowed for Telegram, commands will be ignored");
+    }
+    info!("Telegram bot enabled");
+
+    let connector = HttpsConnectorBuilder::new()
+        .with_webpki_roots()
+        .https_only()
+        .enable_http1()
+        .build();
+    let bot = Bot {
+        config: Arc::new(config),
+        client: Client::builder().build(connector),
+    };
+
+    for device in devices.iter() {
+        tokio::spawn(alert(bot.clone(), device.clone()));
+    }
+    tokio::spawn(poll(bot, devices.clone()));
+    Ok(())
+}
+
+impl Bot {
+    /// Call the Bot API method with the JSON parameters
+    async fn call(&self, method: &str, params: Value) -> Result<Vec<u8>, Error> {
+        let url = format!(
+            "https://api.telegram.org/bot{}/{}",
+            self.config.token, method
+        );
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(url)
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(params.to_string()))?;
+        let response = self.client.request(request).await?;
+        let status = response.status();
+        let body = body::to_bytes(response.into_body()).await?;
+        if !status.is_success() {
+            bail!(
+                "Telegram {} failed: {}: {}",
+                method,
+                status,
+                String::from_utf8_lossy(&body)
+            );
+        }
+        Ok(body.to_vec())
+    }
+
+    /// Updates after `offset`, waiting for one if there are none
+    async fn updates(&self, offset: i64) -> Result<Vec<Update>, Error> {
+        let params = json!({
+            "offset": offset,
+            "timeout": POLL_TIMEOUT,
+            "allowed_updates": ["message"],
+        });
+        let updates: Updates = serde_json::from_slice(&self.call("getUpdates", params).await?)?;
+        if !updates.ok {
+            bail!(
+                "Telegram getUpdates failed: {}",
+                updates.description.unwrap_or_default()
+            );
+        }
+        Ok(updates.result)
+    }
+
+    async fn send(&self, chat: i64, text: &str) {
+        let params = json!({ "chat_id": chat, "text": text });
+        if let Err(e) = self.call("sendMessage", params).await {
+            warn!("Could not send Telegram message: {}", e);
+        }
+    }
+}
+
+/// Answer commands from the allowed chats, until the process exits
+async fn poll(bot: Bot, devices: Devices) {
+    let mut offset = 0;
+
+    loop {
+        let updates = match bot.updates(offset).await {
+            Ok(updates) => updates,
+            Err(e) => {
+                warn!("Could not get Telegram updates: {}", e);
+                sleep(RETRY_DELAY).await;
+                continue;
+            }
+        };
+
+        for update in updates {
+            offset = offset.max(update.update_id + 1);
+            let (chat, text) = match update.message {
+                Some(Message {
+                    chat,
+                    text: Some(text),
+                }) => (chat.id, text),
+                _ => continue,
+            };
+            if !bot.config.chats.contains(&chat) {
+                warn!("Ignoring Telegram message from chat {}", chat);
+                continue;
+            }
+            debug!("Telegram command: {:?}", text);
+
+            let device = match devices.get(bot.config.device.as_deref()) {
+                Some(device) => device,
+                None => continue,
+            };
+            let reply = match command(device, &text).await {
+                Ok(reply) => reply,
+                Err(e) => e.to_string(),
+            };
+            bot.send(chat, &reply).await;
+        }
+    }
+}
+
+/// Process the command, returning the reply
+async fn command(device: &Device, text: &str) -> Result<String, Error> {
+    let mut words = text.split_whitespace();
+    let name = words.next().unwrap_or_default();
+    // Commands are sent as "/vol@BotName" in groups
+    let name = name.split('@').next().unwrap_or_default();
+    let arg = words.collect::<Vec<_>>().join(" ");
+
+    let cmd = match name {
+        "/on" => AvrCommand::PowerOn,
+        "/off" => AvrCommand::PowerOff,
+        "/mute" => AvrCommand::Mute,
+        "/unmute" => AvrCommand::Unmute,
+        "/up" => AvrCommand::VolumeUp,
+        "/down" => AvrCommand::VolumeDown,
+        "/vol" => match arg.parse::<u8>() {
+            Ok(n) if (1..=10).contains(&n) => AvrCommand::SetVolume(n),
+            _ => bail!("The volume must be from 1 to 10."),
+        },
+        "/input" => {
+            let n = match arg.parse::<u8>() {
+                Ok(n) => Some(n),
+                Err(_) => device.inputs.resolve(&arg),
+            };
+            AvrCommand::ChangeInput(n.ok_or_else(|| format_err!("No input named {:?}.", arg))?)
+        }
+        "/status" => return Ok(status(device)),
+        "/start" | "/help" => {
+            return Ok(
+                "/on, /off, /mute, /unmute, /up, /down, /vol 1-10, /input <name>, /status"
+                    .to_owned(),
+            )
+        }
+        _ => bail!("Unknown command: {}", name),
+    };
+
+    info!("Telegram command for {}: {:?}", device.name, cmd);
+    device.queue.process(cmd, Source::Telegram).await?;
+    Ok("Ok.".to_owned())
+}
+
+/// The AVR's cached state, as a message
+fn status(device: &Device) -> String {
+    let state = device.events.state();
+    let protocol = device.events.protocol();
+    let unknown = || "unknown".to_owned();
+    let on_off = |on: Option<bool>| match on {
+        Some(true) => "on".to_owned(),
+        Some(false) => "off".to_owned(),
+        None => unknown(),
+    };
+
+    format!(
+        "{}\npower: {}\nvolume: {}\nmute: {}\ninput: {}",
+        device.name,
+        on_off(state.power),
+        state
+            .volume
+            .map(|level| format!("{}%", protocol.volume_percent(level)))
+            .unwrap_or_else(unknown),
+        on_off(state.mute),
+        state
+            .input
+            .and_then(|input| device.input_name(&input))
+            .unwrap_or_else(unknown),
+    )
+}
+
+/// Tell the allowed chats when the connection to the AVR is lost, and when
+/// it's back
+async fn alert(bot: Bot, device: Device) {
+    let mut events = device.events.subscribe();
+    let mut lost = false;
+
+    loop {
+        let text = match events.recv().await {
+            Ok(AvrEvent::Connected(false)) if !lost => {
+                lost = true;
+                format!("Lost the connection to {}", device.name)
+            }
+            Ok(AvrEvent::Connected(true)) if lost => {
+                lost = false;
+                format!("Connected to {} again", device.name)
+            }
+            Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => return,
+        };
+        for chat in &bot.config.chats {
+            bot.send(*chat, &text).await;
+        }
+    }
+}
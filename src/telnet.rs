@@ -1,108 +1,850 @@
 /// This module is responsible over maintaining a telnet connection
 /// to the AVR device, and receiving commands that need to be sent over
-/// that telnet connection.   
+/// that telnet connection.
 ///
-/// Crossbeam channels are used for communicating between the skill's request
-/// and this thread.   
+/// An `AvrConnection` handle is used by the skill's request tasks to send
+/// commands to this task. Each command carries its own oneshot channel for
+/// the response.
 ///
 /// The AVR device will always respond to the telnet command with a response
-/// code, which needs to be sent back via crossbeam channel to finish
-/// procsesing on the skill side.
-use crate::{log_error, CHANNEL_A, CHANNEL_B};
-use crossbeam_channel::select;
-use failure::{bail, Error, ResultExt};
-use log::{debug, info};
+/// code, which needs to be sent back via the oneshot channel to finish
+/// procsesing on the skill side. Most commands say which message they await,
+/// and the response is read until it arrives, so a command completes as soon
+/// as the AVR has processed it. The AVR's messages don't say which code they
+/// answer, so a command awaiting a message is only answered by that message,
+/// or an error. Anything else read meanwhile, like a delayed reply to an
+/// earlier command, is published but left out of its response, and anything
+/// the AVR sent between commands is discarded before the next is written.
+/// Each code written gets a token, and one that gave up waiting is owed its
+/// answer for a while. An error arriving while one is owed is the late answer
+/// to the oldest, as the AVR answers codes in order, so it's never taken for
+/// the response to a later code, whether or not that awaits a message.
+///
+/// Data received from the AVR is split into messages, which are classified by
+/// the AVR's protocol. Heartbeats are dropped, and state changes, including
+/// unsolicited ones sent when its state is changed some other way, are
+/// published to the `EventBus`.
+/// Exchanges can also be recorded to a transcript, see `crate::replay`.
+/// Each code is written in a span that's a child of the one it was sent from,
+/// so the exchange shows up under the AVR command it was for.
+///
+/// The AVR sends a heartbeat every 30 seconds, so a connection it has been
+/// silent on for longer is probed with a power query, and taken for dead if
+/// that isn't answered either. That way a half-open connection, like one left
+/// behind by the AVR losing power, is replaced before a command times out on
+/// it.
+use crate::{
+    addr,
+    avr::{AvrError, AvrQuery},
+    config::VolumeRampConfig,
+    events::{AvrEvent, EventBus},
+    log_error,
+    protocol::{AvrProtocol, Message},
+    replay::Recorder,
+    resolve,
+};
+use failure::{bail, format_err, Error, ResultExt};
+use rand::Rng;
 use std::{
-    thread::{self, sleep},
+    collections::VecDeque,
+    io::{self, ErrorKind},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
     time::Duration,
 };
-use telnet::{Telnet, TelnetEvent};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        oneshot,
+    },
+    time::{error::Elapsed, sleep, sleep_until, timeout, timeout_at, Instant},
+};
+use tracing::{debug, debug_span, info, warn, Instrument, Span};
+
+/// How long to wait for the message a code awaits before giving up on it
+const AWAIT_TIMEOUT: Duration = Duration::from_millis(4_000);
+
+/// How long to read the response to a code that doesn't await a message
+const READ_WINDOW: Duration = Duration::from_millis(1_000);
+
+/// How long after giving up on a code's awaited message an error from the AVR
+/// is still taken for its late answer
+const LATE_REPLY_WAIT: Duration = Duration::from_millis(4_000);
+
+/// How long the AVR can send nothing, not even a heartbeat, before it's
+/// probed
+const PROBE_AFTER: Duration = Duration::from_secs(45);
+
+/// How long to wait for anything from the AVR after probing it, before
+/// reconnecting
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Command code to be written to the AVR, along with the message from the
+/// AVR that completes its response and how long to wait for it, the channel
+/// to send the AVR's response back on, and the span it was sent from.
+/// Queries can be batched together.
+#[derive(Debug)]
+struct TelnetCommand {
+    code: String,
+    query: bool,
+    awaited: Option<Awaited>,
+    wait: Duration,
+    reply: oneshot::Sender<String>,
+    span: Span,
+}
+
+/// Message from the AVR that ends the wait for its response to a code
+#[derive(Clone, Debug)]
+enum Awaited {
+    /// Answer to a query, whatever state it reports
+    Answer(AvrQuery),
+    /// Specific state change
+    Event(AvrEvent),
+}
+
+impl Awaited {
+    fn matches(&self, event: &AvrEvent) -> bool {
+        match self {
+            Awaited::Answer(query) => query.answered_by(event),
+            Awaited::Event(awaited) => event == awaited,
+        }
+    }
+}
+
+/// Codes written to the AVR that gave up waiting on their awaited message, by
+/// token, each owed its answer until it arrives or `LATE_REPLY_WAIT` has
+/// passed
+#[derive(Default)]
+struct Owed {
+    next_token: u64,
+    codes: VecDeque<(u64, Awaited, Instant)>,
+}
+
+impl Owed {
+    /// Token for the next code written
+    fn token(&mut self) -> u64 {
+        self.next_token += 1;
+        self.next_token
+    }
+
+    /// Remember that the code written with token is still owed the awaited
+    /// message
+    fn owe(&mut self, token: u64, awaited: Awaited) {
+        self.codes
+            .push_back((token, awaited, Instant::now() + LATE_REPLY_WAIT));
+    }
+
+    /// Forget the oldest code the state change was owed to, as it has its
+    /// answer now
+    fn answered(&mut self, event: &AvrEvent) {
+        if let Some(i) = self
+            .codes
+            .iter()
+            .position(|(_, awaited, _)| awaited.matches(event))
+        {
+            self.codes.remove(i);
+        }
+    }
+
+    /// Token of the oldest code still owed its answer, which an error just
+    /// received answers, if any
+    fn claim(&mut self) -> Option<u64> {
+        let now = Instant::now();
+        while let Some((token, _, until)) = self.codes.pop_front() {
+            if until > now {
+                return Some(token);
+            }
+        }
+        None
+    }
+}
+
+/// Handle to the telnet task, used to send codes to the AVR. Cloned for each
+/// request.
+#[derive(Clone)]
+pub struct AvrConnection {
+    commands: Sender<TelnetCommand>,
+    events: EventBus,
+    protocol: Arc<dyn AvrProtocol>,
+    dry_run: bool,
+    volume_ramp: VolumeRampConfig,
+    /// Cleared once the AVR rejects setting the volume straight to a level
+    direct_volume: Arc<AtomicBool>,
+}
+
+impl AvrConnection {
+    /// `EventBus` the telnet task publishes received messages to
+    pub fn events(&self) -> &EventBus {
+        &self.events
+    }
+
+    /// Protocol for the brand of AVR on this connection
+    pub fn protocol(&self) -> &Arc<dyn AvrProtocol> {
+        &self.protocol
+    }
+
+    /// Whether commands must only be logged, and never sent to the AVR
+    pub fn dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    /// How volume steps are paced on this AVR
+    pub fn volume_ramp(&self) -> VolumeRampConfig {
+        self.volume_ramp
+    }
+
+    /// Whether to try setting the volume straight to a level, as the AVR
+    /// hasn't rejected it
+    pub(crate) fn direct_volume(&self) -> bool {
+        self.direct_volume.load(Ordering::Relaxed)
+    }
 
-/// Spawn a new thread to run telnet communication between AVR.   
+    /// Step the volume from now on, as the AVR rejected setting it directly
+    pub(crate) fn reject_direct_volume(&self) {
+        self.direct_volume.store(false, Ordering::Relaxed);
+    }
+
+    /// The same connection, with volume steps paced as configured for its AVR
+    pub fn with_volume_ramp(self, volume_ramp: VolumeRampConfig) -> AvrConnection {
+        AvrConnection {
+            volume_ramp,
+            ..self
+        }
+    }
+
+    /// Send the query's code to the telnet task and wait for the AVR's
+    /// answer, same as `send`. Identical queries waiting to be sent are
+    /// batched.
+    pub async fn query(&self, query: AvrQuery) -> Result<String, Error> {
+        let code = self.protocol.query_code(&query);
+        self.send_command(&code, true, Some(Awaited::Answer(query)), AWAIT_TIMEOUT)
+            .await
+    }
+
+    /// Ask the AVR whether its current input has a signal, using its audio
+    /// status. `None` if its protocol can't tell.
+    pub async fn signal_present(&self) -> Result<Option<bool>, Error> {
+        match self.protocol.audio_status_code() {
+            Some(code) => {
+                let response = self.send(&code, None).await?;
+                Ok(self.protocol.signal_present(&response))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Send code to the telnet task along with a oneshot channel, then wait
+    /// for the AVR's response to come back on it. If `awaited` is given, the
+    /// response is complete once the AVR reports that state change, otherwise
+    /// it's whatever the AVR sends within a fixed window. If the response
+    /// doesn't come back in time, assume error.
+    pub(crate) async fn send(
+        &self,
+        code: &str,
+        awaited: Option<AvrEvent>,
+    ) -> Result<String, Error> {
+        self.send_within(code, awaited, AWAIT_TIMEOUT).await
+    }
+
+    /// Same as `send`, giving up waiting on the awaited state change after
+    /// `wait` rather than `AWAIT_TIMEOUT`, for a code that's only worth
+    /// waiting on for so long
+    pub(crate) async fn send_within(
+        &self,
+        code: &str,
+        awaited: Option<AvrEvent>,
+        wait: Duration,
+    ) -> Result<String, Error> {
+        self.send_command(code, false, awaited.map(Awaited::Event), wait)
+            .await
+    }
+
+    async fn send_command(
+        &self,
+        code: &str,
+        query: bool,
+        awaited: Option<Awaited>,
+        wait: Duration,
+    ) -> Result<String, Error> {
+        let (reply, response) = oneshot::channel();
+        self.commands
+            .send(TelnetCommand {
+                code: code.to_owned(),
+                query,
+                awaited,
+                wait,
+                reply,
+                span: Span::current(),
+            })
+            .await?;
+        debug!("Sent code via channel: {:?}", code);
+
+        // Leave the telnet task time to give up waiting and send back what it
+        // has, before assuming it's stuck
+        let wait = wait.max(READ_WINDOW) + Duration::from_millis(1_000);
+        match timeout(wait, response).await {
+            Ok(msg) => {
+                let msg = msg?;
+                debug!("Response code received via channel: {:?}", msg);
+                Ok(msg)
+            }
+            Err(_) => Err(AvrError::Timeout.into()),
+        }
+    }
+}
+
+/// Delay between attempts to reconnect to the AVR. The delay doubles with
+/// each consecutive failure, from `min` up to `max`. Each attempt gives up on
+/// an address that hasn't accepted the connection after `connect_timeout`.
+#[derive(Clone, Copy)]
+pub struct Backoff {
+    pub min: Duration,
+    pub max: Duration,
+    pub connect_timeout: Duration,
+}
+
+impl Backoff {
+    /// The same backoff, with the AVR's own connect timeout in seconds, if it
+    /// has one
+    pub fn with_connect_timeout(self, secs: Option<u64>) -> Backoff {
+        Backoff {
+            connect_timeout: secs.map_or(self.connect_timeout, Duration::from_secs),
+            ..self
+        }
+    }
+
+    /// Delay to wait after the given number of consecutive failures, with
+    /// jitter so it falls somewhere between half and all of the full delay.
+    fn delay(&self, failures: u32) -> Duration {
+        let max = self.max.max(self.min);
+        let delay = self
+            .min
+            .checked_mul(2u32.saturating_pow(failures))
+            .unwrap_or(max)
+            .min(max);
+
+        let millis = delay.as_millis() as u64;
+        Duration::from_millis(rand::thread_rng().gen_range(millis / 2..=millis))
+    }
+}
+
+/// Spawn a new task to run telnet communication between AVR, returning the
+/// `AvrConnection` handle to send it commands.
 ///
-/// Attempt to reconnect if error occurs, logging error.
-pub fn run(addrs: String, port: u16) -> Result<(), Error> {
-    thread::spawn(move || loop {
-        if let Err(e) = connect(&addrs, port) {
-            log_error(&e);
-            sleep(Duration::from_secs(10));
+/// Attempt to reconnect if error occurs, logging error. Reconnect attempts
+/// follow `backoff`, but a command arriving while disconnected triggers an
+/// immediate attempt. An AVR whose address refuses the connection or doesn't
+/// answer from the start is likely in standby with its network standby
+/// setting off, which is warned about. The host is resolved again on each attempt, in case the
+/// AVR's address has changed.
+///
+/// In dry run mode the connection is still made, so the cached state is kept
+/// up to date, but nothing is written to it. With a `recorder`, every code
+/// sent and the response received back is added to its transcript.
+pub fn run(
+    addrs: String,
+    port: u16,
+    backoff: Backoff,
+    protocol: Arc<dyn AvrProtocol>,
+    events: EventBus,
+    dry_run: bool,
+    recorder: Option<Recorder>,
+) -> AvrConnection {
+    let (sender, mut commands) = mpsc::channel(16);
+    let bus = events.clone();
+
+    tokio::spawn(async move {
+        let mut pending = VecDeque::new();
+        let mut failures = 0;
+        let mut connected_once = false;
+        let mut last_addr = None;
+        let target = addr::join(&addrs, port);
+
+        loop {
+            match connect(&addrs, port, backoff.connect_timeout, &mut last_addr).await {
+                Ok(conn) => {
+                    info!("Successful connection to AVR via telnet: {}", target);
+                    failures = 0;
+                    connected_once = true;
+                    bus.connected(true);
+
+                    let result =
+                        session(conn, &mut commands, &mut pending, &bus, &recorder, dry_run).await;
+                    bus.clear();
+                    bus.connected(false);
+                    match result {
+                        Ok(()) => break,
+                        Err(e) => log_error(&e),
+                    }
+                }
+                Err(e) => {
+                    failures += 1;
+                    let unreachable = unreachable(&e);
+                    bus.connect_failed(failures, unreachable);
+                    log_error(&e);
+                    warn!(
+                        "{} consecutive failed attempts to connect to AVR: {}",
+                        failures, target
+                    );
+                    if !connected_once && failures == 1 && unreachable {
+                        warn!(
+                            "If the AVR is in standby, its network standby setting appears to be \
+                             off, so it can't be turned on remotely. Turn it on by hand, then \
+                             send NetworkStandbyOn: {}",
+                            target
+                        );
+                    }
+                }
+            }
+
+            let delay = backoff.delay(failures);
+            info!("Reconnecting to AVR in {:?}: {}", delay, target);
+
+            tokio::select! {
+                _ = sleep(delay) => {},
+                command = commands.recv() => match command {
+                    Some(command) => {
+                        debug!("Command received while disconnected, reconnecting now");
+                        pending.push_back(command);
+                    }
+                    None => break,
+                }
+            }
         }
     });
 
-    Ok(())
+    AvrConnection {
+        commands: sender,
+        events,
+        protocol,
+        dry_run,
+        volume_ramp: VolumeRampConfig::default(),
+        direct_volume: Arc::new(AtomicBool::new(true)),
+    }
 }
 
-/// Connects to AVR and waits for commands from skill.   
+/// Resolve host and connect to the first of its addresses that accepts the
+/// connection within `wait`, logging when it differs from the last address
+/// connected to.
+async fn connect(
+    host: &str,
+    port: u16,
+    wait: Duration,
+    last_addr: &mut Option<SocketAddr>,
+) -> Result<TcpStream, Error> {
+    let mut result = Err(format_err!("No addresses found for AVR host: {}", host));
+
+    for addr in resolve::resolve(host, port).await? {
+        match timeout(wait, TcpStream::connect(addr)).await {
+            Ok(Ok(conn)) => {
+                if let Some(last) = last_addr.replace(addr).filter(|last| *last != addr) {
+                    info!("AVR address changed from {} to {}", last, addr);
+                }
+                return Ok(conn);
+            }
+            Ok(Err(e)) => {
+                debug!("Could not connect to {}: {}", addr, e);
+                result = Err(e.into());
+            }
+            Err(e) => {
+                debug!("Timed out connecting to {} after {:?}", addr, wait);
+                result = Err(Error::from(e)
+                    .context(format!("Timed out connecting to {}", addr))
+                    .into());
+            }
+        }
+    }
+
+    Ok(result.context("Could not connect to AVR via telnet")?)
+}
+
+/// Whether connecting failed because an address the host resolved to refused
+/// the connection or didn't answer, rather than the host not resolving or
+/// the network being unreachable
+fn unreachable(e: &Error) -> bool {
+    e.iter_chain().any(|cause| {
+        cause.downcast_ref::<Elapsed>().is_some()
+            || cause
+                .downcast_ref::<io::Error>()
+                .map_or(false, |e| e.kind() == ErrorKind::ConnectionRefused)
+    })
+}
+
+/// Waits for commands from skill over a connection to the AVR.
 ///
 /// Upon receiving command, it will send to AVR over telnet connection.
 /// It will then try to get response from AVR, which should be some data code,
-/// and send that back to the skill for further processing.   
+/// and send that back to the skill for further processing. Commands waiting
+/// to be sent are kept in `pending`, which carries over between connections.
+///
+/// If reading or writing fails, or the AVR closes the connection, assume
+/// connection is broken and bail to reconnect. Returns once all senders of
+/// commands have been dropped.
 ///
-/// If this response doesn't occur (timeout), or if the response type isn't valid
-/// (could happen from connection error), assume connection is broken and bail to
-/// reconnect.
+/// Also handles any messages received while not waiting on a response, such
+/// as the heartbeat signal AVR sends every 30 seconds: "R\r\n", or state
+/// changes made with the physical remote.
 ///
-/// Also clears the telnet channel every 1 second, as AVR will send a heartbeat
-/// signal every 30 seconds: "R\r\n". We don't want this present in the response
-/// from AVR after we send our command.
-fn connect(addrs: &str, port: u16) -> Result<(), Error> {
-    let mut conn =
-        Telnet::connect((addrs, port), 256).context("Could not connect to AVR via telnet")?;
-    info!("Successful connection to AVR via telnet");
+/// If nothing is received for `PROBE_AFTER`, the AVR is asked for its power
+/// state, and if nothing is received for `PROBE_TIMEOUT` after that, the
+/// connection is taken for dead. In dry run mode, the probe isn't written, so
+/// only the heartbeat keeps the connection.
+async fn session(
+    conn: TcpStream,
+    commands: &mut Receiver<TelnetCommand>,
+    pending: &mut VecDeque<TelnetCommand>,
+    events: &EventBus,
+    recorder: &Option<Recorder>,
+    dry_run: bool,
+) -> Result<(), Error> {
+    let mut stream = AvrStream::new(conn);
+    let probe = events.protocol().query_code(&AvrQuery::Power);
+    let mut probed: Option<Instant> = None;
+    let mut owed = Owed::default();
 
     loop {
-        select! {
-            recv(CHANNEL_A.1) -> code => {
-                let code = code?;
-                debug!("Code received via channel A: {:?}", code);
-
-                conn.write(code.as_bytes()).context("Could not write to AVR via telnet")?;
-
-                let mut resp_buffer = String::new();
-
-                // AVR responds twice with Power On request, the first being useless. We need to capture it to keep 2nd
-                // response from being missed and populating later requests.
-                thread::sleep(Duration::from_millis(500));
-                let resp = conn.read_timeout(Duration::from_millis(500)).context("Error reading from telnet connection")?;
-                match resp {
-                    TelnetEvent::Data(d) => {
-                        let s = std::str::from_utf8(&d).context(format!("Could not convert response to UTF-8: {:?}", d))?;
-                        resp_buffer.push_str(s);
-                    },
-                    TelnetEvent::TimedOut => {},
-                    _ => {
-                        bail!("Unknown response from AVR, resetting connection: {:?}", resp);
+        let last_heard = events
+            .link()
+            .last_heard
+            .map_or_else(Instant::now, Instant::from_std);
+        if probed.is_some_and(|at| last_heard >= at) {
+            probed = None;
+        }
+        let check = match probed {
+            Some(at) => at + PROBE_TIMEOUT,
+            None => last_heard + PROBE_AFTER,
+        };
+
+        let command = match pending.pop_front() {
+            Some(command) => command,
+            None => tokio::select! {
+                command = commands.recv() => match command {
+                    Some(command) => command,
+                    None => return Ok(()),
+                },
+                messages = stream.read() => {
+                    for message in messages? {
+                        settle(&message, events, &mut owed);
                     }
+                    continue;
                 }
-
-                info!("Code sent to AVR: {:?}. Received back: {:?}", code, resp_buffer);
-                if let Err(e) = send_response(&resp_buffer) {
-                    log_error(&e);
+                _ = sleep_until(check) => {
+                    if probed.is_some() {
+                        bail!("AVR stopped answering, connection assumed dead");
+                    }
+                    debug!("Nothing heard from AVR for {:?}, probing", PROBE_AFTER);
+                    if !dry_run {
+                        stream
+                            .conn
+                            .write_all(probe.as_bytes())
+                            .await
+                            .context("Could not write to AVR via telnet")?;
+                    }
+                    probed = Some(Instant::now());
+                    continue;
                 }
             },
-            // Clear telnet connection of any "R\r\n" heartbeat messages
-            default(Duration::from_millis(1000)) => {
-                let resp = conn.read_nonblocking().context("Error reading from telnet connection")?;
-                if let TelnetEvent::Data(d) = resp {
-                    let s = std::str::from_utf8(&d).context(format!("Could not convert response to UTF-8: {:?}", d))?;
-                    debug!("Cleared from connection: {:?}", s);
+        };
+
+        let span = debug_span!(
+            parent: &command.span,
+            "telnet",
+            code = ?command.code
+        );
+        send_code(
+            command,
+            pending,
+            commands,
+            &mut stream,
+            &mut owed,
+            events,
+            recorder,
+        )
+        .instrument(span)
+        .await?;
+    }
+}
+
+/// Write the command's code to the AVR and send the response back.
+///
+/// If the code is a query, any other commands waiting with the same query are
+/// batched with it, getting the same response without writing the query
+/// again. All other waiting commands are kept in `pending`, in order.
+async fn send_code(
+    command: TelnetCommand,
+    pending: &mut VecDeque<TelnetCommand>,
+    commands: &mut Receiver<TelnetCommand>,
+    stream: &mut AvrStream,
+    owed: &mut Owed,
+    events: &EventBus,
+    recorder: &Option<Recorder>,
+) -> Result<(), Error> {
+    debug!("Code received via channel: {:?}", command.code);
+
+    // Request already timed out waiting on a response, don't execute it late
+    if command.reply.is_closed() {
+        debug!(
+            "Request no longer waiting, skipping code: {:?}",
+            command.code
+        );
+        return Ok(());
+    }
+
+    let TelnetCommand {
+        code,
+        query,
+        awaited,
+        wait,
+        reply,
+        ..
+    } = command;
+    let mut replies = vec![reply];
+    if query {
+        while let Ok(waiting) = commands.try_recv() {
+            pending.push_back(waiting);
+        }
+        let (batched, rest): (VecDeque<_>, VecDeque<_>) =
+            pending.drain(..).partition(|waiting| waiting.code == code);
+        *pending = rest;
+
+        if !batched.is_empty() {
+            debug!(
+                "Batching {} waiting queries with: {:?}",
+                batched.len(),
+                code
+            );
+        }
+        replies.extend(batched.into_iter().map(|waiting| waiting.reply));
+    }
+
+    discard_stale(stream, events, owed)?;
+
+    stream
+        .conn
+        .write_all(code.as_bytes())
+        .await
+        .context("Could not write to AVR via telnet")?;
+
+    let token = owed.token();
+    let (received, resp_buffer) =
+        read_response(stream, events, awaited.as_ref(), wait, token, owed).await?;
+
+    info!(
+        avr_code = %code,
+        avr_response = %received,
+        token,
+        "Code sent to AVR: {:?}. Received back: {:?}",
+        code,
+        received
+    );
+    if let Some(recorder) = recorder {
+        if let Err(e) = recorder.record(&code, &received) {
+            log_error(&e);
+        }
+    }
+    for reply in replies {
+        if reply.send(resp_buffer.clone()).is_err() {
+            debug!("Request no longer waiting on response");
+        }
+    }
+
+    Ok(())
+}
+
+/// Read the AVR's response to the code written with `token` until it sends
+/// the awaited message or an error, or until the response window closes if
+/// nothing is awaited. Gives up waiting after `wait`, leaving the code owed
+/// its answer. Returns everything received, and the response to the code.
+///
+/// Both are made up of the complete messages received, each terminated by
+/// CRLF. Heartbeats are left out, as the AVR can send one at any time. An
+/// error is left out of the response while an earlier code is owed its
+/// answer, as it's that code's. When a message is awaited, the response is
+/// only that message, or an error, as anything else is the answer to some
+/// other code, or a change made some other way. AVR responds twice with Power
+/// On request, the first being useless, so only a message matching what's
+/// awaited ends the response.
+async fn read_response(
+    stream: &mut AvrStream,
+    events: &EventBus,
+    awaited: Option<&Awaited>,
+    wait: Duration,
+    token: u64,
+    owed: &mut Owed,
+) -> Result<(String, String), Error> {
+    let window = match awaited {
+        Some(_) => wait,
+        None => READ_WINDOW,
+    };
+    let deadline = Instant::now() + window;
+    let mut received = String::new();
+    let mut resp_buffer = String::new();
+    let mut done = false;
+
+    'read: while let Ok(messages) = timeout_at(deadline, stream.read()).await {
+        let mut messages = messages?.into_iter();
+        while let Some(message) = messages.next() {
+            let late = match route(&message, events) {
+                Message::Heartbeat => continue,
+                Message::Event(event) => {
+                    owed.answered(&event);
+                    done = awaited.is_some_and(|awaited| awaited.matches(&event));
+                    None
+                }
+                Message::Error(_) => {
+                    let late = owed.claim();
+                    done = late.is_none();
+                    late
                 }
+                Message::Other(_) => None,
+            };
+            received.push_str(&message);
+            received.push_str("\r\n");
+            if let Some(late) = late {
+                debug!(
+                    "Late answer to code #{}, left out of the response to #{}: {:?}",
+                    late, token, message
+                );
+                continue;
+            }
+            if awaited.is_some() && !done {
+                debug!(
+                    "Not the awaited message, left out of the response: {:?}",
+                    message
+                );
+                continue;
+            }
+            resp_buffer.push_str(&message);
+            resp_buffer.push_str("\r\n");
+            if done && awaited.is_some() {
+                // Anything read along with it is still routed, as state
+                // changes are valid regardless
+                for message in messages {
+                    settle(&message, events, owed);
+                }
+                break 'read;
             }
         }
     }
+    if let Some(awaited) = awaited.filter(|_| !done) {
+        debug!("Gave up waiting on AVR for: {:?}", awaited);
+        owed.owe(token, awaited.clone());
+    }
+
+    Ok((received, resp_buffer))
 }
 
-/// Send response code back to skill for further processing.
-fn send_response(s: &str) -> Result<(), Error> {
-    // Clear channel B if full, it shouldn't be
-    if CHANNEL_B.0.is_full() {
-        select! {
-            recv(CHANNEL_B.1) -> _ => {}
-            default() => {}
+/// Discard anything the AVR sent after the previous command's response window
+/// closed, such as a delayed reply, so it isn't read as the response to the
+/// next command. It's still routed, as state changes are valid regardless.
+fn discard_stale(stream: &mut AvrStream, events: &EventBus, owed: &mut Owed) -> Result<(), Error> {
+    while let Some(messages) = stream.try_read()? {
+        for message in messages {
+            debug!("Discarded stale message from connection: {:?}", message);
+            settle(&message, events, owed);
         }
-        debug!("Had to clear channel B");
     }
-    CHANNEL_B.0.send(s.to_owned())?;
-    debug!("Sent response code via channel B: {:?}", s);
     Ok(())
 }
+
+/// Route a message that isn't part of any code's response, settling the code
+/// owed it, if any
+fn settle(message: &str, events: &EventBus, owed: &mut Owed) {
+    match route(message, events) {
+        Message::Event(event) => owed.answered(&event),
+        Message::Error(_) => {
+            if let Some(late) = owed.claim() {
+                debug!("Late answer to code #{}: {:?}", late, message);
+            }
+        }
+        Message::Heartbeat | Message::Other(_) => {}
+    }
+}
+
+/// Classify a message received from the AVR, publishing it if it's a state
+/// change
+fn route(message: &str, events: &EventBus) -> Message {
+    events.heard();
+    let classified = events.protocol().classify(message);
+    match &classified {
+        Message::Heartbeat => debug!("Heartbeat received from AVR"),
+        Message::Event(event) => events.publish(event.clone()),
+        Message::Error(code) => warn!("AVR reported error: {:?}", code),
+        Message::Other(message) => debug!("Unrecognized message from AVR: {:?}", message),
+    }
+    classified
+}
+
+/// Telnet connection to the AVR, with the data read from it split into
+/// messages. Data is read into `buf`, and the end of a message that hasn't
+/// been completely received yet is kept in `partial` until the rest arrives.
+/// It's kept as bytes, as a read can end partway through a UTF-8 character.
+struct AvrStream {
+    conn: TcpStream,
+    buf: [u8; 256],
+    partial: Vec<u8>,
+}
+
+impl AvrStream {
+    fn new(conn: TcpStream) -> Self {
+        AvrStream {
+            conn,
+            buf: [0; 256],
+            partial: vec![],
+        }
+    }
+
+    /// Wait for data from the AVR, returning the messages it completes, if
+    /// any
+    async fn read(&mut self) -> Result<Vec<String>, Error> {
+        let read = self.conn.read(&mut self.buf).await;
+        self.split(read)
+    }
+
+    /// Same as `read`, but returns `None` instead of waiting if no data has
+    /// been received
+    fn try_read(&mut self) -> Result<Option<Vec<String>>, Error> {
+        let read = self.conn.try_read(&mut self.buf);
+        if let Err(e) = &read {
+            if e.kind() == ErrorKind::WouldBlock {
+                return Ok(None);
+            }
+        }
+        self.split(read).map(Some)
+    }
+
+    /// Add the data read to what's left of the last read, and split off the
+    /// complete messages. Messages are terminated by CRLF, but either CR or LF
+    /// is accepted alone, as some AVRs only send one. Only complete messages
+    /// are decoded, replacing any invalid UTF-8.
+    fn split(&mut self, read: std::io::Result<usize>) -> Result<Vec<String>, Error> {
+        self.partial.extend_from_slice(read_bytes(read, &self.buf)?);
+
+        let end = match self.partial.iter().rposition(|&b| b == b'\r' || b == b'\n') {
+            Some(end) => end + 1,
+            None => return Ok(vec![]),
+        };
+        let rest = self.partial.split_off(end);
+        let complete = std::mem::replace(&mut self.partial, rest);
+        Ok(complete
+            .split(|&b| b == b'\r' || b == b'\n')
+            .filter(|message| !message.is_empty())
+            .map(|message| String::from_utf8_lossy(message).into_owned())
+            .collect())
+    }
+}
+
+/// The bytes from a read from the telnet connection. Reading 0 bytes means the
+/// AVR closed the connection.
+fn read_bytes(read: std::io::Result<usize>, buf: &[u8]) -> Result<&[u8], Error> {
+    let n = read.context("Error reading from telnet connection")?;
+    if n == 0 {
+        bail!("Telnet connection closed by AVR");
+    }
+    Ok(&buf[..n])
+}
@@ -0,0 +1,51 @@
+/// This module loads the certificate and key for serving the control API over
+/// TLS, only to clients with a certificate signed by the configured CA.
+///
+/// Clients are checked during the handshake, so a connection without a
+/// certificate the CA signed never gets as far as a request. Requests on it
+/// don't need the bearer token, see `crate::api`.
+use crate::config::ApiTlsConfig;
+use failure::{bail, format_err, Error};
+use std::sync::Arc;
+use tokio_rustls::{
+    rustls::{
+        crypto::ring,
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+        server::WebPkiClientVerifier,
+        RootCertStore, ServerConfig,
+    },
+    TlsAcceptor,
+};
+
+/// Acceptor for TLS connections with the configured certificate, requiring
+/// a client certificate signed by `client_ca`
+pub fn acceptor(config: &ApiTlsConfig) -> Result<TlsAcceptor, Error> {
+    let provider = Arc::new(ring::default_provider());
+
+    let mut roots = RootCertStore::empty();
+    for cert in certs(&config.client_ca)? {
+        roots.add(cert)?;
+    }
+    let verifier =
+        WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider.clone()).build()?;
+
+    let key = PrivateKeyDer::from_pem_file(&config.key)
+        .map_err(|e| format_err!("Could not read TLS key {}: {}", config.key, e))?;
+    let server = ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(certs(&config.cert)?, key)?;
+
+    Ok(TlsAcceptor::from(Arc::new(server)))
+}
+
+/// Certificates in the PEM file
+fn certs(path: &str) -> Result<Vec<CertificateDer<'static>>, Error> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format_err!("Could not read certificates {}: {}", path, e))?;
+    if certs.is_empty() {
+        bail!("No certificates in {}", path);
+    }
+    Ok(certs)
+}
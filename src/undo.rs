@@ -0,0 +1,127 @@
+/// This module keeps the state an AVR was in before each change made through
+/// the skill, so "undo that" can put it back.
+///
+/// Once a command changing the power, volume, mute or input is done, what it
+/// changed goes on the AVR's undo stack, which keeps the last few. What it was
+/// before comes from the cached state, see `crate::events`, from the volume
+/// remembered before it was set, or from the command itself, as turning the
+/// AVR on means it was off. Undoing takes the most recent one off and sends the
+/// commands putting back whatever is different now. Changes made some other
+/// way, like with the physical remote, aren't undone.
+use crate::{avr::AvrCommand, events::CachedState, protocol::AvrProtocol};
+use std::sync::{Arc, Mutex};
+
+/// Number of changes that can be undone
+const DEPTH: usize = 5;
+
+/// Power, volume level, mute and input of the AVR before a change, each
+/// `None` if the change didn't touch it or it wasn't known
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Snapshot {
+    pub power: Option<bool>,
+    pub volume: Option<u8>,
+    pub mute: Option<bool>,
+    pub input: Option<String>,
+}
+
+impl Snapshot {
+    /// What the commands just done changed, given the cached state `before`
+    /// they were sent and `after`, `None` if they didn't change anything that
+    /// can be undone
+    pub fn of(
+        commands: &[AvrCommand],
+        before: &CachedState,
+        after: &CachedState,
+    ) -> Option<Snapshot> {
+        let mut snapshot = Snapshot::default();
+        for cmd in commands {
+            match cmd {
+                AvrCommand::PowerOn => snapshot.power = Some(false),
+                AvrCommand::PowerOff => snapshot.power = Some(true),
+                AvrCommand::SetVolume(_) | AvrCommand::SetVolumeDb(_) => {
+                    snapshot.volume = after
+                        .previous_volume
+                        .and_then(|previous| previous.level)
+                        .or(before.volume);
+                    snapshot.mute = before.mute;
+                }
+                AvrCommand::VolumeUp | AvrCommand::VolumeDown => {
+                    snapshot.volume = before.volume;
+                    snapshot.mute = before.mute;
+                }
+                // It wasn't muted already, or it wouldn't have been asked
+                AvrCommand::Mute => snapshot.mute = before.mute.or(Some(false)),
+                AvrCommand::Unmute => snapshot.mute = before.mute.or(Some(true)),
+                AvrCommand::ChangeInput(_) => snapshot.input = before.input.clone(),
+                _ => {}
+            }
+        }
+        if snapshot == Snapshot::default() {
+            return None;
+        }
+        Some(snapshot)
+    }
+
+    /// Commands putting the AVR back as it was, given its state now. An AVR
+    /// that was off is only turned back off, and one that was on is turned on
+    /// first.
+    pub fn commands(&self, state: &CachedState, protocol: &dyn AvrProtocol) -> Vec<AvrCommand> {
+        let mut commands = vec![];
+        match (self.power, state.power) {
+            (Some(false), Some(false)) => return commands,
+            (Some(false), _) => return vec![AvrCommand::PowerOff],
+            (Some(true), Some(true)) => {}
+            (Some(true), _) => commands.push(AvrCommand::PowerOn),
+            (None, _) => {}
+        }
+        if let Some(input) = &self.input {
+            if state.input.as_ref() != Some(input) {
+                commands.extend(protocol.input_number(input).map(AvrCommand::ChangeInput));
+            }
+        }
+        if let Some(volume) = self.volume {
+            if state.volume != Some(volume) {
+                commands.push(volume_command(protocol, volume));
+            }
+        }
+        match (self.mute, state.mute) {
+            (Some(true), Some(true)) | (Some(false), Some(false)) | (None, _) => {}
+            (Some(true), _) => commands.push(AvrCommand::Mute),
+            (Some(false), _) => commands.push(AvrCommand::Unmute),
+        }
+        commands
+    }
+}
+
+/// Command setting the volume to the level, in dB if the AVR can, for the
+/// exact level, otherwise on the 1 to 10 scale
+pub fn volume_command(protocol: &dyn AvrProtocol, level: u8) -> AvrCommand {
+    match protocol.volume_db(level).map(AvrCommand::SetVolumeDb) {
+        Some(cmd) if protocol.supports(&cmd) => cmd,
+        _ => AvrCommand::SetVolume(((protocol.volume_percent(level) + 5) / 10).max(1)),
+    }
+}
+
+/// Snapshots from before the last few changes to an AVR, the most recent
+/// last. Cloned along with the AVR, sharing the stack.
+#[derive(Clone, Debug, Default)]
+pub struct UndoStack {
+    snapshots: Arc<Mutex<Vec<Snapshot>>>,
+}
+
+impl UndoStack {
+    /// Push the snapshot from before a change, dropping the oldest if there
+    /// are too many
+    pub fn push(&self, snapshot: Snapshot) {
+        let mut snapshots = self.snapshots.lock().unwrap();
+        snapshots.push(snapshot);
+        if snapshots.len() > DEPTH {
+            snapshots.remove(0);
+        }
+    }
+
+    /// Take the snapshot from before the most recent change
+    pub fn pop(&self) -> Option<Snapshot> {
+        self.snapshots.lock().unwrap().pop()
+    }
+}
@@ -0,0 +1,114 @@
+/// This module tells who is talking to the skill, so each person can have
+/// their own settings.
+///
+/// Alexa sends the account's user ID with every request, in its context and
+/// its session, and, when voice profiles are set up and it recognizes the
+/// voice, the person's ID too. Each
+/// `[[skill.user]]` in the config is matched against them, the person's ID
+/// first, so someone sharing the household's account can still have their
+/// own volume ceiling, default input and allowed intents.
+use crate::config::UserConfig;
+use serde::Deserialize;
+use tracing::{debug, info};
+
+/// Part of the Alexa request identifying who sent it, which
+/// `alexa_sdk::Request` doesn't have
+#[derive(Deserialize)]
+struct Envelope {
+    context: Context,
+    session: Option<Session>,
+}
+
+#[derive(Deserialize)]
+struct Session {
+    user: User,
+}
+
+#[derive(Deserialize)]
+struct Context {
+    #[serde(rename = "System")]
+    system: System,
+}
+
+#[derive(Deserialize)]
+struct System {
+    person: Option<Person>,
+    user: Option<User>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Person {
+    person_id: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct User {
+    user_id: String,
+    access_token: Option<String>,
+}
+
+/// Settings of the person or account that sent the raw Alexa request body,
+/// if any are configured. Their IDs are logged either way, to find the ones to
+/// configure.
+pub fn identify<'a>(users: &'a [UserConfig], body: &[u8]) -> Option<&'a UserConfig> {
+    let Envelope { context, session } = serde_json::from_slice(body).ok()?;
+    let system = context.system;
+    let person = system.person.map(|person| person.person_id);
+    let account = system
+        .user
+        .or_else(|| session.map(|session| session.user))
+        .map(|user| user.user_id);
+    debug!("Person ID: {:?}, user ID: {:?}", person, account);
+
+    let user = [person, account]
+        .iter()
+        .flatten()
+        .find_map(|id| users.iter().find(|user| &user.id == id))?;
+    info!("User: {}", user.name.as_deref().unwrap_or(&user.id));
+    Some(user)
+}
+
+/// Whether the account that sent the raw Alexa request body is allowed to use
+/// the skill, any account if `allowed` is empty
+pub fn allowed(allowed: &[String], body: &[u8]) -> bool {
+    if allowed.is_empty() {
+        return true;
+    }
+    match account(body).map(|user| user.user_id) {
+        Some(id) if allowed.contains(&id) => true,
+        id => {
+            info!("Account isn't allowed: {:?}", id);
+            false
+        }
+    }
+}
+
+/// Person ID of the one who sent the raw Alexa request body, if Alexa
+/// recognized their voice, otherwise the account's user ID
+pub fn id(body: &[u8]) -> Option<String> {
+    let Envelope { context, session } = serde_json::from_slice(body).ok()?;
+    let system = context.system;
+    system.person.map(|person| person.person_id).or_else(|| {
+        system
+            .user
+            .or_else(|| session.map(|session| session.user))
+            .map(|user| user.user_id)
+    })
+}
+
+/// Access token the account was linked with, in the raw Alexa request body,
+/// see `crate::linking`
+pub fn access_token(body: &[u8]) -> Option<String> {
+    account(body).and_then(|user| user.access_token)
+}
+
+/// Account that sent the raw Alexa request body
+fn account(body: &[u8]) -> Option<User> {
+    let Envelope { context, session } = serde_json::from_slice(body).ok()?;
+    context
+        .system
+        .user
+        .or_else(|| session.map(|session| session.user))
+}
@@ -0,0 +1,292 @@
+/// This module watches the connection to each AVR, and sends a notification
+/// describing the outage when it looks dead.
+///
+/// A connected AVR sends a heartbeat every 30 seconds, so hearing nothing at
+/// all from it for `heartbeat_timeout` seconds means the connection has
+/// silently died. While disconnected, `max_failures` attempts to reconnect
+/// failing in a row means the AVR is unreachable. Either way, one notification
+/// is sent for the outage, and another once the AVR is back:
+///
+/// ```json
+/// {"device": "living room", "event": "outage", "reason": "5 consecutive failed attempts to connect", "since": "2019-10-16T13:42:07Z", "duration_secs": 0}
+/// ```
+///
+/// Notifications go to any of a webhook, as a JSON POST, an email sent over
+/// SMTP, and an MQTT topic, as JSON.
+use crate::{
+    config::{EmailConfig, MqttConfig, WatchdogConfig},
+    device::{Device, Devices},
+};
+use chrono::{SecondsFormat, Utc};
+use failure::{bail, Error};
+use hyper::{client::HttpConnector, header::CONTENT_TYPE, Body, Client, Method, Request, Uri};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use lettre::{
+    message::Mailbox, transport::smtp::authentication::Credentials, AsyncSmtpTransport,
+    AsyncTransport, Message, Tokio1Executor,
+};
+use rumqttc::{AsyncClient, MqttOptions, QoS};
+use serde::Serialize;
+use std::{sync::Arc, time::Duration};
+use tokio::time::{interval, sleep, Instant};
+use tracing::{info, warn};
+
+type HttpsClient = Client<HttpsConnector<HttpConnector>>;
+
+/// How often each AVR's connection is checked
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Delay before polling the MQTT connection again after it fails
+const MQTT_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Notification of an outage starting, or ending
+#[derive(Serialize, Debug)]
+struct Notice {
+    device: String,
+    event: &'static str,
+    reason: String,
+    since: String,
+    duration_secs: u64,
+}
+
+impl Notice {
+    fn subject(&self) -> String {
+        match self.event {
+            "outage" => format!("AVR unreachable: {}", self.device),
+            _ => format!("AVR reachable again: {}", self.device),
+        }
+    }
+
+    fn text(&self) -> String {
+        match self.event {
+            "outage" => format!(
+                "Lost the connection to {} at {}: {}.",
+                self.device, self.since, self.reason
+            ),
+            _ => format!(
+                "Connected to {} again, after an outage of {}s since {}: {}.",
+                self.device, self.duration_secs, self.since, self.reason
+            ),
+        }
+    }
+}
+
+/// Where notifications are sent
+struct Notifier {
+    webhook: Option<(HttpsClient, Uri)>,
+    email: Option<Email>,
+    mqtt: Option<(AsyncClient, String)>,
+}
+
+struct Email {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+    to: Vec<Mailbox>,
+}
+
+/// Outage in progress, since when and why
+struct Outage {
+    started: Instant,
+    since: String,
+    reason: String,
+}
+
+/// Start a task for each AVR watching its connection, and notifying the
+/// configured webhook, email and MQTT topic of outages
+pub fn run(config: WatchdogConfig, devices: &Devices) -> Result<(), Error> {
+    let notifier = Arc::new(Notifier {
+        webhook: config.webhook.as_deref().map(webhook).transpose()?,
+        email: config.email.as_ref().map(email).transpose()?,
+        mqtt: config.mqtt.as_ref().map(mqtt),
+    });
+    if notifier.webhook.is_none() && notifier.email.is_none() && notifier.mqtt.is_none() {
+        bail!("The watchdog needs a webhook, email or mqtt to notify");
+    }
+    info!("Watchdog enabled");
+
+    let config = Arc::new(config);
+    for device in devices.iter() {
+        tokio::spawn(watch(config.clone(), notifier.clone(), device.clone()));
+    }
+    Ok(())
+}
+
+fn webhook(url: &str) -> Result<(HttpsClient, Uri), Error> {
+    let connector = HttpsConnectorBuilder::new()
+        .with_webpki_roots()
+        .https_or_http()
+        .enable_http1()
+        .build();
+    Ok((Client::builder().build(connector), url.parse()?))
+}
+
+/// SMTP transport, with implicit TLS on port 465, otherwise STARTTLS
+fn email(config: &EmailConfig) -> Result<Email, Error> {
+    let builder = if config.port == 465 {
+        AsyncSmtpTransport::<Tokio1Executor>::relay(&config.server)?
+    } else {
+        AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.server)?
+    };
+    let mut builder = builder.port(config.port);
+    if let (Some(username), Some(password)) = (&config.username, &config.password) {
+        builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+    }
+    if config.to.is_empty() {
+        bail!("The watchdog's email needs at least one address to send to");
+    }
+
+    Ok(Email {
+        transport: builder.build(),
+        from: config.from.parse()?,
+        to: config
+            .to
+            .iter()
+            .map(|to| to.parse())
+            .collect::<Result<_, _>>()?,
+    })
+}
+
+/// MQTT client, with a task polling its connection so messages get sent
+fn mqtt(config: &MqttConfig) -> (AsyncClient, String) {
+    let mut options = MqttOptions::new("alexa-avr-control", &config.host, config.port);
+    options.set_keep_alive(Duration::from_secs(30));
+    if let (Some(username), Some(password)) = (&config.username, &config.password) {
+        options.set_credentials(username, password);
+    }
+    let (client, mut eventloop) = AsyncClient::new(options, 16);
+
+    tokio::spawn(async move {
+        loop {
+            if let Err(e) = eventloop.poll().await {
+                warn!("MQTT connection failed: {}", e);
+                sleep(MQTT_RETRY_DELAY).await;
+            }
+        }
+    });
+    (client, config.topic.clone())
+}
+
+/// Check the AVR's connection, notifying when an outage starts and ends
+async fn watch(config: Arc<WatchdogConfig>, notifier: Arc<Notifier>, device: Device) {
+    let mut ticks = interval(CHECK_INTERVAL);
+    let mut outage: Option<Outage> = None;
+
+    loop {
+        ticks.tick().await;
+        match (problem(&config, &device), &outage) {
+            (Some(reason), None) => {
+                warn!("Outage of {}: {}", device.name, reason);
+                let started = Outage {
+                    started: Instant::now(),
+                    since: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+                    reason,
+                };
+                notifier
+                    .notify(Notice {
+                        device: device.name.clone(),
+                        event: "outage",
+                        reason: started.reason.clone(),
+                        since: started.since.clone(),
+                        duration_secs: 0,
+                    })
+                    .await;
+                outage = Some(started);
+            }
+            (None, Some(ended)) => {
+                info!("Outage of {} is over", device.name);
+                notifier
+                    .notify(Notice {
+                        device: device.name.clone(),
+                        event: "recovered",
+                        reason: ended.reason.clone(),
+                        since: ended.since.clone(),
+                        duration_secs: ended.started.elapsed().as_secs(),
+                    })
+                    .await;
+                outage = None;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Why the AVR's connection looks dead, if it does
+fn problem(config: &WatchdogConfig, device: &Device) -> Option<String> {
+    let link = device.events.link();
+    if device.events.state().connected == Some(true) {
+        let timeout = Duration::from_secs(config.heartbeat_timeout);
+        match link.last_heard {
+            Some(heard) if config.heartbeat_timeout > 0 && heard.elapsed() >= timeout => Some(
+                format!("nothing received for {}s", heard.elapsed().as_secs()),
+            ),
+            _ => None,
+        }
+    } else if link.failures >= config.max_failures {
+        Some(format!(
+            "{} consecutive failed attempts to connect",
+            link.failures
+        ))
+    } else {
+        None
+    }
+}
+
+impl Notifier {
+    /// Send the notice everywhere configured, logging any failures
+    async fn notify(&self, notice: Notice) {
+        let payload = match serde_json::to_string(&notice) {
+            Ok(payload) => payload,
+            Err(e) => {
+                warn!("Could not serialize watchdog notice: {}", e);
+                return;
+            }
+        };
+
+        if let Some((client, url)) = &self.webhook {
+            let request = Request::builder()
+                .method(Method::POST)
+                .uri(url.clone())
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(payload.clone()));
+            let result = match request {
+                Ok(request) => client.request(request).await.map_err(Error::from),
+                Err(e) => Err(e.into()),
+            };
+            match result {
+                Ok(response) if !response.status().is_success() => {
+                    warn!("Watchdog webhook answered {}", response.status())
+                }
+                Ok(_) => {}
+                Err(e) => warn!("Could not call watchdog webhook: {}", e),
+            }
+        }
+
+        if let Some(email) = &self.email {
+            if let Err(e) = email.send(&notice).await {
+                warn!("Could not send watchdog email: {}", e);
+            }
+        }
+
+        if let Some((client, topic)) = &self.mqtt {
+            if let Err(e) = client
+                .publish(topic.clone(), QoS::AtLeastOnce, false, payload)
+                .await
+            {
+                warn!("Could not publish watchdog notice: {}", e);
+            }
+        }
+    }
+}
+
+impl Email {
+    async fn send(&self, notice: &Notice) -> Result<(), Error> {
+        let mut message = Message::builder()
+            .from(self.from.clone())
+            .subject(notice.subject());
+        for to in &self.to {
+            message = message.to(to.clone());
+        }
+        self.transport.send(message.body(notice.text())?).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,234 @@
+/// This module sends webhook notifications when an AVR's state changes.
+///
+/// Each configured webhook gets a JSON POST for the events it's interested
+/// in, such as:
+///
+/// ```json
+/// {"device": "living room", "event": "input", "value": "25", "name": "BD", "timestamp": 1571234567}
+/// ```
+///
+/// Events are `power`, `volume`, `mute`, `input`, `preset`, `sound_retriever`,
+/// `dialog_enhancement`, `listening_mode`, `speaker_system`,
+/// `subwoofer_level`, `lfe_attenuation`, `headphones`, `video_converter`,
+/// `video_resolution`, `network_standby`, `input_label`, `model`, `firmware`
+/// and `connection`, the last being sent when the telnet connection to the
+/// AVR is lost or restored. `input_label` is sent for each
+/// input as its name on the AVR's on-screen menu is learned, and `model` and
+/// `firmware` once the AVR is asked for them.
+use crate::{
+    config::WebhookConfig,
+    device::{Device, Devices},
+    events::AvrEvent,
+};
+use failure::{bail, Error};
+use hyper::{client::HttpConnector, header::CONTENT_TYPE, Body, Client, Method, Request};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use serde_json::{json, Value};
+use std::{
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::broadcast;
+use tracing::{debug, warn};
+
+const EVENTS: [&str; 19] = [
+    "power",
+    "volume",
+    "mute",
+    "input",
+    "preset",
+    "sound_retriever",
+    "dialog_enhancement",
+    "listening_mode",
+    "speaker_system",
+    "subwoofer_level",
+    "lfe_attenuation",
+    "headphones",
+    "video_converter",
+    "video_resolution",
+    "network_standby",
+    "input_label",
+    "model",
+    "firmware",
+    "connection",
+];
+
+type HttpsClient = Client<HttpsConnector<HttpConnector>>;
+
+/// Start a task for each AVR that sends its events to the webhooks
+pub fn run(webhooks: Vec<WebhookConfig>, devices: &Devices) -> Result<(), Error> {
+    for webhook in &webhooks {
+        for event in &webhook.events {
+            if !EVENTS.contains(&event.as_str()) {
+                bail!("Unknown webhook event: {:?}", event);
+            }
+        }
+        if let Some(name) = &webhook.device {
+            if devices.get(Some(name)).is_none() {
+                bail!("No AVR named {:?} for webhook: {}", name, webhook.url);
+            }
+        }
+    }
+    if webhooks.is_empty() {
+        return Ok(());
+    }
+
+    let connector = HttpsConnectorBuilder::new()
+        .with_webpki_roots()
+        .https_or_http()
+        .enable_http1()
+        .build();
+    let client = Client::builder().build(connector);
+    let webhooks = Arc::new(webhooks);
+
+    for device in devices.iter() {
+        tokio::spawn(notify(device.clone(), webhooks.clone(), client.clone()));
+    }
+
+    Ok(())
+}
+
+/// Send each of the AVR's events to the webhooks interested in it
+async fn notify(device: Device, webhooks: Arc<Vec<WebhookConfig>>, client: HttpsClient) {
+    let mut events = device.events.subscribe();
+
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => return,
+        };
+        let (name, payload) = payload(&device, &event);
+
+        for webhook in webhooks.iter() {
+            let for_device = webhook
+                .device
+                .as_ref()
+                .map(|name| name.eq_ignore_ascii_case(&device.name))
+                .unwrap_or(true);
+            let for_event = webhook.events.is_empty() || webhook.events.iter().any(|e| e == name);
+            if for_device && for_event {
+                tokio::spawn(send(client.clone(), webhook.url.clone(), payload.clone()));
+            }
+        }
+    }
+}
+
+/// Event name and JSON payload for the event
+fn payload(device: &Device, event: &AvrEvent) -> (&'static str, Value) {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0);
+    let mut payload = json!({
+        "device": device.name,
+        "timestamp": timestamp,
+    });
+
+    let name = match event {
+        AvrEvent::Power(on) => {
+            payload["value"] = json!(on);
+            "power"
+        }
+        AvrEvent::Volume(volume) => {
+            payload["value"] = json!(volume);
+            "volume"
+        }
+        AvrEvent::Mute(mute) => {
+            payload["value"] = json!(mute);
+            "mute"
+        }
+        AvrEvent::Input(input) => {
+            payload["value"] = json!(input);
+            payload["name"] = json!(device.input_name(input));
+            "input"
+        }
+        AvrEvent::Preset(preset) => {
+            payload["value"] = json!(preset);
+            "preset"
+        }
+        AvrEvent::SoundRetriever(on) => {
+            payload["value"] = json!(on);
+            "sound_retriever"
+        }
+        AvrEvent::DialogEnhancement(on) => {
+            payload["value"] = json!(on);
+            "dialog_enhancement"
+        }
+        AvrEvent::ListeningMode(mode) => {
+            payload["value"] = json!(mode);
+            "listening_mode"
+        }
+        AvrEvent::SpeakerSystem(system) => {
+            payload["value"] = json!(system);
+            "speaker_system"
+        }
+        AvrEvent::SubwooferLevel(level) => {
+            payload["value"] = json!(level);
+            "subwoofer_level"
+        }
+        AvrEvent::LfeAttenuation(db) => {
+            payload["value"] = json!(db);
+            "lfe_attenuation"
+        }
+        AvrEvent::Headphones(plugged) => {
+            payload["value"] = json!(plugged);
+            "headphones"
+        }
+        AvrEvent::VideoConverter(on) => {
+            payload["value"] = json!(on);
+            "video_converter"
+        }
+        AvrEvent::VideoResolution(resolution) => {
+            payload["value"] = json!(resolution);
+            "video_resolution"
+        }
+        AvrEvent::NetworkStandby(on) => {
+            payload["value"] = json!(on);
+            "network_standby"
+        }
+        AvrEvent::InputLabel(input, label) => {
+            payload["value"] = json!(input);
+            payload["name"] = json!(label);
+            "input_label"
+        }
+        AvrEvent::Model(model) => {
+            payload["value"] = json!(model);
+            "model"
+        }
+        AvrEvent::Firmware(firmware) => {
+            payload["value"] = json!(firmware);
+            "firmware"
+        }
+        AvrEvent::Connected(connected) => {
+            payload["value"] = json!(connected);
+            "connection"
+        }
+    };
+    payload["event"] = json!(name);
+
+    (name, payload)
+}
+
+async fn send(client: HttpsClient, url: String, payload: Value) {
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(&url)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(payload.to_string()));
+    let request = match request {
+        Ok(request) => request,
+        Err(e) => {
+            warn!("Invalid webhook url: {}: {}", url, e);
+            return;
+        }
+    };
+
+    match client.request(request).await {
+        Ok(response) if response.status().is_success() => {
+            debug!("Webhook sent: {}: {}", url, payload)
+        }
+        Ok(response) => warn!("Webhook failed: {}: {}", url, response.status()),
+        Err(e) => warn!("Webhook failed: {}: {}", url, e),
+    }
+}
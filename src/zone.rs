@@ -0,0 +1,62 @@
+/// This module keeps an AVR's Zone 2 volume locked to its main zone's, so a
+/// second room follows along at a fixed offset.
+///
+/// Whenever the main zone's volume changes, whether by this skill, the remote
+/// or anything else, Zone 2 is set to the main zone's volume plus the
+/// configured offset in dB. Changes made in quick succession, like holding
+/// volume up, only set Zone 2 to the last of them.
+use crate::{events::AvrEvent, telnet::AvrConnection};
+use failure::{bail, Error};
+use tokio::sync::broadcast::error::{RecvError, TryRecvError};
+use tracing::{debug, info};
+
+/// Start a task setting the named AVR's Zone 2 volume to `offset` dB from the
+/// main zone's whenever it changes
+pub fn link(name: String, offset: f32, conn: AvrConnection) -> Result<(), Error> {
+    let protocol = conn.protocol();
+    if protocol.volume_db(0).is_none() || protocol.zone2_volume_code(0.0).is_none() {
+        bail!("Zone 2 volume can't be linked for {}'s protocol", name);
+    }
+    info!("Linking {}'s Zone 2 volume at {} dB", name, offset);
+    tokio::spawn(follow(name, offset, conn));
+    Ok(())
+}
+
+async fn follow(name: String, offset: f32, conn: AvrConnection) {
+    let mut events = conn.events().subscribe();
+
+    loop {
+        let mut level = match events.recv().await {
+            Ok(AvrEvent::Volume(level)) => level,
+            Ok(_) | Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => return,
+        };
+        // Skip to the latest volume
+        loop {
+            match events.try_recv() {
+                Ok(AvrEvent::Volume(latest)) => level = latest,
+                Ok(_) | Err(TryRecvError::Lagged(_)) => {}
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Closed) => return,
+            }
+        }
+
+        let protocol = conn.protocol();
+        let code = match protocol
+            .volume_db(level)
+            .and_then(|db| protocol.zone2_volume_code(db + offset))
+        {
+            Some(code) => code,
+            None => continue,
+        };
+        if conn.dry_run() {
+            info!("Dry run, not sending to {}: {:?}", name, code);
+            continue;
+        }
+        // Zone 2 being off is reported as an error, which is fine
+        match conn.send(&code, None).await {
+            Ok(response) => debug!("{} Zone 2 volume set: {:?}", name, response),
+            Err(e) => debug!("Could not set {}'s Zone 2 volume: {}", name, e),
+        }
+    }
+}
@@ -0,0 +1,467 @@
+//! Regression tests for the command validation in `avr.rs`, replaying
+//! transcripts of exchanges with AVRs from `tests/transcripts`.
+use alexa_avr_control::{
+    aliases::InputNames,
+    avr::{self, AvrCommand, AvrError, Key},
+    client::{AvrClient, Volume},
+    config::DeviceConfig,
+    events::EventBus,
+    labels,
+    pioneer::Pioneer,
+    protocol::{self, AvrProtocol},
+    raw::{RawCodes, RawError},
+    replay::{self, Recorder},
+    state::{self, Response},
+    telnet::{self, AvrConnection, Backoff},
+};
+use std::{collections::BTreeMap, net::Ipv6Addr, sync::Arc, time::Duration};
+
+/// Connect to a stand-in Pioneer AVR replaying the named transcript
+async fn connect(transcript: &str) -> AvrConnection {
+    connect_with(transcript, Arc::new(Pioneer)).await
+}
+
+/// Connect to a stand-in AVR replaying the named transcript, using the given
+/// protocol
+async fn connect_with(transcript: &str, protocol: Arc<dyn AvrProtocol>) -> AvrConnection {
+    let path = format!("tests/transcripts/{}.jsonl", transcript);
+    let exchanges = replay::load(path).unwrap();
+    let addr = replay::serve(([127, 0, 0, 1], 0).into(), exchanges)
+        .await
+        .unwrap();
+
+    let backoff = Backoff {
+        min: Duration::from_millis(100),
+        max: Duration::from_millis(100),
+        connect_timeout: Duration::from_secs(5),
+    };
+    telnet::run(
+        addr.ip().to_string(),
+        addr.port(),
+        backoff,
+        protocol.clone(),
+        EventBus::new(protocol),
+        false,
+        None,
+    )
+}
+
+/// Process the command, returning the `AvrError` it failed with
+async fn process_err(cmd: AvrCommand, conn: &AvrConnection) -> AvrError {
+    let e = avr::process(cmd, conn).await.unwrap_err();
+    e.downcast_ref::<AvrError>()
+        .cloned()
+        .unwrap_or_else(|| panic!("Not an AvrError: {}", e))
+}
+
+#[tokio::test]
+async fn power_on() {
+    let conn = connect("pioneer_power_on").await;
+    avr::process(AvrCommand::PowerOn, &conn).await.unwrap();
+    assert_eq!(conn.events().state().power, Some(true));
+}
+
+#[tokio::test]
+async fn power_on_ipv6() {
+    let exchanges = replay::load("tests/transcripts/pioneer_power_on.jsonl").unwrap();
+    let addr = replay::serve((Ipv6Addr::LOCALHOST, 0).into(), exchanges)
+        .await
+        .unwrap();
+    let backoff = Backoff {
+        min: Duration::from_millis(100),
+        max: Duration::from_millis(100),
+        connect_timeout: Duration::from_secs(5),
+    };
+    let protocol: Arc<dyn AvrProtocol> = Arc::new(Pioneer);
+    let conn = telnet::run(
+        "[::1]".to_owned(),
+        addr.port(),
+        backoff,
+        protocol.clone(),
+        EventBus::new(protocol),
+        false,
+        None,
+    );
+    avr::process(AvrCommand::PowerOn, &conn).await.unwrap();
+    assert_eq!(conn.events().state().power, Some(true));
+}
+
+#[tokio::test]
+async fn power_already_on() {
+    let conn = connect("pioneer_power_already_on").await;
+    let e = process_err(AvrCommand::PowerOn, &conn).await;
+    assert!(matches!(e, AvrError::PowerAlreadyOn));
+}
+
+#[tokio::test]
+async fn mute_while_off() {
+    let conn = connect("pioneer_power_off_mute").await;
+    let e = process_err(AvrCommand::Mute, &conn).await;
+    assert!(matches!(e, AvrError::PowerOffCantProcess));
+}
+
+#[tokio::test]
+async fn mute() {
+    let conn = connect("pioneer_mute").await;
+    avr::process(AvrCommand::Mute, &conn).await.unwrap();
+    assert_eq!(conn.events().state().mute, Some(true));
+}
+
+#[tokio::test]
+async fn set_volume_in_steps() {
+    let conn = connect("pioneer_set_volume").await;
+    avr::process(AvrCommand::SetVolume(5), &conn).await.unwrap();
+    assert_eq!(conn.events().state().volume, Some(51));
+}
+
+#[tokio::test]
+async fn set_volume_within_a_step() {
+    let conn = connect("pioneer_set_volume_adjacent").await;
+
+    // Stepping two levels at a time from 40 lands on 50, next to 51
+    avr::process(AvrCommand::SetVolume(5), &conn).await.unwrap();
+    assert_eq!(conn.events().state().volume, Some(50));
+}
+
+#[tokio::test]
+async fn set_volume_confirmed_late() {
+    let conn = connect("pioneer_set_volume_lag").await;
+
+    // The first query still answers with a level on the way there
+    avr::process(AvrCommand::SetVolume(5), &conn).await.unwrap();
+    assert_eq!(conn.events().state().volume, Some(51));
+}
+
+#[tokio::test]
+async fn client_set_volume() {
+    let client = AvrClient::from_connection(connect("pioneer_set_volume").await);
+    client.set_volume(Volume::new(5).unwrap()).await.unwrap();
+    assert_eq!(client.events().state().volume, Some(51));
+    assert!(Volume::new(11).is_err());
+}
+
+#[tokio::test]
+async fn client_status_headphones() {
+    let client = AvrClient::from_connection(connect("pioneer_headphones").await);
+    let state = client.status().await.unwrap();
+    assert_eq!(state.power, Some(true));
+    assert_eq!(state.volume, Some(81));
+    assert_eq!(state.mute, Some(false));
+    assert_eq!(state.headphones, Some(true));
+    assert_eq!(state::parse("HP0"), Some(Response::Headphones(false)));
+}
+
+#[tokio::test]
+async fn set_volume_with_offset() {
+    let config: DeviceConfig = toml::from_str(
+        r#"
+        name = "receiver"
+        host = "localhost"
+        [offsets]
+        CD = 6
+        "#,
+    )
+    .unwrap();
+    let protocol = protocol::from_config(&config).unwrap();
+    let conn = connect_with("pioneer_set_volume_offset", protocol).await;
+
+    // The CD input is 6dB hotter, so it's set 12 levels lower
+    avr::process(AvrCommand::SetVolume(5), &conn).await.unwrap();
+    assert_eq!(conn.events().state().volume, Some(51));
+}
+
+#[tokio::test]
+async fn set_volume_db() {
+    let conn = connect("pioneer_set_volume_db").await;
+
+    // -55.5dB is level 50. The AVR doesn't confirm setting it directly in
+    // time, so it's stepped two levels at a time to 49, and the last half dB
+    // is set directly
+    avr::process(AvrCommand::SetVolumeDb(-55.5), &conn)
+        .await
+        .unwrap();
+    assert_eq!(conn.events().state().volume, Some(50));
+
+    let e = process_err(AvrCommand::SetVolumeDb(20.0), &conn).await;
+    assert!(matches!(e, AvrError::Unsupported { .. }));
+    assert_eq!(Pioneer.db_level(-30.25), Some(100));
+    assert_eq!(Pioneer.db_level(-30.2), Some(101));
+}
+
+#[tokio::test]
+async fn set_volume_on_curve() {
+    let config: DeviceConfig = toml::from_str(
+        r#"
+        name = "receiver"
+        host = "localhost"
+        volume_curve = [20, 40, 50, 60, 70, 80, 90, 95, 100, 101]
+        "#,
+    )
+    .unwrap();
+    let protocol = protocol::from_config(&config).unwrap();
+    let conn = connect_with("pioneer_volume_curve", protocol.clone()).await;
+
+    avr::process(AvrCommand::SetVolume(2), &conn).await.unwrap();
+    assert_eq!(conn.events().state().volume, Some(40));
+    assert_eq!(protocol.volume_percent(40), 20);
+    assert_eq!(protocol.volume_percent(45), 25);
+}
+
+#[test]
+fn logarithmic_volume_curve() {
+    let config: DeviceConfig = toml::from_str(
+        r#"
+        name = "receiver"
+        host = "localhost"
+        volume_curve = "logarithmic"
+        "#,
+    )
+    .unwrap();
+    let protocol = protocol::from_config(&config).unwrap();
+    assert_eq!(protocol.volume_level(1), 30);
+    assert_eq!(protocol.volume_level(10), 101);
+    assert_eq!(protocol.volume_percent(protocol.volume_level(6)), 60);
+
+    let config: DeviceConfig = toml::from_str(
+        r#"
+        name = "receiver"
+        host = "localhost"
+        volume_curve = [20, 40, 30]
+        "#,
+    )
+    .unwrap();
+    assert!(protocol::from_config(&config).is_err());
+}
+
+#[tokio::test]
+async fn input_not_changed() {
+    let conn = connect("pioneer_input_not_changed").await;
+    let e = process_err(AvrCommand::ChangeInput(3), &conn).await;
+    assert!(matches!(e, AvrError::ResponseDoesntMatch { .. }));
+}
+
+#[tokio::test]
+async fn select_preset() {
+    let conn = connect("pioneer_select_preset").await;
+    avr::process(AvrCommand::SelectPreset("a2".to_owned()), &conn)
+        .await
+        .unwrap();
+    let state = conn.events().state();
+    assert_eq!(state.input.as_deref(), Some("02"));
+    assert_eq!(state.preset.as_deref(), Some("A02"));
+
+    let e = process_err(AvrCommand::SelectPreset("H1".to_owned()), &conn).await;
+    assert!(matches!(e, AvrError::Unsupported { .. }));
+}
+
+#[tokio::test]
+async fn gallery_keys() {
+    let conn = connect("pioneer_gallery").await;
+    avr::process(AvrCommand::Gallery(Key::Enter), &conn)
+        .await
+        .unwrap();
+
+    let e = process_err(AvrCommand::Gallery(Key::Return), &conn).await;
+    assert!(matches!(e, AvrError::ResponseDoesntMatch { .. }));
+}
+
+#[tokio::test]
+async fn ipod_keys() {
+    let conn = connect("pioneer_ipod").await;
+    avr::process(AvrCommand::Ipod(Key::Pause), &conn)
+        .await
+        .unwrap();
+
+    let e = process_err(AvrCommand::Ipod(Key::Enter), &conn).await;
+    assert!(matches!(e, AvrError::Unsupported { .. }));
+}
+
+#[tokio::test]
+async fn bluetooth_pairing() {
+    let conn = connect("pioneer_adapter").await;
+    avr::process(AvrCommand::BluetoothPairing, &conn)
+        .await
+        .unwrap();
+    assert_eq!(conn.events().state().input.as_deref(), Some("33"));
+
+    avr::process(AvrCommand::Adapter(Key::Pause), &conn)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn sound_retriever() {
+    let conn = connect("pioneer_sound_retriever").await;
+    avr::process(AvrCommand::SoundRetrieverOn, &conn)
+        .await
+        .unwrap();
+    assert_eq!(conn.events().state().sound_retriever, Some(true));
+
+    let e = process_err(AvrCommand::DialogEnhancementOn, &conn).await;
+    assert!(matches!(e, AvrError::ResponseDoesntMatch { .. }));
+    assert_eq!(conn.events().state().dialog_enhancement, None);
+}
+
+#[tokio::test]
+async fn listening_mode() {
+    let conn = connect("pioneer_listening_mode").await;
+    avr::process(AvrCommand::SetListeningMode("0112".to_owned()), &conn)
+        .await
+        .unwrap();
+    assert_eq!(
+        conn.events().state().listening_mode.as_deref(),
+        Some("0112")
+    );
+
+    let e = process_err(AvrCommand::SetListeningMode("stereo".to_owned()), &conn).await;
+    assert!(matches!(e, AvrError::Unsupported { .. }));
+}
+
+#[tokio::test]
+async fn speaker_system() {
+    let conn = connect("pioneer_speaker_system").await;
+    avr::process(AvrCommand::SetSpeakerSystem("04".to_owned()), &conn)
+        .await
+        .unwrap();
+    assert_eq!(conn.events().state().speaker_system.as_deref(), Some("04"));
+
+    let e = process_err(AvrCommand::SetSpeakerSystem("zone 2".to_owned()), &conn).await;
+    assert!(matches!(e, AvrError::Unsupported { .. }));
+}
+
+#[tokio::test]
+async fn video() {
+    let conn = connect("pioneer_video").await;
+    avr::process(AvrCommand::VideoConverterOn, &conn)
+        .await
+        .unwrap();
+    assert_eq!(conn.events().state().video_converter, Some(true));
+
+    avr::process(AvrCommand::SetVideoResolution("06".to_owned()), &conn)
+        .await
+        .unwrap();
+    assert_eq!(
+        conn.events().state().video_resolution.as_deref(),
+        Some("06")
+    );
+
+    // There's no "02"
+    let e = process_err(AvrCommand::SetVideoResolution("02".to_owned()), &conn).await;
+    assert!(matches!(e, AvrError::Unsupported { .. }));
+}
+
+#[tokio::test]
+async fn network_standby() {
+    let conn = connect("pioneer_network_standby").await;
+    avr::process(AvrCommand::NetworkStandbyOn, &conn)
+        .await
+        .unwrap();
+    assert_eq!(conn.events().state().network_standby, Some(true));
+    assert_eq!(state::parse("STJ0"), Some(Response::NetworkStandby(false)));
+}
+
+#[tokio::test]
+async fn subwoofer() {
+    let conn = connect("pioneer_subwoofer").await;
+
+    // Channel levels are set rather than stepped, 0.5dB a level
+    avr::process(AvrCommand::SubwooferDown, &conn)
+        .await
+        .unwrap();
+    assert_eq!(conn.events().state().subwoofer_level, Some(48));
+
+    avr::process(AvrCommand::SetLfeAttenuation(10), &conn)
+        .await
+        .unwrap();
+    assert_eq!(conn.events().state().lfe_attenuation, Some(10));
+
+    let e = process_err(AvrCommand::SetLfeAttenuation(12), &conn).await;
+    assert!(matches!(e, AvrError::Unsupported { .. }));
+}
+
+#[tokio::test]
+async fn input_labels() {
+    let conn = connect("pioneer_input_labels").await;
+    let inputs = InputNames::new(&Pioneer, &BTreeMap::new(), &[]).unwrap();
+    assert_eq!(labels::learn(&conn, &inputs).await.unwrap(), 1);
+
+    // HDMI 1 was renamed, and there's no Phono input
+    assert_eq!(inputs.resolve("ps5"), Some(3));
+    assert!(inputs.visible().contains(&(3, "PS5".to_owned())));
+    assert!(inputs.is_hidden(18));
+    assert!(!inputs.is_hidden(1));
+}
+
+#[tokio::test]
+async fn raw_codes() {
+    let conn = connect("pioneer_raw_codes").await;
+    let mut codes = BTreeMap::new();
+    codes.insert("speaker status".to_owned(), "?SPK".to_owned());
+    let raw = RawCodes::new(&codes, conn);
+
+    let code = raw.resolve("speakers status").unwrap();
+    assert_eq!(raw.send(code).await.unwrap(), "SPK1");
+
+    // Only configured codes are sent
+    let e = raw.send("PF").await.unwrap_err();
+    assert!(matches!(
+        e.downcast_ref::<RawError>(),
+        Some(RawError::NotAllowed { .. })
+    ));
+}
+
+#[test]
+fn record_and_load() {
+    let path = std::env::temp_dir().join(format!("transcript-{}.jsonl", std::process::id()));
+    let recorder = Recorder::create(&path).unwrap();
+    recorder.record("?P\r", "PWR0\r\n").unwrap();
+    recorder.record("PF\r", "PWR2\r\n").unwrap();
+
+    let exchanges = replay::load(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(exchanges.len(), 2);
+    assert_eq!(exchanges[1].code, "PF\r");
+    assert_eq!(exchanges[1].response, "PWR2\r\n");
+}
+
+#[test]
+fn parse_responses() {
+    assert_eq!(state::parse("VOL081\r\n"), Some(Response::Volume(81)));
+    assert_eq!(state::parse("PWR2"), Some(Response::Power(false)));
+    assert_eq!(state::parse("E04"), Some(Response::Error("E04".to_owned())));
+    assert_eq!(state::parse("ATA1"), Some(Response::SoundRetriever(true)));
+    assert_eq!(
+        state::parse("ATG0"),
+        Some(Response::DialogEnhancement(false))
+    );
+    assert_eq!(
+        state::parse("FL02202048444D492031202020202020"),
+        Some(Response::Display("HDMI 1".to_owned()))
+    );
+    assert_eq!(
+        state::parse("RGB191PS5"),
+        Some(Response::InputLabel {
+            input: "19".to_owned(),
+            renamed: true,
+            name: "PS5".to_owned(),
+        })
+    );
+    match state::parse("AST0102010000000") {
+        Some(Response::AudioStatus(status)) => assert!(status.signal_present()),
+        response => panic!("Not an audio status: {:?}", response),
+    }
+    assert_eq!(state::parse("VOLUME"), None);
+}
+
+#[test]
+fn zone2_volume() {
+    assert_eq!(Pioneer.volume_db(161), Some(0.0));
+    assert_eq!(Pioneer.volume_db(121), Some(-20.0));
+    assert_eq!(
+        Pioneer.zone2_volume_code(-20.0 - 10.0),
+        Some("51ZV\r".to_owned())
+    );
+    assert_eq!(Pioneer.zone2_volume_code(6.0), Some("81ZV\r".to_owned()));
+    assert_eq!(Pioneer.zone2_volume_code(-120.0), Some("00ZV\r".to_owned()));
+}
@@ -0,0 +1,639 @@
+//! Integration tests running the web service and telnet tasks in process,
+//! posting the unsigned Alexa requests from `tests/fixtures` and checking the
+//! codes sent to a stand-in AVR replaying `tests/transcripts`, and the speech
+//! sent back.
+use alexa_avr_control::{
+    basic_auth::{self, BasicAuth},
+    config::{
+        AccountLinkingConfig, BasicAuthConfig, DeviceConfig, SkillConfig, SpeechConfig, UserConfig,
+        Verbosity,
+    },
+    device,
+    linking::AccountLinking,
+    pioneer::Pioneer,
+    protocol::AvrProtocol,
+    replay::{self, Recorder},
+    site::{self, Services},
+    speech::Speaker,
+    stats::Stats,
+    telnet::Backoff,
+};
+use hyper::{body, Body, Client, Method, Request};
+use serde_json::Value;
+use std::{collections::BTreeMap, fs, net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
+
+/// Skill running against a stand-in AVR, recording the codes sent to it
+struct Harness {
+    addr: SocketAddr,
+    recording: PathBuf,
+}
+
+impl Harness {
+    /// Start the stand-in AVR replaying the named transcript, then the telnet
+    /// and web service tasks, each on a port picked by the OS
+    async fn start(transcript: &str, verbosity: Verbosity) -> Harness {
+        Harness::start_with(transcript, verbosity, |_, _| {}).await
+    }
+
+    /// Same as `start`, with the skill and AVR configs changed by `setup`
+    async fn start_with(
+        transcript: &str,
+        verbosity: Verbosity,
+        setup: impl FnOnce(&mut SkillConfig, &mut DeviceConfig),
+    ) -> Harness {
+        Harness::start_services(transcript, verbosity, setup, |_| {}).await
+    }
+
+    /// Same as `start_with`, with the web service's services changed by
+    /// `services_setup`
+    async fn start_services(
+        transcript: &str,
+        verbosity: Verbosity,
+        setup: impl FnOnce(&mut SkillConfig, &mut DeviceConfig),
+        services_setup: impl FnOnce(&mut Services),
+    ) -> Harness {
+        let exchanges = replay::load(format!("tests/transcripts/{}.jsonl", transcript)).unwrap();
+        let avr = replay::serve(([127, 0, 0, 1], 0).into(), exchanges)
+            .await
+            .unwrap();
+
+        let recording =
+            std::env::temp_dir().join(format!("skill-{}-{}.jsonl", transcript, std::process::id()));
+        let _ = fs::remove_file(&recording);
+        let recorder = Recorder::create(&recording).unwrap();
+
+        let mut config = DeviceConfig::new("receiver", &avr.ip().to_string(), avr.port());
+        let mut skill = SkillConfig::default();
+        setup(&mut skill, &mut config);
+        let backoff = Backoff {
+            min: Duration::from_millis(100),
+            max: Duration::from_millis(100),
+            connect_timeout: Duration::from_secs(5),
+        };
+        let devices = device::run(
+            vec![config],
+            backoff,
+            false,
+            Some(recorder),
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+
+        let speech = SpeechConfig {
+            verbosity,
+            ..SpeechConfig::default()
+        };
+        let mut services = Services {
+            devices,
+            speaker: Speaker::new(&speech).unwrap(),
+            skill: Arc::new(skill),
+            smart_home: None,
+            google: None,
+            api: None,
+            basic_auth: None,
+            linking: None,
+            history: None,
+            access_log: None,
+            stats: Stats::new(),
+            verify: false,
+        };
+        services_setup(&mut services);
+        let (addr, server) = site::bind(([127, 0, 0, 1], 0).into(), services).unwrap();
+        tokio::spawn(server);
+
+        Harness { addr, recording }
+    }
+
+    /// Post the named fixture and return the response
+    async fn post(&self, fixture: &str) -> Value {
+        let body = fs::read(format!("tests/fixtures/{}.json", fixture)).unwrap();
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(format!("http://{}/", self.addr))
+            .body(Body::from(body))
+            .unwrap();
+
+        let response = Client::new().request(request).await.unwrap();
+        assert!(response.status().is_success());
+        let body = body::to_bytes(response.into_body()).await.unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    /// Codes sent to the AVR so far
+    fn codes(&self) -> Vec<String> {
+        replay::load(&self.recording)
+            .unwrap()
+            .into_iter()
+            .map(|exchange| exchange.code)
+            .collect()
+    }
+}
+
+impl Drop for Harness {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.recording);
+    }
+}
+
+fn speech(response: &Value) -> &str {
+    response["response"]["outputSpeech"]["text"]
+        .as_str()
+        .unwrap_or_default()
+}
+
+#[tokio::test]
+async fn launch() {
+    let harness = Harness::start("pioneer_power_on", Verbosity::Terse).await;
+    let response = harness.post("launch").await;
+
+    assert_eq!(speech(&response), "What can I do for you?");
+    assert_eq!(response["response"]["shouldEndSession"], false);
+    assert!(harness.codes().is_empty());
+}
+
+#[tokio::test]
+async fn power_on() {
+    let harness = Harness::start("pioneer_power_on", Verbosity::Terse).await;
+    let response = harness.post("on").await;
+
+    assert_eq!(speech(&response), "Ok.");
+    assert_eq!(response["response"]["shouldEndSession"], true);
+    assert_eq!(harness.codes(), ["?P\r", "PO\r", "?P\r"]);
+}
+
+#[tokio::test]
+async fn keep_open() {
+    let harness = Harness::start_with("pioneer_power_on", Verbosity::Terse, |skill, _| {
+        skill.keep_open = true;
+    })
+    .await;
+    let response = harness.post("on").await;
+
+    assert_eq!(speech(&response), "Ok.");
+    assert_eq!(response["response"]["shouldEndSession"], false);
+    assert_eq!(
+        response["response"]["reprompt"]["outputSpeech"]["text"],
+        "Anything else?"
+    );
+}
+
+#[tokio::test]
+async fn power_already_on() {
+    let harness = Harness::start("pioneer_power_already_on", Verbosity::Terse).await;
+    let response = harness.post("on").await;
+
+    assert_eq!(speech(&response), "Power is already on.");
+    assert_eq!(harness.codes(), ["?P\r"]);
+}
+
+#[tokio::test]
+async fn mute() {
+    let harness = Harness::start("pioneer_mute", Verbosity::Normal).await;
+    let response = harness.post("mute").await;
+
+    assert_eq!(speech(&response), "Ok, muted.");
+    assert_eq!(harness.codes(), ["?P\r", "MO\r", "?M\r"]);
+}
+
+#[tokio::test]
+async fn mute_for() {
+    let harness = Harness::start("pioneer_mute_for", Verbosity::Normal).await;
+    let response = harness.post("mute_for").await;
+    assert_eq!(speech(&response), "Ok, muted for 5 minutes.");
+    assert_eq!(harness.codes(), ["?P\r", "MO\r", "?M\r"]);
+}
+
+#[tokio::test]
+async fn mute_for_seconds() {
+    let harness = Harness::start("pioneer_mute_for", Verbosity::Normal).await;
+    let response = harness.post("mute_for_seconds").await;
+    assert_eq!(speech(&response), "Ok, muted for a second.");
+
+    // Unmuted once the second is up
+    tokio::time::sleep(Duration::from_millis(1_500)).await;
+    assert_eq!(harness.codes(), ["?P\r", "MO\r", "?M\r", "MF\r", "?M\r"]);
+}
+
+#[tokio::test]
+async fn timer() {
+    let harness = Harness::start("pioneer_timer", Verbosity::Normal).await;
+    let response = harness.post("timer").await;
+    assert_eq!(speech(&response), "Ok, in 5 minutes.");
+    assert!(harness.codes().is_empty());
+}
+
+#[tokio::test]
+async fn timer_seconds() {
+    let harness = Harness::start("pioneer_timer", Verbosity::Normal).await;
+    let response = harness.post("timer_seconds").await;
+    assert_eq!(speech(&response), "Ok, in a second.");
+    assert!(harness.codes().is_empty());
+
+    // Muted once the second is up
+    tokio::time::sleep(Duration::from_millis(1_500)).await;
+    assert_eq!(harness.codes(), ["?P\r", "MO\r", "?M\r"]);
+}
+
+#[tokio::test]
+async fn timer_not_allowed() {
+    let harness = Harness::start_with("pioneer_timer", Verbosity::Normal, |skill, _| {
+        skill.users.push(UserConfig {
+            id: "amzn1.ask.account.0000".to_owned(),
+            name: Some("kids".to_owned()),
+            max_volume: None,
+            default_input: None,
+            intents: Some(vec!["Timer".to_owned()]),
+        });
+    })
+    .await;
+    let response = harness.post("timer_seconds").await;
+    assert_eq!(speech(&response), "Sorry, you can't do that.");
+
+    // Nothing was put on a timer
+    tokio::time::sleep(Duration::from_millis(1_500)).await;
+    assert!(harness.codes().is_empty());
+}
+
+#[tokio::test]
+async fn set_volume() {
+    let harness = Harness::start("pioneer_set_volume", Verbosity::Normal).await;
+    let response = harness.post("volume").await;
+
+    assert_eq!(speech(&response), "Ok, volume 5.");
+    assert_eq!(
+        harness.codes(),
+        [
+            "?P\r",
+            "?V\r",
+            "051VL\r",
+            "VU\r\nVU\r\nVU\r\nVU\r\nVU\r\n",
+            "?V\r",
+        ]
+    );
+}
+
+#[tokio::test]
+async fn set_volume_verbose() {
+    let harness = Harness::start("pioneer_set_volume", Verbosity::Verbose).await;
+    let response = harness.post("volume").await;
+
+    assert_eq!(
+        speech(&response),
+        "Ok, power is on and volume is 5, that's minus 55 decibels."
+    );
+}
+
+#[tokio::test]
+async fn duplicate_delivery() {
+    let harness = Harness::start("pioneer_power_on", Verbosity::Terse).await;
+    harness.post("on").await;
+    let response = harness.post("on").await;
+
+    assert_eq!(speech(&response), "Ok.");
+    assert_eq!(harness.codes(), ["?P\r", "PO\r", "?P\r"]);
+}
+
+#[tokio::test]
+async fn replayed_request() {
+    let harness = Harness::start_with("pioneer_power_on", Verbosity::Terse, |skill, _| {
+        skill.debounce = 0;
+    })
+    .await;
+    harness.post("on").await;
+
+    let body = fs::read("tests/fixtures/on.json").unwrap();
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(format!("http://{}/", harness.addr))
+        .body(Body::from(body))
+        .unwrap();
+    let response = Client::new().request(request).await.unwrap();
+
+    assert_eq!(response.status(), 400);
+    assert_eq!(harness.codes(), ["?P\r", "PO\r", "?P\r"]);
+}
+
+#[tokio::test]
+async fn stats() {
+    let harness = Harness::start("pioneer_power_already_on", Verbosity::Terse).await;
+    harness.post("launch").await;
+    harness.post("on").await;
+
+    let uri = format!("http://{}/stats", harness.addr).parse().unwrap();
+    let response = Client::new().get(uri).await.unwrap();
+    let body = body::to_bytes(response.into_body()).await.unwrap();
+    let stats: Value = serde_json::from_slice(&body).unwrap();
+
+    assert_eq!(stats["total"]["requests"], 2);
+    assert_eq!(stats["intents"]["LaunchRequest"]["succeeded"], 1);
+    assert_eq!(stats["intents"]["On"]["failed"], 1);
+}
+
+#[tokio::test]
+async fn latency() {
+    let harness = Harness::start("pioneer_power_on", Verbosity::Terse).await;
+    harness.post("on").await;
+
+    let uri = format!("http://{}/stats", harness.addr).parse().unwrap();
+    let response = Client::new().get(uri).await.unwrap();
+    let body = body::to_bytes(response.into_body()).await.unwrap();
+    let stats: Value = serde_json::from_slice(&body).unwrap();
+
+    let latency = &stats["latency"]["receiver"]["PowerOn"];
+    assert_eq!(latency["count"], 1);
+    assert!(latency["p50_ms"].as_u64() <= latency["p99_ms"].as_u64());
+}
+
+#[tokio::test]
+async fn basic_auth() {
+    let basic_auth = BasicAuth::new(BasicAuthConfig {
+        username: "admin".to_owned(),
+        password: basic_auth::hash("hunter2"),
+    })
+    .unwrap();
+    let harness = Harness::start_services(
+        "pioneer_power_already_on",
+        Verbosity::Terse,
+        |_, _| {},
+        |services| services.basic_auth = Some(Arc::new(basic_auth)),
+    )
+    .await;
+
+    let get = |path: &str, credentials: Option<&str>| {
+        let mut request = Request::get(format!("http://{}{}", harness.addr, path));
+        if let Some(credentials) = credentials {
+            request = request.header("Authorization", format!("Basic {}", credentials));
+        }
+        Client::new().request(request.body(Body::empty()).unwrap())
+    };
+    // admin:hunter2 and admin:wrong
+    let response = get("/stats", None).await.unwrap();
+    assert_eq!(response.status(), 401);
+    assert!(response.headers().contains_key("WWW-Authenticate"));
+    let response = get("/stats", Some("YWRtaW46d3Jvbmc=")).await.unwrap();
+    assert_eq!(response.status(), 401);
+    let response = get("/stats", Some("YWRtaW46aHVudGVyMg==")).await.unwrap();
+    assert_eq!(response.status(), 200);
+    let response = get("/health", None).await.unwrap();
+    assert_ne!(response.status(), 401);
+}
+
+#[tokio::test]
+async fn account_not_linked() {
+    let harness = Harness::start_services(
+        "pioneer_power_on",
+        Verbosity::Terse,
+        |_, _| {},
+        |services| {
+            let config = AccountLinkingConfig {
+                introspection_url: "https://127.0.0.1:1/introspect".to_owned(),
+                client_id: "alexa-avr-control".to_owned(),
+                client_secret: "secret".to_owned(),
+                accounts: BTreeMap::new(),
+            };
+            let linking = AccountLinking::new(config, &services.devices).unwrap();
+            services.linking = Some(Arc::new(linking));
+        },
+    )
+    .await;
+
+    let response = harness.post("on").await;
+    assert_eq!(response["response"]["card"]["type"], "LinkAccount");
+    assert_eq!(
+        speech(&response),
+        "To control your receiver, link your account in the Alexa app."
+    );
+    assert!(harness.codes().is_empty());
+}
+
+#[tokio::test]
+async fn account_not_allowed() {
+    let harness = Harness::start_with("pioneer_power_on", Verbosity::Terse, |skill, _| {
+        skill.allowed_accounts = vec!["amzn1.ask.account.1111".to_owned()];
+    })
+    .await;
+
+    let response = harness.post("on").await;
+    assert_eq!(
+        speech(&response),
+        "Sorry, this skill isn't set up for your account."
+    );
+    assert!(harness.codes().is_empty());
+}
+
+#[tokio::test]
+async fn user_not_allowed() {
+    let harness = Harness::start_with("pioneer_power_on", Verbosity::Terse, |skill, _| {
+        skill.users.push(UserConfig {
+            id: "amzn1.ask.account.0000".to_owned(),
+            name: Some("kids".to_owned()),
+            max_volume: Some(5),
+            default_input: None,
+            intents: Some(vec!["Volume".to_owned()]),
+        });
+    })
+    .await;
+    let response = harness.post("on").await;
+
+    assert_eq!(speech(&response), "Sorry, you can't do that.");
+    assert!(harness.codes().is_empty());
+}
+
+#[tokio::test]
+async fn input_alias() {
+    let harness = Harness::start_with("pioneer_change_input", Verbosity::Normal, |_, device| {
+        device
+            .aliases
+            .insert("Game".to_owned(), vec!["PlayStation".to_owned()]);
+    })
+    .await;
+    let response = harness.post("input_name").await;
+
+    assert_eq!(speech(&response), "Ok, input Game.");
+    assert_eq!(harness.codes(), ["?P\r", "49FN\r", "?F\r"]);
+}
+
+#[tokio::test]
+async fn input_not_in_profile() {
+    let harness = Harness::start_with("pioneer_mute", Verbosity::Normal, |_, device| {
+        device.profile = Some("VSX-1021".to_owned());
+        device
+            .aliases
+            .insert("Game".to_owned(), vec!["PlayStation".to_owned()]);
+    })
+    .await;
+    let response = harness.post("input_name").await;
+
+    assert_eq!(
+        speech(&response),
+        "Sorry, this receiver doesn't have input 2."
+    );
+    assert!(harness.codes().is_empty());
+}
+
+#[tokio::test]
+async fn list_inputs() {
+    let harness = Harness::start_with("pioneer_mute", Verbosity::Normal, |_, device| {
+        let shown = ["BD", "Game", "HDMI 1", "Tuner"];
+        device.hidden = Pioneer
+            .inputs()
+            .into_iter()
+            .filter(|name| !shown.contains(&name.as_str()))
+            .collect();
+    })
+    .await;
+    let response = harness.post("list_inputs").await;
+
+    assert_eq!(
+        speech(&response),
+        "The inputs are BD, Game, HDMI 1 and Tuner."
+    );
+    assert!(harness.codes().is_empty());
+}
+
+#[tokio::test]
+async fn activity() {
+    let harness = Harness::start("pioneer_activity", Verbosity::Normal).await;
+    let response = harness.post("activity").await;
+
+    // Already on, so only the input and volume are changed
+    assert_eq!(
+        speech(&response),
+        "Ok, power is on, input is now HDMI 1 and volume is 5, that's minus 55 decibels."
+    );
+    assert_eq!(
+        harness.codes(),
+        ["?P\r", "19FN\r", "?F\r", "?V\r", "051VL\r", "?V\r"]
+    );
+}
+
+#[tokio::test]
+async fn activity_not_allowed() {
+    let harness = Harness::start_with("pioneer_activity", Verbosity::Normal, |skill, _| {
+        skill.users.push(UserConfig {
+            id: "amzn1.ask.account.0000".to_owned(),
+            name: Some("kids".to_owned()),
+            max_volume: None,
+            default_input: None,
+            intents: Some(vec![
+                "Activity".to_owned(),
+                "On".to_owned(),
+                "Input".to_owned(),
+            ]),
+        });
+    })
+    .await;
+    let response = harness.post("activity").await;
+
+    // Volume isn't allowed, so not even the input is changed
+    assert_eq!(speech(&response), "Sorry, you can't do that.");
+    assert!(harness.codes().is_empty());
+}
+
+#[tokio::test]
+async fn switch_back() {
+    let harness = Harness::start_with("pioneer_switch_back", Verbosity::Normal, |_, device| {
+        device
+            .aliases
+            .insert("Game".to_owned(), vec!["PlayStation".to_owned()]);
+    })
+    .await;
+    harness.post("input_name").await;
+    let response = harness.post("switch_back").await;
+
+    assert_eq!(speech(&response), "Ok, input HDMI 1.");
+    assert_eq!(
+        harness.codes(),
+        ["?P\r", "49FN\r", "?F\r", "19FN\r", "?F\r"]
+    );
+}
+
+#[tokio::test]
+async fn restore_volume() {
+    let harness = Harness::start("pioneer_restore_volume", Verbosity::Normal).await;
+    harness.post("volume").await;
+    let response = harness.post("restore_volume").await;
+
+    assert_eq!(speech(&response), "Ok, volume minus 60 decibels.");
+    assert_eq!(
+        harness.codes(),
+        ["?P\r", "?V\r", "051VL\r", "?V\r", "?V\r", "041VL\r", "?V\r"]
+    );
+}
+
+#[tokio::test]
+async fn undo() {
+    let harness = Harness::start("pioneer_restore_volume", Verbosity::Normal).await;
+    harness.post("volume").await;
+    let response = harness.post("undo").await;
+
+    assert_eq!(speech(&response), "Ok, that's undone.");
+    assert_eq!(
+        harness.codes(),
+        ["?P\r", "?V\r", "051VL\r", "?V\r", "?V\r", "041VL\r", "?V\r"]
+    );
+}
+
+#[tokio::test]
+async fn speaker_system() {
+    let harness = Harness::start("pioneer_power_on", Verbosity::Terse).await;
+    let response = harness.post("speaker_system").await;
+
+    // Always confirmed first, so nothing is sent yet
+    assert_eq!(
+        speech(&response),
+        "That changes which speakers play. Are you sure?"
+    );
+    assert_eq!(
+        response["sessionAttributes"]["pending_command"],
+        r#"{"SetSpeakerSystem":"04"}"#
+    );
+    assert!(harness.codes().is_empty());
+}
+
+#[tokio::test]
+async fn identify() {
+    let harness = Harness::start("pioneer_identify", Verbosity::Normal).await;
+    let response = harness.post("identify").await;
+
+    assert_eq!(speech(&response), "It's a VSX-1021, on firmware 1-038-07.");
+    assert_eq!(harness.codes(), ["?RGD\r", "?SSI\r"]);
+}
+
+#[tokio::test]
+async fn debug_error() {
+    let harness = Harness::start_with("pioneer_power_on", Verbosity::Terse, |skill, _| {
+        skill.debug = true;
+    })
+    .await;
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(format!("http://{}/", harness.addr))
+        .body(Body::from("{}"))
+        .unwrap();
+    let response = Client::new().request(request).await.unwrap();
+
+    assert_eq!(response.status(), 400);
+    let body = body::to_bytes(response.into_body()).await.unwrap();
+    let error: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(error["error"]["code"], "bad_request");
+}
+
+#[tokio::test]
+async fn openapi() {
+    let harness = Harness::start("pioneer_power_on", Verbosity::Terse).await;
+
+    let uri = format!("http://{}/api/openapi.json", harness.addr)
+        .parse()
+        .unwrap();
+    let response = Client::new().get(uri).await.unwrap();
+    let body = body::to_bytes(response.into_body()).await.unwrap();
+    let spec: Value = serde_json::from_slice(&body).unwrap();
+
+    assert_eq!(spec["openapi"], "3.0.3");
+    assert!(spec["paths"]["/stats"]["get"].is_object());
+    // The control API isn't configured
+    assert!(spec["paths"]["/api/command"].is_null());
+}